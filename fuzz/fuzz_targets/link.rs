@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use leaf_common::leaf_file::LeafAsmObject;
+
+fuzz_target!(|objects: Vec<LeafAsmObject>| {
+  let _ = leaf_asm::fuzz::try_link(&objects);
+});
@@ -1,16 +1,163 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use log::info;
-use leaf_common::leaf_ast::{Arg, Line, OpCode};
-use leaf_common::leaf_file::{LeafAsmObject, RelocationEntry, RelocationType, SymbolEntry};
+use leaf_common::diagnostic::{nearest, Diagnostic, Span};
+use leaf_common::interner::{Interner, Symbol};
+use leaf_common::isa::OperandKind;
+use leaf_common::leaf_ast::{Arg, Instruction, Line, OpCode};
+use leaf_common::limits::DecodeLimits;
+use leaf_common::leaf_file::{Addend, DataRange, DebugInfo, LeafAsmObject, LineEntry, RelocationEntry, RelocationType, SectionFlags, SymbolEntry};
+use leaf_common::opcode::{AddressOperand, ADDRESS_MODES_SINCE, ISA_VERSION};
+use leaf_common::symver;
+use leaf_common::syscall;
+use crate::parser::{parse_integer, parse_word, strip_separators};
 
+/// Registers `r0` to `r31` (LDR-005).
+pub const REGISTER_COUNT: u8 = 32;
+
+/// Every directive, including the section and symbol ones the parser turns into their own lines,
+/// with the ISA revision that introduced it.
+pub const DIRECTIVES: &[(&str, u16)] = &[
+  ("text", 1), ("data", 1), ("rodata", 1), ("section", 1), ("global", 1), ("extern", 1),
+  ("word", 1), ("string", 1), ("asciiz", 1), ("ascii", 1), ("if", 1), ("else", 1), ("endif", 1), ("while", 1), ("endwhile", 1),
+  ("struct", 1), ("field", 1), ("endstruct", 1), ("align", 1), ("space", 1), ("zero", 1), ("equ", 1),
+  ("include", 1), ("alias", 1), ("utf8", 1), ("float", 1), ("double", 1),
+];
+
+/// Directives that open, continue or close a `.if` or `.while` block.
+const BLOCK_DIRECTIVES: &[&str] = &["if", "else", "endif", "while", "endwhile"];
+
+/// Field types besides structures, with their size and alignment.
+const FIELD_TYPES: &[(&str, u32)] = &[("byte", 1), ("word", 8)];
+
+/// Assembles a program in a single pass. Label operands become relocations that are tied to symbol
+/// table entries in `finish`, so forward references need no second pass and lines can be fed one at
+/// a time without keeping the AST around.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Assembler {
   symbol_table: Vec<SymbolEntry>,
-  labels: HashMap<String, (u8, u32)>, // name -> (section, offset)
+  labels: HashMap<Symbol, (u8, u32)>, // name -> (section, offset)
+  names: Interner,
+  /// First symbol table entry for each name, which is the one relocations refer to.
+  symbol_index: HashMap<Symbol, u32>,
   code: Vec<u8>,
   data: Vec<u8>,
   rodata: Vec<u8>,
-  relocations: Vec<RelocationEntry>,
+  /// Label operands seen so far, resolved against the symbol table in `finish`.
+  pending: Vec<PendingRelocation>,
+  section: u8, // 0 = .text, 1 = .data, 2 = .rodata
+  /// Permissions given with `.section NAME, "flags"`, by section.
+  section_flags: [Option<SectionFlags>; 3],
+  /// Source location of each line of the program, if known.
+  spans: Vec<Span>,
+  diagnostics: Vec<Diagnostic>,
+  /// Line table for `.text`, filled in when spans are available.
+  debug_info: DebugInfo,
+  /// Labels and `.extern`s in the order they appear, checked for use in `finish`.
+  declarations: Vec<Declaration>,
+  /// Names listed by `.global`, which other objects may use.
+  globals: HashSet<Symbol>,
+  /// Labels a `.equ` measured from with `$ - label`, which uses them without a relocation.
+  measured: HashSet<Symbol>,
+  /// Offset of every instruction in `.text`, in order.
+  instructions: Vec<u32>,
+  /// Where `.text` holds data from `.word`, `.string` and `.ascii`.
+  data_in_text: Vec<DataRange>,
+  /// Newest ISA revision the program may use.
+  target_version: u16,
+  /// Newest ISA revision the program has used so far.
+  required_version: u16,
+  /// Reject what is otherwise let through for older sources, see `with_strict`.
+  strict: bool,
+  /// `.if` and `.while` blocks that have not been closed yet, innermost last.
+  blocks: Vec<OpenBlock>,
+  /// Blocks opened so far, which numbers their labels.
+  block_count: usize,
+  /// Names that stand for a number rather than an address, such as `POINT.x`.
+  constants: HashMap<Symbol, u32>,
+  /// Register names given with `.alias`, such as `sp` for `r7`.
+  aliases: HashMap<Symbol, u8>,
+  /// Size and alignment of each structure defined so far.
+  structs: HashMap<String, (u32, u32)>,
+  /// The `.struct` whose fields are being listed.
+  open_struct: Option<OpenStruct>,
+  /// The lines the program expanded to, with where each came from, if `with_expansion` asked for them.
+  expansion: Option<Vec<(Line<'static>, Option<Span>)>>,
+  /// The last label that is not local, which local labels belong to.
+  scope: String,
+  /// How often each numeric local label has been defined since `scope` was.
+  numeric_labels: HashMap<u32, u32>,
+  /// Local labels as the source wrote them, for diagnostics.
+  local_names: HashMap<Symbol, String>,
+}
+
+/// A `.struct` up to its `.endstruct`. Fields are laid out in order, each aligned to its type, and
+/// the size is rounded up to the largest alignment so arrays of the structure stay aligned.
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct OpenStruct {
+  name: String,
+  /// Each field with its offset.
+  fields: Vec<(String, u32)>,
+  size: u32,
+  align: u32,
+  span: Option<Span>,
+}
+
+/// A `.if` or `.while` block. They expand to branches to labels named `@if.N.else`, `@if.N.end`,
+/// `@while.N.top` and `@while.N.end`, which cannot clash with labels written in the source and
+/// are not symbols, so every object can number its blocks from 0.
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct OpenBlock {
+  kind: BlockKind,
+  id: usize,
+  /// Whether a `.if` has had its `.else`.
+  has_else: bool,
+  span: Option<Span>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum BlockKind {
+  If,
+  While,
+}
+
+impl BlockKind {
+  fn name(self) -> &'static str {
+    match self {
+      BlockKind::If => "if",
+      BlockKind::While => "while",
+    }
+  }
+
+  fn label(self, id: usize, part: &str) -> String {
+    format!("@{}.{}.{}", self.name(), id, part)
+  }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct Declaration {
+  name: Symbol,
+  external: bool,
+  span: Option<Span>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct PendingRelocation {
+  offset: u32,
+  name: Symbol,
+  section: u8,
+  span: Option<Span>,
+  /// The instruction, if this is the target of a jump or call.
+  branch: Option<OpCode>,
+  kind: RelocationType,
+  /// Added to the label's address, as in `loop+8`.
+  addend: i32,
+}
+
+impl Default for Assembler {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Assembler {
@@ -18,226 +165,990 @@ impl Assembler {
     Self {
       symbol_table: Vec::new(),
       labels: HashMap::new(),
+      names: Interner::new(),
+      symbol_index: HashMap::new(),
       code: Vec::new(),
       data: Vec::new(),
       rodata: Vec::new(),
-      relocations: Vec::new(),
+      pending: Vec::new(),
+      section: 0,
+      section_flags: [None; 3],
+      spans: Vec::new(),
+      diagnostics: Vec::new(),
+      debug_info: DebugInfo::default(),
+      declarations: Vec::new(),
+      globals: HashSet::new(),
+      measured: HashSet::new(),
+      instructions: Vec::new(),
+      data_in_text: Vec::new(),
+      target_version: ISA_VERSION,
+      required_version: 1,
+      strict: false,
+      blocks: Vec::new(),
+      block_count: 0,
+      constants: HashMap::new(),
+      aliases: HashMap::new(),
+      structs: HashMap::new(),
+      open_struct: None,
+      expansion: None,
+      scope: String::new(),
+      numeric_labels: HashMap::new(),
+      local_names: HashMap::new(),
     }
   }
 
-  pub fn assemble(program: &[Line], entry_point: Option<String>) -> LeafAsmObject {
-    let mut assembler = Assembler::new();
-    assembler.first_pass(program);
-    assembler.second_pass(program);
-    LeafAsmObject {
-      bytecode: assembler.code,
-      data: assembler.data,
-      rodata: assembler.rodata,
-      symbols: assembler.symbol_table,
-      entry_point,
-      relocations: assembler.relocations,
-      debug_info: None,
+  /// Attach source locations, where `spans[i]` belongs to line `i` of the program.
+  pub fn with_spans(mut self, spans: Vec<Span>) -> Self {
+    self.spans = spans;
+    self
+  }
+
+  /// Reject instructions and directives from ISA revisions after `version`.
+  pub fn with_target_version(mut self, version: u16) -> Self {
+    self.target_version = version;
+    self
+  }
+
+  /// Also reject what older sources get away with: instructions outside `.text`, and operands of
+  /// built-in instructions that are not what the instruction takes, such as an immediate where a
+  /// register belongs.
+  pub fn with_strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
+  }
+
+  /// Keep the lines the program expands to, for `expanded_source`.
+  pub fn with_expansion(mut self) -> Self {
+    self.expansion = Some(Vec::new());
+    self
+  }
+
+  /// Assemble `program`, returning every diagnostic if any of them is an error.
+  pub fn assemble(program: &[Line], entry_point: Option<String>) -> Result<LeafAsmObject, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    Assembler::new().assemble_program(program, entry_point, &mut diagnostics).ok_or(diagnostics)
+  }
+
+  /// Assemble `program`, appending errors and warnings to `diagnostics`.
+  /// Returns `None` if an error was reported.
+  pub fn assemble_program(
+    mut self,
+    program: &[Line],
+    entry_point: Option<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+  ) -> Option<LeafAsmObject> {
+    let spans = std::mem::take(&mut self.spans);
+    for (index, line) in program.iter().enumerate() {
+      self.feed(line, spans.get(index).cloned());
     }
+    self.finish(entry_point, diagnostics)
   }
 
-  /// First pass: Collect all label definitions and externals
-  pub fn first_pass(&mut self, program: &[Line]) {
-    let mut pos = [0u32; 3]; // code, data, rodata
-    let mut section = 0u8; // 0 = .text, 1 = .data, 2 = .rodata
+  /// Whether a label with this name has been defined so far.
+  pub fn is_defined(&self, label: &str) -> bool {
+    self.names.get(label).is_some_and(|name| self.labels.contains_key(&name))
+  }
 
-    for line in program {
-      info!("ℹ️ Handling line: {:?}", line);
-      match line {
-        Line::Section(s) => {
-          section = match s.as_str() {
-            ".text" => 0,
-            ".data" => 1,
-            ".rodata" => 2,
-            _ => section,
-          };
-        }
-        Line::LabelOnly(label) => {
-          self.labels.insert(label.clone(), (section, pos[section as usize]));
-          self.symbol_table.push(SymbolEntry {
-            name: label.clone(),
-            offset: pos[section as usize],
-            section,
-            kind: section, // kind: 0 = code label, 1 = data, 2 = rodata
-            external: false,
-          });
-        }
-        Line::Instruction(instr) => {
-          if let Some(label) = &instr.label {
-            self.labels.insert(label.clone(), (section, pos[section as usize]));
-            self.symbol_table.push(SymbolEntry {
-              name: label.clone(),
-              offset: pos[section as usize],
-              section,
-              kind: section,
-              external: false,
-            });
+  /// Oldest ISA revision that can run the lines fed so far, for the object header.
+  pub fn required_version(&self) -> u16 {
+    self.required_version
+  }
+
+  /// Diagnostics reported by the lines fed so far.
+  pub fn diagnostics(&self) -> &[Diagnostic] {
+    &self.diagnostics
+  }
+
+  /// The lines fed so far as the assembler consumed them, with `.if` and `.while` blocks replaced by
+  /// the branches and labels they expand to, or `None` without `with_expansion`. A `;#line N "file"`
+  /// comment gives the origin of the next line whenever it is not the line after the previous one.
+  /// Generated labels keep the `@` names that cannot clash with the source's, so the text is for
+  /// reading rather than assembling again.
+  pub fn expanded_source(&self) -> Option<String> {
+    let mut source = String::new();
+    let mut next = None;
+    for (line, span) in self.expansion.as_ref()? {
+      if let Some(span) = span {
+        let origin = (span.file.as_deref(), span.line);
+        if next != Some(origin) {
+          match span.file.as_deref() {
+            Some(file) => source.push_str(&format!(";#line {} \"{}\"\n", span.line, file)),
+            None => source.push_str(&format!(";#line {}\n", span.line)),
           }
-          if section == 0 {
-            // .text: opcode + 4 bytes per arg
-            pos[0] += 1 + 4 * instr.args.len() as u32;
+        }
+        next = Some((origin.0, origin.1 + 1));
+      }
+      let indent = if matches!(line, Line::Instruction(_) | Line::Directive(_)) { "  " } else { "" };
+      source.push_str(&format!("{}{}\n", indent, line));
+    }
+    Some(source)
+  }
+
+  /// Assemble one line; `span` is where it came from, for diagnostics and the line table.
+  pub fn feed(&mut self, line: &Line, span: Option<Span>) {
+    info!("ℹ️ Handling line: {:?}", line);
+    let is_block = matches!(line, Line::Directive(d) if BLOCK_DIRECTIVES.contains(&d.name.as_ref()));
+    if let Some(expansion) = &mut self.expansion && !is_block {
+      expansion.push((line.clone().into_owned(), span.clone()));
+    }
+    let section = self.section;
+    if let Some(open) = &self.open_struct
+      && !matches!(line, Line::Directive(d) if d.name == "field" || d.name == "endstruct") {
+      self.diagnostics.push(
+        Diagnostic::error("struct-body", format!("Only `.field` lines can be inside `.struct {}`", open.name))
+          .with_span(span.clone())
+          .with_note("end the structure with `.endstruct` first"),
+      );
+      return;
+    }
+    match line {
+      Line::Section(s) => {
+        let (name, flags) = match s.split_once(',') {
+          Some((name, flags)) => (name.trim(), Some(flags.trim())),
+          None => (s.as_ref(), None),
+        };
+        self.section = match name {
+          ".text" => 0,
+          ".data" => 1,
+          ".rodata" => 2,
+          _ => {
+            self.diagnostics.push(
+              Diagnostic::error("unknown-section", format!("Unknown section '{}'", s))
+                .with_span(span.clone())
+                .with_note("sections are .text, .data and .rodata"),
+            );
+            return;
           }
-          // You could support data/rodata instructions if your ISA requires
+        };
+        if let Some(flags) = flags {
+          self.set_section_flags(flags, &span);
         }
-        Line::Extern(label) => {
-          self.symbol_table.push(SymbolEntry {
-            name: label.clone(),
-            offset: 0,
-            section: 0,
-            kind: 0,
-            external: true,
-          });
+      }
+      Line::LabelOnly(label) => self.define_label(label, &span),
+      Line::Extern(names) => {
+        for label in names {
+          self.declare_extern(label, &span);
+        }
+      }
+      // Every label is exported; `.global` just says it is meant to be
+      Line::Global(names) => {
+        for name in names {
+          let name = self.names.intern(name);
+          self.globals.insert(name);
         }
-        Line::Directive(d) => {
-          // .word and .ascii directives may exist in data or rodata sections
-          match d.name.as_str() {
-            "word" => {
-              if let Some(args) = &d.args {
-                let before_comment = args.split(';').next().unwrap_or("").trim();
-                let word_count = before_comment.split_whitespace().count();
-                pos[section as usize] += (word_count as u32) * 8;
+      }
+      Line::Directive(d) => {
+        if let Some(&(name, since)) = DIRECTIVES.iter().find(|(name, _)| *name == d.name) {
+          self.require(&format!(".{}", name), since, &span);
+        }
+        // .word and .ascii directives may exist in data or rodata sections
+        match d.name.as_ref() {
+          "word" => {
+            if let Some(args) = &d.args {
+              let before_comment = args.split(';').next().unwrap_or("").trim();
+              for num in before_comment.split_whitespace() {
+                match parse_word(num) {
+                  Some(val) => self.append_data(section, &val.to_le_bytes()),
+                  None => self.diagnostics.push(
+                    Diagnostic::error("invalid-word", format!("Invalid .word value '{}': expected an integer", num))
+                      .with_span(span.clone()),
+                  ),
+                }
               }
             }
-            "string" => {
-              if let Some(args) = &d.args {
-                let s = args.split(';').next().unwrap_or("").trim().trim_matches('"');
-                let parsed_bytes = parse_escaped_string(s);
-                pos[section as usize] += (parsed_bytes.len() as u32) + 1;
+          }
+          // `.asciiz` is `.ascii` with a NUL terminator, as `.string` has always been. They write one
+          // byte per character, so anything past ASCII needs `.utf8`
+          "string" | "asciiz" | "ascii" => {
+            if let Some(args) = &d.args {
+              let text = string_argument(args);
+              if let Some(c) = text.chars().find(|c| !c.is_ascii()) {
+                self.diagnostics.push(
+                  Diagnostic::warning("non-ascii", format!("'{}' in .{} is not ASCII and is cut to one byte", c, d.name))
+                    .with_span(span.clone())
+                    .with_note("use `.utf8` for UTF-8 text"),
+                );
               }
-            }
-            "ascii" => {
-              if let Some(args) = &d.args {
-                let s = args.trim().trim_matches('"');
-                // ONLY increment pos for length, don't push data here!
-                // Should use the escaped length!
-                let parsed_bytes = parse_escaped_string(s);
-                pos[section as usize] += parsed_bytes.len() as u32;
+              let mut parsed_bytes = parse_escaped_string(text);
+              if d.name != "ascii" {
+                parsed_bytes.push(0); // Null terminator
               }
+              self.append_data(section, &parsed_bytes);
             }
-            "extern" => {
-              info!("ℹ️ Found extern directive for: {}", d.args.as_ref().unwrap_or(&"".to_string()));
-              if let Some(args) = &d.args {
-                for label in args.split_whitespace() {
-                  self.symbol_table.push(SymbolEntry {
-                    name: label.to_string(),
-                    offset: 0,
-                    section: 0,
-                    kind: 0, // Extern symbols are not section-specific
-                    external: true,
-                  });
-                }
+          }
+          "utf8" => {
+            if let Some(args) = &d.args {
+              match parse_utf8_string(string_argument(args)) {
+                Ok(bytes) => self.append_data(section, &bytes),
+                Err(message) => self.diagnostics.push(
+                  Diagnostic::error("invalid-utf8", message)
+                    .with_span(span.clone())
+                    .with_note("`\\xNN` writes a single byte; write a character as itself or as `\\u{NNNN}`"),
+                ),
               }
             }
-            _ => {}
+          }
+          "float" | "double" => self.floats(section, &d.name, d.args.as_deref(), &span),
+          "align" => self.align(section, d.args.as_deref(), &span),
+          "space" | "zero" => self.reserve(section, &d.name, d.args.as_deref(), &span),
+          "equ" => self.define_constant(d.args.as_deref(), &span),
+          "alias" => self.define_alias(d.args.as_deref(), &span),
+          // `include::expand_includes` replaces these before the lines get here
+          "include" => self.diagnostics.push(
+            Diagnostic::error("include", "`.include` is not supported here")
+              .with_span(span.clone())
+              .with_note("files are only included by `leaf_asm assemble` and `leaf_asm build`"),
+          ),
+          name if BLOCK_DIRECTIVES.contains(&name) => self.block_directive(&d.name, d.args.as_deref(), &span),
+          "struct" | "field" | "endstruct" => self.struct_directive(&d.name, d.args.as_deref(), &span),
+          name => {
+            let mut diagnostic = Diagnostic::warning("unknown-directive", format!("Unknown directive '.{}'", name))
+              .with_span(span.clone());
+            if let Some(known) = nearest(name, DIRECTIVES.iter().map(|(name, _)| *name)) {
+              diagnostic = diagnostic.with_note(format!("did you mean `.{}`?", known));
+            }
+            self.diagnostics.push(diagnostic);
           }
         }
-        Line::Global(_) => {} // Could be used for exporting symbols (not needed for basic linking)
       }
-    }
-  }
+      Line::Instruction(instr) => {
+        if let Some(label) = &instr.label {
+          self.define_label(label, &span);
+        }
+        if self.strict && section != 0 {
+          self.diagnostics.push(
+            Diagnostic::error("instruction-section", format!("{} is outside .text", instr.opcode))
+              .with_span(span.clone())
+              .with_note("switch to .text first, or use .word for data"),
+          );
+        }
+        let offset = self.section_len(section);
+        if section == 0 && let Some(span) = &span {
+          let file = self.debug_info.file_index(span.file.as_deref().unwrap_or("<input>"));
+          self.debug_info.lines.push(LineEntry { offset, file, line: span.line as u32 });
+        }
+        let mut instr_bytes = Vec::new();
+        let opcode = &instr.opcode;
+        let args = self.resolve_aliases(&instr.args);
+        let args = &args[..];
 
-  /// Second pass: Emit bytes and generate relocations
-  pub fn second_pass(&mut self, program: &[Line]) {
-    let mut section = 0u8; // 0=text, 1=data, 2=rodata
-    let mut pos = [0u32; 3];
+        // Determine the actual opcode to emit (e.g. LOAD -> LOADI if using label/imm)
+        let target_opcode = if args.len() >= 2 {
+          match (opcode, &args[1]) {
+            (OpCode::Load | OpCode::Loadi, Arg::Mem(inner)) => match &**inner { Arg::Register(_) | Arg::RegisterOffset(..) | Arg::Indexed(..) => OpCode::Load, _ => OpCode::Loadi },
+            (OpCode::Store | OpCode::Storei, Arg::Mem(inner)) => match &**inner { Arg::Register(_) | Arg::RegisterOffset(..) | Arg::Indexed(..) => OpCode::Store, _ => OpCode::Storei },
+            _ => *opcode,
+          }
+        } else {
+          *opcode
+        };
 
-    for line in program {
-      match line {
-        Line::Section(s) => {
-          section = match s.as_str() {
-            ".text" => 0,
-            ".data" => 1,
-            ".rodata" => 2,
-            _ => section,
-          };
+        if let Some(info) = target_opcode.info() {
+          self.require(info.mnemonic, info.since, &span);
         }
-        Line::LabelOnly(_) | Line::Extern(_) | Line::Global(_) => {}
-        Line::Directive(d) => {
-          match d.name.as_str() {
-            "word" => {
-              if let Some(args) = &d.args {
-                let before_comment = args.split(';').next().unwrap_or("").trim();
-                for num in before_comment.split_whitespace() {
-                  let val: i64 = num.parse().unwrap();
-                  let bytes = val.to_le_bytes();
-                  self.append_to_section(section, &bytes);
-                  pos[section as usize] += 8;
-                }
-              }
-            }
-            "string" => {
-              if let Some(args) = &d.args {
-                let s = args.split(';').next().unwrap_or("").trim().trim_matches('"');
-                let mut parsed_bytes = parse_escaped_string(s);
-                parsed_bytes.push(0); // Null terminator
-                self.append_to_section(section, &parsed_bytes);
-                pos[section as usize] += parsed_bytes.len() as u32;
-              }
-            }
-            "ascii" => {
-              if let Some(args) = &d.args {
-                let s = args.trim().trim_matches('"');
-                let parsed_bytes = parse_escaped_string(s);
-                self.append_to_section(section, &parsed_bytes);
-                pos[section as usize] += parsed_bytes.len() as u32;
-              }
+
+        // LOAD and STORE only go through memory, so a bare register is not the same as `[r2]`
+        if matches!(opcode, OpCode::Load | OpCode::Store) && let [first, address] = args && !matches!(address, Arg::Mem(_)) {
+          self.diagnostics.push(
+            Diagnostic::error("addressing-mode", format!("{} takes a memory operand, found '{}'", opcode, address))
+              .with_span(span.clone())
+              .with_note(format!("write `{} {}, [{}]`", opcode, first, address)),
+          );
+        }
+
+        // `[x]` is only an address for the memory instructions; anywhere else it would encode as the
+        // plain operand `x` and silently mean something else (LDR-003)
+        for (index, arg) in args.iter().enumerate() {
+          if matches!(arg, Arg::Mem(_)) && !Self::takes_memory_operand(target_opcode, index) {
+            self.diagnostics.push(
+              Diagnostic::error("memory-operand", format!("{} does not take a memory operand, found '{}'", opcode, arg))
+                .with_span(span.clone())
+                .with_note("only the address of LOAD, STORE, LOADI and STOREI can be written as `[...]`"),
+            );
+          }
+        }
+
+        instr_bytes.push(OpCode::opcode_to_byte(&target_opcode));
+        let mut current_instr_pos = offset + 1;
+
+        let operands = target_opcode.operand_count();
+        if args.len() != operands {
+          self.diagnostics.push(
+            Diagnostic::error(
+              "operand-count",
+              format!("{} takes {} operand(s) but {} were given", opcode, operands, args.len()),
+            ).with_span(span.clone()),
+          );
+        }
+        // Built-in instructions are checked when they run unless strict; an extension only has its
+        // ISA description
+        if self.strict || matches!(target_opcode, OpCode::Ext(_)) {
+          for (kind, arg) in target_opcode.operand_kinds().iter().zip(args) {
+            let arg = match arg {
+              Arg::Mem(inner) => &**inner,
+              arg => arg,
+            };
+            let matches = match kind {
+              OperandKind::Register => matches!(arg, Arg::Register(_) | Arg::RegisterOffset(..) | Arg::Indexed(..)),
+              OperandKind::Immediate => matches!(arg, Arg::Immediate(_) | Arg::Label(_) | Arg::Relocated(..) | Arg::LabelOffset(..)),
+            };
+            if !matches {
+              let expected = match kind { OperandKind::Register => "a register", OperandKind::Immediate => "an immediate or label" };
+              self.diagnostics.push(
+                Diagnostic::error("operand-kind", format!("{} expects {}, found '{}'", opcode, expected, arg))
+                  .with_span(span.clone()),
+              );
             }
-            _ => {}
           }
         }
-        Line::Instruction(instr) => {
-          let mut instr_bytes = Vec::new();
-          let opcode = &instr.opcode;
-          let args = &instr.args;
-
-          // Determine the actual opcode to emit (e.g. LOAD -> LOADI if using label/imm)
-          let target_opcode = if args.len() >= 2 {
-            match (opcode, &args[1]) {
-              (OpCode::Load, Arg::Mem(inner)) => match &**inner { Arg::Register(_) => OpCode::Load, _ => OpCode::Loadi },
-              (OpCode::Store, Arg::Mem(inner)) => match &**inner { Arg::Register(_) => OpCode::Store, _ => OpCode::Storei },
-              _ => opcode.clone(),
+        for (index, arg) in args.iter().take(operands).enumerate() {
+          let pending = self.pending.len();
+          self.append_arg(&span, &mut instr_bytes, arg, section, &mut current_instr_pos);
+          if target_opcode.branch_operand() == Some(index) {
+            for reloc in self.pending[pending..].iter_mut().filter(|reloc| reloc.kind == RelocationType::Absolute) {
+              reloc.branch = Some(target_opcode);
             }
-          } else {
-            opcode.clone()
+          }
+        }
+
+        if section == 0 {
+          self.instructions.push(offset);
+        }
+        self.append_to_section(section, &instr_bytes);
+      }
+    }
+  }
+
+  /// Resolve label operands against the symbol table and produce the object. Errors and warnings
+  /// are appended to `diagnostics`; returns `None` if there was an error.
+  pub fn finish(mut self, entry_point: Option<String>, diagnostics: &mut Vec<Diagnostic>) -> Option<LeafAsmObject> {
+    self.warn_unused(entry_point.as_deref());
+    self.check_branch_targets();
+    if let Some(open) = self.open_struct.take() {
+      self.diagnostics.push(
+        Diagnostic::error("unclosed-struct", format!("`.struct {}` is never closed", open.name))
+          .with_span(open.span)
+          .with_note("end it with `.endstruct`"),
+      );
+    }
+    self.section = 0;
+    for block in std::mem::take(&mut self.blocks) {
+      let kind = block.kind.name();
+      self.diagnostics.push(
+        Diagnostic::error("unclosed-block", format!("`.{}` is never closed", kind))
+          .with_span(block.span)
+          .with_note(format!("end it with `.end{}`", kind)),
+      );
+      // Define the label its branch skips to, so that is not reported as undefined as well
+      self.place_hidden(&block.kind.label(block.id, if block.kind == BlockKind::If && !block.has_else { "else" } else { "end" }));
+    }
+    let mut relocations = Vec::with_capacity(self.pending.len());
+    let mut addends = Vec::with_capacity(self.pending.len());
+    let mut section_relocations = Vec::new();
+    for reloc in std::mem::take(&mut self.pending) {
+      let label = self.names.resolve(reloc.name);
+      let symbol = self.symbol_index.get(&reloc.name).map(|&index| (index, reloc.addend, false))
+        // A label that is not a symbol is an offset in its section
+        .or_else(|| self.labels.get(&reloc.name).map(|&(section, offset)| {
+          (section as u32, offset.wrapping_add_signed(reloc.addend) as i32, true)
+        }))
+        // `parse` refers to `parse@@V2` if that is all this file defines
+        .or_else(|| symver::resolve(label, self.symbol_table.iter()
+          .enumerate()
+          .filter(|(_, symbol)| !symbol.external)
+          .map(|(index, symbol)| (symbol.name.as_str(), index as u32)))
+          .map(|index| (index, reloc.addend, false)));
+      match symbol {
+        Some((symbol_idx, addend, section)) => {
+          if section {
+            section_relocations.push(relocations.len() as u32);
+          }
+          relocations.push(RelocationEntry {
+            offset: reloc.offset,
+            symbol_index: symbol_idx,
+            reloc_type: reloc.kind,
+            target_section: reloc.section,
+          });
+          addends.push(addend);
+        }
+        // Constants and syscall names are filled in here unless the program defines a label of
+        // the same name
+        None if let Some(value) = self.constants.get(&reloc.name).copied()
+          .or_else(|| syscall::by_name(label).map(|number| number as u32)) => {
+          let at = reloc.offset as usize;
+          let section = match reloc.section {
+            0 => &mut self.code,
+            1 => &mut self.data,
+            _ => &mut self.rodata,
+          };
+          section[at..at + 4].copy_from_slice(&reloc.kind.value(value.wrapping_add_signed(reloc.addend), 0).to_le_bytes());
+        }
+        None => {
+          let note = match self.local_names.get(&reloc.name) {
+            Some(_) => "local labels can only be used up to the next label that is not local".to_string(),
+            None => format!("declare it with `.extern {}` if it is defined in another object", label),
           };
+          self.diagnostics.push(
+            Diagnostic::error("undefined-symbol", format!("Undefined symbol '{}'", self.written(reloc.name)))
+              .with_span(reloc.span.clone())
+              .with_note(note),
+          );
+        }
+      }
+    }
 
-          instr_bytes.push(OpCode::opcode_to_byte(&target_opcode));
-          let mut current_instr_pos = pos[section as usize] + 1;
+    let failed = self.diagnostics.iter().any(Diagnostic::is_error);
+    diagnostics.append(&mut self.diagnostics);
+    if failed {
+      return None;
+    }
+    Some(LeafAsmObject {
+      bytecode: self.code,
+      data: self.data,
+      rodata: self.rodata,
+      symbols: self.symbol_table,
+      entry_point,
+      relocations,
+      debug_info: (!self.debug_info.lines.is_empty()).then_some(self.debug_info),
+      exports: Vec::new(),
+      data_in_text: self.data_in_text,
+      section_flags: (0..3).map(|section| self.section_flags[section].unwrap_or(SectionFlags::default_for(section as u8))).collect(),
+      segments: Vec::new(),
+      addends: Addend::table(addends),
+      section_relocations,
+    })
+  }
 
-          match target_opcode {
-            // Three register args: OP r1, r2, r3
-            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div |
-            OpCode::And | OpCode::Or | OpCode::Xor |
-            OpCode::Lt | OpCode::Gt | OpCode::Eq => {
-              for i in 0..3 {
-                Self::append_arg(&mut self.relocations, &mut self.symbol_table, &mut instr_bytes, &args[i], section, &mut current_instr_pos);
-              }
-            }
-            // Two register args: OP r1, r2
-            OpCode::Mov | OpCode::Load | OpCode::Store | OpCode::Not | OpCode::Jz | OpCode::Jnz | OpCode::Movi | OpCode::Loadi | OpCode::Storei => {
-              for i in 0..2 {
-                Self::append_arg(&mut self.relocations, &mut self.symbol_table, &mut instr_bytes, &args[i], section, &mut current_instr_pos);
-              }
-            }
-            // One immediate/label: OP imm/label
-            OpCode::Jmp | OpCode::Call | OpCode::Push | OpCode::Pop => {
-              Self::append_arg(&mut self.relocations, &mut self.symbol_table, &mut instr_bytes, &args[0], section, &mut current_instr_pos);
-            }
-            // No args: OP
-            OpCode::Ret | OpCode::Syscall | OpCode::Halt | OpCode::Nop | OpCode::Break => {
-              // No arguments to emit
-            }
-            OpCode::Invalid => {}
+  /// Give the current section the permissions in `flags`, a quoted string such as `"rw"`.
+  fn set_section_flags(&mut self, flags: &str, span: &Option<Span>) {
+    let Some(parsed) = flags.strip_prefix('"').and_then(|f| f.strip_suffix('"')).and_then(SectionFlags::parse) else {
+      self.diagnostics.push(
+        Diagnostic::error("section-flags", format!("Invalid section flags {}", flags))
+          .with_span(span.clone())
+          .with_note("flags are a quoted string of r, w and x, such as \"rw\""),
+      );
+      return;
+    };
+    let current = &mut self.section_flags[self.section as usize];
+    if let Some(earlier) = current.replace(parsed) && earlier != parsed {
+      self.diagnostics.push(
+        Diagnostic::warning("section-flags", format!("Section flags changed from {} to {}", earlier, parsed))
+          .with_span(span.clone())
+          .with_note("the flags given last apply to the whole section"),
+      );
+    }
+  }
+
+  /// Warn about labels and `.extern`s nothing in this file refers to. The entry point and names
+  /// marked `.global` are used from outside, so they never are.
+  fn warn_unused(&mut self, entry_point: Option<&str>) {
+    let mut used: HashSet<Symbol> = self.pending.iter().map(|reloc| reloc.name).collect();
+    used.extend(self.globals.iter().copied());
+    used.extend(self.measured.iter().copied());
+    used.extend(entry_point.and_then(|entry| self.names.get(entry)));
+    for declaration in std::mem::take(&mut self.declarations) {
+      if !used.insert(declaration.name) {
+        continue;
+      }
+      let name = self.written(declaration.name);
+      let diagnostic = if declaration.external {
+        Diagnostic::warning("unused-extern", format!("Extern '{}' is never used", name))
+      } else if self.local_names.contains_key(&declaration.name) {
+        Diagnostic::warning("unused-label", format!("Label '{}' is never used", name))
+      } else {
+        Diagnostic::warning("unused-label", format!("Label '{}' is never used", name))
+          .with_note(format!("mark it `.global {}` if other objects use it", name))
+      };
+      self.diagnostics.push(diagnostic.with_span(declaration.span));
+    }
+  }
+
+  /// Report jumps and calls to labels in this file that are not on an instruction in `.text`,
+  /// such as a label on data or one placed past the last instruction.
+  fn check_branch_targets(&mut self) {
+    for reloc in &self.pending {
+      let (Some(opcode), Some(&(section, offset))) = (reloc.branch, self.labels.get(&reloc.name)) else {
+        continue;
+      };
+      let offset = offset.wrapping_add_signed(reloc.addend);
+      let label = match reloc.addend {
+        0 => self.written(reloc.name).to_string(),
+        addend => Arg::LabelOffset(self.written(reloc.name).into(), addend).to_string(),
+      };
+      let problem = match section {
+        0 if self.instructions.binary_search(&offset).is_ok() => continue,
+        0 => format!("at .text+0x{:X}, which is not the start of an instruction", offset),
+        1 => "in .data".to_string(),
+        _ => "in .rodata".to_string(),
+      };
+      self.diagnostics.push(
+        Diagnostic::error("branch-target", format!("{} target '{}' is {}", opcode, label, problem))
+          .with_span(reloc.span.clone()),
+      );
+    }
+  }
+
+  /// `.align N`: pad `section` with zeros up to the next multiple of `N`, a power of two, counted
+  /// from the start of this object's section. Zero is `NOP`, so code can run through the padding.
+  /// Like `.space`, the padding cannot make the section larger than an object file may hold.
+  fn align(&mut self, section: u8, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    let Some(align) = parse_count(args).filter(|align| align.is_power_of_two()) else {
+      self.diagnostics.push(
+        Diagnostic::error("invalid-align", format!("Invalid .align value '{}': expected a power of two", args))
+          .with_span(span.clone()),
+      );
+      return;
+    };
+    let len = self.section_len(section);
+    let limit = DecodeLimits::default().max_section_size;
+    let Some(end) = len.checked_next_multiple_of(align).filter(|&end| end as usize <= limit) else {
+      self.diagnostics.push(
+        Diagnostic::error("section-too-large", format!(".align {} makes the section larger than {} bytes", align, limit))
+          .with_span(span.clone()),
+      );
+      return;
+    };
+    self.append_to_section(section, &vec![0; (end - len) as usize]);
+  }
+
+  /// `.space N` or `.zero N`: `N` zero bytes of data in `section`, for buffers. A section can grow
+  /// no larger than an object file may hold.
+  fn reserve(&mut self, section: u8, directive: &str, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    let Some(len) = parse_count(args) else {
+      self.diagnostics.push(
+        Diagnostic::error("invalid-space", format!("Invalid .{} size '{}': expected a number of bytes", directive, args))
+          .with_span(span.clone()),
+      );
+      return;
+    };
+    let limit = DecodeLimits::default().max_section_size;
+    if self.section_len(section) as usize + len as usize > limit {
+      self.diagnostics.push(
+        Diagnostic::error("section-too-large", format!(".{} {} makes the section larger than {} bytes", directive, len, limit))
+          .with_span(span.clone()),
+      );
+      return;
+    }
+    self.append_data(section, &vec![0; len as usize]);
+  }
+
+  /// `.float` or `.double`: each number in `args`, separated by commas or whitespace, as a 4- or
+  /// 8-byte IEEE 754 value in `section`. Numbers are written as Rust reads them, such as `1.5`,
+  /// `-2e-3`, `inf` or `NaN`; one too large for a `.float` is an error rather than infinity.
+  fn floats(&mut self, section: u8, directive: &str, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("");
+    for text in args.split(|c: char| c == ',' || c.is_whitespace()).filter(|text| !text.is_empty()) {
+      let value = strip_separators(text).and_then(|text| text.parse::<f64>().ok());
+      let bytes = match (directive, value) {
+        ("float", Some(value)) if value.is_finite() && (value as f32).is_infinite() => {
+          self.diagnostics.push(
+            Diagnostic::error("invalid-float", format!("'{}' is too large for .float", text))
+              .with_span(span.clone())
+              .with_note(format!("the largest .float is {:e}; use .double", f32::MAX)),
+          );
+          continue;
+        }
+        ("float", Some(value)) => (value as f32).to_le_bytes().to_vec(),
+        (_, Some(value)) => value.to_le_bytes().to_vec(),
+        (_, None) => {
+          self.diagnostics.push(
+            Diagnostic::error("invalid-float", format!("Invalid .{} value '{}': expected a number", directive, text))
+              .with_span(span.clone()),
+          );
+          continue;
+        }
+      };
+      self.append_data(section, &bytes);
+    }
+  }
+
+  /// `.equ NAME, value`: `NAME` stands for `value`, an integer, a constant defined earlier or the
+  /// size of what follows a label, `$ - label`, wherever an operand can be a label. Like a structure
+  /// field it is filled in by the assembler and never becomes a relocation, and a label of the same
+  /// name takes precedence.
+  fn define_constant(&mut self, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    let error = |code: &'static str, message: String| Diagnostic::error(code, message).with_span(span.clone());
+    let Some((name, value)) = args.split_once(',').map(|(name, value)| (name.trim(), value.trim())) else {
+      self.diagnostics.push(error("invalid-equ", format!("Invalid .equ '{}': expected `NAME, value`", args)));
+      return;
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+      && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+      self.diagnostics.push(error("invalid-equ", format!("Invalid constant name '{}'", name)));
+      return;
+    }
+    let resolved = parse_integer(value).map(|value| value as u32)
+      .or_else(|| self.names.get(value).and_then(|value| self.constants.get(&value)).copied())
+      .or_else(|| syscall::by_name(value).map(|number| number as u32))
+      .or_else(|| self.distance_from_label(value));
+    let Some(resolved) = resolved else {
+      self.diagnostics.push(error("invalid-equ", format!("Invalid .equ value '{}': expected an integer, an earlier constant or `$ - label`", value)));
+      return;
+    };
+    let name = self.names.intern(name);
+    if self.constants.insert(name, resolved).is_some() {
+      self.diagnostics.push(error("duplicate-constant", format!("Constant '{}' is already defined", self.names.resolve(name))));
+    }
+  }
+
+  /// `.alias NAME, rN`: `NAME` can be written for register `rN` from here on, as in `.alias sp, r7`.
+  /// The register can itself be an alias, and an alias can be given again to name another register.
+  fn define_alias(&mut self, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    let error = |message: String| Diagnostic::error("invalid-alias", message).with_span(span.clone());
+    let Some((name, register)) = args.split_once(',').map(|(name, register)| (name.trim(), register.trim())) else {
+      self.diagnostics.push(error(format!("Invalid .alias '{}': expected `NAME, register`", args)).with_note("for example `.alias sp, r7`"));
+      return;
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+      && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name || Self::numbered_register(name).is_some() {
+      self.diagnostics.push(error(format!("Invalid alias name '{}'", name)));
+      return;
+    }
+    let Some(number) = self.reg_number(register) else {
+      self.diagnostics.push(
+        error(format!("Cannot alias '{}' to '{}', which is not a register", name, register))
+          .with_note(format!("registers are r0 to r{}", REGISTER_COUNT - 1)),
+      );
+      return;
+    };
+    let name = self.names.intern(name);
+    self.aliases.insert(name, number);
+  }
+
+  /// Open, continue or close a `.if` or `.while` block. `.if rN` runs the block if `rN` is not
+  /// zero, and `.while rN` until it is; `!rN` tests for zero instead.
+  fn block_directive(&mut self, directive: &str, args: Option<&str>, span: &Option<Span>) {
+    if self.section != 0 {
+      self.diagnostics.push(
+        Diagnostic::error("block-section", format!("`.{}` is only allowed in .text", directive)).with_span(span.clone()),
+      );
+      return;
+    }
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    match directive {
+      "if" | "while" => {
+        let kind = if directive == "if" { BlockKind::If } else { BlockKind::While };
+        let id = self.block_count;
+        self.block_count += 1;
+        if kind == BlockKind::While {
+          self.block_label(&kind.label(id, "top"), span);
+        }
+        let (negated, register) = match args.strip_prefix('!') {
+          Some(register) => (true, register.trim()),
+          None => (false, args),
+        };
+        if self.reg_number(register).is_some() {
+          let skip = kind.label(id, if kind == BlockKind::If { "else" } else { "end" });
+          let branch = if negated { OpCode::Jnz } else { OpCode::Jz };
+          self.emit(branch, vec![Arg::Register(register.to_string().into()), Arg::Label(skip.into())], span);
+        } else {
+          self.diagnostics.push(
+            Diagnostic::error("block-condition", format!("`.{}` takes a register, found '{}'", directive, args))
+              .with_span(span.clone())
+              .with_note("the condition holds while the register is not zero, or is zero if written `!rN`"),
+          );
+        }
+        self.blocks.push(OpenBlock { kind, id, has_else: false, span: span.clone() });
+      }
+      "else" => match self.blocks.last_mut() {
+        Some(block) if block.kind == BlockKind::If && !block.has_else => {
+          block.has_else = true;
+          let id = block.id;
+          self.emit(OpCode::Jmp, vec![Arg::Label(BlockKind::If.label(id, "end").into())], span);
+          self.block_label(&BlockKind::If.label(id, "else"), span);
+        }
+        _ => self.diagnostics.push(
+          Diagnostic::error("unmatched-block", "`.else` without an open `.if`").with_span(span.clone()),
+        ),
+      },
+      _ => {
+        let kind = if directive == "endif" { BlockKind::If } else { BlockKind::While };
+        let Some(block) = self.blocks.pop() else {
+          self.diagnostics.push(
+            Diagnostic::error("unmatched-block", format!("`.{}` without an open `.{}`", directive, kind.name()))
+              .with_span(span.clone()),
+          );
+          return;
+        };
+        if block.kind != kind {
+          let mut diagnostic = Diagnostic::error(
+            "unmatched-block",
+            format!("`.{}` closes a `.{}`", directive, block.kind.name()),
+          ).with_span(span.clone());
+          if let Some(open) = &block.span {
+            diagnostic = diagnostic.with_note(format!("the `.{}` is on line {}", block.kind.name(), open.line));
           }
+          self.diagnostics.push(diagnostic);
+        }
+        // Close the block that is open either way, so its labels are defined
+        self.close_block(&block, span);
+      }
+    }
+  }
 
-          self.append_to_section(section, &instr_bytes);
-          pos[section as usize] = current_instr_pos;
+  fn close_block(&mut self, block: &OpenBlock, span: &Option<Span>) {
+    match block.kind {
+      BlockKind::If => {
+        self.block_label(&BlockKind::If.label(block.id, if block.has_else { "end" } else { "else" }), span);
+      }
+      BlockKind::While => {
+        self.emit(OpCode::Jmp, vec![Arg::Label(BlockKind::While.label(block.id, "top").into())], span);
+        self.block_label(&BlockKind::While.label(block.id, "end"), span);
+      }
+    }
+  }
+
+  /// Define a structure: `.struct NAME`, then a `.field NAME, TYPE` line per field, then
+  /// `.endstruct`. A type is `byte`, `word` or an earlier structure, optionally with a count as in
+  /// `word[4]`. Afterwards `NAME.field` is the offset of each field and `NAME.size` the size.
+  fn struct_directive(&mut self, directive: &str, args: Option<&str>, span: &Option<Span>) {
+    let args = args.and_then(|args| args.split(';').next()).unwrap_or("").trim();
+    let error = |code: &'static str, message: String| Diagnostic::error(code, message).with_span(span.clone());
+    match directive {
+      "struct" => {
+        if args.is_empty() || !args.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+          self.diagnostics.push(error("struct-name", format!("Invalid structure name '{}'", args)));
+        } else if self.structs.contains_key(args) {
+          self.diagnostics.push(error("duplicate-struct", format!("Structure '{}' is already defined", args)));
+        }
+        self.open_struct = Some(OpenStruct { name: args.to_string(), fields: Vec::new(), size: 0, align: 1, span: span.clone() });
+      }
+      "field" => {
+        let Some(open) = &self.open_struct else {
+          self.diagnostics.push(error("struct-body", "`.field` outside of a `.struct`".to_string()));
+          return;
+        };
+        let Some((name, ty)) = args.split_once(',').map(|(name, ty)| (name.trim(), ty.trim())) else {
+          self.diagnostics.push(error("invalid-field", format!("Invalid field '{}': expected `NAME, TYPE`", args)));
+          return;
+        };
+        let (base, count) = match ty.strip_suffix(']').and_then(|ty| ty.split_once('[')) {
+          Some((base, count)) => (base.trim(), count.trim().parse::<u32>().ok()),
+          None => (ty, Some(1)),
+        };
+        let Some(count) = count else {
+          self.diagnostics.push(error("invalid-field", format!("Invalid field count in '{}'", ty)));
+          return;
+        };
+        let layout = FIELD_TYPES.iter().find(|(known, _)| *known == base).map(|&(_, size)| (size, size))
+          .or_else(|| self.structs.get(base).copied());
+        let Some((size, align)) = layout else {
+          let known: Vec<&str> = FIELD_TYPES.iter().map(|(known, _)| *known).chain(self.structs.keys().map(String::as_str)).collect();
+          let mut diagnostic = error("unknown-type", format!("Unknown field type '{}'", base));
+          if let Some(known) = nearest(base, known) {
+            diagnostic = diagnostic.with_note(format!("did you mean `{}`?", known));
+          }
+          self.diagnostics.push(diagnostic);
+          return;
+        };
+        if name == "size" || open.fields.iter().any(|(field, _)| field == name) {
+          let message = if name == "size" {
+            format!("A field cannot be called `size`, which is reserved for `{}.size`", open.name)
+          } else {
+            format!("Structure '{}' already has a field '{}'", open.name, name)
+          };
+          self.diagnostics.push(error("duplicate-field", message));
+          return;
         }
+        let open = self.open_struct.as_mut().unwrap();
+        let offset = open.size.next_multiple_of(align);
+        open.fields.push((name.to_string(), offset));
+        open.size = offset + size * count;
+        open.align = open.align.max(align);
       }
+      _ => {
+        let Some(open) = self.open_struct.take() else {
+          self.diagnostics.push(error("struct-body", "`.endstruct` without an open `.struct`".to_string()));
+          return;
+        };
+        let size = open.size.next_multiple_of(open.align);
+        for (field, offset) in &open.fields {
+          let name = self.names.intern(&format!("{}.{}", open.name, field));
+          self.constants.insert(name, *offset);
+        }
+        let name = self.names.intern(&format!("{}.size", open.name));
+        self.constants.insert(name, size);
+        self.structs.insert(open.name, (size, open.align));
+      }
+    }
+  }
+
+  /// Assemble an instruction the assembler wrote itself, as if it were on the line at `span`.
+  fn emit(&mut self, opcode: OpCode, args: Vec<Arg<'static>>, span: &Option<Span>) {
+    self.feed(&Line::Instruction(Instruction { label: None, opcode, args }), span.clone());
+  }
+
+  /// Record that the program needs ISA revision `since`, which is an error above the target.
+  fn require(&mut self, what: &str, since: u16, span: &Option<Span>) {
+    if since > self.target_version {
+      self.diagnostics.push(
+        Diagnostic::error(
+          "isa-version",
+          format!("{} needs ISA revision {}, but the target is revision {}", what, since, self.target_version),
+        )
+          .with_span(span.clone())
+          .with_note(format!("target revision {} or later with `--target-version`", since)),
+      );
+    }
+    self.required_version = self.required_version.max(since);
+  }
+
+  fn define_label(&mut self, label: &str, span: &Option<Span>) {
+    if let Some(local) = self.local_label(label) {
+      self.check_duplicate_label(&local, span);
+      let name = self.place_hidden(&local);
+      self.declarations.push(Declaration { name, external: false, span: span.clone() });
+      return;
+    }
+    self.scope = label.to_string();
+    self.numeric_labels.clear();
+    let (base, _, default) = symver::split(label);
+    if default
+      && let Some(other) = self.symbol_table.iter()
+        .find(|symbol| !symbol.external && matches!(symver::split(&symbol.name), (other, _, true) if other == base))
+    {
+      self.diagnostics.push(
+        Diagnostic::error("duplicate-default-version", format!("'{}' and '{}' are both the default version of '{}'", other.name, label, base))
+          .with_span(span.clone())
+          .with_note(format!("only one version of '{}' can use `@@`; name the others with a single `@`", base)),
+      );
+    }
+    self.check_duplicate_label(label, span);
+    let name = self.place_label(label);
+    self.declarations.push(Declaration { name, external: false, span: span.clone() });
+  }
+
+  /// Report `label` if it has already been defined. It is still placed, so later uses resolve to
+  /// the last definition and assembly carries on to report any other errors.
+  fn check_duplicate_label(&mut self, label: &str, span: &Option<Span>) {
+    let Some(name) = self.names.get(label).filter(|name| self.labels.contains_key(name)) else {
+      return;
+    };
+    let first = self.declarations.iter().find(|declaration| declaration.name == name && !declaration.external);
+    let mut diagnostic = Diagnostic::error("duplicate-label", format!("Label '{}' is already defined", self.written(name)))
+      .with_span(span.clone());
+    if let Some(span) = first.and_then(|declaration| declaration.span.as_ref()) {
+      diagnostic = diagnostic.with_note(format!("first defined on line {}", span.line));
+    }
+    self.diagnostics.push(diagnostic);
+  }
+
+  /// The name a local label is defined or used under, or `None` if `label` is not local. `.Lname`
+  /// belongs to the last label that is not local. `N:` can be defined any number of times, and `Nb`
+  /// and `Nf` mean the closest `N:` before and after, up to the next label that is not local. The
+  /// names start with `@`, like those of blocks, so they cannot clash with any other label, and
+  /// like those they are not symbols.
+  fn local_label(&mut self, label: &str) -> Option<String> {
+    let local = if label.len() > 2 && label.starts_with(".L") {
+      format!("@{}{}", self.scope, label)
+    } else if let Ok(number) = label.parse::<u32>() {
+      let count = self.numeric_labels.entry(number).or_default();
+      *count += 1;
+      format!("@{}.{}.{}", self.scope, number, *count - 1)
+    } else {
+      let (number, forward) = match label.strip_suffix('b') {
+        Some(number) => (number, false),
+        None => (label.strip_suffix('f')?, true),
+      };
+      let number = number.parse::<u32>().ok()?;
+      let count = self.numeric_labels.get(&number).copied().unwrap_or(0) as i64;
+      format!("@{}.{}.{}", self.scope, number, if forward { count } else { count - 1 })
+    };
+    let name = self.names.intern(&local);
+    self.local_names.entry(name).or_insert_with(|| label.to_string());
+    Some(local)
+  }
+
+  /// A label at the start of the statement being assembled, which `$` and `.` stand for. Like
+  /// local labels it starts with `@`, so it cannot clash with any other label, and is not a symbol.
+  fn here(&mut self, written: &str) -> Symbol {
+    let here = format!("@$.{}.{}", self.section, self.section_len(self.section));
+    let name = self.names.intern(&here);
+    if !self.labels.contains_key(&name) {
+      self.place_hidden(&here);
+    }
+    self.local_names.entry(name).or_insert_with(|| written.to_string());
+    name
+  }
+
+  /// `$ - label`: how many bytes there are from `label`, defined earlier in the current section, to
+  /// here.
+  fn distance_from_label(&mut self, value: &str) -> Option<u32> {
+    let (here, label) = value.split_once('-')?;
+    if !matches!(here.trim(), "$" | ".") {
+      return None;
+    }
+    let label = self.names.get(label.trim())?;
+    let &(section, offset) = self.labels.get(&label).filter(|(section, _)| *section == self.section)?;
+    self.measured.insert(label);
+    Some(self.section_len(section) - offset)
+  }
+
+  /// `name` as the source wrote it.
+  fn written(&self, name: Symbol) -> &str {
+    self.local_names.get(&name).map_or_else(|| self.names.resolve(name), String::as_str)
+  }
+
+  /// Define a label a block generated, as if it were on the line at `span`.
+  fn block_label(&mut self, label: &str, span: &Option<Span>) {
+    if let Some(expansion) = &mut self.expansion {
+      expansion.push((Line::LabelOnly(label.to_string().into()), span.clone()));
+    }
+    self.place_hidden(label);
+  }
+
+  /// Define `label` here as a symbol, without checking that it is used.
+  fn place_label(&mut self, label: &str) -> Symbol {
+    let (section, offset) = (self.section, self.section_len(self.section));
+    let name = self.names.intern(label);
+    self.labels.insert(name, (section, offset));
+    self.push_symbol(name, SymbolEntry {
+      name: label.to_string(),
+      offset,
+      section,
+      kind: section, // kind: 0 = code label, 1 = data, 2 = rodata
+      external: false,
+    });
+    name
+  }
+
+  /// Define `label` here for this file alone: relocations against it refer to its section, so it
+  /// never reaches the symbol table.
+  fn place_hidden(&mut self, label: &str) -> Symbol {
+    let name = self.names.intern(label);
+    self.labels.insert(name, (self.section, self.section_len(self.section)));
+    name
+  }
+
+  fn declare_extern(&mut self, label: &str, span: &Option<Span>) {
+    let name = self.names.intern(label);
+    self.declarations.push(Declaration { name, external: true, span: span.clone() });
+    self.push_symbol(name, SymbolEntry {
+      name: label.to_string(),
+      offset: 0,
+      section: 0,
+      kind: 0, // Extern symbols are not section-specific
+      external: true,
+    });
+  }
+
+  fn push_symbol(&mut self, name: Symbol, entry: SymbolEntry) {
+    self.symbol_index.entry(name).or_insert(self.symbol_table.len() as u32);
+    self.symbol_table.push(entry);
+  }
+
+  fn section_len(&self, section: u8) -> u32 {
+    match section {
+      0 => self.code.len() as u32,
+      1 => self.data.len() as u32,
+      2 => self.rodata.len() as u32,
+      _ => unreachable!(),
     }
   }
 
@@ -250,63 +1161,173 @@ impl Assembler {
     }
   }
 
-  fn append_arg(relocations: &mut Vec<RelocationEntry>, symbol_table: &mut Vec<SymbolEntry>, buffer: &mut Vec<u8>, arg: &Arg, section: u8, pos: &mut u32) {
+  /// Append the bytes of a data directive, recording where they are if that is in `.text`.
+  fn append_data(&mut self, section: u8, bytes: &[u8]) {
+    if section == 0 && !bytes.is_empty() {
+      let offset = self.code.len() as u32;
+      match self.data_in_text.last_mut() {
+        Some(range) if range.offset + range.len == offset => range.len += bytes.len() as u32,
+        _ => self.data_in_text.push(DataRange { offset, len: bytes.len() as u32 }),
+      }
+    }
+    self.append_to_section(section, bytes);
+  }
+
+  /// Encode one operand. A bad operand is reported and encoded as zero so the rest of the line
+  /// still lines up.
+  fn append_arg(&mut self, span: &Option<Span>, buffer: &mut Vec<u8>, arg: &Arg, section: u8, pos: &mut u32) {
     match arg {
       Arg::Register(name) => {
-        let reg = Self::reg_number(name);
+        let reg = self.register(name, span);
         buffer.extend_from_slice(&[reg, 0, 0, 0]);
         *pos += 4;
       }
+      Arg::RegisterOffset(name, offset) => {
+        let base = self.register(name, span);
+        self.require("An address with an offset", ADDRESS_MODES_SINCE, span);
+        let offset = i16::try_from(*offset).unwrap_or_else(|_| {
+          self.diagnostics.push(
+            Diagnostic::error("invalid-offset", format!("Offset in '[{}]' does not fit in 16 bits", arg))
+              .with_span(span.clone())
+              .with_note(format!("offsets from a register are {} to {}; add to the register first for more", i16::MIN, i16::MAX)),
+          );
+          0
+        });
+        buffer.extend_from_slice(&AddressOperand { base, index: None, offset }.encode());
+        *pos += 4;
+      }
+      Arg::Indexed(base, index, scale) => {
+        let (base, index) = (self.register(base, span), self.register(index, span));
+        self.require("An indexed address", ADDRESS_MODES_SINCE, span);
+        let scale = u8::try_from(*scale).ok().filter(|scale| matches!(scale, 1 | 2 | 4 | 8)).unwrap_or_else(|| {
+          self.diagnostics.push(
+            Diagnostic::error("invalid-scale", format!("Scale in '[{}]' must be 1, 2, 4 or 8", arg)).with_span(span.clone()),
+          );
+          1
+        });
+        buffer.extend_from_slice(&AddressOperand { base, index: Some((index, scale)), offset: 0 }.encode());
+        *pos += 4;
+      }
       Arg::Immediate(val) => {
         buffer.extend_from_slice(&(*val as u32).to_le_bytes());
         *pos += 4;
       }
-      Arg::Label(label) => {
-        let symbol_idx = symbol_table.iter()
-          .position(|s| s.name == *label)
-          .expect(&format!("Reloc symbol '{}' must be in symbol table", label));
-        let patch_offset = *pos;
-        relocations.push(RelocationEntry {
-          offset: patch_offset,
-          symbol_index: symbol_idx as u32,
-          reloc_type: RelocationType::Absolute,
-          target_section: section,
+      Arg::Label(label) | Arg::Relocated(_, label) | Arg::LabelOffset(label, _) => {
+        let (kind, addend) = match arg {
+          Arg::Relocated(kind, _) => (*kind, 0),
+          Arg::LabelOffset(_, addend) => (RelocationType::Absolute, *addend),
+          _ => (RelocationType::Absolute, 0),
+        };
+        let name = if matches!(label.as_ref(), "$" | ".") {
+          self.here(label)
+        } else {
+          match self.local_label(label) {
+            Some(local) => self.names.intern(&local),
+            None => self.names.intern(label),
+          }
+        };
+        self.pending.push(PendingRelocation {
+          offset: *pos,
+          name,
+          section,
+          span: span.clone(),
+          branch: None,
+          kind,
+          addend,
         });
         buffer.extend_from_slice(&0u32.to_le_bytes());
         *pos += 4;
       }
-      Arg::Mem(inner) => {
-        Self::append_arg(relocations, symbol_table, buffer, inner, section, pos);
+      Arg::Mem(inner) if matches!(**inner, Arg::Mem(_)) => {
+        self.diagnostics.push(
+          Diagnostic::error("nested-memory-operand", format!("Memory operands cannot be nested: '{}'", arg))
+            .with_span(span.clone()),
+        );
+        buffer.extend_from_slice(&0u32.to_le_bytes());
+        *pos += 4;
       }
+      Arg::Mem(inner) => self.append_arg(span, buffer, inner, section, pos),
     }
   }
 
-  fn reg_number(name: &str) -> u8 {
-    if let Some(n) = name.strip_prefix("r") {
-      n.parse().unwrap_or(0xFF)
-    } else {
-      0xFF
+  /// The number of register `name`, reporting it if there is no such register.
+  fn register(&mut self, name: &str, span: &Option<Span>) -> u8 {
+    self.reg_number(name).unwrap_or_else(|| {
+      self.diagnostics.push(
+        Diagnostic::error("invalid-register", format!("Unknown register '{}'", name))
+          .with_span(span.clone())
+          .with_note(format!("registers are r0 to r{}", REGISTER_COUNT - 1)),
+      );
+      0
+    })
+  }
+
+  /// Whether operand `index` of `opcode` is an address that may be written as `[...]`.
+  fn takes_memory_operand(opcode: OpCode, index: usize) -> bool {
+    index == 1 && matches!(opcode, OpCode::Load | OpCode::Store | OpCode::Loadi | OpCode::Storei)
+  }
+
+  /// The number of register `name`, `r0` to `r31` or a name given to one with `.alias`.
+  fn reg_number(&self, name: &str) -> Option<u8> {
+    Self::numbered_register(name)
+      .or_else(|| self.names.get(name).and_then(|name| self.aliases.get(&name)).copied())
+  }
+
+  fn numbered_register(name: &str) -> Option<u8> {
+    name.strip_prefix(['r', 'R'])?.parse().ok().filter(|reg| *reg < REGISTER_COUNT)
+  }
+
+  /// `args` with the labels that name an aliased register turned into registers, since the parser
+  /// cannot tell `sp` from a label. `reg_number` then resolves the alias as it encodes them.
+  fn resolve_aliases<'src>(&self, args: &'src [Arg<'src>]) -> Cow<'src, [Arg<'src>]> {
+    if self.aliases.is_empty() {
+      return Cow::Borrowed(args);
     }
+    let is_alias = |name: &str| self.names.get(name).is_some_and(|name| self.aliases.contains_key(&name));
+    let resolve = |arg: &Arg<'src>| match arg {
+      Arg::Label(name) if is_alias(name) => Arg::Register(name.clone()),
+      Arg::LabelOffset(name, offset) if is_alias(name) => Arg::RegisterOffset(name.clone(), *offset),
+      arg => arg.clone(),
+    };
+    Cow::Owned(args.iter().map(|arg| match arg {
+      Arg::Mem(inner) => Arg::Mem(Box::new(resolve(inner))),
+      arg => resolve(arg),
+    }).collect())
   }
 }
 
+/// The text of a string directive's argument: what is between the quotes, up to the first one
+/// that is not escaped, so a comment after it is left out but a `;` inside it is not.
+fn string_argument(args: &str) -> &str {
+  let args = args.trim();
+  let Some(quoted) = args.strip_prefix('"') else {
+    return args.split(';').next().unwrap_or("").trim();
+  };
+  let mut escaped = false;
+  for (i, c) in quoted.char_indices() {
+    match c {
+      '"' if !escaped => return &quoted[..i],
+      '\\' => escaped = !escaped,
+      _ => escaped = false,
+    }
+  }
+  quoted
+}
+
+/// A byte count or alignment: an integer literal written like a `.word`, such as `0x100` or
+/// `1_024`, that fits in 32 bits and is not negative.
+fn parse_count(text: &str) -> Option<u32> {
+  parse_word(text).and_then(|count| u32::try_from(count).ok())
+}
+
 fn parse_escaped_string(s: &str) -> Vec<u8> {
   let mut out = Vec::new();
   let mut chars = s.chars().peekable();
   while let Some(c) = chars.next() {
     if c == '\\' {
       match chars.next() {
-        Some('0') => out.push(0),
-        Some('n') => out.push(b'\n'),
-        Some('t') => out.push(b'\t'),
-        Some('r') => out.push(b'\r'),
-        Some('\\') => out.push(b'\\'),
-        Some('\'') => out.push(b'\''),
-        Some('\"') => out.push(b'"'),
-        Some(other) => {
-          // Unknown escape, just push as char
-          out.push(other as u8);
-        }
+        // Unknown escapes are just the character
+        Some(escaped) => out.push(unescape(escaped) as u8),
         None => break,
       }
     } else {
@@ -316,34 +1337,87 @@ fn parse_escaped_string(s: &str) -> Vec<u8> {
   out
 }
 
+/// The character `\c` stands for in a string.
+fn unescape(c: char) -> char {
+  match c {
+    '0' => '\0',
+    'n' => '\n',
+    't' => '\t',
+    'r' => '\r',
+    other => other,
+  }
+}
+
+/// The bytes of a `.utf8` string: its characters in UTF-8, with the escapes of `.ascii` and also
+/// `\u{NNNN}`, a character by its code point, and `\xNN`, a single byte. The result must be valid
+/// UTF-8, so bytes from `\x` have to make up whole characters.
+fn parse_utf8_string(s: &str) -> Result<Vec<u8>, String> {
+  let mut out = Vec::new();
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    let c = match c {
+      '\\' => match chars.next() {
+        Some('x') => {
+          let digits: String = chars.by_ref().take(2).collect();
+          let byte = u8::from_str_radix(&digits, 16).map_err(|_| format!("Invalid escape '\\x{}' in .utf8 string", digits))?;
+          out.push(byte);
+          continue;
+        }
+        Some('u') => {
+          let rest = chars.as_str();
+          let (code, after) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}')).unwrap_or((rest, ""));
+          let Some(c) = u32::from_str_radix(code, 16).ok().and_then(char::from_u32) else {
+            return Err(format!("Invalid escape '\\u{{{}}}' in .utf8 string", code));
+          };
+          chars = after.chars();
+          c
+        }
+        Some(escaped) => unescape(escaped),
+        None => break,
+      },
+      c => c,
+    };
+    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+  }
+  match std::str::from_utf8(&out) {
+    Ok(_) => Ok(out),
+    Err(e) => Err(format!("Invalid UTF-8 in .utf8 string at byte {}", e.valid_up_to())),
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use leaf_common::leaf_ast::{Directive, Instruction};
+  use leaf_common::isa::{InstructionDef, IsaExtension};
+  use leaf_common::leaf_ast::Directive;
   use super::*;
 
-  fn line_instr(op: OpCode, args: Vec<Arg>, label: Option<&str>) -> Line {
+  fn line_instr(op: OpCode, args: Vec<Arg<'static>>, label: Option<&'static str>) -> Line<'static> {
     Line::Instruction(Instruction {
-      label: label.map(|s| s.to_string()),
+      label: label.map(Into::into),
       opcode: op,
       args,
     })
   }
 
+  fn directive(name: &'static str, args: Option<&'static str>) -> Line<'static> {
+    Line::Directive(Directive { name: name.into(), args: args.map(Into::into) })
+  }
+
   #[test]
   fn assembles_simple_add_instruction() {
     // ADD r1, r2, r3
     let program = vec![
-      Line::Section(".text".to_string()),
+      Line::Section(".text".into()),
       line_instr(OpCode::Add,
                  vec![
-                   Arg::Register("r1".to_string()),
-                   Arg::Register("r2".to_string()),
-                   Arg::Register("r3".to_string()),
+                   Arg::Register("r1".into()),
+                   Arg::Register("r2".into()),
+                   Arg::Register("r3".into()),
                  ],
                  None),
     ];
 
-    let obj = Assembler::assemble(&program, Some("main".to_string()));
+    let obj = Assembler::assemble(&program, Some("main".to_string())).unwrap();
     // Should encode as: opcode(1) + 3 * reg(4)
     // e.g., [0x01, r1, 0, 0, 0, r2, 0, 0, 0, r3, 0, 0, 0]
     assert_eq!(obj.bytecode[0], 0x01); // ADD opcode
@@ -359,12 +1433,12 @@ mod tests {
   fn assembles_with_label_and_jmp() {
     // main: NOP, JMP to main (should resolve directly)
     let program = vec![
-      Line::Section(".text".to_string()),
-      Line::LabelOnly("main".to_string()),
+      Line::Section(".text".into()),
+      Line::LabelOnly("main".into()),
       line_instr(OpCode::Nop, vec![], None),
-      line_instr(OpCode::Jmp, vec![Arg::Label("main".to_string())], None),
+      line_instr(OpCode::Jmp, vec![Arg::Label("main".into())], None),
     ];
-    let obj = Assembler::assemble(&program, Some("main".to_string()));
+    let obj = Assembler::assemble(&program, Some("main".to_string())).unwrap();
     // Expect JMP opcode (0x09) and address 0 (main)
     assert_eq!(obj.bytecode[0], 0x00); // NOP
     assert_eq!(obj.bytecode[1], 0x09); // JMP
@@ -378,28 +1452,159 @@ mod tests {
   #[test]
   fn assembles_data_and_rodata_sections() {
     let program = vec![
-      Line::Section(".data".to_string()),
-      Line::Directive(Directive { name: "word".to_string(), args: Some("42 1337".to_string()) }),
-      Line::Section(".rodata".to_string()),
-      Line::Directive(Directive { name: "ascii".to_string(), args: Some("\"hello\"".to_string()) }),
+      Line::Section(".data".into()),
+      directive("word", Some("42 1337")),
+      directive("word", Some("0xFF -0b101 0o17 1_000 0xFFFF_FFFF_FFFF_FFFF 5000000000")),
+      Line::Section(".rodata".into()),
+      directive("ascii", Some("\"hello\"")),
     ];
-    let obj = Assembler::assemble(&program, None);
-    // .data = [42, 1337] as i32 LE
-    assert_eq!(obj.data.len(), 8);
-    assert_eq!(i32::from_le_bytes(obj.data[0..4].try_into().unwrap()), 42);
-    assert_eq!(i32::from_le_bytes(obj.data[4..8].try_into().unwrap()), 1337);
+    let obj = Assembler::assemble(&program, None).unwrap();
+    // .data = [42, 1337, ...] as 64-bit words (LDR-004), LE, written like immediates
+    let words: Vec<i64> = obj.data.chunks(8).map(|word| i64::from_le_bytes(word.try_into().unwrap())).collect();
+    assert_eq!(words, vec![42, 1337, 0xFF, -5, 0o17, 1000, -1, 5_000_000_000]);
     // .rodata = b"hello"
     assert_eq!(&obj.rodata, b"hello");
   }
 
+  #[test]
+  fn string_directives_differ_only_in_the_terminator() {
+    let program = vec![
+      Line::Section(".rodata".into()),
+      directive("asciiz", Some("\"a;b\" ; comment")),
+      directive("string", Some("\"q\\\"\"")),
+      directive("ascii", Some("\"c\"")),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(&obj.rodata, b"a;b\0q\"\0c");
+  }
+
+  #[test]
+  fn utf8_strings_are_checked() {
+    let program = vec![
+      Line::Section(".rodata".into()),
+      directive("utf8", Some("\"héllo ; \\u{1F600}\\n\"")),
+      directive("utf8", Some("\"\\xC3\\xA9\"")),
+    ];
+    let mut diagnostics = Vec::new();
+    let obj = Assembler::new().assemble_program(&program, None, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty());
+    assert_eq!(obj.rodata, "héllo ; \u{1F600}\né".as_bytes());
+
+    let program = vec![
+      Line::Section(".rodata".into()),
+      directive("utf8", Some("\"a\\xFFb\"")),
+      directive("utf8", Some("\"\\u{D800}\"")),
+      directive("ascii", Some("\"é\"")),
+    ];
+    let mut diagnostics = Vec::new();
+    assert!(Assembler::new().assemble_program(&program, None, &mut diagnostics).is_none());
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("invalid-utf8", "Invalid UTF-8 in .utf8 string at byte 1"),
+      ("invalid-utf8", "Invalid escape '\\u{D800}' in .utf8 string"),
+      ("non-ascii", "'é' in .ascii is not ASCII and is cut to one byte"),
+    ]);
+  }
+
+  #[test]
+  fn floats_are_little_endian_ieee_754() {
+    let program = vec![
+      Line::Section(".data".into()),
+      directive("float", Some("1.5, -2 ; comment")),
+      directive("double", Some("0.1 1_000.25 -inf")),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    let mut expected = Vec::new();
+    expected.extend_from_slice(&1.5f32.to_le_bytes());
+    expected.extend_from_slice(&(-2f32).to_le_bytes());
+    for value in [0.1, 1000.25, f64::NEG_INFINITY] {
+      expected.extend_from_slice(&value.to_le_bytes());
+    }
+    assert_eq!(obj.data, expected);
+
+    let program = vec![Line::Section(".data".into()), directive("float", Some("1e39 x")), directive("double", Some("1.0.0"))];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("invalid-float", "'1e39' is too large for .float"),
+      ("invalid-float", "Invalid .float value 'x': expected a number"),
+      ("invalid-float", "Invalid .double value '1.0.0': expected a number"),
+    ]);
+  }
+
+  #[test]
+  fn align_pads_the_current_section() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Halt, vec![], None),
+      directive("align", Some("4")),
+      Line::LabelOnly("after".into()),
+      Line::Section(".data".into()),
+      directive("ascii", Some("\"abc\"")),
+      directive("align", Some("8 ; a word")),
+      Line::LabelOnly("table".into()),
+      directive("word", Some("7")),
+      directive("align", Some("8")),
+      Line::Section(".rodata".into()),
+      directive("ascii", Some("\"x\"")),
+      directive("align", Some("0x10")),
+      directive("ascii", Some("\"y\"")),
+      directive("align", Some("1_024")),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    // Padded with NOPs, which are still code
+    assert_eq!(obj.bytecode.len(), 4);
+    assert!(obj.data_in_text.is_empty());
+    assert_eq!(&obj.data[..8], b"abc\0\0\0\0\0");
+    assert_eq!(obj.data.len(), 16);
+    assert_eq!(obj.rodata.len(), 1024);
+    assert_eq!(obj.rodata[16], b'y');
+    assert!(obj.symbols.iter().any(|s| s.name == "after" && s.section == 0 && s.offset == 4));
+    assert!(obj.symbols.iter().any(|s| s.name == "table" && s.section == 1 && s.offset == 8));
+
+    let err = Assembler::assemble(&[directive("align", Some("3"))], None).unwrap_err();
+    assert!(err.iter().any(|d| d.code == "invalid-align"));
+    for program in [
+      vec![Line::Section(".data".into()), directive("word", Some("1")), directive("align", Some("2147483648"))],
+      vec![Line::Section(".data".into()), directive("word", Some("1")), directive("align", Some("1073741824"))],
+      vec![Line::Section(".data".into()), directive("word", Some("1")), directive("align", Some("0x80000000"))],
+    ] {
+      let err = Assembler::assemble(&program, None).unwrap_err();
+      assert!(err.iter().any(|d| d.code == "section-too-large"));
+    }
+  }
+
+  #[test]
+  fn space_reserves_zero_bytes() {
+    let program = vec![
+      Line::Section(".data".into()),
+      Line::LabelOnly("buffer".into()),
+      directive("space", Some("64 ; line buffer")),
+      Line::LabelOnly("after".into()),
+      Line::Section(".rodata".into()),
+      directive("zero", Some("3")),
+      directive("space", Some("0x10")),
+      directive("zero", Some("1_024")),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(obj.data, vec![0; 64]);
+    assert_eq!(obj.rodata, vec![0; 3 + 16 + 1024]);
+    assert!(obj.symbols.iter().any(|s| s.name == "after" && s.offset == 64));
+
+    let err = Assembler::assemble(&[directive("space", Some("-1"))], None).unwrap_err();
+    assert!(err.iter().any(|d| d.code == "invalid-space"));
+    let err = Assembler::assemble(&[directive("zero", Some("4000000000"))], None).unwrap_err();
+    assert!(err.iter().any(|d| d.code == "section-too-large"));
+  }
+
   #[test]
   fn assembles_extern_symbol_and_relocation() {
     let program = vec![
-      Line::Section(".text".to_string()),
-      Line::Extern("external_func".to_string()),
-      line_instr(OpCode::Call, vec![Arg::Label("external_func".to_string())], None),
+      Line::Section(".text".into()),
+      Line::Extern(vec!["external_func".into()]),
+      line_instr(OpCode::Call, vec![Arg::Label("external_func".into())], None),
     ];
-    let obj = Assembler::assemble(&program, None);
+    let obj = Assembler::assemble(&program, None).unwrap();
     // Should create a relocation for external_func
     assert_eq!(obj.relocations.len(), 1);
     let reloc = &obj.relocations[0];
@@ -414,12 +1619,12 @@ mod tests {
   fn assembles_label_prefixed_instruction() {
     // label: MOV r1, 123
     let program = vec![
-      Line::Section(".text".to_string()),
+      Line::Section(".text".into()),
       line_instr(OpCode::Mov,
-                 vec![Arg::Register("r1".to_string()), Arg::Immediate(123)],
+                 vec![Arg::Register("r1".into()), Arg::Immediate(123)],
                  Some("start")),
     ];
-    let obj = Assembler::assemble(&program, Some("start".to_string()));
+    let obj = Assembler::assemble(&program, Some("start".to_string())).unwrap();
     // Symbol table includes start at offset 0
     assert!(obj.symbols.iter().any(|s| s.name == "start" && s.offset == 0));
     // MOV r1, 123: opcode, r1, 123
@@ -433,16 +1638,508 @@ mod tests {
   fn handles_unresolved_label_as_external_relocation() {
     // Will only work if the symbol is listed in the symbol_table as external
     let program = vec![
-      Line::Section(".text".to_string()),
-      Line::Extern("missing".to_string()),
-      line_instr(OpCode::Jmp, vec![Arg::Label("missing".to_string())], None),
+      Line::Section(".text".into()),
+      Line::Extern(vec!["missing".into()]),
+      line_instr(OpCode::Jmp, vec![Arg::Label("missing".into())], None),
     ];
-    let obj = Assembler::assemble(&program, None);
+    let obj = Assembler::assemble(&program, None).unwrap();
     // Should create a relocation for missing
     assert_eq!(obj.relocations.len(), 1);
     let reloc = &obj.relocations[0];
     assert_eq!(reloc.symbol_index as usize, 0); // Only symbol in table is missing
     assert_eq!(reloc.offset, 1);
   }
-}
 
+  #[test]
+  fn reports_undefined_symbol_at_its_line() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Jmp, vec![Arg::Label("nowhere".into())], None),
+    ];
+    let spans = vec![Span::new(1, 1, 5), Span::new(2, 3, 11)];
+    let mut diagnostics = Vec::new();
+    let obj = Assembler::new().with_spans(spans).assemble_program(&program, None, &mut diagnostics);
+    assert!(obj.is_none());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, "undefined-symbol");
+    assert_eq!(diagnostics[0].span, Some(Span::new(2, 3, 11)));
+  }
+
+  #[test]
+  fn reports_wrong_operand_count() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Add, vec![Arg::Register("r1".into())], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!(diagnostics[0].code, "operand-count");
+    assert_eq!(diagnostics[0].message, "ADD takes 3 operand(s) but 1 were given");
+  }
+
+  #[test]
+  fn extension_operands_follow_the_isa_description() {
+    let isa = IsaExtension { instructions: vec![InstructionDef { mnemonic: "BSWAP".to_string(), byte: 0xE8, operands: vec![OperandKind::Register] }] };
+    isa.install().unwrap();
+    let bswap = OpCode::from_mnemonic("BSWAP").unwrap();
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(bswap, vec![Arg::Register("r3".into())], None),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(object.bytecode, vec![0xE8, 3, 0, 0, 0]);
+
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(bswap, vec![Arg::Immediate(3)], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!(diagnostics[0].code, "operand-kind");
+    assert_eq!(diagnostics[0].message, "BSWAP expects a register, found '3'");
+  }
+
+  #[test]
+  fn older_targets_reject_newer_instructions() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Lt, vec![Arg::Register("r1".into()), Arg::Register("r2".into()), Arg::Register("r3".into())], None),
+      line_instr(OpCode::Halt, vec![], None),
+    ];
+    let mut assembler = Assembler::new();
+    for line in &program {
+      assembler.feed(line, None);
+    }
+    assert_eq!(assembler.required_version(), 2);
+
+    let mut diagnostics = Vec::new();
+    assert!(Assembler::new().with_target_version(1).assemble_program(&program, None, &mut diagnostics).is_none());
+    assert_eq!(diagnostics[0].code, "isa-version");
+    assert_eq!(diagnostics[0].message, "LT needs ISA revision 2, but the target is revision 1");
+    assert!(Assembler::new().with_target_version(1).assemble_program(&program[2..], None, &mut Vec::new()).is_some());
+  }
+
+  #[test]
+  fn register_offsets_encode_in_the_address_operand() {
+    let address = |offset: i32| Arg::Mem(Box::new(Arg::RegisterOffset("r7".into(), offset)));
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Load, vec![Arg::Register("r1".into()), address(8)], None),
+      line_instr(OpCode::Storei, vec![Arg::Register("r2".into()), address(-4)], None),
+    ];
+    let mut assembler = Assembler::new();
+    for line in &program {
+      assembler.feed(line, None);
+    }
+    assert_eq!(assembler.required_version(), 3);
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(object.bytecode, vec![0x0D, 1, 0, 0, 0, 7, 0, 8, 0, 0x0E, 2, 0, 0, 0, 7, 0, 0xFC, 0xFF]);
+
+    let mut diagnostics = Vec::new();
+    assert!(Assembler::new().with_target_version(2).assemble_program(&program, None, &mut diagnostics).is_none());
+    assert_eq!(diagnostics[0].message, "An address with an offset needs ISA revision 3, but the target is revision 2");
+
+    let program = vec![line_instr(OpCode::Load, vec![Arg::Register("r1".into()), address(40000)], None)];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!((diagnostics[0].code, diagnostics[0].message.as_str()), ("invalid-offset", "Offset in '[r7+40000]' does not fit in 16 bits"));
+  }
+
+  #[test]
+  fn indexed_addresses_encode_their_scale() {
+    let address = |scale: i32| Arg::Mem(Box::new(Arg::Indexed("r1".into(), "r2".into(), scale)));
+    let program = vec![
+      line_instr(OpCode::Load, vec![Arg::Register("r3".into()), address(8)], None),
+      line_instr(OpCode::Store, vec![Arg::Register("r3".into()), address(1)], None),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(object.bytecode, vec![0x0D, 3, 0, 0, 0, 1, 0xE2, 0, 0, 0x0E, 3, 0, 0, 0, 1, 0x82, 0, 0]);
+
+    let program = vec![line_instr(OpCode::Load, vec![Arg::Register("r3".into()), address(3)], None)];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!((diagnostics[0].code, diagnostics[0].message.as_str()), ("invalid-scale", "Scale in '[r1+r2*3]' must be 1, 2, 4 or 8"));
+  }
+
+  #[test]
+  fn strict_mode_rejects_legacy_leniencies() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Add, vec![Arg::Register("r1".into()), Arg::Immediate(5), Arg::Register("r2".into())], None),
+      line_instr(OpCode::Load, vec![Arg::Register("r1".into()), Arg::Mem(Box::new(Arg::Register("r2".into())))], None),
+      Line::Section(".data".into()),
+      line_instr(OpCode::Halt, vec![], None),
+    ];
+    assert!(Assembler::assemble(&program, None).is_ok());
+
+    let mut diagnostics = Vec::new();
+    assert!(Assembler::new().with_strict(true).assemble_program(&program, None, &mut diagnostics).is_none());
+    let messages: Vec<(&str, &str)> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("operand-kind", "ADD expects a register, found '5'"),
+      ("instruction-section", "HALT is outside .text"),
+    ]);
+  }
+
+  #[test]
+  fn blocks_must_nest() {
+    let program = vec![
+      Line::Section(".text".into()),
+      directive("while", Some("r1")),
+      directive("if", Some("42")),
+      directive("endwhile", None),
+      directive("else", None),
+      directive("if", Some("!r2")),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<(&str, &str)> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("block-condition", "`.if` takes a register, found '42'"),
+      ("unmatched-block", "`.endwhile` closes a `.if`"),
+      ("unmatched-block", "`.else` without an open `.if`"),
+      ("unclosed-block", "`.while` is never closed"),
+      ("unclosed-block", "`.if` is never closed"),
+    ]);
+  }
+
+  #[test]
+  fn expansion_replaces_blocks_with_their_branches() {
+    let program = [
+      Line::LabelOnly("main".into()),
+      directive("if", Some("r1")),
+      line_instr(OpCode::Halt, vec![], None),
+      directive("endif", None),
+      line_instr(OpCode::Ret, vec![], None),
+    ];
+    let mut assembler = Assembler::new().with_expansion();
+    for (index, line) in program.iter().enumerate() {
+      assembler.feed(line, Some(Span::new(index + 1, 1, 1).in_file(Some("a.leaf"))));
+    }
+    assert_eq!(
+      assembler.expanded_source().unwrap(),
+      ";#line 1 \"a.leaf\"\nmain:\n  JZ r1, @if.0.else\n  HALT\n@if.0.else:\n  RET\n",
+    );
+    assert_eq!(Assembler::new().expanded_source(), None);
+  }
+
+  #[test]
+  fn struct_fields_are_constants() {
+    let program = vec![
+      Line::Section(".text".into()),
+      directive("struct", Some("POINT")),
+      directive("field", Some("tag, byte")),
+      directive("field", Some("x, word")),
+      directive("field", Some("y, word")),
+      directive("endstruct", None),
+      directive("struct", Some("SHAPE")),
+      directive("field", Some("corners, POINT[4] ; four of them")),
+      directive("field", Some("visible, byte")),
+      directive("endstruct", None),
+      line_instr(OpCode::Movi, vec![Arg::Register("r1".into()), Arg::Label("POINT.y".into())], None),
+      line_instr(OpCode::Movi, vec![Arg::Register("r2".into()), Arg::Label("SHAPE.size".into())], None),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert!(object.relocations.is_empty());
+    // POINT is 24 bytes, so SHAPE is 4 * 24 + 1, padded to 8
+    assert_eq!(&object.bytecode[5..9], &16u32.to_le_bytes());
+    assert_eq!(&object.bytecode[14..18], &104u32.to_le_bytes());
+
+    let program = vec![
+      Line::Section(".text".into()),
+      directive("struct", Some("NODE")),
+      directive("field", Some("next, wrod")),
+      line_instr(OpCode::Halt, vec![], None),
+      directive("field", Some("size, word")),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<(&str, &str)> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("unknown-type", "Unknown field type 'wrod'"),
+      ("struct-body", "Only `.field` lines can be inside `.struct NODE`"),
+      ("duplicate-field", "A field cannot be called `size`, which is reserved for `NODE.size`"),
+      ("unclosed-struct", "`.struct NODE` is never closed"),
+    ]);
+    assert_eq!(diagnostics[0].notes, vec!["did you mean `word`?".to_string()]);
+  }
+
+  #[test]
+  fn equ_defines_constants() {
+    let program = vec![
+      Line::Section(".text".into()),
+      directive("equ", Some("BUFFER_SIZE, 0x40 ; bytes")),
+      directive("equ", Some("LIMIT, BUFFER_SIZE")),
+      line_instr(OpCode::Movi, vec![Arg::Register("r1".into()), Arg::Label("LIMIT".into())], None),
+      line_instr(OpCode::Movi, vec![Arg::Register("r2".into()), Arg::Label("MASK".into())], None),
+      directive("equ", Some("MASK, -1")),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert!(object.relocations.is_empty());
+    assert!(object.symbols.is_empty());
+    assert_eq!(&object.bytecode[5..9], &64u32.to_le_bytes());
+    assert_eq!(&object.bytecode[14..18], &u32::MAX.to_le_bytes());
+
+    let program = vec![directive("equ", Some("A, 1")), directive("equ", Some("A, 2")), directive("equ", Some("B 3")), directive("equ", Some("C, D"))];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec!["duplicate-constant", "invalid-equ", "invalid-equ"]);
+  }
+
+  #[test]
+  fn label_offsets_become_addends() {
+    let program = vec![
+      Line::Section(".text".into()),
+      directive("equ", Some("SIZE, 16")),
+      line_instr(OpCode::Halt, vec![], Some("main")),
+      line_instr(OpCode::Jmp, vec![Arg::LabelOffset("main".into(), 1)], None),
+      line_instr(OpCode::Movi, vec![Arg::Register("r1".into()), Arg::LabelOffset("SIZE".into(), -1)], None),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(object.relocations.len(), 1);
+    assert_eq!(object.addends, vec![Addend { relocation: 0, value: 1 }]);
+    // Constants take their offset straight away
+    assert_eq!(&object.bytecode[11..15], &15u32.to_le_bytes());
+
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Halt, vec![], Some("main")),
+      line_instr(OpCode::Jmp, vec![Arg::LabelOffset("main".into(), 2)], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!(diagnostics[0].message, "JMP target 'main+2' is at .text+0x2, which is not the start of an instruction");
+  }
+
+  #[test]
+  fn local_labels_belong_to_the_last_global_label() {
+    let mut program = vec![
+      Line::Section(".text".into()),
+      Line::LabelOnly("first".into()),
+      line_instr(OpCode::Halt, vec![], Some("1")),
+      line_instr(OpCode::Jmp, vec![Arg::Label("1b".into())], None),
+      line_instr(OpCode::Jmp, vec![Arg::Label("1f".into())], None),
+      line_instr(OpCode::Halt, vec![], Some("1")),
+      Line::LabelOnly(".Lend".into()),
+      line_instr(OpCode::Jmp, vec![Arg::Label(".Lend".into())], None),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    // Relocated against .text at their offsets, and kept out of the symbol table
+    let targets: Vec<_> = (0..object.relocations.len()).map(|index| (object.section_target(index), object.addend(index))).collect();
+    assert_eq!(targets, vec![(Some(0), 0), (Some(0), 11), (Some(0), 12)]);
+    assert!(object.symbols.iter().all(|symbol| !symbol.name.starts_with('@')));
+
+    // A global label starts over, so `1b` no longer sees the `1:`s before it
+    program.push(Line::LabelOnly("second".into()));
+    program.push(line_instr(OpCode::Jmp, vec![Arg::Label("1b".into())], None));
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let error = diagnostics.iter().find(|diagnostic| diagnostic.is_error()).unwrap();
+    assert_eq!(error.message, "Undefined symbol '1b'");
+  }
+
+  #[test]
+  fn dollar_is_the_start_of_the_statement() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Movi, vec![Arg::Register("r1".into()), Arg::Label("LEN".into())], Some("main")),
+      line_instr(OpCode::Jmp, vec![Arg::Label("$".into())], None),
+      line_instr(OpCode::Jmp, vec![Arg::LabelOffset(".".into(), -5)], None),
+      Line::Section(".data".into()),
+      Line::LabelOnly("msg".into()),
+      directive("ascii", Some("\"hello\"")),
+      directive("equ", Some("LEN, $ - msg")),
+    ];
+    let object = Assembler::assemble(&program, None).unwrap();
+    let targets: Vec<_> = (0..object.relocations.len()).map(|index| (object.section_target(index), object.addend(index))).collect();
+    assert_eq!(targets, vec![(Some(0), 9), (Some(0), 9)]);
+    assert!(object.symbols.iter().all(|symbol| !symbol.name.starts_with('@')));
+    assert_eq!(&object.bytecode[5..9], &5u32.to_le_bytes());
+
+    // `$ - label` is only a constant once the label is defined, in the same section
+    let program = vec![Line::Section(".text".into()), directive("equ", Some("LEN, $ - msg"))];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    assert_eq!(diagnostics[0].message, "Invalid .equ value '$ - msg': expected an integer, an earlier constant or `$ - label`");
+  }
+
+  #[test]
+  fn syscall_names_are_constants() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Movi, vec![Arg::Register("r0".into()), Arg::Label("SYS_WRITE".into())], None),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(obj.bytecode, vec![0x16, 0, 0, 0, 0, 5, 0, 0, 0]);
+    assert!(obj.relocations.is_empty());
+  }
+
+  #[test]
+  fn emits_line_table_when_spans_are_known() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Nop, vec![], None),
+      line_instr(OpCode::Push, vec![Arg::Register("r1".into())], None),
+    ];
+    let spans = vec![Span::new(1, 1, 5), Span::new(2, 1, 3), Span::new(4, 1, 7)]
+      .into_iter().map(|s| s.in_file(Some("a.leaf"))).collect();
+    let mut diagnostics = Vec::new();
+    let obj = Assembler::new().with_spans(spans).assemble_program(&program, None, &mut diagnostics).unwrap();
+    let debug = obj.debug_info.unwrap();
+    assert_eq!(debug.files, vec!["a.leaf".to_string()]);
+    assert_eq!(debug.lines, vec![
+      LineEntry { offset: 0, file: 0, line: 2 },
+      LineEntry { offset: 1, file: 0, line: 4 },
+    ]);
+  }
+
+  #[test]
+  fn reports_every_malformed_word() {
+    let program = vec![
+      Line::Section(".data".into()),
+      directive("word", Some("abc")),
+      directive("word", Some("1 zz ; comment")),
+    ];
+    let spans = vec![Span::new(1, 1, 5), Span::new(2, 3, 11), Span::new(3, 3, 14)];
+    let mut diagnostics = Vec::new();
+    let obj = Assembler::new().with_spans(spans).assemble_program(&program, None, &mut diagnostics);
+    assert!(obj.is_none());
+    let found: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str(), d.span.as_ref().map(|s| s.line))).collect();
+    assert_eq!(found, vec![
+      ("invalid-word", "Invalid .word value 'abc': expected an integer", Some(2)),
+      ("invalid-word", "Invalid .word value 'zz': expected an integer", Some(3)),
+    ]);
+  }
+
+  #[test]
+  fn memory_operands_only_address_loads_and_stores() {
+    let mem = |arg| Arg::Mem(Box::new(arg));
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Load, vec![Arg::Register("r1".into()), mem(Arg::Immediate(42))], None),
+      line_instr(OpCode::Loadi, vec![Arg::Register("r1".into()), mem(Arg::Register("r2".into()))], None),
+    ];
+    let obj = Assembler::assemble(&program, None).unwrap();
+    assert_eq!(obj.bytecode, vec![0x17, 1, 0, 0, 0, 42, 0, 0, 0, 0x0D, 1, 0, 0, 0, 2, 0, 0, 0]);
+
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Mov, vec![Arg::Register("r1".into()), mem(Arg::Immediate(42))], None),
+      line_instr(OpCode::Storei, vec![mem(Arg::Register("r1".into())), Arg::Immediate(8)], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("memory-operand", "MOV does not take a memory operand, found '[42]'"),
+      ("memory-operand", "STOREI does not take a memory operand, found '[r1]'"),
+    ]);
+  }
+
+  #[test]
+  fn bad_operands_are_diagnostics() {
+    let nested = Arg::Mem(Box::new(Arg::Mem(Box::new(Arg::Immediate(8)))));
+    let program = vec![
+      Line::Section(".txt".into()),
+      line_instr(OpCode::Load, vec![Arg::Register("r1".into()), nested], None),
+      line_instr(OpCode::Push, vec![Arg::Register("r32".into())], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("unknown-section", "Unknown section '.txt'"),
+      ("nested-memory-operand", "Memory operands cannot be nested: '[[8]]'"),
+      ("invalid-register", "Unknown register 'r32'"),
+    ]);
+  }
+
+  #[test]
+  fn warns_about_unknown_directives() {
+    let program = vec![
+      Line::Section(".data".into()),
+      directive("wrod", Some("42")),
+    ];
+    let mut diagnostics = Vec::new();
+    let obj = Assembler::new().assemble_program(&program, None, &mut diagnostics).unwrap();
+    assert!(obj.data.is_empty());
+    assert_eq!(diagnostics.len(), 1);
+    assert!(!diagnostics[0].is_error());
+    assert_eq!(diagnostics[0].message, "Unknown directive '.wrod'");
+    assert_eq!(diagnostics[0].notes, vec!["did you mean `.word`?".to_string()]);
+  }
+
+  #[test]
+  fn load_and_store_need_a_memory_operand() {
+    let program = vec![
+      Line::Section(".text".into()),
+      line_instr(OpCode::Load, vec![Arg::Register("r1".into()), Arg::Register("r2".into())], None),
+      line_instr(OpCode::Store, vec![Arg::Register("r1".into()), Arg::Label("x".into())], None),
+    ];
+    let diagnostics = Assembler::assemble(&program, None).unwrap_err();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str(), d.notes[0].as_str())).collect();
+    assert_eq!(messages[..2], [
+      ("addressing-mode", "LOAD takes a memory operand, found 'r2'", "write `LOAD r1, [r2]`"),
+      ("addressing-mode", "STORE takes a memory operand, found 'x'", "write `STORE r1, [x]`"),
+    ]);
+  }
+
+  #[test]
+  fn warns_about_unused_labels_and_externs() {
+    let program = vec![
+      Line::Section(".text".into()),
+      Line::Extern(vec!["used".into(), "unused".into()]),
+      Line::Global(vec!["api".into()]),
+      Line::LabelOnly("main".into()),
+      line_instr(OpCode::Call, vec![Arg::Label("used".into())], Some("body")),
+      Line::LabelOnly("api".into()),
+      Line::LabelOnly("dead".into()),
+      line_instr(OpCode::Jmp, vec![Arg::Label("dead".into())], Some("again")),
+      // Used only to measure the table
+      Line::Section(".data".into()),
+      Line::LabelOnly("start".into()),
+      directive("word", Some("1 2 3")),
+      directive("equ", Some("LEN, $ - start")),
+    ];
+    let mut diagnostics = Vec::new();
+    Assembler::new().assemble_program(&program, Some("main".to_string()), &mut diagnostics).unwrap();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("unused-extern", "Extern 'unused' is never used"),
+      ("unused-label", "Label 'body' is never used"),
+      ("unused-label", "Label 'again' is never used"),
+    ]);
+  }
+
+  #[test]
+  fn branch_targets_must_be_instructions() {
+    let program = vec![
+      Line::Section(".text".into()),
+      Line::LabelOnly("main".into()),
+      line_instr(OpCode::Call, vec![Arg::Label("table".into())], None),
+      line_instr(OpCode::Jz, vec![Arg::Register("r1".into()), Arg::Label("value".into())], None),
+      line_instr(OpCode::Jmp, vec![Arg::Label("main".into())], None),
+      Line::LabelOnly("table".into()),
+      directive("word", Some("0")),
+      Line::Section(".data".into()),
+      Line::LabelOnly("value".into()),
+      directive("word", Some("0")),
+    ];
+    let diagnostics = Assembler::assemble(&program, Some("main".to_string())).unwrap_err();
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("branch-target", "CALL target 'table' is at .text+0x13, which is not the start of an instruction"),
+      ("branch-target", "JZ target 'value' is in .data"),
+    ]);
+  }
+
+  #[test]
+  fn section_flags_override_the_defaults() {
+    let program = vec![
+      Line::Section(".data, \"rwx\"".into()),
+      Line::Section(".rodata, \"\"".into()),
+      Line::Section(".data, \"r\"".into()),
+      Line::Section(".text, \"rz\"".into()),
+    ];
+    let mut diagnostics = Vec::new();
+    assert!(Assembler::new().assemble_program(&program, None, &mut diagnostics).is_none());
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("section-flags", "Section flags changed from rwx to r--"),
+      ("section-flags", "Invalid section flags \"rz\""),
+    ]);
+
+    let obj = Assembler::assemble(&program[..3], None).unwrap();
+    let flags: Vec<_> = obj.section_flags.iter().map(ToString::to_string).collect();
+    assert_eq!(flags, vec!["r-x", "r--", "---"]);
+  }
+}
@@ -0,0 +1,167 @@
+//! A content-addressed cache of assembled objects, used by `leaf_asm build`. An object is stored
+//! under the SHA-256 of everything that goes into assembling it: the source as the assembler sees
+//! it, with its `.include`s expanded, the file name its debug info records, the assembler options,
+//! the installed extension instructions and the toolchain version. An unchanged file hashes to the same key and is read
+//! back instead of being assembled again; anything that changes the output changes the key, so
+//! entries never need invalidating.
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::isa;
+use leaf_common::leaf_file::LeafAsmFile;
+use leaf_common::{ReadableResource, WriteableResource};
+use crate::assembler::assemble::Assembler;
+use crate::{assemble_program, preprocess, AssembleOptions};
+
+/// Directory `build` keeps its cache in unless told otherwise.
+pub const DEFAULT_DIR: &str = ".leafcache";
+
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+  dir: PathBuf,
+}
+
+impl BuildCache {
+  /// A cache in `dir`, which is created when the first object is stored.
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    BuildCache { dir: dir.into() }
+  }
+
+  /// The key `source` is stored under when assembled as `file` with `options`.
+  pub fn key(source: &str, file: Option<&str>, options: AssembleOptions) -> String {
+    let mut hasher = Sha256::new();
+    // Length-prefixed so no two inputs run together into the same bytes
+    let mut field = |bytes: &[u8]| {
+      hasher.update((bytes.len() as u64).to_le_bytes());
+      hasher.update(bytes);
+    };
+    field(env!("CARGO_PKG_VERSION").as_bytes());
+    field(&options.target_version.to_le_bytes());
+    field(&[options.strict as u8]);
+    field(file.unwrap_or("").as_bytes());
+    for ext in isa::installed() {
+      field(format!("{} {:02X} {:?}", ext.info.mnemonic, ext.info.byte, ext.operands).as_bytes());
+    }
+    field(source.as_bytes());
+    hasher.finalize().iter().fold(String::new(), |mut hex, byte| {
+      write!(hex, "{:02x}", byte).unwrap();
+      hex
+    })
+  }
+
+  fn path(&self, key: &str) -> PathBuf {
+    self.dir.join(format!("{}.leafobj", key))
+  }
+
+  /// The object stored under `key`, if there is one and it still reads back.
+  pub fn get(&self, key: &str) -> Option<LeafAsmFile> {
+    LeafAsmFile::read_from_path(self.path(key)).ok()
+  }
+
+  /// Store `file` under `key`. It is written next to its final name and renamed into place, so a
+  /// build that is interrupted never leaves a truncated entry behind.
+  pub fn put(&self, key: &str, file: &LeafAsmFile) -> std::io::Result<()> {
+    std::fs::create_dir_all(&self.dir)?;
+    let temporary = self.dir.join(format!("{}.tmp{}", key, std::process::id()));
+    file.write_to_path(&temporary).map_err(std::io::Error::other)?;
+    std::fs::rename(&temporary, self.path(key))
+  }
+
+  /// Assemble `source` like `assemble_expanded`, including files from next to `file` and then
+  /// `include_dirs`, or read it from the cache if the same source was assembled before. The key is
+  /// taken after the includes are expanded, so changing an included file misses the cache. The flag
+  /// is true for a cache hit. Only objects that assembled without any diagnostics are stored, so a
+  /// hit never hides a warning.
+  pub fn assemble(
+    &self,
+    source: &str,
+    file: Option<&str>,
+    options: AssembleOptions,
+    include_dirs: &[PathBuf],
+    diagnostics: &mut Vec<Diagnostic>,
+  ) -> Option<(LeafAsmFile, bool)> {
+    let before = diagnostics.len();
+    let program = preprocess(source, file, options, include_dirs, diagnostics);
+    // Every line with the file and line it came from, which the line table records
+    let key = Self::key(&format!("{:?}", program), file, options);
+    if diagnostics.len() == before
+      && let Some(cached) = self.get(&key)
+    {
+      return Some((cached, true));
+    }
+    let assembled = assemble_program(&program, Assembler::new(), options, before, diagnostics).0?;
+    if diagnostics.len() == before
+      && let Err(e) = self.put(&key, &assembled)
+    {
+      diagnostics.push(Diagnostic::warning("cache", format!("Failed to cache {}: {}", file.unwrap_or("object"), e)));
+    }
+    Some((assembled, false))
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unchanged_sources_come_from_the_cache() {
+    let dir = std::env::temp_dir().join(format!("leaf-cache-test-{}", std::process::id()));
+    let cache = BuildCache::new(&dir);
+    let source = "main:\n  MOVI r1, 1\n  HALT\n";
+    let mut diagnostics = Vec::new();
+    let (first, hit) = cache.assemble(source, Some("a.leaf"), AssembleOptions::default(), &[], &mut diagnostics).unwrap();
+    assert!(!hit);
+    let (second, hit) = cache.assemble(source, Some("a.leaf"), AssembleOptions::default(), &[], &mut diagnostics).unwrap();
+    assert!(hit);
+    assert_eq!(second.object, first.object);
+    assert!(diagnostics.is_empty());
+
+    let options = AssembleOptions::default();
+    let key = BuildCache::key(source, Some("a.leaf"), options);
+    assert_ne!(BuildCache::key(source, Some("b.leaf"), options), key);
+    assert_ne!(BuildCache::key(source, Some("a.leaf"), AssembleOptions { target_version: 1, ..options }), key);
+    assert_ne!(BuildCache::key(source, Some("a.leaf"), AssembleOptions { strict: true, ..options }), key);
+    assert_ne!(BuildCache::key("main:\n  MOVI r1, 2\n  HALT\n", Some("a.leaf"), options), key);
+
+    // Warnings are reported every time rather than cached away
+    let unused = "main:\n  HALT\nunused:\n  RET\n";
+    for _ in 0..2 {
+      let (_, hit) = cache.assemble(unused, None, AssembleOptions::default(), &[], &mut diagnostics).unwrap();
+      assert!(!hit);
+    }
+    assert_eq!(diagnostics.len(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn included_files_are_part_of_the_key() {
+    let dir = std::env::temp_dir().join(format!("leaf-cache-include-test-{}", std::process::id()));
+    let lib = dir.join("lib");
+    std::fs::create_dir_all(&lib).unwrap();
+    let cache = BuildCache::new(dir.join("cache"));
+    let main = dir.join("main.leaf");
+    let main = main.to_str().unwrap();
+    let source = ".include \"consts.leaf\"\nmain:\n  MOVI r1, ANSWER\n  HALT\n";
+    let mut diagnostics = Vec::new();
+    let mut build = || cache.assemble(source, Some(main), AssembleOptions::default(), std::slice::from_ref(&lib), &mut diagnostics).unwrap();
+
+    std::fs::write(lib.join("consts.leaf"), ".equ ANSWER, 42\n").unwrap();
+    let (first, hit) = build();
+    assert!(!hit);
+    assert_eq!(&first.object.bytecode[5..9], &42u32.to_le_bytes());
+    assert!(build().1);
+
+    // The source is unchanged, but what it includes is not
+    std::fs::write(lib.join("consts.leaf"), ".equ ANSWER, 7\n").unwrap();
+    let (second, hit) = build();
+    assert!(!hit);
+    assert_eq!(&second.object.bytecode[5..9], &7u32.to_le_bytes());
+    assert!(diagnostics.is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
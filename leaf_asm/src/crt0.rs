@@ -0,0 +1,78 @@
+//! The startup runtime linked in front of executables. `_start` aligns the stack, calls the
+//! program's entry function and passes what it returns in `r0` to the `EXIT` syscall, so a `main`
+//! can simply `RET`. Leaf has no `.bss`, and the VM zeroes memory and sets up `r15` before running
+//! anything, so there is nothing else to do.
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::leaf_file::LeafAsmObject;
+use crate::assemble_source;
+use leaf_vm::profile::Profile;
+use crate::linker::linker::{link, link_with, link_with_profile};
+use crate::linker::Region;
+
+/// Source of `crt0`, in `src/runtime/crt0.leaf`.
+pub const SOURCE: &str = include_str!("runtime/crt0.leaf");
+
+/// Where executables linked with `crt0` start.
+pub const START_SYMBOL: &str = "_start";
+
+/// The name `crt0` calls, replaced by the entry function in `object`.
+const MAIN_SYMBOL: &str = "main";
+
+/// `crt0` assembled, calling `entry` instead of `main`.
+pub fn object(entry: &str) -> LeafAsmObject {
+  let mut diagnostics = Vec::new();
+  let mut object = assemble_source(SOURCE, Some("crt0.leaf"), &mut diagnostics)
+    .expect("crt0 assembles")
+    .object;
+  for symbol in object.symbols.iter_mut().filter(|s| s.external && s.name == MAIN_SYMBOL) {
+    symbol.name = entry.to_string();
+  }
+  object.entry_point = None;
+  object
+}
+
+/// Link `objects` into an executable that starts in `crt0`, which calls `entry`. `crt0` comes
+/// first, so `_start` is at address 0.
+pub fn link_executable(objects: &[LeafAsmObject], entry: &str) -> Result<LeafAsmObject, Diagnostic> {
+  link(&with_crt0(objects, entry), START_SYMBOL)
+}
+
+/// Like `link_executable`, with `.text` ordered by `profile` as `link_with_profile` does, so
+/// `_start` is only at address 0 if nothing ran more than it.
+pub fn link_executable_with_profile(objects: &[LeafAsmObject], entry: &str, profile: &Profile) -> Result<LeafAsmObject, Diagnostic> {
+  link_with_profile(&with_crt0(objects, entry), START_SYMBOL, profile)
+}
+
+/// Like `link_executable`, linked with `link_with`.
+pub fn link_executable_with(objects: &[LeafAsmObject], entry: &str, profile: Option<&Profile>, regions: &[Region]) -> Result<LeafAsmObject, Diagnostic> {
+  link_with(&with_crt0(objects, entry), START_SYMBOL, profile, regions)
+}
+
+fn with_crt0(objects: &[LeafAsmObject], entry: &str) -> Vec<LeafAsmObject> {
+  let mut all = Vec::with_capacity(objects.len() + 1);
+  all.push(object(entry));
+  all.extend_from_slice(objects);
+  all
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_vm::vm::{ExitStatus, VM};
+
+  #[test]
+  fn main_returns_its_exit_status() {
+    let mut diagnostics = Vec::new();
+    let file = assemble_source("start:\n  MOVI r0, 3\n  RET\n", None, &mut diagnostics).unwrap();
+    let linked = link_executable(&[file.object], "start").unwrap();
+    assert_eq!(linked.entry_point.as_deref(), Some(START_SYMBOL));
+
+    let mut vm = VM::new(0x1003);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    assert_eq!(vm.pc, 0);
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Exited(3)));
+    assert_eq!(vm.registers[15], 0x1000);
+  }
+}
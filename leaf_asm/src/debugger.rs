@@ -0,0 +1,272 @@
+//! Terminal debugger for linked executables: shows disassembly around the PC, registers, a memory
+//! window and the call stack, and steps the VM under user control.
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use leaf_common::disassembler::disassemble_at;
+use leaf_common::leaf_ast::OpCode;
+use leaf_common::leaf_file::LeafAsmObject;
+use leaf_common::error::LeafError;
+use leaf_common::symbolicate::symbolicate;
+use leaf_vm::vm::{ExitStatus, VM};
+
+const HELP: &str = "\
+  s, step             execute one instruction, entering calls
+  n, next             execute one instruction, running calls to completion
+  c, continue         run until a breakpoint, BREAK or the program stops
+  b, break LOC        set a breakpoint at a symbol or address
+  d, delete LOC       remove a breakpoint
+  x ADDR [LEN]        show LEN bytes of memory from ADDR in the memory view
+  h, help             show this message
+  q, quit             leave the debugger
+";
+
+/// Instructions shown before and after the PC.
+const CONTEXT: usize = 5;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+struct Frame {
+  /// Address of the called function.
+  target: usize,
+  /// Where `RET` will continue.
+  return_addr: usize,
+}
+
+pub struct Debugger {
+  vm: VM,
+  object: LeafAsmObject,
+  /// Start address of every instruction in `.text`, for showing code before the PC.
+  instructions: Vec<usize>,
+  breakpoints: BTreeSet<usize>,
+  frames: Vec<Frame>,
+  memory_view: (usize, usize),
+}
+
+impl Debugger {
+  pub fn new(object: LeafAsmObject, memory_size: usize) -> Result<Self, LeafError> {
+    let mut vm = VM::new(memory_size);
+    vm.debug = false;
+    vm.load_object(&object)?;
+
+    let mut instructions = Vec::new();
+    let mut pc = 0;
+    while pc < object.bytecode.len() {
+      instructions.push(pc);
+      pc += disassemble_at(&object.bytecode, pc).1;
+    }
+    // Show the start of .data by default
+    let memory_view = (vm.data_base, 64);
+    Ok(Self { vm, object, instructions, breakpoints: BTreeSet::new(), frames: Vec::new(), memory_view })
+  }
+
+  pub fn vm(&self) -> &VM {
+    &self.vm
+  }
+
+  /// Handle one command, returning a message to show under the next screen.
+  pub fn eval(&mut self, input: &str) -> Result<String, String> {
+    let mut parts = input.split_whitespace();
+    match parts.next().unwrap_or("s") {
+      "s" | "step" => {
+        self.step();
+        Ok(String::new())
+      }
+      "n" | "next" => {
+        let depth = self.frames.len();
+        self.step();
+        while !self.vm.halted && self.frames.len() > depth && !self.breakpoints.contains(&self.vm.pc) {
+          self.step();
+        }
+        Ok(String::new())
+      }
+      "c" | "continue" => {
+        self.step();
+        while !self.vm.halted && !self.breakpoints.contains(&self.vm.pc) {
+          self.step();
+        }
+        Ok(String::new())
+      }
+      "b" | "break" => {
+        let addr = self.location(parts.next().ok_or("usage: break SYMBOL|ADDR")?)?;
+        self.breakpoints.insert(addr);
+        Ok(format!("breakpoint at {}\n", symbolicate(&self.object, addr as u32)))
+      }
+      "d" | "delete" => {
+        let addr = self.location(parts.next().ok_or("usage: delete SYMBOL|ADDR")?)?;
+        match self.breakpoints.remove(&addr) {
+          true => Ok(format!("deleted breakpoint at 0x{:04X}\n", addr)),
+          false => Err(format!("no breakpoint at 0x{:04X}", addr)),
+        }
+      }
+      "x" => {
+        let addr = self.location(parts.next().ok_or("usage: x ADDR [LEN]")?)?;
+        let len = parts.next().map(parse_number).transpose()?.unwrap_or(64);
+        self.memory_view = (addr, len);
+        Ok(String::new())
+      }
+      "h" | "help" => Ok(HELP.to_string()),
+      other => Err(format!("unknown command '{}' (try help)", other)),
+    }
+  }
+
+  /// Execute one instruction, keeping the call stack in sync.
+  fn step(&mut self) {
+    if self.vm.halted {
+      // Resume after BREAK; any other stop is final
+      if self.vm.status != Some(ExitStatus::Breakpoint) {
+        return;
+      }
+      self.vm.halted = false;
+      self.vm.status = None;
+    }
+    let pc = self.vm.pc;
+    let opcode = self.object.bytecode.get(pc).and_then(|b| OpCode::byte_to_opcode(*b));
+    self.vm.step();
+    match opcode {
+      Some(OpCode::Call) if !self.vm.halted => self.frames.push(Frame { target: self.vm.pc, return_addr: pc + 5 }),
+      Some(OpCode::Ret) if !self.vm.halted => {
+        self.frames.pop();
+      }
+      _ => {}
+    }
+  }
+
+  /// A symbol name or a number.
+  fn location(&self, text: &str) -> Result<usize, String> {
+    match self.object.symbols.iter().find(|s| s.name == text && !s.external) {
+      Some(symbol) => Ok(self.vm.run_base(symbol.section).unwrap_or(0) + symbol.offset as usize),
+      None => parse_number(text).map_err(|_| format!("unknown symbol or address '{}'", text)),
+    }
+  }
+
+  /// The whole screen: status line, code, registers, memory and call stack.
+  pub fn render(&self) -> String {
+    let pc = self.vm.pc;
+    let mut out = String::new();
+    let status = match &self.vm.status {
+      None => "stopped".to_string(),
+      Some(ExitStatus::Breakpoint) => "paused at BREAK".to_string(),
+      Some(status) => format!("finished: {:?}", status),
+    };
+    out.push_str(&format!("── {} ── pc 0x{:04X} {} ──\n", status, pc, symbolicate(&self.object, pc as u32)));
+
+    let at = self.instructions.partition_point(|start| *start < pc);
+    for &addr in &self.instructions[at.saturating_sub(CONTEXT)..(at + CONTEXT + 1).min(self.instructions.len())] {
+      let marker = if addr == pc { "=>" } else { "  " };
+      let breakpoint = if self.breakpoints.contains(&addr) { "*" } else { " " };
+      let location = symbolicate(&self.object, addr as u32);
+      let label = match location.symbol {
+        Some(symbol) if symbol.offset as usize == addr => format!("<{}>", symbol.name),
+        _ => String::new(),
+      };
+      let line = location.line.map(|line| format!("; line {}", line)).unwrap_or_default();
+      let row = format!("{}{} 0x{:04X} {:<10} {:<28} {}",
+        breakpoint, marker, addr, label, disassemble_at(&self.object.bytecode, addr).0, line);
+      out.push_str(row.trim_end());
+      out.push('\n');
+    }
+
+    out.push_str("── registers ──\n");
+    for (i, value) in self.vm.registers.iter().enumerate() {
+      out.push_str(&format!("r{:<2} {:016X}", i, value));
+      out.push_str(if i % 4 == 3 { "\n" } else { "  " });
+    }
+
+    let (addr, len) = self.memory_view;
+    out.push_str(&format!("── memory 0x{:04X} ──\n", addr));
+    let end = addr.saturating_add(len).min(self.vm.heap.len());
+    if addr < end {
+      for (row, chunk) in self.vm.heap[addr..end].chunks(16).enumerate() {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        out.push_str(&format!("0x{:04X} | {}\n", addr + row * 16, bytes.join(" ")));
+      }
+    }
+
+    out.push_str("── call stack ──\n");
+    out.push_str(&format!("#0 {}\n", symbolicate(&self.object, pc as u32)));
+    for (depth, frame) in self.frames.iter().rev().enumerate() {
+      out.push_str(&format!("#{} {} (called {})\n",
+        depth + 1, symbolicate(&self.object, frame.return_addr as u32), symbolicate(&self.object, frame.target as u32)));
+    }
+    out
+  }
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+  let parsed = match s.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  parsed.map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// Debug `object` interactively on stdin/stdout, redrawing the screen after every command.
+pub fn run(object: LeafAsmObject, memory_size: usize) -> Result<(), Box<dyn std::error::Error>> {
+  let mut debugger = Debugger::new(object, memory_size)?;
+  let stdin = std::io::stdin();
+  let mut stdout = std::io::stdout();
+  let mut message = "type help for commands; an empty line steps\n".to_string();
+
+  loop {
+    // Clear the screen and move the cursor home
+    print!("\x1b[2J\x1b[H{}{}(leafdb) ", debugger.render(), message);
+    stdout.flush()?;
+    let mut line = String::new();
+    if stdin.lock().read_line(&mut line)? == 0 {
+      break;
+    }
+    let line = line.trim();
+    if line == "q" || line == "quit" {
+      break;
+    }
+    message = debugger.eval(line).unwrap_or_else(|e| format!("error: {}\n", e));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{assemble_source, linker::linker::link};
+
+  const PROGRAM: &str = ".text\nmain:\n  MOVI r1, 3\n  CALL square\n  MOVI r3, 1\n  HALT\nsquare:\n  MUL r2, r1, r1\n  RET\n";
+
+  fn debugger() -> Debugger {
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(PROGRAM, Some("sq.lasm"), &mut diagnostics).unwrap();
+    Debugger::new(link(&[file.object], "main").unwrap(), 0x1000).unwrap()
+  }
+
+  #[test]
+  fn step_enters_calls_and_tracks_the_stack() {
+    let mut db = debugger();
+    db.eval("s").unwrap();
+    db.eval("s").unwrap();
+    let screen = db.render();
+    assert!(screen.contains("=> 0x0018 <square>"), "{}", screen);
+    assert!(screen.contains("#1 main+0xE (sq.lasm:5) (called square+0x0"), "{}", screen);
+    db.eval("s").unwrap();
+    db.eval("s").unwrap();
+    assert_eq!(db.vm().pc, 14);
+    assert!(!db.render().contains("#1"));
+  }
+
+  #[test]
+  fn next_steps_over_calls_and_continue_stops_at_breakpoints() {
+    let mut db = debugger();
+    db.eval("b 0x000E").unwrap();
+    db.eval("s").unwrap();
+    db.eval("n").unwrap();
+    assert_eq!((db.vm().pc, db.vm().registers[2]), (14, 9));
+
+    let mut db = debugger();
+    db.eval("break square").unwrap();
+    db.eval("c").unwrap();
+    assert_eq!(db.vm().pc, 24);
+    assert!(db.render().contains("*=> 0x0018"));
+    db.eval("delete square").unwrap();
+    db.eval("c").unwrap();
+    assert_eq!(db.vm().status, Some(ExitStatus::Halted));
+    assert_eq!(db.vm().registers[3], 1);
+    assert!(db.eval("break nowhere").is_err());
+  }
+}
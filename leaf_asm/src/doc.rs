@@ -0,0 +1,127 @@
+//! API documentation for assembly libraries, taken from `;;;` comments. A run of `;;;` lines right
+//! above a label documents it, and every label listed with `.global` gets an entry, documented or
+//! not. Other comments, and doc comments on labels that are not exported, are left out.
+//!
+//! ```asm
+//! .global strlen
+//! ;;; Length of the string at `r1`, not counting its terminator.
+//! ;;; Returns the length in `r0`.
+//! strlen:
+//! ```
+use std::collections::HashMap;
+use serde::Serialize;
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::leaf_ast::Line;
+use crate::parser::parse_source;
+
+/// Marks a documentation comment.
+const DOC_PREFIX: &str = ";;;";
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize)]
+pub struct SymbolDoc {
+  pub name: String,
+  /// Line the label is defined on.
+  pub line: usize,
+  /// The comment text with the `;;;` markers removed, one line per comment line; empty if the
+  /// label has no doc comment.
+  pub doc: String,
+}
+
+/// Documentation for the exported symbols of one source file, in definition order.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize)]
+pub struct Documentation {
+  pub file: Option<String>,
+  pub symbols: Vec<SymbolDoc>,
+}
+
+impl Documentation {
+  /// Parse `source` and collect the docs of its exported labels. `file` names it in the output.
+  pub fn extract(source: &str, file: Option<&str>) -> Result<Self, Diagnostic> {
+    let program = parse_source(source, file)?;
+    // Comment blocks by the line after their last line
+    let mut blocks: HashMap<usize, Vec<&str>> = HashMap::new();
+    let mut block = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+      match text.trim_start().strip_prefix(DOC_PREFIX) {
+        Some(doc) => block.push(doc.strip_prefix(' ').unwrap_or(doc).trim_end()),
+        None if !block.is_empty() => {
+          blocks.insert(index + 1, std::mem::take(&mut block));
+        }
+        None => {}
+      }
+    }
+
+    let mut globals = Vec::new();
+    let mut labels = Vec::new();
+    for (line, span) in program.lines.iter().zip(&program.spans) {
+      match line {
+        Line::Global(names) => globals.extend(names.iter().map(AsRef::as_ref)),
+        Line::LabelOnly(label) => labels.push((label.as_ref(), span.line)),
+        Line::Instruction(instr) if let Some(label) = &instr.label => labels.push((label.as_ref(), span.line)),
+        _ => {}
+      }
+    }
+    let symbols = labels.into_iter()
+      .filter(|(label, _)| globals.contains(label))
+      .map(|(label, line)| SymbolDoc {
+        name: label.to_string(),
+        line,
+        doc: blocks.get(&line).map(|block| block.join("\n")).unwrap_or_default(),
+      })
+      .collect();
+    Ok(Documentation { file: file.map(str::to_string), symbols })
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("documentation serializes")
+  }
+
+  /// A Markdown page with a section per symbol.
+  pub fn to_markdown(&self) -> String {
+    let mut out = format!("# {}\n", self.file.as_deref().unwrap_or("API"));
+    for symbol in &self.symbols {
+      out.push_str(&format!("\n## `{}`\n\n", symbol.name));
+      if symbol.doc.is_empty() {
+        out.push_str("_Undocumented._\n");
+      } else {
+        out.push_str(&symbol.doc);
+        out.push('\n');
+      }
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exported_labels_keep_their_doc_comments() {
+    let source = "\
+.global strlen memset
+;;; Length of the string at `r1`.
+;;;
+;;;   Returns it in `r0`.
+strlen:
+  RET
+;;; Not exported, so not documented.
+helper:
+  RET
+;;; Separated from its label by a blank line.
+
+memset: MOVI r0, 0 ; fill
+  RET
+";
+    let docs = Documentation::extract(source, Some("string.leaf")).unwrap();
+    assert_eq!(docs.symbols, vec![
+      SymbolDoc { name: "strlen".to_string(), line: 5, doc: "Length of the string at `r1`.\n\n  Returns it in `r0`.".to_string() },
+      SymbolDoc { name: "memset".to_string(), line: 12, doc: String::new() },
+    ]);
+    assert_eq!(
+      docs.to_markdown(),
+      "# string.leaf\n\n## `strlen`\n\nLength of the string at `r1`.\n\n  Returns it in `r0`.\n\n## `memset`\n\n_Undocumented._\n",
+    );
+    assert!(docs.to_json().contains("\"name\": \"strlen\""));
+  }
+}
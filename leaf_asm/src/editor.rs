@@ -0,0 +1,166 @@
+//! Syntax highlighting grammars for editors, built from the tables the assembler itself uses:
+//! `OPCODES` and any installed extension instructions, `DIRECTIVES`, the registers and the syscall
+//! names. Regenerating them after the instruction set changes keeps highlighting in step with
+//! what actually assembles.
+use serde_json::json;
+use leaf_common::isa;
+use leaf_common::opcode::OPCODES;
+use leaf_common::syscall::SYSCALLS;
+use crate::assembler::assemble::{DIRECTIVES, REGISTER_COUNT};
+
+/// Every mnemonic, built-in ones first.
+fn mnemonics() -> Vec<&'static str> {
+  OPCODES.iter().map(|info| info.mnemonic)
+    .chain(isa::installed().into_iter().map(|ext| ext.info.mnemonic))
+    .collect()
+}
+
+fn registers() -> Vec<String> {
+  (0..REGISTER_COUNT).map(|n| format!("r{}", n)).collect()
+}
+
+fn directives() -> Vec<String> {
+  DIRECTIVES.iter().map(|(name, _)| format!(".{}", name)).collect()
+}
+
+fn syscalls() -> Vec<&'static str> {
+  SYSCALLS.iter().map(|(name, _)| *name).collect()
+}
+
+/// A regex matching any of `words` as a whole word.
+fn word_pattern<S: AsRef<str>>(words: &[S]) -> String {
+  let words: Vec<_> = words.iter().map(|word| word.as_ref().replace('.', "\\.")).collect();
+  format!("(?<![\\w.])(?:{})(?![\\w.])", words.join("|"))
+}
+
+/// Like `word_pattern`, in either case, as mnemonics and registers are written.
+fn caseless_pattern<S: AsRef<str>>(words: &[S]) -> String {
+  format!("(?i:{})", word_pattern(words))
+}
+
+/// A TextMate grammar (`.tmLanguage.json`), as read by VS Code, Sublime Text and most other editors.
+pub fn textmate() -> String {
+  let grammar = json!({
+    "name": "Leaf Assembly",
+    "scopeName": "source.leaf",
+    "fileTypes": ["leaf"],
+    "patterns": [
+      { "include": "#doc-comment" },
+      { "include": "#comment" },
+      { "include": "#block-comment" },
+      { "include": "#string" },
+      { "include": "#label" },
+      { "include": "#directive" },
+      { "include": "#mnemonic" },
+      { "include": "#register" },
+      { "include": "#syscall" },
+      { "include": "#number" },
+    ],
+    "repository": {
+      "doc-comment": { "name": "comment.line.documentation.leaf", "match": ";;;.*$" },
+      "comment": { "name": "comment.line.leaf", "match": "(?:;|//).*$" },
+      "block-comment": { "name": "comment.block.leaf", "begin": "/\\*", "end": "\\*/" },
+      "string": {
+        "name": "string.quoted.double.leaf",
+        "begin": "\"",
+        "end": "\"",
+        "patterns": [{ "name": "constant.character.escape.leaf", "match": "\\\\." }],
+      },
+      "label": {
+        "match": "^\\s*([A-Za-z_.][A-Za-z0-9_.]*(?:@@?[A-Za-z0-9_.]+)?|[0-9]+)\\s*:",
+        "captures": { "1": { "name": "entity.name.function.label.leaf" } },
+      },
+      "directive": { "name": "keyword.control.directive.leaf", "match": word_pattern(&directives()) },
+      "mnemonic": { "name": "keyword.other.mnemonic.leaf", "match": caseless_pattern(&mnemonics()) },
+      "register": { "name": "variable.language.register.leaf", "match": caseless_pattern(&registers()) },
+      "syscall": { "name": "constant.language.syscall.leaf", "match": word_pattern(&syscalls()) },
+      "number": { "name": "constant.numeric.integer.leaf", "match": "-?\\b(?:0[xX][0-9A-Fa-f](?:_?[0-9A-Fa-f])*|0[bB][01](?:_?[01])*|0[oO][0-7](?:_?[0-7])*|[0-9](?:_?[0-9])*)\\b" },
+    },
+  });
+  serde_json::to_string_pretty(&grammar).expect("grammar serializes") + "\n"
+}
+
+/// `choice('A', 'B', ...)` in tree-sitter's DSL.
+fn choice<S: AsRef<str>>(words: &[S]) -> String {
+  let words: Vec<_> = words.iter().map(|word| format!("'{}'", word.as_ref())).collect();
+  format!("choice({})", words.join(", "))
+}
+
+/// A tree-sitter `grammar.js`. Mnemonics, registers and directives are keywords of `identifier`,
+/// so the parser tells them apart from labels the same way the assembler does.
+pub fn tree_sitter() -> String {
+  format!(r#"// Leaf assembly, generated by leaf_asm. Do not edit.
+module.exports = grammar({{
+  name: 'leaf',
+  word: $ => $.identifier,
+  extras: $ => [/[ \t\r]/, $.comment],
+  rules: {{
+    program: $ => seq(repeat(seq(optional($._line), '\n')), optional($._line)),
+    _line: $ => choice($.label, seq(optional($.label), $.instruction), $.directive),
+    label: $ => seq(field('name', choice($.identifier, $.number)), ':'),
+    instruction: $ => seq(field('mnemonic', $.mnemonic), optional(seq($._operand, repeat(seq(',', $._operand))))),
+    directive: $ => seq(field('name', $.directive_name), optional(field('arguments', $.directive_arguments))),
+    directive_arguments: $ => repeat1(choice($.string, $.register, $.syscall, $.number, $.identifier, ',', '!', '[', ']')),
+    _operand: $ => choice($.memory, $.relocated, $.register, $.syscall, $.number, $.local_ref, $.here, $.identifier),
+    memory: $ => seq('[', choice($.register, $.relocated, $.local_ref, $.here, $.identifier), ']'),
+    relocated: $ => seq('%', field('operator', $.identifier), '(', $.identifier, ')'),
+    mnemonic: $ => {mnemonics},
+    directive_name: $ => {directives},
+    register: $ => {registers},
+    syscall: $ => {syscalls},
+    number: $ => /-?(0[xX][0-9A-Fa-f](_?[0-9A-Fa-f])*|0[bB][01](_?[01])*|0[oO][0-7](_?[0-7])*|[0-9](_?[0-9])*)/,
+    local_ref: $ => /[0-9]+[bf]/,
+    here: $ => '$',
+    string: $ => /"([^"\\\n]|\\.)*"/,
+    identifier: $ => /[A-Za-z_.][A-Za-z0-9_.]*(@@?[A-Za-z0-9_.]+)?/,
+    comment: $ => token(choice(seq(choice(';', '//'), /[^\n]*/), seq('/*', /[^*]*\*+([^/*][^*]*\*+)*/, '/'))),
+  }},
+}});
+"#,
+    mnemonics = choice(&mnemonics()),
+    directives = choice(&directives()),
+    registers = choice(&registers()),
+    syscalls = choice(&syscalls()),
+  )
+}
+
+/// `queries/highlights.scm` for the tree-sitter grammar.
+pub const TREE_SITTER_HIGHLIGHTS: &str = "\
+(comment) @comment
+(string) @string
+(number) @number
+(register) @variable.builtin
+(syscall) @constant.builtin
+(mnemonic) @keyword
+(directive_name) @keyword.directive
+(label name: (_) @label)
+(local_ref) @label
+(here) @label
+(memory [\"[\" \"]\"] @punctuation.bracket)
+(relocated operator: (identifier) @function.builtin)
+";
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::isa::{InstructionDef, IsaExtension};
+
+  #[test]
+  fn grammars_follow_the_assembler_tables() {
+    let isa = IsaExtension { instructions: vec![InstructionDef { mnemonic: "POPCNT".to_string(), byte: 0xE9, operands: vec![] }] };
+    isa.install().unwrap();
+
+    let grammar: serde_json::Value = serde_json::from_str(&textmate()).unwrap();
+    let mnemonic = grammar["repository"]["mnemonic"]["match"].as_str().unwrap();
+    assert!(mnemonic.contains("|SYSCALL|") && mnemonic.contains("|POPCNT"));
+    let directive = grammar["repository"]["directive"]["match"].as_str().unwrap();
+    assert!(directive.contains("|\\.endwhile|"));
+    let register = grammar["repository"]["register"]["match"].as_str().unwrap();
+    assert!(register.starts_with("(?i:") && register.ends_with("|r31)(?![\\w.]))"));
+    assert_eq!(grammar["repository"]["block-comment"]["begin"], "/\\*");
+
+    let grammar = tree_sitter();
+    assert!(grammar.contains("'HALT'") && grammar.contains("'POPCNT'"));
+    assert!(grammar.contains("'.struct'") && grammar.contains("'SYS_EXIT'"));
+  }
+}
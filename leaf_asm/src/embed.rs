@@ -0,0 +1,106 @@
+//! Rust source describing a linked program, for host crates that embed the VM. A `build.rs` can
+//! link the program and write `rust_module` into `OUT_DIR`, then `include!` it, so the host reads
+//! symbols by name instead of hard-coding offsets that move whenever the program changes.
+//!
+//! ```no_run
+//! # use leaf_common::leaf_file::LeafAsmFile;
+//! # use leaf_common::ReadableResource;
+//! let file = LeafAsmFile::read_from_path("program.leafexe").unwrap();
+//! let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("program.rs");
+//! std::fs::write(out, leaf_asm::embed::rust_module(&file.object, "program.leafexe")).unwrap();
+//! ```
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::ops::Range;
+use leaf_common::leaf_file::{LeafAsmObject, SymbolEntry, ABSOLUTE_SECTION};
+
+/// A module of `pub const` items: the bounds of each section, the entry point and the address of
+/// every symbol `object` defines, as the VM loads it with the default layout: where its segments
+/// say, and sections without one packed after the section before. Symbols are at their run address,
+/// which differs from the load address only in a section that runs elsewhere, and absolute symbols
+/// are as they are. Symbols are named in upper case
+/// with `.` replaced by `_`; names that cannot be written in source, such as the labels `.if`
+/// generates, and names already taken are left out. `source` names the object in the header.
+pub fn rust_module(object: &LeafAsmObject, source: &str) -> String {
+  let place = |section: u8, after: u32| match object.segments.iter().find(|segment| segment.section == section) {
+    Some(segment) => segment.address..segment.address + segment.mem_size,
+    None => after..after + object.section_len(section).unwrap_or(0) as u32,
+  };
+  let text = place(0, 0);
+  let data = place(1, text.end);
+  let rodata = place(2, data.end);
+  let run = |section: u8, range: &Range<u32>| object.segments.iter().find(|s| s.section == section).map_or(range.start, |s| s.run_address);
+  let starts: [u32; ABSOLUTE_SECTION as usize + 1] = [run(0, &text), run(1, &data), run(2, &rodata), 0];
+
+  let mut out = String::new();
+  writeln!(out, "// Symbols of `{}`, generated by leaf_asm. Do not edit.", source).unwrap();
+  writeln!(out).unwrap();
+  let mut taken = HashSet::new();
+  for (name, range) in [("TEXT", &text), ("DATA", &data), ("RODATA", &rodata)] {
+    writeln!(out, "pub const {}_START: u32 = 0x{:X};", name, range.start).unwrap();
+    writeln!(out, "pub const {}_END: u32 = 0x{:X};", name, range.end).unwrap();
+    taken.extend([format!("{}_START", name), format!("{}_END", name)]);
+  }
+  let address = |symbol: &SymbolEntry| starts[symbol.section as usize] + symbol.offset;
+  let entry = object.entry_point.as_ref()
+    .and_then(|entry| object.symbols.iter().find(|s| !s.external && &s.name == entry && (s.section as usize) < starts.len()));
+  if let Some(entry) = entry {
+    writeln!(out, "pub const ENTRY: u32 = 0x{:X};", address(entry)).unwrap();
+    taken.insert("ENTRY".to_string());
+  }
+  let mut symbols: Vec<_> = object.symbols.iter().filter(|symbol| !symbol.external && (symbol.section as usize) < starts.len()).collect();
+  symbols.sort_by_key(|symbol| (address(symbol), symbol.name.as_str()));
+  if !symbols.is_empty() {
+    writeln!(out).unwrap();
+  }
+  for symbol in symbols {
+    let Some(name) = const_name(&symbol.name) else { continue };
+    if !taken.insert(name.clone()) {
+      continue;
+    }
+    writeln!(out, "pub const {}: u32 = 0x{:X};", name, address(symbol)).unwrap();
+  }
+  out
+}
+
+/// `name` as a constant, if it is a name the assembler accepts.
+fn const_name(name: &str) -> Option<String> {
+  let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+  if !valid {
+    return None;
+  }
+  let name: String = name.chars().map(|c| if c == '.' { '_' } else { c.to_ascii_uppercase() }).collect();
+  // `_` on its own is not an identifier
+  Some(if name.chars().all(|c| c == '_') { format!("{}SYMBOL", name) } else { name })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assemble_source;
+  use crate::linker::linker::link;
+
+  #[test]
+  fn symbols_become_constants() {
+    let source = "main:\n  .if r1\n    CALL done.1\n  .endif\n  HALT\ndone.1:\n  RET\n.data\ncounter:\n  .word 0\n";
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, None, &mut diagnostics).unwrap();
+    let linked = link(&[file.object], "main").unwrap();
+    assert_eq!(rust_module(&linked, "test.leafexe"), "\
+// Symbols of `test.leafexe`, generated by leaf_asm. Do not edit.
+
+pub const TEXT_START: u32 = 0x0;
+pub const TEXT_END: u32 = 0x10;
+pub const DATA_START: u32 = 0x10;
+pub const DATA_END: u32 = 0x18;
+pub const RODATA_START: u32 = 0x18;
+pub const RODATA_END: u32 = 0x18;
+pub const ENTRY: u32 = 0x0;
+
+pub const MAIN: u32 = 0x0;
+pub const DONE_1: u32 = 0xF;
+pub const COUNTER: u32 = 0x10;
+");
+  }
+}
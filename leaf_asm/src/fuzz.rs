@@ -0,0 +1,57 @@
+//! Entry points for fuzzing the toolchain with malformed input. Each one accepts anything and
+//! reports bad input as an error, so a panic under a fuzzer is always a bug. The cargo-fuzz
+//! targets in `fuzz/` call these.
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::error::{FormatError, LeafError};
+use leaf_common::leaf_file::{LeafAsmFile, LeafAsmObject};
+use leaf_common::ReadableResource;
+use crate::assemble_source;
+use crate::linker::linker::{link, link_shared};
+
+/// Parse and assemble `data` as leaf assembly, returning the object or every diagnostic.
+pub fn try_parse(data: &[u8]) -> Result<LeafAsmFile, Vec<Diagnostic>> {
+  let source = std::str::from_utf8(data)
+    .map_err(|e| vec![Diagnostic::error("invalid-utf8", format!("Source is not UTF-8: {}", e))])?;
+  let mut diagnostics = Vec::new();
+  assemble_source(source, Some("fuzz.leaf"), &mut diagnostics).ok_or(diagnostics)
+}
+
+/// Decode `data` as an object file and check its tables, as `leaf_asm link` and the VM do when
+/// reading one.
+pub fn try_decode_object(data: &[u8]) -> Result<LeafAsmFile, LeafError> {
+  let file = LeafAsmFile::read_from(&mut &data[..])?;
+  file.object.check().into_result().map_err(FormatError::Invalid)?;
+  Ok(file)
+}
+
+/// Link `objects` as an executable starting at `main` and, if that fails, as a shared object.
+pub fn try_link(objects: &[LeafAsmObject]) -> Result<LeafAsmObject, LeafError> {
+  link(objects, "main")
+    .or_else(|_| link_shared(objects))
+    .map_err(|e| LeafError::Link(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::WriteableResource;
+
+  #[test]
+  fn malformed_input_is_an_error() {
+    assert!(try_parse(b"\xFF\xFE").is_err());
+    assert!(try_parse(b"main:\n  MOVI r1, ,\n").is_err());
+    assert!(try_decode_object(b"").is_err());
+    assert!(try_decode_object(&[0xFF; 64]).is_err());
+
+    let file = try_parse(b".extern helper\nmain:\n  CALL helper\n  HALT\n").unwrap();
+    let mut bytes = Vec::new();
+    file.write_to(&mut bytes).unwrap();
+    let decoded = try_decode_object(&bytes).unwrap();
+    // `helper` is undefined, so only a shared object links
+    let linked = try_link(&[decoded.object]).unwrap();
+    assert_eq!(linked.entry_point, None);
+    for len in 0..bytes.len() {
+      let _ = try_decode_object(&bytes[..len]);
+    }
+  }
+}
@@ -0,0 +1,167 @@
+//! `.include "file"`, which splices another source file in before assembly. A name is looked up
+//! next to the file that includes it, then in each `-I` directory in order. The included lines keep
+//! spans in their own file, so diagnostics and the line table point at where each line really is.
+use std::path::{Path, PathBuf};
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::leaf_ast::Line;
+use crate::parser::{parse_source_recovering, ParsedProgram};
+
+/// `program`, parsed from `file`, with every `.include` replaced by the lines of the file it names,
+/// recursively. Included files are parsed with `parse_source_recovering` and `strict`. Errors, such
+/// as a file that cannot be found or one that ends up including itself, are appended to
+/// `diagnostics` and the `.include` left out.
+pub fn expand_includes(
+  program: ParsedProgram<'_>,
+  file: Option<&str>,
+  search: &[PathBuf],
+  strict: bool,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> ParsedProgram<'static> {
+  let mut stack: Vec<PathBuf> = file.and_then(|file| Path::new(file).canonicalize().ok()).into_iter().collect();
+  let mut expanded = ParsedProgram::default();
+  expand(program, file, search, strict, &mut stack, &mut expanded, diagnostics);
+  expanded
+}
+
+fn expand(
+  program: ParsedProgram<'_>,
+  file: Option<&str>,
+  search: &[PathBuf],
+  strict: bool,
+  stack: &mut Vec<PathBuf>,
+  expanded: &mut ParsedProgram<'static>,
+  diagnostics: &mut Vec<Diagnostic>,
+) {
+  for (line, span) in program.lines.into_iter().zip(program.spans) {
+    let Line::Directive(directive) = &line else {
+      expanded.lines.push(line.into_owned());
+      expanded.spans.push(span);
+      continue;
+    };
+    if directive.name != "include" {
+      expanded.lines.push(line.into_owned());
+      expanded.spans.push(span);
+      continue;
+    }
+    let Some(name) = directive.args.as_deref().and_then(quoted) else {
+      diagnostics.push(Diagnostic::error("invalid-include", "`.include` takes a file name in quotes")
+        .with_span(Some(span))
+        .with_note("for example `.include \"macros.leaf\"`"));
+      continue;
+    };
+    let Some(path) = resolve(name, file, search) else {
+      let dirs: Vec<String> = file.and_then(|file| Path::new(file).parent())
+        .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+        .into_iter().chain(search.iter().map(PathBuf::as_path))
+        .map(|dir| dir.display().to_string())
+        .collect();
+      diagnostics.push(Diagnostic::error("include-not-found", format!("Cannot find '{}' to include", name))
+        .with_span(Some(span))
+        .with_note(format!("searched {}; add directories with -I", dirs.join(", "))));
+      continue;
+    };
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+    if let Some(start) = stack.iter().position(|included| *included == canonical) {
+      let chain: Vec<String> = stack[start..].iter().chain([&canonical]).map(|path| path.display().to_string()).collect();
+      diagnostics.push(Diagnostic::error("include-cycle", format!("'{}' includes itself", name))
+        .with_span(Some(span))
+        .with_note(chain.join(" -> ")));
+      continue;
+    }
+    let path = path.display().to_string();
+    let source = match std::fs::read_to_string(&path) {
+      Ok(source) => source,
+      Err(e) => {
+        diagnostics.push(Diagnostic::error("io", format!("Failed to read {}: {}", path, e)).with_span(Some(span)));
+        continue;
+      }
+    };
+    let (included, errors) = parse_source_recovering(&source, Some(&path), strict);
+    diagnostics.extend(errors);
+    stack.push(canonical);
+    expand(included, Some(&path), search, strict, stack, expanded, diagnostics);
+    stack.pop();
+  }
+}
+
+/// The file name between the quotes of `.include`'s argument.
+fn quoted(args: &str) -> Option<&str> {
+  let (name, rest) = args.trim().strip_prefix('"')?.split_once('"')?;
+  let rest = rest.trim();
+  (!name.is_empty() && (rest.is_empty() || rest.starts_with(';'))).then_some(name)
+}
+
+/// Where `name` is: as given if it is absolute, otherwise next to `file` or in the first of
+/// `search` that has it.
+fn resolve(name: &str, file: Option<&str>, search: &[PathBuf]) -> Option<PathBuf> {
+  if Path::new(name).is_absolute() {
+    return Some(PathBuf::from(name)).filter(|path| path.is_file());
+  }
+  let beside = file.and_then(|file| Path::new(file).parent()).map(Path::to_path_buf).unwrap_or_default();
+  std::iter::once(beside).chain(search.iter().cloned())
+    .map(|dir| dir.join(name))
+    .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use crate::parser::parse_source;
+
+  fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("leaf-include-test-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn includes_are_spliced_in_with_their_own_spans() {
+    let root = dir("splice");
+    let lib = root.join("lib");
+    fs::create_dir_all(&lib).unwrap();
+    fs::write(root.join("consts.leaf"), ".equ ONE, 1\n.include \"helpers.leaf\"\n").unwrap();
+    fs::write(lib.join("helpers.leaf"), "helper:\n  RET\n").unwrap();
+    let main = root.join("main.leaf");
+    let main = main.to_str().unwrap();
+    let source = ".include \"consts.leaf\" ; constants\nmain:\n  HALT\n";
+
+    let program = parse_source(source, Some(main)).unwrap();
+    let mut diagnostics = Vec::new();
+    let expanded = expand_includes(program, Some(main), std::slice::from_ref(&lib), false, &mut diagnostics);
+    assert!(diagnostics.is_empty());
+    let files: Vec<_> = expanded.spans.iter().map(|span| (Path::new(span.file.as_deref().unwrap()).file_name().unwrap().to_str().unwrap(), span.line)).collect();
+    assert_eq!(files, vec![("consts.leaf", 1), ("helpers.leaf", 1), ("helpers.leaf", 2), ("main.leaf", 2), ("main.leaf", 3)]);
+
+    // Without the search directory the nested include is not found
+    let program = parse_source(source, Some(main)).unwrap();
+    let expanded = expand_includes(program, Some(main), &[], false, &mut diagnostics);
+    assert_eq!(expanded.lines.len(), 3);
+    let error = diagnostics.pop().unwrap();
+    assert_eq!(error.code, "include-not-found");
+    assert_eq!(error.span.unwrap().file, Some(root.join("consts.leaf").display().to_string()));
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn include_cycles_are_errors() {
+    let root = dir("cycle");
+    fs::write(root.join("a.leaf"), ".include \"b.leaf\"\n").unwrap();
+    fs::write(root.join("b.leaf"), ".include \"a.leaf\"\n").unwrap();
+    let a = root.join("a.leaf").display().to_string();
+    let source = fs::read_to_string(&a).unwrap();
+
+    let mut diagnostics = Vec::new();
+    expand_includes(parse_source(&source, Some(&a)).unwrap(), Some(&a), &[], false, &mut diagnostics);
+    let error = diagnostics.pop().unwrap();
+    assert_eq!(error.code, "include-cycle");
+    assert_eq!(error.message, "'a.leaf' includes itself");
+    let (a, b) = (root.join("a.leaf").canonicalize().unwrap(), root.join("b.leaf").canonicalize().unwrap());
+    assert_eq!(error.notes[0], format!("{} -> {} -> {}", a.display(), b.display(), a.display()));
+
+    expand_includes(parse_source(".include nope.leaf\n", None).unwrap(), None, &[], false, &mut diagnostics);
+    let error = diagnostics.pop().unwrap();
+    assert_eq!(error.code, "invalid-include");
+    fs::remove_dir_all(root).unwrap();
+  }
+}
@@ -0,0 +1,187 @@
+//! A tokenizer for editor tooling. It splits source into tokens with their spans without parsing
+//! it, so it never fails: text the parser would reject still comes back as tokens, and anything it
+//! cannot classify is `Unknown`. Tokens never span lines, so an editor only needs to run
+//! `tokenize_line` again on the lines that changed, and those after them up to where any `/* */`
+//! comment it opens or closes ends.
+use leaf_common::diagnostic::Span;
+use leaf_common::syscall::SYSCALLS;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+  /// A word where an instruction goes, whether or not it is a known instruction.
+  Mnemonic,
+  /// `.name` where a statement starts, including the section directives.
+  Directive,
+  Register,
+  /// A syscall name, such as `SYS_EXIT`.
+  Syscall,
+  Number,
+  /// A label where it is defined, without the `:` after it.
+  Label,
+  /// Any other name, usually a label used as an operand.
+  Symbol,
+  String,
+  Comment,
+  /// `:`, `,`, `[`, `]`, `!`, and the `%`, `(` and `)` of relocation operators.
+  Punctuation,
+  Unknown,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Token {
+  pub kind: TokenKind,
+  pub span: Span,
+}
+
+/// Every token in `source`. A `/* */` comment over several lines is a `Comment` token on each.
+pub fn tokenize(source: &str) -> Vec<Token> {
+  let mut in_comment = false;
+  source.lines().enumerate().flat_map(|(index, line)| tokenize_continuing(line, index + 1, &mut in_comment)).collect()
+}
+
+/// Where the `*/` that ends a comment, if any, finishes in `chars`, from `from` on.
+fn comment_end(chars: &[char], from: usize) -> Option<usize> {
+  (from..chars.len().saturating_sub(1)).find(|&i| chars[i] == '*' && chars[i + 1] == '/').map(|i| i + 2)
+}
+
+fn is_word_start(c: char) -> bool {
+  c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_word(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+/// The tokens of `text`, which is line `line` (1-based) of a file and contains no newline.
+pub fn tokenize_line(text: &str, line: usize) -> Vec<Token> {
+  tokenize_continuing(text, line, &mut false)
+}
+
+/// Like `tokenize_line`, for a line that starts inside a `/* */` comment if `in_comment` is set,
+/// which is left set if the line ends inside one.
+fn tokenize_continuing(text: &str, line: usize, in_comment: &mut bool) -> Vec<Token> {
+  let chars: Vec<char> = text.chars().collect();
+  let mut tokens = Vec::new();
+  // Whether the next word is where an instruction or directive goes
+  let mut statement_start = true;
+  let mut i = 0;
+  if *in_comment {
+    let end = comment_end(&chars, 0);
+    *in_comment = end.is_none();
+    i = end.unwrap_or(chars.len());
+    if i > 0 {
+      tokens.push(Token { kind: TokenKind::Comment, span: Span::new(line, 1, i) });
+    }
+  }
+  while i < chars.len() {
+    let c = chars[i];
+    let start = i;
+    let kind = if c.is_whitespace() {
+      i += 1;
+      continue;
+    } else if c == ';' || (c == '/' && chars.get(i + 1) == Some(&'/')) {
+      i = chars.len();
+      TokenKind::Comment
+    } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+      let end = comment_end(&chars, i + 2);
+      *in_comment = end.is_none();
+      i = end.unwrap_or(chars.len());
+      TokenKind::Comment
+    } else if c == '"' {
+      i += 1;
+      while i < chars.len() && chars[i] != '"' {
+        i += if chars[i] == '\\' { 2 } else { 1 };
+      }
+      i = (i + 1).min(chars.len());
+      TokenKind::String
+    } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) {
+      i += 1;
+      while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+      // Numeric local labels: `1:` defines one, `1b` and `1f` refer to one
+      let (digits, suffix) = chars[start..i].split_at(chars[start..i].iter().take_while(|c| c.is_ascii_digit()).count());
+      if suffix.is_empty() && statement_start && chars.get(i) == Some(&':') {
+        TokenKind::Label
+      } else if !digits.is_empty() && matches!(suffix, ['b'] | ['f']) {
+        TokenKind::Symbol
+      } else {
+        TokenKind::Number
+      }
+    } else if is_word_start(c) {
+      while i < chars.len() && is_word(chars[i]) {
+        i += 1;
+      }
+      let word: String = chars[start..i].iter().collect();
+      let defined = chars.get(i) == Some(&':');
+      let is_register = word.len() > 1 && word.starts_with(['r', 'R']) && word[1..].bytes().all(|b| b.is_ascii_digit());
+      if defined && statement_start {
+        TokenKind::Label
+      } else if statement_start && word.starts_with('.') {
+        TokenKind::Directive
+      } else if statement_start && word.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        TokenKind::Mnemonic
+      } else if is_register {
+        TokenKind::Register
+      } else if SYSCALLS.iter().any(|(name, _)| *name == word) {
+        TokenKind::Syscall
+      } else {
+        TokenKind::Symbol
+      }
+    } else if c == '$' {
+      // The current location
+      i += 1;
+      TokenKind::Symbol
+    } else {
+      i += 1;
+      if matches!(c, ':' | ',' | '[' | ']' | '!' | '%' | '(' | ')') { TokenKind::Punctuation } else { TokenKind::Unknown }
+    };
+    // A label keeps the statement start open for the instruction after it, as does a comment
+    let after_label = tokens.last().is_some_and(|token: &Token| token.kind == TokenKind::Label);
+    statement_start = kind == TokenKind::Label || (after_label && c == ':') || (statement_start && kind == TokenKind::Comment);
+    tokens.push(Token { kind, span: Span::new(line, start + 1, i - start) });
+  }
+  tokens
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn kinds(line: &str) -> Vec<(TokenKind, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    tokenize_line(line, 1).into_iter()
+      .map(|token| (token.kind, chars[token.span.column - 1..][..token.span.length].iter().collect()))
+      .collect()
+  }
+
+  #[test]
+  fn lines_split_into_classified_tokens() {
+    use TokenKind::*;
+    let tokens = |pairs: &[(TokenKind, &str)]| pairs.iter().map(|&(kind, text)| (kind, text.to_string())).collect::<Vec<_>>();
+    assert_eq!(kinds("loop: LOADI r2, [msg] ; next"), tokens(&[
+      (Label, "loop"), (Punctuation, ":"), (Mnemonic, "LOADI"), (Register, "r2"), (Punctuation, ","),
+      (Punctuation, "["), (Symbol, "msg"), (Punctuation, "]"), (Comment, "; next"),
+    ]));
+    assert_eq!(kinds("  .ascii \"a\\\"b\", -12"), tokens(&[(Directive, ".ascii"), (String, "\"a\\\"b\""), (Punctuation, ","), (Number, "-12")]));
+    assert_eq!(kinds("  movi R0, SYS_EXIT"), tokens(&[(Mnemonic, "movi"), (Register, "R0"), (Punctuation, ","), (Syscall, "SYS_EXIT")]));
+    assert_eq!(kinds("  MOVI r0, SYS_EXIT"), tokens(&[(Mnemonic, "MOVI"), (Register, "r0"), (Punctuation, ","), (Syscall, "SYS_EXIT")]));
+    assert_eq!(kinds("1: JNZ r1, 1b"), tokens(&[(Label, "1"), (Punctuation, ":"), (Mnemonic, "JNZ"), (Register, "r1"), (Punctuation, ","), (Symbol, "1b")]));
+    assert_eq!(kinds("  MOVI r1, 0b1"), tokens(&[(Mnemonic, "MOVI"), (Register, "r1"), (Punctuation, ","), (Number, "0b1")]));
+    // Text that does not parse still tokenizes
+    assert_eq!(kinds("JMP r1 # \"open"), tokens(&[(Mnemonic, "JMP"), (Register, "r1"), (Unknown, "#"), (String, "\"open")]));
+
+    assert_eq!(kinds("  JMP $"), tokens(&[(Mnemonic, "JMP"), (Symbol, "$")]));
+    assert_eq!(kinds("  HALT // done"), tokens(&[(Mnemonic, "HALT"), (Comment, "// done")]));
+    assert_eq!(kinds("/* a */ MOVI r1, 1"), tokens(&[(Comment, "/* a */"), (Mnemonic, "MOVI"), (Register, "r1"), (Punctuation, ","), (Number, "1")]));
+
+    let comments: Vec<_> = tokenize("HALT /* one\ntwo\n\nthree */ NOP\n").into_iter().map(|token| (token.kind, token.span)).collect();
+    assert_eq!(comments, vec![
+      (Mnemonic, Span::new(1, 1, 4)), (Comment, Span::new(1, 6, 6)), (Comment, Span::new(2, 1, 3)),
+      (Comment, Span::new(4, 1, 8)), (Mnemonic, Span::new(4, 10, 3)),
+    ]);
+
+    let spans: Vec<_> = tokenize("main:\n  HALT\n").into_iter().map(|token| token.span).collect();
+    assert_eq!(spans, vec![Span::new(1, 1, 4), Span::new(1, 5, 1), Span::new(2, 3, 4)]);
+  }
+}
@@ -0,0 +1,417 @@
+use std::io::BufRead;
+use std::path::PathBuf;
+use leaf_common::checksum::Checksum;
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::disassembler::disassemble_object;
+use leaf_common::leaf_ast::{to_source, Line};
+use leaf_common::leaf_file::{ByteOrder, LeafAsmFile, LeafAsmObject, LeafAsmObjectHeader};
+use leaf_common::opcode::ISA_VERSION;
+use crate::assembler::assemble::Assembler;
+use crate::parser::ParsedProgram;
+
+pub mod parser;
+pub mod linker;
+pub mod assembler;
+pub mod cache;
+pub mod repl;
+pub mod debugger;
+pub mod doc;
+pub mod fuzz;
+pub mod crt0;
+pub mod library;
+pub mod embed;
+pub mod editor;
+pub mod lexer;
+pub mod include;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Generate a header for a new object file that needs ISA revision `isa_version`
+pub fn make_header(isa_version: u16) -> LeafAsmObjectHeader {
+  LeafAsmObjectHeader {
+    magic: *b"LAF\0",
+    version: 1,
+    byte_order: ByteOrder::Little,
+    isa_version,
+    checksum: Checksum::default(), // filled in during write_to
+    build_id: None,
+  }
+}
+
+/// Parse and assemble a single source file into an object file, ready to be written out.
+/// Errors and warnings are appended to `diagnostics`; `None` is returned if there was an error.
+/// `file` names the source in diagnostic spans.
+pub fn assemble_source(source: &str, file: Option<&str>, diagnostics: &mut Vec<Diagnostic>) -> Option<LeafAsmFile> {
+  assemble_for_target(source, file, ISA_VERSION, diagnostics)
+}
+
+/// Like `assemble_source`, but instructions and directives from ISA revisions after
+/// `target_version` are errors.
+pub fn assemble_for_target(
+  source: &str,
+  file: Option<&str>,
+  target_version: u16,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Option<LeafAsmFile> {
+  assemble_with_options(source, file, AssembleOptions { target_version, ..AssembleOptions::default() }, diagnostics)
+}
+
+/// How `assemble_with_options` assembles a source file.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct AssembleOptions {
+  /// Newest ISA revision the source may use.
+  pub target_version: u16,
+  /// See `Assembler::with_strict`.
+  pub strict: bool,
+}
+
+impl Default for AssembleOptions {
+  fn default() -> Self {
+    AssembleOptions { target_version: ISA_VERSION, strict: false }
+  }
+}
+
+/// Like `assemble_source`, configured by `options`.
+pub fn assemble_with_options(
+  source: &str,
+  file: Option<&str>,
+  options: AssembleOptions,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Option<LeafAsmFile> {
+  assemble_parsed(source, file, Assembler::new(), options, None, diagnostics).0
+}
+
+/// Like `assemble_with_options`, also returning the source as the assembler consumed it, see
+/// `Assembler::expanded_source`. `.include`s are expanded first, looking for the files next to
+/// `file` and then in `include_dirs`. The expansion is there even if assembling fails, unless the
+/// source or an included file does not parse.
+pub fn assemble_expanded(
+  source: &str,
+  file: Option<&str>,
+  options: AssembleOptions,
+  include_dirs: &[PathBuf],
+  diagnostics: &mut Vec<Diagnostic>,
+) -> (Option<LeafAsmFile>, Option<String>) {
+  assemble_parsed(source, file, Assembler::new().with_expansion(), options, Some(include_dirs), diagnostics)
+}
+
+fn assemble_parsed(
+  source: &str,
+  file: Option<&str>,
+  assembler: Assembler,
+  options: AssembleOptions,
+  include_dirs: Option<&[PathBuf]>,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> (Option<LeafAsmFile>, Option<String>) {
+  // Lines that do not parse are reported and left out, and the rest still assembled so their errors
+  // are reported in the same run
+  let errors = diagnostics.len();
+  let (program, parse_errors) = parser::parse_source_recovering(source, file, options.strict);
+  diagnostics.extend(parse_errors);
+  let program = match include_dirs {
+    Some(dirs) => include::expand_includes(program, file, dirs, options.strict, diagnostics),
+    None => program,
+  };
+  assemble_program(&program, assembler, options, errors, diagnostics)
+}
+
+/// `source` parsed as `assemble_expanded` parses it, carrying on past lines that do not parse, with
+/// its `.include`s expanded. Errors are appended to `diagnostics`.
+pub(crate) fn preprocess(
+  source: &str,
+  file: Option<&str>,
+  options: AssembleOptions,
+  include_dirs: &[PathBuf],
+  diagnostics: &mut Vec<Diagnostic>,
+) -> ParsedProgram<'static> {
+  let (program, parse_errors) = parser::parse_source_recovering(source, file, options.strict);
+  diagnostics.extend(parse_errors);
+  include::expand_includes(program, file, include_dirs, options.strict, diagnostics)
+}
+
+/// Assemble a parsed `program`. Errors in `diagnostics` from index `errors` on, such as lines that
+/// did not parse, fail it, though it is still assembled so the rest of its errors are reported.
+pub(crate) fn assemble_program(
+  program: &ParsedProgram<'_>,
+  assembler: Assembler,
+  options: AssembleOptions,
+  errors: usize,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> (Option<LeafAsmFile>, Option<String>) {
+  let failed = diagnostics[errors..].iter().any(Diagnostic::is_error);
+  // Entry point: pick "main" if it exists, else None
+  let entry_point = program.lines.iter().filter_map(|l| match l {
+    Line::LabelOnly(l) => Some(l),
+    _ => None,
+  }).find(|l| l.as_ref() == "main").map(|_| "main".to_string());
+  let mut assembler = assembler.with_target_version(options.target_version).with_strict(options.strict);
+  for (line, span) in program.lines.iter().zip(&program.spans) {
+    assembler.feed(line, Some(span.clone()));
+  }
+  let expanded = assembler.expanded_source();
+  let header = make_header(assembler.required_version());
+  let object = assembler.finish(entry_point, diagnostics);
+  if failed {
+    return (None, None);
+  }
+
+  (object.map(|object| LeafAsmFile { header, object }), expanded)
+}
+
+/// Like `assemble_source`, but reads and assembles `reader` one line at a time so only the output
+/// sections, not the whole source and AST, are held in memory. Parsing carries on past syntax errors
+/// so every bad line is reported. A `/* */` comment over several lines is read in whole.
+pub fn assemble_reader(mut reader: impl BufRead, file: Option<&str>, diagnostics: &mut Vec<Diagnostic>) -> Option<LeafAsmFile> {
+  let mut assembler = Assembler::new();
+  let mut has_main = false;
+  let mut failed = false;
+  let mut buffer = String::new();
+  let mut line_number = 0;
+  loop {
+    let first_line = line_number + 1;
+    buffer.clear();
+    loop {
+      match reader.read_line(&mut buffer) {
+        Ok(0) => break,
+        Ok(_) => line_number += 1,
+        Err(e) => {
+          diagnostics.push(Diagnostic::error("io", format!("Failed to read source: {}", e)));
+          return None;
+        }
+      }
+      if !parser::ends_in_block_comment(&buffer) {
+        break;
+      }
+    }
+    if buffer.is_empty() {
+      break;
+    }
+    match parser::parse_fragment(&buffer, file, first_line) {
+      Ok(program) => {
+        for (line, span) in program.lines.iter().zip(program.spans) {
+          has_main |= matches!(line, Line::LabelOnly(l) if l.as_ref() == "main");
+          assembler.feed(line, Some(span));
+        }
+      }
+      Err(e) => {
+        diagnostics.push(e);
+        failed = true;
+      }
+    }
+  }
+  let entry_point = has_main.then(|| "main".to_string());
+  let header = make_header(assembler.required_version());
+  let object = assembler.finish(entry_point, diagnostics)?;
+  if failed {
+    return None;
+  }
+
+  Some(LeafAsmFile { header, object })
+}
+
+/// Disassemble `object` with `disassemble_object`, assemble the listing again and check that every
+/// section comes out byte for byte the same, as it does for anything the toolchain wrote.
+pub fn verify_round_trip(object: &LeafAsmObject) -> Result<(), Diagnostic> {
+  let source = to_source(&disassemble_object(object));
+  let program = parser::parse_program(&source)
+    .map_err(|e| e.with_note("in the disassembly, which should always parse"))?;
+  let mut diagnostics = Vec::new();
+  let Some(again) = Assembler::new().assemble_program(&program, None, &mut diagnostics) else {
+    let error = diagnostics.into_iter().find(Diagnostic::is_error).expect("assembly failed with an error");
+    return Err(error.with_note("in the disassembly, which should always assemble"));
+  };
+  let sections = [(".text", &object.bytecode, &again.bytecode), (".data", &object.data, &again.data), (".rodata", &object.rodata, &again.rodata)];
+  for (name, before, after) in sections {
+    if before != after {
+      let at = before.iter().zip(after.iter()).position(|(a, b)| a != b).unwrap_or(before.len().min(after.len()));
+      return Err(Diagnostic::error("round-trip", format!("{} differs from offset 0x{:X} once disassembled and assembled again", name, at)));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SOURCE: &str = ".text\nmain:\n  MOVI r1, 5\n  CALL helper\n  HALT\nhelper:\n  LOADI r2, [msg]\n  RET\n.data\nmsg:\n  .string \"hi\"\n";
+
+  #[test]
+  fn streaming_matches_whole_file_assembly() {
+    let mut diagnostics = Vec::new();
+    let whole = assemble_source(SOURCE, Some("a.lasm"), &mut diagnostics).unwrap();
+    let streamed = assemble_reader(SOURCE.as_bytes(), Some("a.lasm"), &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty());
+    assert_eq!(streamed, whole);
+  }
+
+  #[test]
+  fn streaming_reports_every_bad_line() {
+    let mut diagnostics = Vec::new();
+    let source = "main:\n  MOVI r1, ,\n  HALT\n  ADD r1 r2 r3 r4 ,\n";
+    assert!(assemble_reader(source.as_bytes(), None, &mut diagnostics).is_none());
+    let lines: Vec<usize> = diagnostics.iter().map(|d| d.span.as_ref().unwrap().line).collect();
+    assert_eq!(lines, vec![2, 4]);
+
+    // A comment over several lines is read in whole, and lines after it keep their numbers
+    diagnostics.clear();
+    let source = "main:\n  HALT /* one\n  two */\n  FROB r1\n";
+    assert!(assemble_reader(source.as_bytes(), None, &mut diagnostics).is_none());
+    assert_eq!(diagnostics.iter().map(|d| (d.code, d.span.as_ref().unwrap().line)).collect::<Vec<_>>(), vec![("unknown-opcode", 4)]);
+  }
+
+  #[test]
+  fn every_error_in_a_file_is_reported_together() {
+    let mut diagnostics = Vec::new();
+    let source = "main:\n  MOVI r1, ,\n  FROB r2\n  MOVI r40, 1\nmain:\n  HALT\n";
+    assert!(assemble_source(source, Some("a.leaf"), &mut diagnostics).is_none());
+    let errors: Vec<_> = diagnostics.iter().map(|d| (d.code, d.span.as_ref().unwrap().line)).collect();
+    assert_eq!(errors, vec![("syntax", 2), ("unknown-opcode", 3), ("invalid-register", 4), ("duplicate-label", 5)]);
+    assert_eq!(diagnostics[3].notes, vec!["first defined on line 1"]);
+  }
+
+  #[test]
+  fn assembled_and_linked_program_runs() {
+    let source = ".text\nmain:\n  MOVI r1, 6\n  CALL square\n  STOREI r2, [result]\n  HALT\nsquare:\n  MUL r2, r1, r1\n  RET\n.data\nresult:\n  .word 0\n";
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, None, &mut diagnostics).unwrap();
+    let linked = linker::linker::link(&[file.object], "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    let result = vm.code_len;
+    assert_eq!(u64::from_le_bytes(vm.heap[result..result + 8].try_into().unwrap()), 36);
+  }
+
+  #[test]
+  fn local_labels_run() {
+    // r2 = 1 + 2 + 3, then r3 = 3 * 4, reusing `1:` and `.Ldone` under each label
+    let source = "main:\n  MOVI r1, 3\n  MOVI r4, 1\n1:\n  ADD r2, r2, r1\n  SUB r1, r1, r4\n  JNZ r1, 1b\n  JMP 1f\n  HALT\n1:\n  CALL times\n  JMP .Ldone\n  NOP\n.Ldone:\n  HALT\ntimes:\n  MOVI r1, 4\n1:\n  ADD r3, r3, r4\n  JZ r1, .Ldone\n  SUB r1, r1, r4\n  JMP 1b\n.Ldone:\n  SUB r3, r3, r4\n  RET\n";
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, None, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    verify_round_trip(&file.object).unwrap();
+    let linked = linker::linker::link(&[file.object], "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    assert_eq!((vm.registers[2], vm.registers[3]), (6, 4));
+  }
+
+  #[test]
+  fn label_offsets_are_applied_when_linked() {
+    let main = ".extern values\nmain:\n  LOADI r1, [table+8]\n  LOADI r2, [values + 8]\n  JMP end-1\n  MOVI r3, 1\n  NOP\nend:\n  HALT\n.data\ntable:\n  .word 5 6\n";
+    let helper = ".global values\n.data\nvalues:\n  .word 7 8\n";
+    let mut diagnostics = Vec::new();
+    let objects = [main, helper].map(|source| assemble_source(source, None, &mut diagnostics).unwrap().object);
+    assert!(diagnostics.is_empty());
+    assert_eq!(objects[0].addends.len(), 3);
+    verify_round_trip(&objects[0]).unwrap();
+    let linked = linker::linker::link(&objects, "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    assert_eq!((vm.registers[1], vm.registers[2], vm.registers[3]), (6, 8, 0));
+  }
+
+  #[test]
+  fn register_offsets_address_memory() {
+    let source = "main:\n  MOVI r1, table\n  LOAD r2, [r1+8]\n  ADD r2, r2, r2\n  STORE r2, [r1 + 16]\n  MOVI r1, end\n  LOAD r3, [r1-8]\n  HALT\n.data\ntable:\n  .word 5 6 0\nend:\n";
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, None, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    assert_eq!(file.header.isa_version, 3);
+    verify_round_trip(&file.object).unwrap();
+    let linked = linker::linker::link(&[file.object], "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    assert_eq!((vm.registers[2], vm.registers[3]), (12, 12));
+  }
+
+  #[test]
+  fn indexed_addresses_walk_arrays() {
+    // r4 = table[0] + table[1] + table[2], then table[3] = r4
+    let source = "main:\n  MOVI r1, table\n  MOVI r2, 3\n  MOVI r3, 1\n1:\n  SUB r2, r2, r3\n  LOAD r5, [r1 + r2*8]\n  ADD r4, r4, r5\n  JNZ r2, 1b\n  MOVI r2, 3\n  STORE r4, [r1+r2*8]\n  HALT\n.data\ntable:\n  .word 5 6 7 0\n";
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, None, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    verify_round_trip(&file.object).unwrap();
+    let linked = linker::linker::link(&[file.object], "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    let table = vm.code_len;
+    assert_eq!(vm.registers[4], 18);
+    assert_eq!(u64::from_le_bytes(vm.heap[table + 24..table + 32].try_into().unwrap()), 18);
+  }
+
+  #[test]
+  fn aliases_stand_for_their_registers() {
+    let aliased = ".alias sp, r7\n.alias base, r1\n.alias i, r2\n.alias top, sp\nmain:\n  PUSH sp\n  LOAD r3, [top]\n  STORE r3, [sp-8]\n  LOAD r4, [base + i*8]\n  .if i\n    ADD sp, sp, i\n  .endif\n  HALT\n";
+    let plain = "main:\n  PUSH r7\n  LOAD r3, [r7]\n  STORE r3, [r7-8]\n  LOAD r4, [r1 + r2*8]\n  .if r2\n    ADD r7, r7, r2\n  .endif\n  HALT\n";
+    let mut diagnostics = Vec::new();
+    let aliased = assemble_source(aliased, None, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let plain = assemble_source(plain, None, &mut diagnostics).unwrap();
+    assert_eq!(aliased.object.bytecode, plain.object.bytecode);
+
+    let source = ".alias r1, r2\n.alias fp, x\n.alias fp\nmain:\n  PUSH fp\n  HALT\n";
+    assert!(assemble_source(source, None, &mut diagnostics).is_none());
+    let messages: Vec<_> = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect();
+    assert_eq!(messages, vec![
+      ("invalid-alias", "Invalid alias name 'r1'"),
+      ("invalid-alias", "Cannot alias 'fp' to 'x', which is not a register"),
+      ("invalid-alias", "Invalid .alias 'fp': expected `NAME, register`"),
+      ("undefined-symbol", "Undefined symbol 'fp'"),
+    ]);
+  }
+
+  #[test]
+  fn programs_survive_disassembly_and_reassembly() {
+    let source = ".extern helper\nmain:\n  MOVI r1, -7\n  CALL helper\n  LOADI r2, [msg]\n  .if r2\n    JMP done\n  .endif\n  RET\ntable:\n  .word 19\n  .ascii \"a\\\"\"\ndone:\n  HALT\n.data\nmsg:\n  .string \"hi\\n\"\nend:\n";
+    let mut diagnostics = Vec::new();
+    let object = assemble_source(source, None, &mut diagnostics).unwrap().object;
+    assert_eq!(object.data_in_text, vec![leaf_common::leaf_file::DataRange { offset: 38, len: 10 }]);
+    verify_round_trip(&object).unwrap();
+
+    let helper = assemble_source(".global helper\nhelper:\n  RET\n", None, &mut diagnostics).unwrap().object;
+    let linked = crt0::link_executable(&[object, helper], "main").unwrap();
+    assert!(!linked.data_in_text.is_empty());
+    verify_round_trip(&linked).unwrap();
+
+    // Bytes the assembler would never write are caught
+    let odd = LeafAsmObject { bytecode: vec![0x16, 1, 0, 0, 9, 0, 0, 0, 0], ..LeafAsmObject::default() };
+    assert_eq!(verify_round_trip(&odd).unwrap_err().code, "round-trip");
+  }
+
+  #[test]
+  fn structured_blocks_run() {
+    let main = ".text\n.extern helper\nmain:\n  CALL helper\n  MOVI r1, 5\n  MOVI r2, 0\n  MOVI r3, 1\n.while r1\n  AND r4, r1, r3\n  .if r4\n    ADD r2, r2, r1\n  .else\n    ADD r2, r2, r3\n  .endif\n  SUB r1, r1, r3\n.endwhile\n  HALT\n";
+    // Numbers its blocks like `main` does, so both objects branch to an `@if.1.else`
+    let helper = ".text\n.global helper\nhelper:\n  .if r9\n    MOVI r5, 1\n  .endif\n  .if !r0\n    MOVI r5, 7\n  .endif\n  RET\n";
+    let mut diagnostics = Vec::new();
+    let objects = [main, helper].map(|source| assemble_source(source, None, &mut diagnostics).unwrap().object);
+    assert!(diagnostics.is_empty());
+    // Block labels stay in the object that generated them
+    assert!(objects.iter().flat_map(|object| &object.symbols).all(|symbol| !symbol.name.starts_with('@')));
+    let linked = linker::linker::link(&objects, "main").unwrap();
+
+    let mut vm = leaf_vm::vm::VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    // 5 + 1 + 3 + 1 + 1
+    assert_eq!((vm.registers[2], vm.registers[5]), (11, 7));
+  }
+}
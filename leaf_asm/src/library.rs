@@ -0,0 +1,161 @@
+//! Libraries for `leaf_asm link -l NAME`, and the standard library `std` shipped with the
+//! toolchain. Its routines are written in Leaf assembly under `src/runtime/std`, one member each,
+//! and take their arguments in `r1` to `r3` and return in `r0`. They may change `r0` to `r9` and
+//! leave `r10` to `r15` as they found them.
+use std::path::{Path, PathBuf};
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::library::{LeafLibrary, LibraryMember};
+use leaf_common::ReadableResource;
+use crate::assemble_source;
+
+/// Name of the standard library, as in `-lstd`.
+pub const STD: &str = "std";
+
+/// The standard library's members and their sources.
+pub const STD_SOURCES: &[(&str, &str)] = &[
+  ("memcpy", include_str!("runtime/std/memcpy.leaf")),
+  ("memset", include_str!("runtime/std/memset.leaf")),
+  ("strlen", include_str!("runtime/std/strlen.leaf")),
+  ("strcmp", include_str!("runtime/std/strcmp.leaf")),
+  ("int_to_str", include_str!("runtime/std/int_to_str.leaf")),
+  ("str_to_int", include_str!("runtime/std/str_to_int.leaf")),
+  ("print_int", include_str!("runtime/std/print_int.leaf")),
+  ("malloc", include_str!("runtime/std/malloc.leaf")),
+];
+
+/// The standard library, assembled.
+pub fn std_library() -> LeafLibrary {
+  let members = STD_SOURCES.iter().map(|(name, source)| {
+    let mut diagnostics = Vec::new();
+    let file = assemble_source(source, Some(&format!("std/{}.leaf", name)), &mut diagnostics)
+      .unwrap_or_else(|| panic!("std/{}.leaf assembles", name));
+    LibraryMember { name: name.to_string(), file }
+  });
+  LeafLibrary { members: members.collect() }
+}
+
+/// Where `leaf_asm stdlib` installs the standard library by default: next to the running binary.
+pub fn install_path() -> Option<PathBuf> {
+  std::env::current_exe().ok().map(|exe| exe.with_file_name(format!("{}.leaflib", STD)))
+}
+
+/// The library `-l name` means: `name.leaflib` or `libname.leaflib` in the first of `dirs` that has
+/// one, then next to the running binary. `std` falls back to the copy built into the toolchain.
+pub fn find_library(name: &str, dirs: &[PathBuf]) -> Result<LeafLibrary, Diagnostic> {
+  let installed = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf));
+  let mut candidates = dirs.iter().chain(&installed)
+    .flat_map(|dir| [dir.join(format!("{}.leaflib", name)), dir.join(format!("lib{}.leaflib", name))]);
+  if let Some(path) = candidates.find(|path| path.is_file()) {
+    return LeafLibrary::read_from_path(&path)
+      .map_err(|e| Diagnostic::error("io", format!("Failed to read library {}: {}", path.display(), e)));
+  }
+  if name == STD {
+    return Ok(std_library());
+  }
+  let searched: Vec<_> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+  let mut diagnostic = Diagnostic::error("missing-library", format!("No library '{}' was found", name));
+  if !searched.is_empty() {
+    diagnostic = diagnostic.with_note(format!("searched {}", searched.join(", ")));
+  }
+  Err(diagnostic)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::WriteableResource;
+  use leaf_vm::vm::{ExitStatus, VM};
+  use crate::linker::linker::{library_members, link};
+
+  #[test]
+  fn std_routines_run() {
+    let source = "\
+.extern memcpy
+.extern memset
+.extern strlen
+.extern strcmp
+.extern int_to_str
+.extern str_to_int
+.extern malloc
+.extern free
+.rodata
+hello: .string \"hello\"
+help: .string \"help\"
+number: .string \"-1234x\"
+.data
+buf: .word 0 0 0 0
+.text
+main:
+  MOVI r1, buf
+  MOVI r2, hello
+  MOVI r3, 6
+  CALL memcpy
+  MOVI r1, buf
+  CALL strlen
+  MOV r10, r0
+  MOVI r1, buf
+  MOVI r2, help
+  CALL strcmp
+  MOV r11, r0
+  MOVI r1, number
+  CALL str_to_int
+  MOV r12, r0
+  MOV r1, r0
+  MOVI r2, buf
+  CALL int_to_str
+  MOV r13, r0
+  MOVI r1, buf
+  MOVI r2, 120
+  MOVI r3, 2
+  CALL memset
+  MOVI r1, 16
+  CALL malloc
+  MOV r14, r0
+  MOV r1, r0
+  CALL free
+  MOVI r1, 12
+  CALL malloc
+  SUB r14, r14, r0
+  HALT
+";
+    let mut diagnostics = Vec::new();
+    for (name, source) in STD_SOURCES {
+      assemble_source(source, Some(name), &mut diagnostics).unwrap();
+    }
+    let program = assemble_source(source, None, &mut diagnostics).unwrap().object;
+    let libraries = [std_library()];
+    let members: Vec<_> = library_members(std::slice::from_ref(&program), &libraries).into_iter().map(|m| m.file.object.clone()).collect();
+    assert_eq!(members.len(), STD_SOURCES.len() - 1);
+    let linked = link(&[vec![program], members].concat(), "main").unwrap();
+    assert!(diagnostics.is_empty());
+
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&linked).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+    assert_eq!(vm.registers[10], 5);
+    assert_eq!(vm.registers[11] as i64, b'l' as i64 - b'p' as i64);
+    assert_eq!(vm.registers[12] as i64, -1234);
+    assert_eq!(vm.registers[13], 5);
+    // The freed block is handed out again
+    assert_eq!(vm.registers[14], 0);
+    let buf = linked.symbols.iter().find(|s| s.name == "buf").unwrap();
+    let buf = linked.bytecode.len() + buf.offset as usize;
+    assert_eq!(&vm.heap[buf..buf + 6], b"xx234\0");
+  }
+
+  #[test]
+  fn libraries_are_found_in_the_search_directories() {
+    let dir = std::env::temp_dir().join(format!("leaf-library-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let only_strlen = LeafLibrary { members: vec![std_library().member("strlen").unwrap().clone()] };
+    only_strlen.write_to_path(dir.join("libstrings.leaflib")).unwrap();
+
+    let found = find_library("strings", std::slice::from_ref(&dir)).unwrap();
+    assert_eq!(found.members[0].file.object, only_strlen.members[0].file.object);
+    assert_eq!(find_library("strings", &[]).unwrap_err().code, "missing-library");
+    assert_eq!(find_library(STD, &[]).unwrap().members.len(), STD_SOURCES.len());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,49 +1,299 @@
-use log::{debug, info};
-use leaf_common::leaf_file::{LeafAsmObject, RelocationType, SymbolEntry};
+use std::collections::{HashMap, HashSet};
+use log::info;
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::interner::Interner;
+use leaf_common::leaf_ast::OpCode;
+use leaf_common::leaf_file::{Addend, ABSOLUTE_SECTION, DataRange, DebugInfo, ExportEntry, LeafAsmObject, LeafAsmObjectHeader, LineEntry, RelocationEntry, RelocationType, SectionFlags, SymbolEntry};
+use leaf_common::library::{LeafLibrary, LibraryMember};
+use leaf_common::symver;
+use leaf_vm::profile::Profile;
+use super::Region;
 
-pub fn link(objects: &[LeafAsmObject], entry_point: &str) -> Result<LeafAsmObject, String> {
+/// Names of sections 0, 1 and 2.
+const SECTION_NAMES: [&str; 3] = [".text", ".data", ".rodata"];
+
+pub fn link(objects: &[LeafAsmObject], entry_point: &str) -> Result<LeafAsmObject, Diagnostic> {
+  link_objects(objects, Some(entry_point), None, &[])
+}
+
+/// Like `link`, but `.text` is laid out hottest first according to `profile`, a profile of an
+/// earlier build of the same program. Code is moved in runs that control cannot fall into or out
+/// of: from a symbol right after a `JMP`, `RET` or `HALT` up to the next such symbol. Runs nothing
+/// in the profile executed go last, in their original order.
+pub fn link_with_profile(objects: &[LeafAsmObject], entry_point: &str, profile: &Profile) -> Result<LeafAsmObject, Diagnostic> {
+  link_objects(objects, Some(entry_point), Some(profile), &[])
+}
+
+/// Like `link`, with `.text` ordered by `profile` if there is one, and `.data` and `.rodata`
+/// running at the addresses `regions` give them. See `Region` for overlays.
+pub fn link_with(objects: &[LeafAsmObject], entry_point: &str, profile: Option<&Profile>, regions: &[Region]) -> Result<LeafAsmObject, Diagnostic> {
+  link_objects(objects, Some(entry_point), profile, regions)
+}
+
+/// Link objects into a shared object (`.leafso`) for `VM::load_shared`: like `link`, but symbols
+/// no object defines stay external as imports, and every relocation is kept so the loader can
+/// place the module at any address and bind its imports.
+pub fn link_shared(objects: &[LeafAsmObject]) -> Result<LeafAsmObject, Diagnostic> {
+  link_objects(objects, None, None, &[])
+}
+
+/// The ISA revision a program linked from these files needs: the newest any of them needs. An
+/// object that needs a revision after `target` is an error naming it.
+pub fn isa_version<'a>(files: impl IntoIterator<Item = (&'a str, &'a LeafAsmObjectHeader)>, target: u16) -> Result<u16, Diagnostic> {
+  let mut required = 1;
+  for (name, header) in files {
+    let version = header.required_isa_version();
+    if version > target {
+      return Err(
+        Diagnostic::error("isa-version", format!("{} needs ISA revision {}, but the target is revision {}", name, version, target))
+          .with_note(format!("target revision {} or later with `--target-version`", version)),
+      );
+    }
+    required = required.max(version);
+  }
+  Ok(required)
+}
+
+/// Check that the sections linked from `objects`, each named for messages, fit the `regions` they
+/// are assigned to. An overflow names the region, the sections in it, how many bytes too many there
+/// are and the objects that contributed most to them. Each section of an overlay only has to fit
+/// on its own.
+pub fn check_regions(regions: &[Region], objects: &[(&str, &LeafAsmObject)]) -> Result<(), Diagnostic> {
+  let section_size = |object: &LeafAsmObject, section: usize| [object.bytecode.len(), object.data.len(), object.rodata.len()][section] as u64;
+  let size = |section: usize| objects.iter().map(|(_, object)| section_size(object, section)).sum::<u64>();
+  for (region, sections) in regions.iter().zip(region_sections(regions)?) {
+    let sections: Vec<usize> = match region.overlay {
+      true => sections.into_iter().filter(|&section| size(section) > region.length as u64).collect(),
+      false => sections,
+    };
+    let sizes: Vec<u64> = sections.iter().map(|&section| size(section)).collect();
+    let total: u64 = match region.overlay {
+      true => sizes.iter().max().copied().unwrap_or(0),
+      false => sizes.iter().sum(),
+    };
+    if total <= region.length as u64 {
+      continue;
+    }
+    let names: Vec<&str> = sections.iter().map(|&section| SECTION_NAMES[section]).collect();
+    let mut diagnostic = Diagnostic::error("region-overflow", format!(
+      "{} overflow{} region '{}' by {} bytes ({} of {})",
+      names.join(" and "), if names.len() == 1 { "s" } else { "" }, region.name, total - region.length as u64, total, region.length));
+    if sections.len() > 1 {
+      let parts: Vec<String> = names.iter().zip(&sizes).map(|(name, size)| format!("{} is {} bytes", name, size)).collect();
+      diagnostic = diagnostic.with_note(parts.join(", "));
+    }
+    let mut contributions: Vec<(&str, u64)> = objects.iter()
+      .map(|(name, object)| (*name, sections.iter().map(|&section| section_size(object, section)).sum()))
+      .filter(|(_, size)| *size > 0)
+      .collect();
+    contributions.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    let largest: Vec<String> = contributions.iter().take(3).map(|(name, size)| format!("{} ({} bytes)", name, size)).collect();
+    return Err(diagnostic.with_note(format!("largest contributions: {}", largest.join(", "))));
+  }
+  Ok(())
+}
+
+/// The sections each of `regions` lists, by number. A section can be in one region only, `.text`
+/// runs where it is loaded so its region has no address, and an overlay needs one.
+fn region_sections(regions: &[Region]) -> Result<Vec<Vec<usize>>, Diagnostic> {
+  let mut assigned = HashMap::new();
+  let mut all = Vec::new();
+  for region in regions {
+    let mut sections = Vec::new();
+    for name in &region.sections {
+      let Some(section) = SECTION_NAMES.iter().position(|s| s == name) else {
+        return Err(Diagnostic::error("unknown-section", format!("Region '{}' lists unknown section '{}'", region.name, name))
+          .with_note("sections are .text, .data and .rodata"));
+      };
+      if let Some(other) = assigned.insert(section, &region.name) {
+        return Err(Diagnostic::error("duplicate-region", format!("{} is in both region '{}' and region '{}'", name, other, region.name)));
+      }
+      sections.push(section);
+    }
+    if region.address.is_some() && sections.contains(&0) {
+      return Err(Diagnostic::error("region-address", format!("Region '{}' has an address, but .text always runs where it is loaded", region.name)));
+    }
+    if region.overlay && region.address.is_none() {
+      return Err(Diagnostic::error("region-address", format!("Overlay region '{}' has no address for its sections to run at", region.name)));
+    }
+    all.push(sections);
+  }
+  Ok(all)
+}
+
+/// Where the merged sections, `sizes` bytes long, run with `regions`: from the address of their
+/// region, one after another or each at the start of an overlay, or where `layout` loads them if
+/// their region has none. A section may not run over the loaded image, which it is copied from.
+fn run_starts(regions: &[Region], layout: &ImageLayout, sizes: [u32; 3]) -> Result<[u32; 3], Diagnostic> {
+  let mut runs = layout.starts;
+  let image_end = layout.starts[2] as u64 + sizes[2] as u64;
+  for (region, sections) in regions.iter().zip(region_sections(regions)?) {
+    let Some(address) = region.address else {
+      continue;
+    };
+    let mut start = address as u64;
+    for section in sections {
+      let end = start + sizes[section] as u64;
+      if sizes[section] > 0 && (start < image_end || end > 1 << 32) {
+        return Err(Diagnostic::error("region-address", format!(
+          "{} would run at 0x{:X}..0x{:X} in region '{}', which is not free memory",
+          SECTION_NAMES[section], start, end, region.name))
+          .with_note(format!("the loaded image takes 0x0..0x{:X}", image_end)));
+      }
+      runs[section] = start as u32;
+      if !region.overlay {
+        start = end;
+      }
+    }
+  }
+  Ok(runs)
+}
+
+/// A table of how many bytes of `.text`, `.data` and `.rodata` each of `objects` contributes to
+/// the linked image, then every symbol with its size, biggest first within each object. A symbol
+/// runs up to the next one in its section, or to the end of the object's section.
+pub fn size_report(objects: &[(&str, &LeafAsmObject)]) -> String {
+  let sizes = |object: &LeafAsmObject| [object.bytecode.len(), object.data.len(), object.rodata.len()];
+  let width = objects.iter().map(|(name, _)| name.len()).chain([6]).max().unwrap_or(6);
+  let mut out = format!("{:<width$} {:>8} {:>8} {:>8} {:>8}\n", "object", ".text", ".data", ".rodata", "total");
+  let mut totals = [0; 3];
+  for (name, object) in objects {
+    let sizes = sizes(object);
+    out.push_str(&format!("{:<width$} {:>8} {:>8} {:>8} {:>8}\n", name, sizes[0], sizes[1], sizes[2], sizes.iter().sum::<usize>()));
+    for (total, size) in totals.iter_mut().zip(sizes) {
+      *total += size;
+    }
+  }
+  out.push_str(&format!("{:<width$} {:>8} {:>8} {:>8} {:>8}\n", "total", totals[0], totals[1], totals[2], totals.iter().sum::<usize>()));
+
+  out.push_str(&format!("\n{:<width$} {:<8} {:>8}  symbol\n", "object", "section", "size"));
+  for (name, object) in objects {
+    let sizes = sizes(object);
+    let mut defined: Vec<&SymbolEntry> = object.symbols.iter().filter(|s| !s.external && (s.section as usize) < sizes.len()).collect();
+    defined.sort_by_key(|s| (s.section, s.offset));
+    let mut symbols: Vec<(&SymbolEntry, usize)> = defined.iter().enumerate()
+      .map(|(i, symbol)| {
+        let end = defined[i + 1..].iter()
+          .find(|next| next.section == symbol.section && next.offset > symbol.offset)
+          .map_or(sizes[symbol.section as usize], |next| next.offset as usize);
+        (*symbol, end.saturating_sub(symbol.offset as usize))
+      })
+      .collect();
+    symbols.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    for (symbol, size) in symbols {
+      out.push_str(&format!("{:<width$} {:<8} {:>8}  {}\n", name, SECTION_NAMES[symbol.section as usize], size, symbol.name));
+    }
+  }
+  out
+}
+
+/// The export table of a linked executable, from `name` or `name=signature` specs. Every name has
+/// to be a `.text` symbol of `object`, since only code can be called.
+pub fn export_table(object: &LeafAsmObject, specs: &[String]) -> Result<Vec<ExportEntry>, Diagnostic> {
+  let mut exports: Vec<ExportEntry> = Vec::new();
+  for spec in specs {
+    let (name, signature) = match spec.split_once('=') {
+      Some((name, signature)) => (name.trim(), Some(signature.trim().to_string())),
+      None => (spec.trim(), None),
+    };
+    let symbol = object.symbols.iter().find(|s| s.name == name && !s.external)
+      .ok_or_else(|| Diagnostic::error("unknown-export", format!("Cannot export '{}': no such symbol", name)))?;
+    if symbol.section != 0 {
+      let section = SECTION_NAMES.get(symbol.section as usize).copied().unwrap_or("no section");
+      return Err(Diagnostic::error("export-not-code", format!("Cannot export '{}': it is in {}, not .text", name, section)));
+    }
+    if exports.iter().any(|export| export.name == name) {
+      return Err(Diagnostic::error("duplicate-export", format!("'{}' is exported twice", name)));
+    }
+    exports.push(ExportEntry { name: name.to_string(), address: symbol.offset, signature });
+  }
+  Ok(exports)
+}
+
+/// The members of `libraries` that linking `objects` needs: each member that defines a symbol the
+/// objects use but none of them define, then the members those need, and so on. Libraries are
+/// searched in order, and the first member that defines a symbol is the one used.
+pub fn library_members<'a>(objects: &[LeafAsmObject], libraries: &'a [LeafLibrary]) -> Vec<&'a LibraryMember> {
+  let mut members: Vec<&LibraryMember> = Vec::new();
+  loop {
+    let linked: Vec<&LeafAsmObject> = objects.iter().chain(members.iter().map(|member| &member.file.object)).collect();
+    let defined: HashSet<&str> = linked.iter().flat_map(|object| &object.symbols)
+      .filter(|s| !s.external)
+      .map(|s| s.name.as_str())
+      .collect();
+    let next = linked.iter().flat_map(|object| &object.symbols)
+      .filter(|s| s.external && !defined.contains(s.name.as_str()))
+      .find_map(|s| libraries.iter().flat_map(|library| &library.members).find(|member| member.defines(&s.name)));
+    match next {
+      Some(member) => members.push(member),
+      None => return members,
+    }
+  }
+}
+
+/// Link an executable with `entry_point`, or a shared object if there is none, which `regions`
+/// do not apply to.
+fn link_objects(objects: &[LeafAsmObject], entry_point: Option<&str>, profile: Option<&Profile>, regions: &[Region]) -> Result<LeafAsmObject, Diagnostic> {
+  let shared = entry_point.is_none();
+  for (index, object) in objects.iter().enumerate() {
+    let report = object.check();
+    if !report.is_valid() {
+      return Err(Diagnostic::error("invalid-object", format!("Object {} is malformed: {}", index, report)));
+    }
+  }
   let mut final_bytecode = vec![];
   let mut final_data = vec![];
   let mut final_rodata = vec![];
   let mut symbol_table = vec![];
 
-  let mut text_bases = Vec::new();
   let mut data_bases = Vec::new();
   let mut rodata_bases = Vec::new();
 
-  let mut text_offset = 0u32;
   let mut data_offset = 0u32;
   let mut rodata_offset = 0u32;
 
   for object in objects {
-    text_bases.push(text_offset);
     data_bases.push(data_offset);
     rodata_bases.push(rodata_offset);
 
-    text_offset += object.bytecode.len() as u32;
     data_offset += object.data.len() as u32;
     rodata_offset += object.rodata.len() as u32;
   }
 
+  // Instruction boundaries and the jump and call operands of each object
+  let decoded: Vec<_> = objects.iter().map(decode_instructions).collect();
+  let text = TextLayout::new(objects, &decoded, profile);
+  for (object, start, end) in text.pieces() {
+    final_bytecode.extend(&objects[object].bytecode[start as usize..end as usize]);
+  }
   for object in objects {
-    final_bytecode.extend(&object.bytecode);
     final_data.extend(&object.data);
     final_rodata.extend(&object.rodata);
   }
 
-  let total_code_size = final_bytecode.len() as u32;
-  let total_data_size = final_data.len() as u32;
+  // Addresses in the default layout, with the merged sections packed from 0; the VM can load the
+  // sections elsewhere by reapplying the relocations kept in the output
+  let mut layout = ImageLayout::packed(&final_bytecode, &final_data);
+  let sizes = [final_bytecode.len(), final_data.len(), final_rodata.len()].map(|len| len as u32);
+  if !shared {
+    layout.runs = run_starts(regions, &layout, sizes)?;
+  }
+  let address = |symbol: &SymbolEntry| layout.address(symbol.section, symbol.offset);
 
+  let mut symbol_starts = Vec::new();
   for (index, object) in objects.iter().enumerate() {
-    let text_base = text_bases[index];
+    symbol_starts.push(symbol_table.len());
     let data_base = data_bases[index];
     let rodata_base = rodata_bases[index];
 
     for symbol in &object.symbols {
+      // Offsets stay relative to the symbol's merged section; a reference to another object's
+      // symbol has no place of its own, so it is left alone
       let adjusted_offset = match symbol.section {
-        0 => symbol.offset + text_base,
-        1 => symbol.offset + data_base + total_code_size,
-        2 => symbol.offset + rodata_base + total_code_size + total_data_size,
+        _ if symbol.external => symbol.offset,
+        0 => text.place(index, symbol.offset),
+        1 => symbol.offset + data_base,
+        2 => symbol.offset + rodata_base,
         _ => symbol.offset,
       };
       info!("Linking symbol '{}' (section {}) from object {}: original offset {}, adjusted offset {}", 
@@ -58,79 +308,417 @@ pub fn link(objects: &[LeafAsmObject], entry_point: &str) -> Result<LeafAsmObjec
     }
   }
 
+  // The bounds of each section that runs away from where it is loaded, for the code that copies it
+  for section in 1..3 {
+    let (load, run) = (layout.starts[section], layout.runs[section]);
+    if load == run {
+      continue;
+    }
+    let bounds = [("load_start", load), ("load_end", load + sizes[section]), ("run_start", run), ("run_end", run + sizes[section])];
+    for (bound, offset) in bounds {
+      let name = format!("__{}_{}", &SECTION_NAMES[section][1..], bound);
+      symbol_table.push(SymbolEntry { name, offset, section: ABSOLUTE_SECTION, kind: 0, external: false });
+    }
+  }
+
+  // Global definitions by interned name; the first definition of a name wins
+  let mut names = Interner::new();
+  let mut defined = HashMap::new();
+  for (index, symbol) in symbol_table.iter().enumerate().filter(|(_, s)| !s.external) {
+    defined.entry(names.intern(&symbol.name)).or_insert(index);
+  }
+  // Then the default version of a versioned name, see `symver`
+  let resolve = |name: &str| names.get(name).and_then(|name| defined.get(&name)).copied()
+    .or_else(|| symver::resolve(name, symbol_table.iter().enumerate().filter(|(_, s)| !s.external).map(|(index, s)| (s.name.as_str(), index))));
+
+  // Instruction boundaries in the merged .text
+  let mut instruction_starts = HashSet::new();
+  for (index, decoded) in decoded.iter().enumerate() {
+    instruction_starts.extend(decoded.starts.iter().map(|&start| text.place(index, start)));
+  }
+
   // apply relocations
+  let mut relocations = Vec::new();
   for (index, object) in objects.iter().enumerate() {
-    let text_base = text_bases[index];
-
-    for reloc in &object.relocations {
-      let symbol = &object.symbols[reloc.symbol_index as usize];
-      // find symbol in the global symbol table
-      let resolved = symbol_table.iter().find(|s| s.name == symbol.name && !s.external);
-      let resolved_offset = match resolved {
-        Some(s) => s.offset,
-        None => return Err(format!("Unresolved symbol: {}", symbol.name))
+    for (reloc_index, reloc) in object.relocations.iter().enumerate() {
+      let section_target;
+      let (definition, target, name, addend) = match object.section_target(reloc_index) {
+        // A label the object keeps to itself: the addend is its offset in the object's section,
+        // which moves with the section
+        Some(section) => {
+          let offset = object.addend(reloc_index) as u32;
+          let offset = match section {
+            0 => text.place(index, offset),
+            1 => offset + data_bases[index],
+            _ => offset + rodata_bases[index],
+          };
+          let name = format!("{}+0x{:X}", SECTION_NAMES[section as usize], offset);
+          section_target = SymbolEntry { name, offset, section, kind: section, external: false };
+          (None, &section_target, &section_target.name, 0)
+        }
+        None => {
+          let symbol = &object.symbols[reloc.symbol_index as usize];
+          let definition = match resolve(&symbol.name) {
+            Some(definition) => definition,
+            // An import, bound when the shared object is loaded
+            None if shared => symbol_starts[index] + reloc.symbol_index as usize,
+            None => return Err(
+              Diagnostic::error("unresolved-symbol", format!("Unresolved symbol: {}", symbol.name))
+                .with_note(format!("referenced by object #{}, but no object defines it", index))
+            ),
+          };
+          (Some(definition), &symbol_table[definition], &symbol.name, object.addend(reloc_index))
+        }
       };
+      let resolved_offset = if target.external { 0 } else { address(target) };
 
-      info!("Resolved symbol '{}' to offset {}", symbol.name, resolved_offset);
+      info!("Resolved symbol '{}' to offset {}", name, resolved_offset);
+      let resolved_offset = resolved_offset.wrapping_add_signed(addend);
+
+      if let Some(opcode) = decoded[index].branches.get(&reloc.offset).filter(|_| reloc.target_section == 0 && !target.external)
+        .filter(|_| matches!(reloc.reloc_type, RelocationType::Absolute | RelocationType::Relative))
+        && (target.section != 0 || !instruction_starts.contains(&target.offset.wrapping_add_signed(addend))) {
+        let name = match addend {
+          0 => name.to_string(),
+          addend => format!("{}{:+}", name, addend),
+        };
+        return Err(Diagnostic::error("branch-target", format!(
+          "{} at .text+0x{:X} in object #{} targets '{}', which is not the start of an instruction in .text",
+          opcode, reloc.offset - 1 - 4 * opcode.branch_operand().unwrap_or(0) as u32, index, name)));
+      }
 
       // Compute base offset for the section being patched
-      let (base, slice, slice_name) = match reloc.target_section {
-        0 => (text_bases[index], &mut final_bytecode, "bytecode"),
-        1 => (data_bases[index], &mut final_data, "data"),
-        2 => (rodata_bases[index], &mut final_rodata, "rodata"),
-        _ => return Err(format!("Invalid target_section in relocation: {}", reloc.target_section)),
+      let (patch_offset, slice, slice_name) = match reloc.target_section {
+        0 => (text.place(index, reloc.offset), &mut final_bytecode, "bytecode"),
+        1 => (data_bases[index] + reloc.offset, &mut final_data, "data"),
+        2 => (rodata_bases[index] + reloc.offset, &mut final_rodata, "rodata"),
+        _ => return Err(Diagnostic::error(
+          "invalid-relocation",
+          format!("Invalid target_section in relocation: {}", reloc.target_section),
+        )),
       };
 
-      let patch_offset = (base + reloc.offset) as usize;
-      info!("Patching at patch_offset={} (base={}, reloc.offset={})", patch_offset, base, reloc.offset);
+      let patch_offset = patch_offset as usize;
+      info!("Patching at patch_offset={} (reloc.offset={})", patch_offset, reloc.offset);
       if patch_offset + 4 > slice.len() {
-        return Err(format!(
+        return Err(Diagnostic::error("invalid-relocation", format!(
           "Relocation offset {} out of bounds ({} size: {})",
           patch_offset, slice_name, slice.len()
-        ));
+        )));
       }
 
-      // Now patch in the correct section
-      match reloc.reloc_type {
-        RelocationType::Absolute => {
-          info!(
-            "Patching absolute relocation in {} at offset {} for symbol {} with resolved offset {}",
-            slice_name, patch_offset, symbol.name, resolved_offset
-        );
-          slice[patch_offset..patch_offset + 4].copy_from_slice(&resolved_offset.to_le_bytes());
-        }
-        RelocationType::Relative => {
-          let rel = (resolved_offset as i32) - (patch_offset as i32 + 4);
-          info!(
-            "Patching relative relocation in {} at offset {} for symbol {} with relative value {}",
-            slice_name, patch_offset, symbol.name, rel
-        );
-          slice[patch_offset..patch_offset + 4].copy_from_slice(&(rel as u32).to_le_bytes());
-        }
+      // Both ends are image addresses: the patch is section-local like the symbol's offset
+      let patch_address = layout.load_address(reloc.target_section, patch_offset as u32);
+      let value = match reloc.reloc_type {
+        RelocationType::SectionRelative if target.external => return Err(Diagnostic::error("invalid-relocation", format!(
+          "Section-relative relocation in object #{} against '{}', which another module defines", index, name,
+        ))),
+        RelocationType::SectionRelative => reloc.reloc_type.value(target.offset.wrapping_add_signed(addend), patch_address),
+        _ => reloc.reloc_type.value(resolved_offset, patch_address),
+      };
+      info!(
+        "Patching {:?} relocation in {} at offset {} for symbol {} with value {}",
+        reloc.reloc_type, slice_name, patch_offset, name, value
+      );
+      slice[patch_offset..patch_offset + 4].copy_from_slice(&value.to_le_bytes());
+      // Addresses change wherever the image is loaded, a distance only across sections or to an
+      // import, and an offset in a section never does
+      let keep = match reloc.reloc_type {
+        RelocationType::Absolute | RelocationType::Hi16 | RelocationType::Lo16 => true,
+        RelocationType::Relative => shared || target.section != reloc.target_section,
+        RelocationType::SectionRelative => false,
+      };
+      if keep {
+        // A label an object kept to itself stays an offset in the merged section
+        let (symbol_index, addend) = match definition {
+          Some(definition) => (definition as u32, addend),
+          None => (target.section as u32, target.offset as i32),
+        };
+        relocations.push((RelocationEntry {
+          offset: patch_offset as u32,
+          symbol_index,
+          reloc_type: reloc.reloc_type,
+          target_section: reloc.target_section,
+        }, addend, definition.is_none()));
       }
     }
   }
 
-  let entry_offset = symbol_table.iter()
-    .find(|s| s.name == entry_point && !s.external)
-    .map(|s| s.offset);
+  let debug_info = merge_debug_info(objects, &text);
+  let data_in_text = merge_data_in_text(objects, &text);
 
-  info!("Entry point: {} with offset: {}", entry_point, entry_offset.unwrap_or(0));
+  if let Some(entry_point) = entry_point {
+    let entry_offset = resolve(entry_point).map(|index| address(&symbol_table[index]));
+    info!("Entry point: {} with offset: {}", entry_point, entry_offset.unwrap_or(0));
+  }
+
+  let winners: HashSet<usize> = defined.values().copied().collect();
+  let symbol_table = canonical_symbols(symbol_table, &winners, &mut relocations);
+  let addends = Addend::table(relocations.iter().map(|(_, addend, _)| *addend));
+  let section_relocations = relocations.iter().enumerate()
+    .filter(|(_, (_, _, section))| *section)
+    .map(|(index, _)| index as u32)
+    .collect();
+  let relocations = relocations.into_iter().map(|(reloc, _, _)| reloc).collect();
 
-  Ok(LeafAsmObject {
+  let mut linked = LeafAsmObject {
     bytecode: final_bytecode,
     data: final_data,
     rodata: final_rodata,
     symbols: symbol_table,
-    entry_point: Some(entry_point.to_string()),
-    relocations: vec![], // No relocations in the final object
-    debug_info: None, // No debug info in the final object
-  })
+    entry_point: entry_point.map(str::to_string),
+    relocations,
+    debug_info,
+    exports: Vec::new(),
+    data_in_text,
+    section_flags: merge_section_flags(objects),
+    segments: Vec::new(),
+    addends,
+    section_relocations,
+  };
+  // Shared objects are mapped wherever the VM puts them, so only executables get a fixed layout
+  if !shared {
+    linked.segments = linked.packed_segments();
+    for segment in &mut linked.segments {
+      segment.run_address = layout.runs[segment.section as usize];
+    }
+  }
+  Ok(linked)
+}
+
+/// The output symbol table sorted by name, so it does not depend on the order the objects came
+/// in. The definition a name resolves to (`winners`) comes before the other entries of that name,
+/// since the VM and `export_table` take the first, and entries that are exactly alike are merged.
+/// `relocations`, with their addends and whether they refer to a section rather than a symbol, are
+/// pointed at the new indices and sorted by where they patch.
+fn canonical_symbols(symbols: Vec<SymbolEntry>, winners: &HashSet<usize>, relocations: &mut [(RelocationEntry, i32, bool)]) -> Vec<SymbolEntry> {
+  let mut order: Vec<usize> = (0..symbols.len()).collect();
+  order.sort_by(|&a, &b| {
+    let key = |index: usize| {
+      let s = &symbols[index];
+      (&s.name, s.external, !winners.contains(&index), s.section, s.offset, s.kind)
+    };
+    key(a).cmp(&key(b))
+  });
+  let mut canonical: Vec<SymbolEntry> = Vec::with_capacity(symbols.len());
+  let mut moved = vec![0; symbols.len()];
+  for index in order {
+    if canonical.last() != Some(&symbols[index]) {
+      canonical.push(symbols[index].clone());
+    }
+    moved[index] = canonical.len() - 1;
+  }
+  for (reloc, _, _) in relocations.iter_mut().filter(|(_, _, section)| !section) {
+    reloc.symbol_index = moved[reloc.symbol_index as usize] as u32;
+  }
+  relocations.sort_by_key(|(reloc, _, _)| (reloc.target_section, reloc.offset));
+  canonical
+}
+
+/// Each section of the output may do whatever that section of any input that has bytes in it may,
+/// so an empty section (such as `crt0`'s `.data`) does not widen it. If no input has any, their
+/// flags are combined all the same.
+fn merge_section_flags(objects: &[LeafAsmObject]) -> Vec<SectionFlags> {
+  (0..3)
+    .map(|section| {
+      let combined = |only_filled: bool| objects.iter()
+        .filter(|object| !only_filled || object.section_len(section) != Some(0))
+        .map(|object| object.flags(section))
+        .reduce(|a, b| a | b);
+      combined(true).or_else(|| combined(false)).unwrap_or(SectionFlags::default_for(section))
+    })
+    .collect()
+}
+
+/// The instructions of one object's `.text`.
+struct Decoded {
+  /// Offset of each instruction.
+  starts: Vec<u32>,
+  /// Jump and call target operands, by offset.
+  branches: HashMap<u32, OpCode>,
+  /// Offsets right after a `JMP`, `RET` or `HALT`, which control never falls through to.
+  breaks: HashSet<u32>,
+}
+
+/// Decode the `.text` of `object` from the start, stepping over its data ranges. Decoding stops at
+/// the first other byte that is not an opcode, since from there on it could be data.
+fn decode_instructions(object: &LeafAsmObject) -> Decoded {
+  let code = &object.bytecode;
+  let mut decoded = Decoded { starts: Vec::new(), branches: HashMap::new(), breaks: HashSet::new() };
+  let mut data = object.data_in_text.iter().peekable();
+  let mut pc = 0;
+  loop {
+    while let Some(range) = data.next_if(|range| range.offset as usize <= pc) {
+      pc = pc.max((range.offset + range.len) as usize);
+    }
+    let Some(info) = code.get(pc).and_then(|byte| OpCode::decode(*byte)) else {
+      break;
+    };
+    if pc + info.size() > code.len() {
+      break;
+    }
+    decoded.starts.push(pc as u32);
+    if let Some(operand) = info.opcode.branch_operand() {
+      decoded.branches.insert((pc + 1 + 4 * operand) as u32, info.opcode);
+    }
+    pc += info.size();
+    if matches!(info.opcode, OpCode::Jmp | OpCode::Ret | OpCode::Halt) {
+      decoded.breaks.insert(pc as u32);
+    }
+  }
+  decoded
+}
+
+/// `(object, start, end)` of a contiguous part of one object's `.text`.
+type Piece = (usize, u32, u32);
+
+/// Where each part of every object's `.text` goes in the merged `.text`. Without a profile the
+/// objects are simply concatenated.
+struct TextLayout {
+  /// Every piece, in merged order.
+  pieces: Vec<Piece>,
+  /// `(start, merged start)` of the pieces of each object, by start.
+  starts: Vec<Vec<(u32, u32)>>,
+}
+
+impl TextLayout {
+  fn new(objects: &[LeafAsmObject], decoded: &[Decoded], profile: Option<&Profile>) -> Self {
+    let counts: HashMap<&str, u64> = profile.into_iter()
+      .flat_map(|profile| &profile.symbols)
+      .map(|symbol| (symbol.name.as_str(), symbol.count))
+      .collect();
+    // Runs of pieces that must stay together, with how often their code ran; a piece control can
+    // fall into from the one before joins that one's run
+    let mut runs: Vec<(Vec<Piece>, u64)> = Vec::new();
+    let mut falls_through = false;
+    for (index, object) in objects.iter().enumerate() {
+      let len = object.bytecode.len() as u32;
+      if len == 0 {
+        // Keeps any labels it has where they would be without a profile
+        match runs.last_mut() {
+          Some((pieces, _)) => pieces.push((index, 0, 0)),
+          None => runs.push((vec![(index, 0, 0)], 0)),
+        }
+        continue;
+      }
+      let code_symbols: Vec<_> = object.symbols.iter().filter(|s| s.section == 0 && !s.external).collect();
+      let mut cuts: Vec<u32> = code_symbols.iter().map(|s| s.offset)
+        .filter(|offset| *offset > 0 && *offset < len && decoded[index].breaks.contains(offset))
+        .collect();
+      cuts.sort_unstable();
+      cuts.dedup();
+      let bounds: Vec<u32> = std::iter::once(0).chain(cuts).chain(std::iter::once(len)).collect();
+      for (i, piece) in bounds.windows(2).enumerate() {
+        let heat: u64 = code_symbols.iter()
+          .filter(|s| s.offset >= piece[0] && s.offset < piece[1])
+          .map(|s| counts.get(s.name.as_str()).copied().unwrap_or(0))
+          .sum();
+        match runs.last_mut() {
+          Some((pieces, total)) if i == 0 && falls_through => {
+            pieces.push((index, piece[0], piece[1]));
+            *total += heat;
+          }
+          _ => runs.push((vec![(index, piece[0], piece[1])], heat)),
+        }
+      }
+      falls_through = !decoded[index].breaks.contains(&len);
+    }
+    if profile.is_some() {
+      // The last run stays last if control can fall off its end
+      let pinned = if falls_through { runs.pop() } else { None };
+      runs.sort_by_key(|(_, heat)| std::cmp::Reverse(*heat));
+      runs.extend(pinned);
+    }
+
+    let pieces: Vec<_> = runs.into_iter().flat_map(|(pieces, _)| pieces).collect();
+    let mut starts = vec![Vec::new(); objects.len()];
+    let mut merged = 0;
+    for &(object, start, end) in &pieces {
+      starts[object].push((start, merged));
+      merged += end - start;
+    }
+    for object in &mut starts {
+      object.sort_unstable();
+    }
+    TextLayout { pieces, starts }
+  }
+
+  /// Every piece, in merged order.
+  fn pieces(&self) -> impl Iterator<Item = Piece> + '_ {
+    self.pieces.iter().copied()
+  }
+
+  /// The merged offset of `offset` in the `.text` of object `object`. An offset at the very end
+  /// of an object's code stays just after its last piece.
+  fn place(&self, object: usize, offset: u32) -> u32 {
+    let starts = &self.starts[object];
+    let index = starts.partition_point(|(start, _)| *start <= offset);
+    index.checked_sub(1).map_or(offset, |i| starts[i].1 + (offset - starts[i].0))
+  }
+}
+
+/// Load and run addresses of the merged `.text`, `.data` and `.rodata` in the image the linker
+/// patches.
+struct ImageLayout {
+  starts: [u32; 3],
+  runs: [u32; 3],
+}
+
+impl ImageLayout {
+  /// The default layout: `.text` at 0, then `.data`, then `.rodata`, each running where it is.
+  fn packed(text: &[u8], data: &[u8]) -> Self {
+    let text_len = text.len() as u32;
+    let starts = [0, text_len, text_len + data.len() as u32];
+    ImageLayout { starts, runs: starts }
+  }
+
+  /// The address `offset` in `section` runs at, which symbols refer to. The offset of an absolute
+  /// symbol is its address.
+  fn address(&self, section: u8, offset: u32) -> u32 {
+    self.runs.get(section as usize).copied().unwrap_or(0) + offset
+  }
+
+  /// The image address of `offset` in `section`.
+  fn load_address(&self, section: u8, offset: u32) -> u32 {
+    self.starts.get(section as usize).copied().unwrap_or(0) + offset
+  }
+}
+
+/// Concatenate the line tables of all objects, moving offsets to where `text` puts them.
+fn merge_debug_info(objects: &[LeafAsmObject], text: &TextLayout) -> Option<DebugInfo> {
+  let mut merged = DebugInfo::default();
+  for (index, object) in objects.iter().enumerate() {
+    let Some(debug) = &object.debug_info else { continue };
+    for entry in &debug.lines {
+      let file = merged.file_index(&debug.files[entry.file as usize]);
+      merged.lines.push(LineEntry { offset: text.place(index, entry.offset), file, line: entry.line });
+    }
+  }
+  // Lookups need them in address order, which a profile can change
+  merged.lines.sort_by_key(|entry| entry.offset);
+  (!merged.lines.is_empty()).then_some(merged)
+}
+
+/// The data ranges of every object's `.text`, moved to where `text` puts them. A range the
+/// layout splits up becomes one range per piece.
+fn merge_data_in_text(objects: &[LeafAsmObject], text: &TextLayout) -> Vec<DataRange> {
+  let mut merged = Vec::new();
+  for (object, start, end) in text.pieces() {
+    for range in &objects[object].data_in_text {
+      let (from, to) = (range.offset.max(start), (range.offset + range.len).min(end));
+      if from < to {
+        merged.push(DataRange { offset: text.place(object, from), len: to - from });
+      }
+    }
+  }
+  merged
 }
 
 #[cfg(test)]
 mod tests {
-  use leaf_common::leaf_file::RelocationEntry;
+  use leaf_common::checksum::Checksum;
+  use leaf_common::leaf_file::{ByteOrder, RelocationEntry};
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
   use super::*;
 
   fn mock_obj(
@@ -140,15 +728,9 @@ mod tests {
     symbols: Vec<SymbolEntry>,
     relocations: Vec<RelocationEntry>,
   ) -> LeafAsmObject {
-    LeafAsmObject {
-      bytecode,
-      data,
-      rodata,
-      symbols,
-      entry_point: None,
-      relocations,
-      debug_info: None,
-    }
+    let builder = LeafAsmObjectBuilder::new().text(bytecode).data(data).rodata(rodata);
+    let builder = symbols.into_iter().fold(builder, |b, s| b.symbol(s));
+    relocations.into_iter().fold(builder, |b, r| b.relocation(r)).build().expect("mock object is well-formed")
   }
 
   #[test]
@@ -201,11 +783,11 @@ mod tests {
   fn test_link_absolute_relocation() {
     // obj1: references 'func' (external, in obj2)
     // At offset 1 in obj1, needs patching to func's address in final image
-    let mut symbols1 = vec![
+    let symbols1 = vec![
       SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
       SymbolEntry { name: "func".to_string(), offset: 0, section: 0, kind: 0, external: true }
     ];
-    let mut reloc1 = vec![
+    let reloc1 = vec![
       RelocationEntry { offset: 1, symbol_index: 1, reloc_type: RelocationType::Absolute, target_section: 0 }
     ];
     // .text = [CALL, 0, 0, 0, 0] (CALL opcode, then placeholder for address)
@@ -222,16 +804,45 @@ mod tests {
     let func_offset = 5u32;
     let patched = &linked.bytecode[1..5];
     assert_eq!(patched, &func_offset.to_le_bytes());
+    // Kept, pointing at the definition, so the loader can relocate the image
+    assert_eq!(linked.relocations, vec![RelocationEntry { offset: 1, symbol_index: 0, reloc_type: RelocationType::Absolute, target_section: 0 }]);
+    assert_eq!(linked.symbols[0], SymbolEntry { name: "func".to_string(), offset: 5, section: 0, kind: 0, external: false });
+  }
+
+  #[test]
+  fn test_link_symbol_table_is_sorted_and_independent_of_input_order() {
+    let data = |name: &str, external: bool| mock_obj(vec![], vec![1, 2], vec![], vec![
+      SymbolEntry { name: name.to_string(), offset: 0, section: 1, kind: 0, external: false },
+      SymbolEntry { name: "shared".to_string(), offset: 0, section: 1, kind: 0, external },
+    ], vec![]);
+    let code = mock_obj(vec![0x10], vec![], vec![], vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "shared".to_string(), offset: 0, section: 1, kind: 0, external: true },
+    ], vec![]);
+    let (a, b) = (data("a", true), data("b", true));
+
+    let forward = link(&[code.clone(), a.clone(), b.clone()], "main").expect("Should link");
+    let names: Vec<_> = forward.symbols.iter().map(|s| (s.name.as_str(), s.external)).collect();
+    // The three identical imports of `shared` become one entry
+    assert_eq!(names, vec![("a", false), ("b", false), ("main", false), ("shared", true)]);
+    let backward = link(&[code.clone(), b.clone(), a.clone()], "main").expect("Should link");
+    let names: Vec<_> = backward.symbols.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["a", "b", "main", "shared"]);
+
+    // Of two definitions, the one that wins comes first, whatever its offset
+    let linked = link(&[code, data("a", false), data("b", false)], "main").expect("Should link");
+    let shared: Vec<_> = linked.symbols.iter().filter(|s| s.name == "shared").map(|s| (s.offset, s.external)).collect();
+    assert_eq!(shared, vec![(0, false), (2, false), (0, true)]);
   }
 
   #[test]
   fn test_link_relative_relocation() {
     // Similar to above, but with relative addressing
-    let mut symbols1 = vec![
+    let symbols1 = vec![
       SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
       SymbolEntry { name: "func".to_string(), offset: 0, section: 0, kind: 0, external: true }
     ];
-    let mut reloc1 = vec![
+    let reloc1 = vec![
       RelocationEntry { offset: 1, symbol_index: 1, reloc_type: RelocationType::Relative, target_section: 0 }
     ];
     // .text = [JMP, 0, 0, 0, 0] (JMP opcode, then placeholder for relative addr)
@@ -255,6 +866,260 @@ mod tests {
     assert_eq!(patched, rel);
   }
 
+  #[test]
+  fn test_link_relative_relocation_across_sections() {
+    // .text = [MOVI r1, rel msg], .data = [word 0] with a relative pointer back to main
+    let symbols = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "msg".to_string(), offset: 0, section: 1, kind: 1, external: false },
+    ];
+    let relocs = vec![
+      RelocationEntry { offset: 5, symbol_index: 1, reloc_type: RelocationType::Relative, target_section: 0 },
+      RelocationEntry { offset: 8, symbol_index: 0, reloc_type: RelocationType::Relative, target_section: 1 },
+    ];
+    let obj = mock_obj(vec![0x16, 1, 0, 0, 0, 0, 0, 0, 0], vec![0; 16], vec![], symbols, relocs);
+
+    let linked = link(&[obj], "main").expect("Should link");
+    // msg is at 9, just after the operand at 5..9
+    assert_eq!(i32::from_le_bytes(linked.bytecode[5..9].try_into().unwrap()), 0);
+    // The pointer at .data+8 is image address 17, so main is 21 bytes before the end of it
+    assert_eq!(i32::from_le_bytes(linked.data[8..12].try_into().unwrap()), -21);
+    // Both depend on where the sections are loaded, so both are kept
+    assert_eq!(linked.relocations.len(), 2);
+  }
+
+  #[test]
+  fn test_link_combines_section_flags() {
+    let main = LeafAsmObjectBuilder::new().text(vec![0x13]).define("main", 0, 0).build().unwrap();
+    let table = LeafAsmObjectBuilder::new().data(vec![0; 8]).section_flags(1, SectionFlags::READ).build().unwrap();
+    let code = LeafAsmObjectBuilder::new().text(vec![0x13]).section_flags(0, SectionFlags::EXECUTE).build().unwrap();
+    let linked = link(&[main, table, code], "main").unwrap();
+    // The empty .data of the first and last object does not make the table writable
+    let flags: Vec<_> = linked.section_flags.iter().map(ToString::to_string).collect();
+    assert_eq!(flags, vec!["r-x", "r--", "r--"]);
+  }
+
+  #[test]
+  fn test_executables_get_a_segment_per_section() {
+    let main = LeafAsmObjectBuilder::new().text(vec![0x13]).rodata(b"hi".to_vec()).define("main", 0, 0).build().unwrap();
+    let linked = link(std::slice::from_ref(&main), "main").unwrap();
+    let sections: Vec<_> = linked.segments.iter().map(|segment| (segment.section, segment.address, segment.mem_size)).collect();
+    assert_eq!(sections, vec![(0, 0, 1), (2, 1, 2)]);
+    assert!(link_shared(&[main]).unwrap().segments.is_empty());
+  }
+
+  #[test]
+  fn test_link_split_and_section_relative_relocations() {
+    // .text = MOVI r1, %hi(msg); MOVI r2, %lo(msg); MOVI r3, %secrel(msg), with msg far into .data
+    let symbols = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "msg".to_string(), offset: 0x10004, section: 1, kind: 1, external: false },
+    ];
+    let relocs = [RelocationType::Hi16, RelocationType::Lo16, RelocationType::SectionRelative].into_iter().enumerate()
+      .map(|(i, reloc_type)| RelocationEntry { offset: 9 * i as u32 + 5, symbol_index: 1, reloc_type, target_section: 0 })
+      .collect();
+    let text = [1u8, 2, 3].iter().flat_map(|&reg| [0x16, reg, 0, 0, 0, 0, 0, 0, 0]).collect();
+    let obj = mock_obj(text, vec![0; 0x10008], vec![], symbols, relocs);
+
+    let linked = link(&[obj], "main").expect("Should link");
+    let operand = |at: usize| u32::from_le_bytes(linked.bytecode[at..at + 4].try_into().unwrap());
+    // .data starts right after the 27 bytes of .text, so msg is at 0x1001F
+    assert_eq!((operand(5), operand(14), operand(23)), (0x1, 0x1F, 0x10004));
+    // The halves move with the image, the offset in .data does not
+    let kept: Vec<_> = linked.relocations.iter().map(|reloc| reloc.reloc_type).collect();
+    assert_eq!(kept, vec![RelocationType::Hi16, RelocationType::Lo16]);
+  }
+
+  #[test]
+  fn test_link_section_relocations_move_with_their_section() {
+    let main = LeafAsmObjectBuilder::new().text(vec![0x13]).data(vec![0; 8]).define("main", 0, 0).build().unwrap();
+    // MOVI r1, <.data+4 of this object>
+    let table = LeafAsmObjectBuilder::new()
+      .text(vec![0x16, 1, 0, 0, 0, 0, 0, 0, 0])
+      .data(vec![0; 8])
+      .section_relocation(5, 1, RelocationType::Absolute, 0)
+      .addend(0, 4)
+      .build()
+      .unwrap();
+    let linked = link(&[main, table], "main").unwrap();
+    // .data starts after the 10 bytes of .text, and this object's after the first object's 8
+    assert_eq!(u32::from_le_bytes(linked.bytecode[6..10].try_into().unwrap()), 10 + 8 + 4);
+    // Kept against the merged .data, with no symbol of its own
+    assert_eq!(linked.symbols.len(), 1);
+    assert_eq!(linked.section_relocations, vec![0]);
+    assert_eq!((linked.relocations[0].offset, linked.section_target(0), linked.addend(0)), (6, Some(1), 12));
+    assert!(linked.validate().is_ok());
+  }
+
+  #[test]
+  fn test_link_rejects_calls_into_the_middle_of_instructions() {
+    let symbols1 = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "func".to_string(), offset: 0, section: 0, kind: 0, external: true }
+    ];
+    let reloc1 = vec![
+      RelocationEntry { offset: 1, symbol_index: 1, reloc_type: RelocationType::Absolute, target_section: 0 }
+    ];
+    // main: CALL func; HALT
+    let obj1 = mock_obj(vec![0x0F, 0, 0, 0, 0, 0x13], vec![], vec![], symbols1, reloc1);
+    // MOVI r1, 0 with func two bytes in, on its operand
+    let symbols2 = vec![
+      SymbolEntry { name: "func".to_string(), offset: 2, section: 0, kind: 0, external: false }
+    ];
+    let obj2 = mock_obj(vec![0x16, 1, 0, 0, 0, 0, 0, 0, 0], vec![], vec![], symbols2.clone(), vec![]);
+
+    let err = link(&[obj1.clone(), obj2], "main").unwrap_err();
+    assert_eq!(err.code, "branch-target");
+    assert_eq!(err.message, "CALL at .text+0x0 in object #0 targets 'func', which is not the start of an instruction in .text");
+
+    let symbols2 = vec![
+      SymbolEntry { name: "func".to_string(), offset: 0, section: 0, kind: 0, external: false }
+    ];
+    let obj2 = mock_obj(vec![0x10], vec![], vec![], symbols2, vec![]);
+    assert!(link(&[obj1, obj2], "main").is_ok());
+  }
+
+  #[test]
+  fn test_link_with_profile_puts_hot_code_first() {
+    use leaf_vm::profile::SymbolCount;
+    let symbols = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "cold".to_string(), offset: 6, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "hot".to_string(), offset: 7, section: 0, kind: 0, external: false },
+    ];
+    let reloc = vec![
+      RelocationEntry { offset: 1, symbol_index: 2, reloc_type: RelocationType::Absolute, target_section: 0 }
+    ];
+    // main: CALL hot; HALT   cold: RET   hot: RET
+    let obj = mock_obj(vec![0x0F, 0, 0, 0, 0, 0x13, 0x10, 0x10], vec![], vec![], symbols, reloc);
+    let count = |name: &str, count| SymbolCount { name: name.to_string(), count };
+    let profile = Profile { total: 3, instructions: vec![], symbols: vec![count("hot", 1), count("main", 2)] };
+
+    let linked = link_with_profile(std::slice::from_ref(&obj), "main", &profile).expect("Should link");
+    // `cold` never ran, so it moves behind `hot`
+    assert_eq!(linked.bytecode, vec![0x0F, 6, 0, 0, 0, 0x13, 0x10, 0x10]);
+    let profile = Profile { symbols: vec![count("hot", 5), count("main", 2)], ..profile };
+    let linked = link_with_profile(&[obj], "main", &profile).expect("Should link");
+    assert_eq!(linked.bytecode, vec![0x10, 0x0F, 0, 0, 0, 0, 0x13, 0x10]);
+    let offsets: Vec<_> = linked.symbols.iter().map(|s| (s.name.as_str(), s.offset)).collect();
+    assert_eq!(offsets, vec![("cold", 7), ("hot", 0), ("main", 1)]);
+  }
+
+  #[test]
+  fn test_regions_report_the_largest_contributions() {
+    let big = mock_obj(vec![0; 0x30], vec![0; 8], vec![0; 0x10], vec![], vec![]);
+    let small = mock_obj(vec![0; 0x8], vec![], vec![0; 4], vec![], vec![]);
+    let region = |name: &str, length, sections: &[&str]| Region {
+      name: name.to_string(),
+      length,
+      sections: sections.iter().map(|s| s.to_string()).collect(),
+      address: None,
+      overlay: false,
+    };
+    let objects = [("big.leafobj", &big), ("small.leafobj", &small)];
+    let regions = [region("rom", 0x4C, &[".text", ".rodata"]), region("ram", 8, &[".data"])];
+    assert!(check_regions(&regions, &objects).is_ok());
+
+    let regions = [region("rom", 0x40, &[".text", ".rodata"])];
+    let err = check_regions(&regions, &objects).unwrap_err();
+    assert_eq!(err.code, "region-overflow");
+    assert_eq!(err.message, ".text and .rodata overflow region 'rom' by 12 bytes (76 of 64)");
+    assert_eq!(err.notes, vec![
+      ".text is 56 bytes, .rodata is 20 bytes".to_string(),
+      "largest contributions: big.leafobj (64 bytes), small.leafobj (12 bytes)".to_string(),
+    ]);
+
+    let regions = [region("rom", 0x100, &[".text"]), region("flash", 0x100, &[".text"])];
+    assert_eq!(check_regions(&regions, &objects).unwrap_err().code, "duplicate-region");
+    assert_eq!(check_regions(&[region("rom", 0x100, &[".bss"])], &objects).unwrap_err().code, "unknown-section");
+  }
+
+  #[test]
+  fn test_overlay_regions_run_sections_at_their_address() {
+    let source = ".extern __data_load_start\nmain:\n  MOVI r1, table\n  MOVI r2, msg\n  MOVI r3, __data_load_start\n  HALT\n\
+      .data\ntable:\n  .word 1\n.rodata\nmsg:\n  .word 2\n";
+    let mut diagnostics = Vec::new();
+    let object = crate::assemble_source(source, None, &mut diagnostics).unwrap().object;
+    let bank = |length, address, overlay| Region {
+      name: "bank".to_string(),
+      length,
+      sections: vec![".data".to_string(), ".rodata".to_string()],
+      address,
+      overlay,
+    };
+    let linked = link_with(std::slice::from_ref(&object), "main", None, &[bank(8, Some(0x8000), true)]).unwrap();
+    // Both run at 0x8000 and load after the 28 bytes of .text, where the bounds say
+    let operand = |at: usize| u32::from_le_bytes(linked.bytecode[at..at + 4].try_into().unwrap());
+    assert_eq!((operand(5), operand(14), operand(23)), (0x8000, 0x8000, 28));
+    let segments: Vec<_> = linked.segments.iter().map(|segment| (segment.section, segment.address, segment.run_address)).collect();
+    assert_eq!(segments, vec![(0, 0, 0), (1, 28, 0x8000), (2, 36, 0x8000)]);
+    let bounds: Vec<_> = linked.symbols.iter().filter(|s| s.section == ABSOLUTE_SECTION).map(|s| (s.name.as_str(), s.offset)).collect();
+    assert_eq!(bounds, vec![
+      ("__data_load_end", 36), ("__data_load_start", 28), ("__data_run_end", 0x8008), ("__data_run_start", 0x8000),
+      ("__rodata_load_end", 44), ("__rodata_load_start", 36), ("__rodata_run_end", 0x8008), ("__rodata_run_start", 0x8000),
+    ]);
+    assert!(linked.validate().is_ok());
+
+    // Without an overlay they run one after the other, and without an address where they load, so
+    // there are no bounds to copy between
+    let linked = link_with(std::slice::from_ref(&object), "main", None, &[bank(16, Some(0x8000), false)]).unwrap();
+    assert_eq!(linked.segments[2].run_address, 0x8008);
+    let err = link_with(std::slice::from_ref(&object), "main", None, &[bank(16, None, false)]).unwrap_err();
+    assert_eq!(err.message, "Unresolved symbol: __data_load_start");
+
+    // Only the largest section of an overlay has to fit
+    let objects = [("main.leafobj", &object)];
+    assert!(check_regions(&[bank(8, Some(0x8000), true)], &objects).is_ok());
+    let err = check_regions(&[bank(4, Some(0x8000), true)], &objects).unwrap_err();
+    assert_eq!(err.message, ".data and .rodata overflow region 'bank' by 4 bytes (8 of 4)");
+    assert_eq!(check_regions(&[bank(8, None, true)], &objects).unwrap_err().code, "region-address");
+    let text = Region { sections: vec![".text".to_string()], ..bank(0x100, Some(0x8000), false) };
+    assert_eq!(check_regions(&[text], &objects).unwrap_err().code, "region-address");
+    let err = link_with(&[object], "main", None, &[bank(8, Some(0x20), true)]).unwrap_err();
+    assert_eq!(err.message, ".data would run at 0x20..0x28 in region 'bank', which is not free memory");
+  }
+
+  #[test]
+  fn test_size_report_breaks_sizes_down_by_object_and_symbol() {
+    let symbols = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "table".to_string(), offset: 0, section: 2, kind: 1, external: false },
+      SymbolEntry { name: "loop".to_string(), offset: 2, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "helper".to_string(), offset: 0, section: 0, kind: 0, external: true },
+    ];
+    let main = mock_obj(vec![0; 10], vec![], vec![0; 16], symbols, vec![]);
+    let lib = mock_obj(vec![0; 4], vec![0; 8], vec![], vec![], vec![]);
+    assert_eq!(size_report(&[("main.leafobj", &main), ("lib.leafobj", &lib)]), "\
+object          .text    .data  .rodata    total
+main.leafobj       10        0       16       26
+lib.leafobj         4        8        0       12
+total              14        8       16       38
+
+object       section      size  symbol
+main.leafobj .rodata        16  table
+main.leafobj .text           8  loop
+main.leafobj .text           2  main
+");
+  }
+
+  #[test]
+  fn test_export_table_lists_code_symbols() {
+    let symbols = vec![
+      SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "update".to_string(), offset: 5, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "state".to_string(), offset: 0, section: 1, kind: 1, external: false },
+    ];
+    let object = mock_obj(vec![0; 10], vec![0; 8], vec![], symbols, vec![]);
+    let exports = export_table(&object, &["update = (r1: dt) -> r0".to_string(), "main".to_string()]).unwrap();
+    assert_eq!(exports, vec![
+      ExportEntry { name: "update".to_string(), address: 5, signature: Some("(r1: dt) -> r0".to_string()) },
+      ExportEntry { name: "main".to_string(), address: 0, signature: None },
+    ]);
+    assert_eq!(export_table(&object, &["state".to_string()]).unwrap_err().code, "export-not-code");
+    assert_eq!(export_table(&object, &["draw".to_string()]).unwrap_err().code, "unknown-export");
+    assert_eq!(export_table(&object, &["main".to_string(), "main".to_string()]).unwrap_err().code, "duplicate-export");
+  }
+
   #[test]
   fn test_link_unresolved_symbol_error() {
     // Reference to symbol not defined in any object
@@ -269,7 +1134,92 @@ mod tests {
 
     let result = link(&[obj], "main");
     assert!(result.is_err());
-    assert!(result.unwrap_err().contains("Unresolved symbol"));
+    let err = result.unwrap_err();
+    assert_eq!(err.code, "unresolved-symbol");
+    assert!(err.message.contains("Unresolved symbol"));
+  }
+
+  #[test]
+  fn test_link_shared_keeps_imports_and_relocations() {
+    let symbols = vec![
+      SymbolEntry { name: "twice".to_string(), offset: 0, section: 0, kind: 0, external: false },
+      SymbolEntry { name: "helper".to_string(), offset: 0, section: 0, kind: 0, external: true }
+    ];
+    let reloc = vec![
+      RelocationEntry { offset: 1, symbol_index: 1, reloc_type: RelocationType::Absolute, target_section: 0 },
+      RelocationEntry { offset: 6, symbol_index: 0, reloc_type: RelocationType::Relative, target_section: 0 }
+    ];
+    let obj = mock_obj(vec![0x01, 0, 0, 0, 0, 0x02, 0, 0, 0, 0], vec![], vec![], symbols, reloc);
+
+    let shared = link_shared(&[obj]).expect("Should link");
+    assert_eq!(shared.entry_point, None);
+    // Sorted by name: the import `helper`, then `twice`
+    assert!(shared.symbols[0].external);
+    assert_eq!(shared.relocations.len(), 2);
+    assert_eq!((shared.relocations[0].symbol_index, shared.relocations[1].symbol_index), (0, 1));
+  }
+
+  #[test]
+  fn test_link_binds_versioned_symbols() {
+    let mut diagnostics = Vec::new();
+    let program = ".extern parse@V1\n.extern parse\nmain:\n  CALL parse@V1\n  CALL parse\n  HALT\n";
+    let program = crate::assemble_source(program, None, &mut diagnostics).unwrap().object;
+    let library = ".global parse@V1 parse@@V2\nparse@V1:\n  RET\nparse@@V2:\n  RET\n";
+    let library = crate::assemble_source(library, None, &mut diagnostics).unwrap().object;
+    assert!(diagnostics.is_empty());
+
+    // The old version where it is asked for by name, the default one everywhere else
+    let linked = link(&[program, library], "main").expect("Should link");
+    assert_eq!(&linked.bytecode[1..5], &11u32.to_le_bytes());
+    assert_eq!(&linked.bytecode[6..10], &12u32.to_le_bytes());
+
+    let clash = "parse@@V1:\n  RET\nparse@@V2:\n  RET\n";
+    assert!(crate::assemble_source(clash, None, &mut diagnostics).is_none());
+    assert_eq!(diagnostics[0].message, "'parse@@V1' and 'parse@@V2' are both the default version of 'parse'");
+  }
+
+  #[test]
+  fn test_libraries_supply_only_the_members_used() {
+    let mut diagnostics = Vec::new();
+    let program = ".extern print_int\nmain:\n  CALL print_int\n  RET\n";
+    let program = crate::assemble_source(program, None, &mut diagnostics).unwrap().object;
+    let libraries = [crate::library::std_library()];
+    // print_int needs int_to_str in turn, but nothing else
+    let members: Vec<_> = library_members(std::slice::from_ref(&program), &libraries).iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(members, ["print_int", "int_to_str"]);
+
+    let defined = crate::assemble_source("print_int:\n  RET\n", None, &mut diagnostics).unwrap().object;
+    assert!(library_members(&[program, defined], &libraries).is_empty());
+  }
+
+  #[test]
+  fn test_link_merges_line_tables() {
+    let mut obj1 = mock_obj(vec![0x00, 0x00], vec![], vec![], vec![], vec![]);
+    obj1.debug_info = Some(DebugInfo {
+      files: vec!["a.leaf".to_string()],
+      lines: vec![LineEntry { offset: 1, file: 0, line: 5 }],
+    });
+    let mut obj2 = mock_obj(vec![0x00], vec![], vec![], vec![], vec![]);
+    obj2.debug_info = Some(DebugInfo {
+      files: vec!["b.leaf".to_string()],
+      lines: vec![LineEntry { offset: 0, file: 0, line: 9 }],
+    });
+
+    let linked = link(&[obj1, obj2], "main").expect("Should link");
+    let debug = linked.debug_info.unwrap();
+    assert_eq!(debug.files, vec!["a.leaf".to_string(), "b.leaf".to_string()]);
+    assert_eq!(debug.lines[1], LineEntry { offset: 2, file: 1, line: 9 });
+  }
+
+  #[test]
+  fn test_isa_version_is_the_newest_needed() {
+    let header = |isa_version| LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Little, isa_version, checksum: Checksum::Crc32(0), build_id: None };
+    let (old, new) = (header(0), header(2));
+    assert_eq!(isa_version([("old.leafobj", &old), ("new.leafobj", &new)], 2), Ok(2));
+    assert_eq!(isa_version([("old.leafobj", &old)], 1), Ok(1));
+    let err = isa_version([("old.leafobj", &old), ("new.leafobj", &new)], 1).unwrap_err();
+    assert_eq!(err.code, "isa-version");
+    assert_eq!(err.message, "new.leafobj needs ISA revision 2, but the target is revision 1");
   }
 
   #[test]
@@ -284,5 +1234,17 @@ mod tests {
     // But the symbol does not exist
     assert!(!linked.symbols.iter().any(|s| s.name == "main"));
   }
-}
 
+  #[test]
+  fn test_link_arbitrary_objects_does_not_panic() {
+    use arbitrary::{Arbitrary, Unstructured};
+    let seed: Vec<u8> = (0..8192u32).map(|i| (i.wrapping_mul(2246822519) >> 11) as u8).collect();
+    let mut u = Unstructured::new(&seed);
+    for _ in 0..64 {
+      let objects: Vec<LeafAsmObject> = (0..3).map(|_| LeafAsmObject::arbitrary(&mut u).unwrap()).collect();
+      if let Ok(linked) = link(&objects, "main") {
+        assert_eq!(linked.bytecode.len(), objects.iter().map(|o| o.bytecode.len()).sum::<usize>());
+      }
+    }
+  }
+}
@@ -1,18 +1,67 @@
+#[allow(clippy::module_inception)]
 pub mod linker;
 
-use std::fs;
+use std::io::Read;
 use serde::Deserialize;
+use leaf_common::ReadableResource;
+use leaf_common::error::{FormatError, LeafError};
 
 #[derive(Debug, Deserialize)]
 pub struct LinkerFile {
   pub input_files: Vec<String>,
   pub output_file: String,
   pub entry_point: Option<String>,
+  /// Size budgets the linked sections must fit in.
+  #[serde(rename = "region", default)]
+  pub regions: Vec<Region>,
 }
 
-pub fn parse_linker_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<LinkerFile> {
-  let content = fs::read_to_string(path)
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-  toml::from_str(&content)
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+/// A memory region of `length` bytes holding `sections` (`.text`, `.data`, `.rodata`), which
+/// together must fit in it.
+///
+/// A region with an `address` is where its sections run, one after another, while they are still
+/// loaded with the rest of the image; the program copies them there. In an `overlay` region every
+/// section runs at `address` and only the largest has to fit, so a runtime can swap them in and out
+/// using the `__<section>_load_start`, `_load_end`, `_run_start` and `_run_end` symbols the linker
+/// defines for each section that runs away from where it is loaded.
+///
+/// ```toml
+/// [[region]]
+/// name = "rom"
+/// length = 0x4000
+/// sections = [".text"]
+///
+/// [[region]]
+/// name = "bank"
+/// length = 0x400
+/// address = 0x8000
+/// overlay = true
+/// sections = [".data", ".rodata"]
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+pub struct Region {
+  pub name: String,
+  pub length: u32,
+  pub sections: Vec<String>,
+  #[serde(default)]
+  pub address: Option<u32>,
+  #[serde(default)]
+  pub overlay: bool,
+}
+
+impl ReadableResource for LinkerFile {
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError>
+  where
+    Self: Sized
+  {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    toml::from_str(&content)
+      .map_err(|e| FormatError::Decode(e.to_string()).into())
+  }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_linker_file<P: AsRef<std::path::Path>>(path: P) -> Result<LinkerFile, LeafError> {
+  LinkerFile::read_from_path(path)
 }
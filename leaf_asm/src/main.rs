@@ -1,26 +1,29 @@
-use std::{fs::File, io::{BufReader, BufWriter, Read, Write}, path::Path};
-use clap::{Parser as ClapParser, Subcommand};
-use log::{info, error};
-use leaf_common::leaf_file::{LeafAsmFile, LeafAsmObjectHeader};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
+use log::info;
+use leaf_common::checksum::ChecksumAlgorithm;
+use leaf_common::diagnostic::{deny_warnings, Diagnostic};
+use leaf_common::disassembler::{disassemble, disassemble_with_source};
+use leaf_common::error::LeafError;
+use leaf_common::isa::IsaExtension;
+use leaf_common::leaf_file::{BuildId, LeafAsmFile, LeafAsmObject};
+use leaf_common::opcode::ISA_VERSION;
+use leaf_common::source_map::SourceMap;
 use leaf_common::{ReadableResource, WriteableResource};
-use leaf_common::leaf_ast::Line;
-use crate::assembler::assemble::Assembler;
-use crate::linker::linker::link;
-
-mod parser;
-pub mod linker;
-pub mod assembler;
-
-
-/// Generate a header for a new object file
-fn make_header() -> LeafAsmObjectHeader {
-  LeafAsmObjectHeader {
-    magic: *b"LAF\0",
-    version: 1,
-    reserved: 0,
-    checksum: 0, // filled in during write_to
-  }
-}
+use leaf_asm::{assemble_expanded, make_header, AssembleOptions};
+use leaf_asm::cache::{self, BuildCache};
+use leaf_asm::crt0::{link_executable, link_executable_with};
+use leaf_asm::doc::Documentation;
+use leaf_asm::editor;
+use leaf_asm::embed::rust_module;
+use leaf_asm::library::{find_library, install_path, std_library};
+use leaf_asm::linker::linker::{check_regions, export_table, isa_version, library_members, link, link_shared, link_with, size_report};
+use leaf_asm::linker::parse_linker_file;
+use leaf_vm::coverage::Coverage;
+use leaf_vm::profile::Profile;
+use leaf_vm::host::LayoutArgs;
+use leaf_vm::vm::VM;
 
 #[derive(ClapParser)]
 #[command(author, version, about, long_about = None)]
@@ -29,10 +32,108 @@ struct Cli {
   #[arg(short, long, action = clap::ArgAction::Count)]
   verbose: u8,
 
+  /// How to print errors and warnings
+  #[arg(long, value_enum, default_value_t = MessageFormat::Human, global = true)]
+  message_format: MessageFormat,
+
+  /// Warning options; `-Werror` turns warnings into errors
+  #[arg(short = 'W', value_enum, value_name = "OPTION", global = true)]
+  warnings: Vec<WarningOption>,
+
+  /// Turn warnings into errors and also reject instructions outside .text, operands of the wrong kind, and
+  /// mnemonics and registers not written in upper and lower case
+  #[arg(long, global = true)]
+  strict: bool,
+
+  /// Add the extension instructions described in this TOML or JSON file
+  #[arg(long, value_name = "FILE", global = true)]
+  isa: Vec<String>,
+
+  /// Checksum written into object and executable headers: crc32, crc32c, xxhash64 or sha256
+  #[arg(long, value_name = "ALGORITHM", global = true, default_value_t = ChecksumAlgorithm::Crc32)]
+  checksum: ChecksumAlgorithm,
+
+  /// ISA revision to target; instructions and directives from later revisions are errors
+  #[arg(long, value_name = "N", global = true, value_parser = clap::value_parser!(u16).range(1..=ISA_VERSION as i64))]
+  target_version: Option<u16>,
+
   #[command(subcommand)]
   command: Command,
 }
 
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum WarningOption {
+  /// Fail on any warning
+  Error,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DocFormat {
+  Markdown,
+  Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GrammarFormat {
+  /// A `.tmLanguage.json` grammar
+  Textmate,
+  /// A tree-sitter `grammar.js`
+  TreeSitter,
+  /// `queries/highlights.scm` for the tree-sitter grammar
+  TreeSitterHighlights,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Emit {
+  /// The assembled `.leafobj`
+  Object,
+  /// The source after `.if` and `.while` blocks are expanded, with `;#line` markers for where each line came from
+  Preprocessed,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MessageFormat {
+  Human,
+  /// One JSON object per line
+  Json,
+}
+
+/// Print diagnostics to stderr; `source` is quoted in human output when spans point into it.
+fn report(format: MessageFormat, diagnostics: &[Diagnostic], source: Option<&str>) {
+  for diagnostic in diagnostics {
+    match format {
+      MessageFormat::Human => eprint!("{}", diagnostic.render_human(source)),
+      MessageFormat::Json => eprintln!("{}", diagnostic.to_json()),
+    }
+  }
+}
+
+/// Report the diagnostics of assembling `input`, whose source is `source`, quoting each line from
+/// the file it is in, which may be one the input includes.
+fn report_assembled(format: MessageFormat, diagnostics: &[Diagnostic], input: &str, source: &str) {
+  let mut included = HashMap::new();
+  for diagnostic in diagnostics {
+    let source = match diagnostic.span.as_ref().and_then(|span| span.file.as_deref()) {
+      Some(file) if file != input => included.entry(file.to_string())
+        .or_insert_with(|| std::fs::read_to_string(file).ok())
+        .as_deref(),
+      _ => Some(source),
+    };
+    report(format, std::slice::from_ref(diagnostic), source);
+  }
+}
+
+/// The members of the `-l` libraries that `files` need, each named `library(member)`.
+fn library_files(names: &[String], dirs: &[PathBuf], files: &[LeafAsmFile]) -> Result<Vec<(String, LeafAsmFile)>, Diagnostic> {
+  let libraries = names.iter().map(|name| find_library(name, dirs)).collect::<Result<Vec<_>, _>>()?;
+  let objects: Vec<_> = files.iter().map(|file| file.object.clone()).collect();
+  let members = library_members(&objects, &libraries).into_iter().map(|member| {
+    let library = libraries.iter().position(|library| library.members.iter().any(|m| std::ptr::eq(m, member))).unwrap();
+    (format!("{}({})", names[library], member.name), member.file.clone())
+  });
+  Ok(members.collect())
+}
+
 #[derive(Subcommand)]
 enum Command {
   /// Assemble one or more .leaf files into .leafobj
@@ -44,11 +145,71 @@ enum Command {
     /// Output files (optional, same count as input)
     #[arg(short, long, required = false)]
     outputs: Option<Vec<String>>,
+
+    /// Source map from the compiler that generated each input, putting its own source in the line table
+    /// (same count as input)
+    #[arg(long, value_name = "FILE")]
+    source_maps: Option<Vec<String>>,
+
+    /// What to write for each input: the object, and/or the preprocessed source next to it as `.i`
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Emit::Object])]
+    emit: Vec<Emit>,
+
+    /// Search DIR for `.include`d files not found next to the file including them
+    #[arg(short = 'I', value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
   },
 
   /// Link one or more .leafobj files into a single executable
   Link {
-    /// Input object files to link
+    /// Input object files to link, after those the linker script lists
+    #[arg(required_unless_present = "script")]
+    inputs: Vec<String>,
+
+    /// Output file for the linked executable (default: the linker script's)
+    #[arg(short, long, required_unless_present = "script")]
+    output: Option<String>,
+
+    /// Linker script (TOML) with input files, the output file, the entry point and memory regions
+    #[arg(long)]
+    script: Option<String>,
+
+    /// Print how much each object and symbol contributes to the size of each section
+    #[arg(long)]
+    size_report: bool,
+
+    /// Add a function to the export table hosts enumerate and call, as NAME or NAME=SIGNATURE
+    #[arg(long = "export", value_name = "NAME[=SIGNATURE]", conflicts_with = "shared")]
+    exports: Vec<String>,
+
+    /// Entry point for the executable, called by the startup code (default: main)
+    #[arg(short, long, required = false)]
+    entry: Option<String>,
+
+    /// Produce a shared object (.leafso) whose unresolved symbols are bound when the VM loads it
+    #[arg(long, conflicts_with = "entry")]
+    shared: bool,
+
+    /// Leave out the startup code (crt0) and start the executable at the entry point itself
+    #[arg(long)]
+    no_crt: bool,
+
+    /// Profile written by `run --profile`; hot code is placed first and code that never ran last
+    #[arg(long, conflicts_with = "shared")]
+    profile: Option<String>,
+
+    /// Link the members of library NAME (NAME.leaflib or libNAME.leaflib) that the objects use
+    #[arg(short = 'l', value_name = "NAME")]
+    libraries: Vec<String>,
+
+    /// Search DIR for `-l` libraries, before the directory leaf_asm is installed in
+    #[arg(short = 'L', value_name = "DIR")]
+    library_dirs: Vec<PathBuf>,
+  },
+
+  /// Assemble and link source files in one step, reusing cached objects for unchanged files
+  Build {
+    /// Source files to build
     #[arg(required = true)]
     inputs: Vec<String>,
 
@@ -56,10 +217,128 @@ enum Command {
     #[arg(short, long, required = true)]
     output: String,
 
-    /// Entry point for the executable
+    /// Entry point for the executable, called by the startup code (default: main)
     #[arg(short, long, required = false)]
     entry: Option<String>,
-  }
+
+    /// Leave out the startup code (crt0) and start the executable at the entry point itself
+    #[arg(long)]
+    no_crt: bool,
+
+    /// Directory for cached objects
+    #[arg(long, default_value = cache::DEFAULT_DIR)]
+    cache_dir: String,
+
+    /// Search DIR for `.include`d files not found next to the file including them
+    #[arg(short = 'I', value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+
+    /// Link the members of library NAME (NAME.leaflib or libNAME.leaflib) that the objects use
+    #[arg(short = 'l', value_name = "NAME")]
+    libraries: Vec<String>,
+
+    /// Search DIR for `-l` libraries, before the directory leaf_asm is installed in
+    #[arg(short = 'L', value_name = "DIR")]
+    library_dirs: Vec<PathBuf>,
+  },
+
+  /// Run a linked executable in the VM
+  Run {
+    /// Linked executable (.leafexe)
+    input: String,
+
+    /// Initial memory size in bytes; the stack starts at the top
+    #[arg(short, long, default_value_t = 0x10000)]
+    memory: usize,
+
+    /// Count executions per instruction, print a report to stderr and write the profile as JSON here
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Write an lcov report of the source lines that ran here (needs a line table)
+    #[arg(long)]
+    coverage: Option<String>,
+
+    #[command(flatten)]
+    layout: LayoutArgs,
+
+    /// Fault when a RET pops a return address other than the one its CALL pushed
+    #[arg(long)]
+    stack_canaries: bool,
+
+    /// Fail unless the program exits with this code, and succeed if it does
+    #[arg(long, value_name = "N")]
+    expect_exit: Option<i32>,
+
+    /// Directory the program can open files in; without it, the OPEN syscall always fails
+    #[arg(long, value_name = "DIR")]
+    fs_root: Option<PathBuf>,
+  },
+
+  /// Step through a linked executable in a terminal debugger
+  Debug {
+    /// Linked executable (.leafexe)
+    input: String,
+
+    /// Initial memory size in bytes; the stack starts at the top
+    #[arg(short, long, default_value_t = 0x10000)]
+    memory: usize,
+  },
+
+  /// Disassemble the .text of an object or executable, with source lines when it has a line table
+  Disasm {
+    /// Object or executable (.leafobj, .leafexe, .leafso)
+    input: String,
+
+    /// Leave out source lines and labels even when the line table is there
+    #[arg(long)]
+    no_source: bool,
+  },
+
+  /// Write a Rust module with the section bounds and symbol addresses of a linked executable
+  Symbols {
+    /// Linked executable (.leafexe)
+    input: String,
+
+    /// Output file for the module (default: stdout)
+    #[arg(short, long)]
+    output: Option<String>,
+  },
+
+  /// Extract the `;;;` doc comments of a source file's exported symbols
+  Doc {
+    /// Source file (.leaf)
+    input: String,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = DocFormat::Markdown)]
+    format: DocFormat,
+
+    /// Output file (default: stdout)
+    #[arg(short, long)]
+    output: Option<String>,
+  },
+
+  /// Write an editor syntax highlighting grammar for the instruction set, including `--isa` extensions
+  Grammar {
+    /// Grammar format
+    #[arg(long, value_enum, default_value_t = GrammarFormat::Textmate)]
+    format: GrammarFormat,
+
+    /// Output file (default: stdout)
+    #[arg(short, long)]
+    output: Option<String>,
+  },
+
+  /// Write the standard library for `link -lstd`, built from the sources in this toolchain
+  Stdlib {
+    /// Output file (default: std.leaflib next to leaf_asm, where `-l` looks for it)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+  },
+
+  /// Interactively assemble and execute instructions one line at a time
+  Repl,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -67,6 +346,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   // Set up logging level
   let log_level = match cli.verbose {
+    // Commands that print their own results keep routine logging out of the way
+    0 if matches!(
+      cli.command,
+      Command::Repl | Command::Run { .. } | Command::Debug { .. } | Command::Doc { .. } | Command::Symbols { .. } | Command::Grammar { .. } | Command::Disasm { .. }
+    ) => "warn",
     0 => "info",
     1 => "debug",
     _ => "trace",
@@ -76,12 +360,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   }
   env_logger::init();
 
+  let format = cli.message_format;
+  let target_version = cli.target_version.unwrap_or(ISA_VERSION);
+  let options = AssembleOptions { target_version, strict: cli.strict };
+  let deny = cli.strict || cli.warnings.contains(&WarningOption::Error);
+  for path in &cli.isa {
+    let installed = IsaExtension::read_from_path(path)
+      .map_err(|e| e.to_string())
+      .and_then(|isa| isa.install().map_err(|e| e.to_string()));
+    if let Err(e) = installed {
+      report(format, &[Diagnostic::error("isa", format!("Failed to load ISA description {}: {}", path, e))], None);
+      std::process::exit(1);
+    }
+  }
   match &cli.command {
-    Command::Assemble { inputs, outputs } => {
+    Command::Assemble { inputs, outputs, source_maps, emit, include_dirs } => {
       // Output file logic
       let output_files: Vec<String> = if let Some(out) = outputs {
         if out.len() != inputs.len() {
-          error!("Number of outputs must match inputs");
+          report(format, &[Diagnostic::error("usage", "Number of outputs must match inputs")], None);
           std::process::exit(1);
         }
         out.clone()
@@ -98,76 +395,385 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
           .collect()
       };
 
-      for (input_path, output_path) in inputs.iter().zip(output_files.iter()) {
+      if source_maps.as_ref().is_some_and(|maps| maps.len() != inputs.len()) {
+        report(format, &[Diagnostic::error("usage", "Number of source maps must match inputs")], None);
+        std::process::exit(1);
+      }
+      // Every input is assembled and its diagnostics reported before failing
+      let mut failed = false;
+      for (index, (input_path, output_path)) in inputs.iter().zip(output_files.iter()).enumerate() {
+        let source_map = match source_maps.as_ref().map(|maps| &maps[index]) {
+          Some(path) => match SourceMap::read_from_path(path) {
+            Ok(map) => Some(map),
+            Err(e) => {
+              report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", path, e))], None);
+              failed = true;
+              continue;
+            }
+          },
+          None => None,
+        };
         // Read source
         let src = match std::fs::read_to_string(input_path) {
           Ok(s) => s,
           Err(e) => {
-            error!("Failed to read {}: {}", input_path, e);
+            report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", input_path, e))], None);
+            failed = true;
             continue;
           }
         };
         // Parse and assemble
-        let program = match parser::parse_program(&src) {
-          Ok(lines) => lines,
-          Err(e) => {
-            error!("Failed to parse {}: {}", input_path, e);
-            continue;
+        let mut diagnostics = Vec::new();
+        let (assembled, expanded) = assemble_expanded(&src, Some(input_path), options, include_dirs, &mut diagnostics);
+        let denied = deny && deny_warnings(&mut diagnostics);
+        report_assembled(format, &diagnostics, input_path, &src);
+        if let Some(expanded) = expanded.filter(|_| emit.contains(&Emit::Preprocessed)) {
+          let path = Path::new(output_path).with_extension("i");
+          if let Err(e) = std::fs::write(&path, expanded) {
+            report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path.display(), e))], None);
           }
+        }
+        let Some(mut file) = assembled.filter(|_| !denied) else {
+          failed = true;
+          continue;
         };
-        // Entry point: pick "main" if it exists, else None
-        let entry_point = program.iter().filter_map(|l| match l {
-          Line::LabelOnly(l) => Some(l),
-          _ => None,
-        }).find(|l| l.as_str() == "main").map(|_| "main".to_string());
-        let object = Assembler::assemble(&program, entry_point);
-
-        let file = LeafAsmFile {
-          header: make_header(),
-          object,
-        };
-        let mut output_file = BufWriter::new(File::create(output_path)?);
-        if let Err(e) = file.write_to(&mut output_file) {
-          error!("Failed to write {}: {}", output_path, e);
+        if !emit.contains(&Emit::Object) {
+          continue;
+        }
+        if let (Some(map), Some(debug)) = (&source_map, &mut file.object.debug_info) {
+          *debug = map.apply(debug, input_path);
+        }
+        file.header.checksum = cli.checksum.zero();
+        file.header.build_id = Some(BuildId::default());
+        if let Err(e) = file.write_to_path(output_path) {
+          report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", output_path, e))], None);
+          failed = true;
         } else {
           info!("Assembled {} -> {}", input_path, output_path);
         }
       }
+      if failed {
+        std::process::exit(1);
+      }
     }
-    Command::Link { inputs, output, entry } => {
+    Command::Link { inputs, output, script, size_report: print_sizes, exports, entry, shared, no_crt, profile, libraries, library_dirs } => {
+      let script = script.as_ref().map(|path| match parse_linker_file(path) {
+        Ok(script) => script,
+        Err(e) => {
+          report(format, &[Diagnostic::error("io", format!("Failed to read linker script {}: {}", path, e))], None);
+          std::process::exit(1);
+        }
+      });
+      let mut inputs: Vec<String> = script.iter().flat_map(|s| s.input_files.iter()).chain(inputs).cloned().collect();
+      let output = output.as_ref().or(script.as_ref().map(|s| &s.output_file)).expect("output or script is required");
+      let entry = entry.as_ref().or(script.as_ref().and_then(|s| s.entry_point.as_ref()));
+      let profile = profile.as_ref().map(|path| {
+        match std::fs::read_to_string(path).map_err(LeafError::from).and_then(|json| Profile::from_json(&json)) {
+          Ok(profile) => profile,
+          Err(e) => {
+            report(format, &[Diagnostic::error("io", format!("Failed to read profile {}: {}", path, e))], None);
+            std::process::exit(1);
+          }
+        }
+      });
       // Read all input object files
-      let mut objects = Vec::new();
-      for in_path in inputs {
-        let mut file = BufReader::new(File::open(in_path)?);
-        let asm_file = match LeafAsmFile::read_from(&mut file) {
+      let mut files = Vec::new();
+      for in_path in &inputs {
+        let asm_file = match LeafAsmFile::read_from_path(in_path) {
           Ok(obj) => obj,
           Err(e) => {
-            error!("Failed to read {}: {}", in_path, e);
+            report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", in_path, e))], None);
             std::process::exit(1);
           }
         };
-        objects.push(asm_file.object);
+        files.push(asm_file);
       }
-      let entry_name = entry.clone().unwrap_or_else(|| "main".to_string());
-      let linked = match link(&objects, &entry_name) {
+      match library_files(libraries, library_dirs, &files) {
+        Ok(members) => for (name, file) in members {
+          inputs.push(name);
+          files.push(file);
+        },
+        Err(e) => {
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      }
+      let version = match isa_version(inputs.iter().map(String::as_str).zip(files.iter().map(|f| &f.header)), target_version) {
+        Ok(version) => version,
+        Err(e) => {
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      };
+      let objects: Vec<_> = files.into_iter().map(|file| file.object).collect();
+      let entry_name = entry.cloned().unwrap_or_else(|| "main".to_string());
+      let startup = (!*shared && !*no_crt).then(|| leaf_asm::crt0::object(&entry_name));
+      let contributions: Vec<(&str, &LeafAsmObject)> = startup.iter().map(|crt0| ("crt0", crt0))
+        .chain(inputs.iter().map(String::as_str).zip(&objects))
+        .collect();
+      if let Some(script) = &script
+        && let Err(e) = check_regions(&script.regions, &contributions)
+      {
+        report(format, &[e], None);
+        std::process::exit(1);
+      }
+      if *print_sizes {
+        print!("{}", size_report(&contributions));
+      }
+      let regions = script.as_ref().map_or(&[][..], |script| &script.regions);
+      let linked = match (*shared, *no_crt) {
+        (true, _) => link_shared(&objects),
+        (_, true) => link_with(&objects, &entry_name, profile.as_ref(), regions),
+        (_, false) => link_executable_with(&objects, &entry_name, profile.as_ref(), regions),
+      };
+      let mut linked = match linked {
         Ok(obj) => obj,
         Err(e) => {
-          error!("Linking failed: {}", e);
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      };
+      linked.exports = match export_table(&linked, exports) {
+        Ok(exports) => exports,
+        Err(e) => {
+          report(format, &[e], None);
           std::process::exit(1);
         }
       };
-      let file = LeafAsmFile {
-        header: make_header(),
+      let mut file = LeafAsmFile {
+        header: make_header(version),
         object: linked,
       };
-      let mut out_file = BufWriter::new(File::create(output)?);
-      if let Err(e) = file.write_to(&mut out_file) {
-        error!("Failed to write output file: {}", e);
+      file.header.checksum = cli.checksum.zero();
+      file.header.build_id = Some(BuildId::default());
+      if let Err(e) = file.write_to_path(output) {
+        report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", output, e))], None);
         std::process::exit(1);
       } else {
         info!("Linked {} object(s) into {}", inputs.len(), output);
       }
     }
+    Command::Build { inputs, output, entry, no_crt, cache_dir, include_dirs, libraries, library_dirs } => {
+      let cache = BuildCache::new(cache_dir);
+      let mut files = Vec::new();
+      let mut failed = false;
+      let mut reused = 0;
+      for input_path in inputs {
+        let src = match std::fs::read_to_string(input_path) {
+          Ok(s) => s,
+          Err(e) => {
+            report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", input_path, e))], None);
+            std::process::exit(1);
+          }
+        };
+        let mut diagnostics = Vec::new();
+        let assembled = cache.assemble(&src, Some(input_path), options, include_dirs, &mut diagnostics);
+        let denied = deny && deny_warnings(&mut diagnostics);
+        report_assembled(format, &diagnostics, input_path, &src);
+        match assembled.filter(|_| !denied) {
+          Some((file, hit)) => {
+            reused += hit as usize;
+            files.push(file);
+          }
+          None => failed = true,
+        }
+      }
+      if failed {
+        std::process::exit(1);
+      }
+      info!("Reused {} of {} object(s) from {}", reused, inputs.len(), cache.dir().display());
+      let mut names = inputs.clone();
+      match library_files(libraries, library_dirs, &files) {
+        Ok(members) => for (name, file) in members {
+          names.push(name);
+          files.push(file);
+        },
+        Err(e) => {
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      }
+      let version = match isa_version(names.iter().map(String::as_str).zip(files.iter().map(|f| &f.header)), target_version) {
+        Ok(version) => version,
+        Err(e) => {
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      };
+      let objects: Vec<_> = files.into_iter().map(|file| file.object).collect();
+      let entry_name = entry.clone().unwrap_or_else(|| "main".to_string());
+      let linked = if *no_crt { link(&objects, &entry_name) } else { link_executable(&objects, &entry_name) };
+      let linked = match linked {
+        Ok(obj) => obj,
+        Err(e) => {
+          report(format, &[e], None);
+          std::process::exit(1);
+        }
+      };
+      let mut file = LeafAsmFile { header: make_header(version), object: linked };
+      file.header.checksum = cli.checksum.zero();
+      file.header.build_id = Some(BuildId::default());
+      if let Err(e) = file.write_to_path(output) {
+        report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", output, e))], None);
+        std::process::exit(1);
+      } else {
+        info!("Built {} source(s) into {}", inputs.len(), output);
+      }
+    }
+    Command::Run { input, memory, profile, coverage, layout, stack_canaries, expect_exit, fs_root } => {
+      let mut vm = VM::new(*memory);
+      vm.debug = cli.verbose > 0;
+      vm.layout = layout.layout();
+      vm.stack_canaries = *stack_canaries;
+      vm.fs_root = fs_root.clone();
+      let file = match LeafAsmFile::read_from_path(input).and_then(|file| vm.load_program(&file).map(|_| file)) {
+        Ok(file) => file,
+        Err(e) => {
+          report(format, &[Diagnostic::error("load", format!("Failed to load {}: {}", input, e))], None);
+          std::process::exit(1);
+        }
+      };
+      if profile.is_some() || coverage.is_some() {
+        vm.enable_profiling();
+      }
+      vm.run();
+      let counts = vm.profile().unwrap_or_default();
+      let mut outputs = Vec::new();
+      if let Some(path) = profile {
+        let profile = Profile::new(&file.object, counts);
+        eprint!("{}", profile.report(10));
+        outputs.push((path, profile.to_json()));
+      }
+      if let Some(path) = coverage {
+        let coverage = Coverage::new(&file.object, counts);
+        let (found, hit) = coverage.summary();
+        if file.object.debug_info.is_none() {
+          report(format, &[Diagnostic::warning("coverage", format!("{} has no line table; coverage is empty", input))], None);
+        }
+        eprintln!("lines: {}/{} executed", hit, found);
+        outputs.push((path, coverage.to_lcov()));
+      }
+      for (path, contents) in outputs {
+        if let Err(e) = std::fs::write(path, contents) {
+          report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path, e))], None);
+          std::process::exit(1);
+        }
+      }
+      let code = vm.exit_code().unwrap_or(0);
+      match expect_exit {
+        Some(expected) if code != *expected => {
+          report(format, &[Diagnostic::error("exit-code", format!("{} exited with {}, expected {}", input, code, expected))], None);
+          std::process::exit(1);
+        }
+        Some(_) => {}
+        None => std::process::exit(code),
+      }
+    }
+    Command::Debug { input, memory } => {
+      let file = match LeafAsmFile::read_from_path(input) {
+        Ok(file) => file,
+        Err(e) => {
+          report(format, &[Diagnostic::error("load", format!("Failed to load {}: {}", input, e))], None);
+          std::process::exit(1);
+        }
+      };
+      leaf_asm::debugger::run(file.object, *memory)?;
+    }
+    Command::Disasm { input, no_source } => {
+      let file = match LeafAsmFile::read_from_path(input) {
+        Ok(file) => file,
+        Err(e) => {
+          report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", input, e))], None);
+          std::process::exit(1);
+        }
+      };
+      if let Some(build_id) = file.header.build_id {
+        println!("; build-id {}", build_id);
+      }
+      if *no_source || file.object.debug_info.is_none() {
+        print!("{}", disassemble(&file.object.bytecode));
+      } else {
+        // Sources are named as they were given to the assembler, so also look next to the input
+        let dir = Path::new(input).parent().unwrap_or(Path::new(""));
+        print!("{}", disassemble_with_source(&file.object, |source| {
+          std::fs::read_to_string(source).or_else(|_| std::fs::read_to_string(dir.join(source))).ok()
+            .or_else(|| (source == "crt0.leaf").then(|| leaf_asm::crt0::SOURCE.to_string()))
+        }));
+      }
+    }
+    Command::Symbols { input, output } => {
+      let file = match LeafAsmFile::read_from_path(input) {
+        Ok(file) => file,
+        Err(e) => {
+          report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", input, e))], None);
+          std::process::exit(1);
+        }
+      };
+      let name = Path::new(input).file_name().map_or(input.clone(), |name| name.to_string_lossy().into_owned());
+      let module = rust_module(&file.object, &name);
+      match output {
+        Some(path) => if let Err(e) = std::fs::write(path, module) {
+          report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path, e))], None);
+          std::process::exit(1);
+        },
+        None => print!("{}", module),
+      }
+    }
+    Command::Doc { input, format: doc_format, output } => {
+      let src = match std::fs::read_to_string(input) {
+        Ok(s) => s,
+        Err(e) => {
+          report(format, &[Diagnostic::error("io", format!("Failed to read {}: {}", input, e))], None);
+          std::process::exit(1);
+        }
+      };
+      let docs = match Documentation::extract(&src, Some(input)) {
+        Ok(docs) => docs,
+        Err(e) => {
+          report(format, &[e], Some(&src));
+          std::process::exit(1);
+        }
+      };
+      let text = match doc_format {
+        DocFormat::Markdown => docs.to_markdown(),
+        DocFormat::Json => docs.to_json() + "\n",
+      };
+      match output {
+        Some(path) => if let Err(e) = std::fs::write(path, text) {
+          report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path, e))], None);
+          std::process::exit(1);
+        },
+        None => print!("{}", text),
+      }
+    }
+    Command::Grammar { format: grammar_format, output } => {
+      let text = match grammar_format {
+        GrammarFormat::Textmate => editor::textmate(),
+        GrammarFormat::TreeSitter => editor::tree_sitter(),
+        GrammarFormat::TreeSitterHighlights => editor::TREE_SITTER_HIGHLIGHTS.to_string(),
+      };
+      match output {
+        Some(path) => if let Err(e) = std::fs::write(path, text) {
+          report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path, e))], None);
+          std::process::exit(1);
+        },
+        None => print!("{}", text),
+      }
+    }
+    Command::Stdlib { output } => {
+      let Some(path) = output.clone().or_else(install_path) else {
+        report(format, &[Diagnostic::error("io", "Cannot tell where leaf_asm is installed; pass --output")], None);
+        std::process::exit(1);
+      };
+      if let Err(e) = std_library().write_to_path(&path) {
+        report(format, &[Diagnostic::error("io", format!("Failed to write {}: {}", path.display(), e))], None);
+        std::process::exit(1);
+      }
+      info!("Wrote the standard library to {}", path.display());
+    }
+    Command::Repl => {
+      leaf_asm::repl::run()?;
+    }
   }
   Ok(())
 }
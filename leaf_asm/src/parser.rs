@@ -1,43 +1,268 @@
-use log::{debug, info};
+use log::info;
 use pest::Parser;
-use pest::iterators::{Pair, Pairs};
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
+use pest::iterators::Pair;
 use pest_derive::Parser;
-use leaf_common::leaf_ast::{Arg, Directive, Instruction, Line, OpCode};
+use leaf_common::diagnostic::{Diagnostic, Span};
+use leaf_common::leaf_ast::{Arg, Directive, Instruction, Line, OpCode, Text};
+use leaf_common::leaf_file::RelocationType;
 
 #[derive(Parser)]
 #[grammar = "grammar/leaf_asm.pest"]
 pub struct LeafAsmParser;
 
-pub fn parse_program(source: &str) -> Result<Vec<Line>, String> {
+/// Parsed lines together with where each one came from; `spans[i]` is the location of `lines[i]`.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ParsedProgram<'src> {
+  pub lines: Vec<Line<'src>>,
+  pub spans: Vec<Span>,
+}
+
+pub fn parse_program(source: &str) -> Result<Vec<Line<'_>>, Diagnostic> {
+  parse_source(source, None).map(|program| program.lines)
+}
+
+/// Parse `source` for tools that must cope with code being typed, such as an editor highlighting
+/// it: never fails, but returns the lines that parsed, with their spans, and a diagnostic for each
+/// one that did not, recovering at the next line as `parse_source_recovering` does.
+pub fn parse_program_lossy(source: &str) -> (ParsedProgram<'_>, Vec<Diagnostic>) {
+  parse_source_recovering(source, None, false)
+}
+
+/// Parse `source`, recording spans so later stages can point diagnostics back at the input.
+/// `file` is only used to label those spans. Mnemonics and registers may be in either case.
+pub fn parse_source<'src>(source: &'src str, file: Option<&str>) -> Result<ParsedProgram<'src>, Diagnostic> {
+  parse_source_with(source, file, false)
+}
+
+/// Like `parse_source`, but with `strict` only upper-case mnemonics and lower-case registers are
+/// accepted, as before either case was.
+pub fn parse_source_with<'src>(source: &'src str, file: Option<&str>, strict: bool) -> Result<ParsedProgram<'src>, Diagnostic> {
   info!("Parsing program:\n{}", source);
   let pairs = LeafAsmParser::parse(Rule::program, source)
-    .map_err(|e| format!("Parse error: {}", e))?;
-  let mut lines = Vec::new();
+    .map_err(|e| syntax_error(e, source, file))?;
+  if strict && let Some(pair) = pairs.clone().flatten().find(|pair| match pair.as_rule() {
+    Rule::opcode => pair.as_str() != pair.as_str().to_ascii_uppercase(),
+    Rule::register => pair.as_str().starts_with('R'),
+    _ => false,
+  }) {
+    let (what, expected) = match pair.as_rule() {
+      Rule::opcode => ("Mnemonic", pair.as_str().to_ascii_uppercase()),
+      _ => ("Register", pair.as_str().to_ascii_lowercase()),
+    };
+    return Err(invalid(&pair, file, "case", format!("{} '{}' is not written '{}'", what, pair.as_str(), expected))
+      .with_note("--strict only accepts upper-case mnemonics and lower-case registers"));
+  }
+  let mut program = ParsedProgram::default();
 
   for pair in pairs {
-    match pair.as_rule() {
-      Rule::program => {
-        for item in pair.into_inner() {
-          match item.as_rule() {
-            Rule::line | Rule::last_line => {
-              if let Some(line) = parse_line(item) {
-                info!("Parsed line: {:?}", line);
-                lines.push(line);
-              }
+    if pair.as_rule() == Rule::program {
+      for item in pair.into_inner() {
+        match item.as_rule() {
+          Rule::line | Rule::last_line => {
+            let span = line_span(&item, file);
+            if let Some(line) = parse_line(item, file)? {
+              info!("Parsed line: {:?}", line);
+              program.lines.push(line);
+              program.spans.push(span);
             }
-            _ => {}
           }
+          _ => {}
+        }
+      }
+    }
+  }
+
+  Ok(program)
+}
+
+/// Like `parse_source_with`, but carries on past errors a line at a time, so every bad line is
+/// reported. Returns the lines that did parse, and an error for each one that did not.
+pub fn parse_source_recovering<'src>(source: &'src str, file: Option<&str>, strict: bool) -> (ParsedProgram<'src>, Vec<Diagnostic>) {
+  if let Ok(program) = parse_source_with(source, file, strict) {
+    return (program, Vec::new());
+  }
+  let mut program = ParsedProgram::default();
+  let mut errors = Vec::new();
+  for (index, lines) in statement_lines(source) {
+    let shift = |span: &mut Span| span.line += index;
+    match parse_source_with(lines, file, strict) {
+      Ok(mut part) => {
+        part.spans.iter_mut().for_each(shift);
+        program.lines.extend(part.lines);
+        program.spans.extend(part.spans);
+      }
+      Err(mut e) => {
+        e.span.iter_mut().for_each(shift);
+        errors.push(e);
+      }
+    }
+  }
+  (program, errors)
+}
+
+/// The lines of `source`, each with its 0-based index, except that a line ending inside a `/* */`
+/// comment comes with the lines after it, up to the one the comment ends on.
+fn statement_lines(source: &str) -> Vec<(usize, &str)> {
+  let mut statements = Vec::new();
+  let (mut start, mut first) = (0, 0);
+  for (index, line) in source.split_inclusive('\n').enumerate() {
+    let end = line.as_ptr() as usize - source.as_ptr() as usize + line.len();
+    if !ends_in_block_comment(&source[start..end]) {
+      statements.push((first, &source[start..end]));
+      (start, first) = (end, index + 1);
+    }
+  }
+  if start < source.len() {
+    statements.push((first, &source[start..]));
+  }
+  statements
+}
+
+/// Whether `text` ends inside a `/* */` comment, so it cannot be parsed without what comes next.
+pub fn ends_in_block_comment(text: &str) -> bool {
+  scan_comments(text, |_, _| {})
+}
+
+/// Call `code` with the byte offset and character of everything in `text` that is not a comment,
+/// including what is in quotes. Returns whether `text` ends inside a `/* */` comment.
+fn scan_comments(text: &str, mut code: impl FnMut(usize, char)) -> bool {
+  let (mut block, mut line, mut quoted) = (false, false, false);
+  let mut chars = text.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    let next = chars.peek().map(|&(_, c)| c);
+    match c {
+      '\n' => (line, quoted) = (false, false),
+      _ if line => continue,
+      '*' if block && next == Some('/') => {
+        chars.next();
+        block = false;
+        continue;
+      }
+      _ if block => continue,
+      '\\' if quoted => {
+        code(i, c);
+        if let Some((i, c)) = chars.next() {
+          code(i, c);
         }
+        continue;
+      }
+      '"' => quoted = !quoted,
+      _ if quoted => {}
+      ';' => line = true,
+      '/' if next == Some('/') => line = true,
+      '/' if next == Some('*') => {
+        chars.next();
+        block = true;
       }
       _ => {}
     }
+    if !line && !block {
+      code(i, c);
+    }
   }
+  block
+}
 
-  Ok(lines)
+/// Parse a fragment of a larger input that starts at line `first_line` (1-based), so spans and
+/// syntax errors point at the right line of the whole file.
+pub fn parse_fragment<'src>(
+  source: &'src str,
+  file: Option<&str>,
+  first_line: usize,
+) -> Result<ParsedProgram<'src>, Diagnostic> {
+  let shift = |span: &mut Span| span.line += first_line - 1;
+  match parse_source(source, file) {
+    Ok(mut program) => {
+      program.spans.iter_mut().for_each(shift);
+      Ok(program)
+    }
+    Err(mut e) => {
+      e.span.iter_mut().for_each(shift);
+      Err(e)
+    }
+  }
 }
 
-fn parse_line(pair: Pair<Rule>) -> Option<Line> {
-  match pair.as_rule() {
+/// A syntax error naming what the parser found and what it would have accepted there, with the
+/// span covering the offending token.
+fn syntax_error(error: pest::error::Error<Rule>, source: &str, file: Option<&str>) -> Diagnostic {
+  let (line, column) = match error.line_col {
+    LineColLocation::Pos(pos) => pos,
+    LineColLocation::Span(start, _) => start,
+  };
+  let at = match error.location {
+    InputLocation::Pos(pos) => pos,
+    InputLocation::Span((start, _)) => start,
+  };
+  let rest = &source[at..];
+  let token = match rest.find(|c: char| !(c.is_alphanumeric() || "_.@".contains(c))) {
+    Some(0) => &rest[..rest.chars().next().map_or(0, char::len_utf8)],
+    Some(end) => &rest[..end],
+    None => rest,
+  };
+  let found = match token {
+    "" => "the end of the file".to_string(),
+    "\n" | "\r" => "the end of the line".to_string(),
+    token => format!("'{}'", token),
+  };
+  let mut expected: Vec<&str> = Vec::new();
+  if let ErrorVariant::ParsingError { positives, .. } = &error.variant {
+    for rule in positives.iter().filter_map(|rule| describe(*rule)) {
+      if !expected.contains(&rule) {
+        expected.push(rule);
+      }
+    }
+  }
+  let message = match expected.split_last() {
+    None => format!("Unexpected {}", found),
+    Some((last, [])) => format!("Expected {}, found {}", last, found),
+    Some((last, rest)) => format!("Expected {} or {}, found {}", rest.join(", "), last, found),
+  };
+  let length = token.trim_end_matches(['\n', '\r']).chars().count().max(1);
+  Diagnostic::error("syntax", message).with_span(Some(Span::new(line, column, length).in_file(file)))
+}
+
+/// How a syntax error describes a rule the parser expected.
+fn describe(rule: Rule) -> Option<&'static str> {
+  Some(match rule {
+    Rule::opcode => "an instruction",
+    Rule::directive => "a directive",
+    Rule::label_only | Rule::label_prefix => "a label definition",
+    Rule::mem => "a memory operand",
+    Rule::register | Rule::register_offset | Rule::indexed => "a register",
+    Rule::num => "a number",
+    Rule::ident | Rule::local_ref | Rule::here | Rule::label_offset => "a label",
+    Rule::relocated => "a relocation such as `%hi(label)`",
+    Rule::reloc_operator => "a relocation operator",
+    Rule::offset_sign => "an offset such as `+8`",
+    Rule::arg_list => "operands",
+    Rule::local_number => "a local label number",
+    // Nothing else can follow a complete statement
+    Rule::EOI => "the end of the line",
+    _ => return None,
+  })
+}
+
+/// Span of the statement on a line, excluding indentation and trailing comments.
+fn line_span(pair: &Pair<Rule>, file: Option<&str>) -> Span {
+  let statement = pair.clone().into_inner().next().map(|p| p.as_span()).unwrap_or(pair.as_span());
+  let (line, column) = statement.start_pos().line_col();
+  let mut end = 0;
+  scan_comments(statement.as_str(), |i, c| if !c.is_whitespace() {
+    end = i + c.len_utf8();
+  });
+  Span::new(line, column, statement.as_str()[..end].chars().count()).in_file(file)
+}
+
+/// Error for a line that matched the grammar but cannot be represented, e.g. an out-of-range number.
+fn invalid(pair: &Pair<Rule>, file: Option<&str>, code: &'static str, message: String) -> Diagnostic {
+  let (line, column) = pair.as_span().start_pos().line_col();
+  Diagnostic::error(code, message).with_span(Some(Span::new(line, column, pair.as_str().chars().count()).in_file(file)))
+}
+
+fn parse_line<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<Option<Line<'src>>, Diagnostic> {
+  Ok(match pair.as_rule() {
     Rule::line | Rule::last_line => {
       let mut inner = pair.into_inner();
       match inner.next() {
@@ -45,9 +270,9 @@ fn parse_line(pair: Pair<Rule>) -> Option<Line> {
           Rule::label_only => {
             let ident = l.into_inner().next().unwrap().as_str();
             info!("Parsed label only: {}", ident);
-            Some(Line::LabelOnly(ident.to_string()))
+            Some(Line::LabelOnly(ident.into()))
           }
-          Rule::instruction_decl => Some(parse_instruction_decl(l)),
+          Rule::instruction_decl => Some(parse_instruction_decl(l, file)?),
           Rule::directive => Some(parse_directive(l)),
           _ => None,
         },
@@ -55,39 +280,43 @@ fn parse_line(pair: Pair<Rule>) -> Option<Line> {
       }
     }
     _ => None,
-  }
+  })
 }
 
-fn parse_directive(pair: Pair<Rule>) -> Line {
+fn parse_directive(pair: Pair<'_, Rule>) -> Line<'_> {
   let mut inner = pair.into_inner();
-  let name = inner.next().unwrap().as_str().to_string();
-  let args = inner.next().map(|p| p.as_str().trim().to_string());
+  let name = inner.next().unwrap().as_str();
+  let args = inner.next().map(|p| p.as_str().trim());
 
   info!("Parsed directive: {} with args: {:?}", name, args);
-  match name.as_str() {
-    "text" => Line::Section(".text".to_string()),
-    "data" => Line::Section(".data".to_string()),
-    "rodata" => Line::Section(".roddata".to_string()),
-    "section" => Line::Section(args.unwrap_or_default()),
-    "global"  => Line::Global(args.unwrap_or_default()),
-    _         => Line::Directive(Directive { name, args }),
+  match name {
+    "text" => Line::Section(".text".into()),
+    "data" => Line::Section(".data".into()),
+    "rodata" => Line::Section(".rodata".into()),
+    "section" => Line::Section(args.unwrap_or_default().into()),
+    "global"  => Line::Global(symbol_list(args.unwrap_or_default())),
+    "extern"  => Line::Extern(symbol_list(args.unwrap_or_default())),
+    _         => Line::Directive(Directive { name: name.into(), args: args.map(Into::into) }),
   }
 }
 
-fn parse_instruction_decl(pair: Pair<Rule>) -> Line {
+/// The names in a `.global` or `.extern` list, separated by commas, whitespace or both.
+fn symbol_list(args: &str) -> Vec<Text<'_>> {
+  args.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()).map(Into::into).collect()
+}
+
+fn parse_instruction_decl<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<Line<'src>, Diagnostic> {
   let mut inner = pair.clone().into_inner().peekable();
   let mut label = None;
-  let mut opcode_str = None;
   let mut args = Vec::new();
 
   info!("Parsing instruction declaration: {}", pair.as_str());
 
   // If label_prefix exists, it's first
-  if let Some(peek) = inner.peek() {
-    if peek.as_rule() == Rule::label_prefix {
-      let prefix = inner.next().unwrap();
-      label = Some(prefix.into_inner().next().unwrap().as_str().to_string());
-    }
+  if let Some(peek) = inner.peek()
+    && peek.as_rule() == Rule::label_prefix {
+    let prefix = inner.next().unwrap();
+    label = Some(prefix.into_inner().next().unwrap().as_str());
   }
 
   // At this point, the next part of the string is the opcode (as a slice of the parent)
@@ -95,7 +324,7 @@ fn parse_instruction_decl(pair: Pair<Rule>) -> Line {
   let full_str = pair.as_str();
   let mut rest = full_str;
 
-  if let Some(ref l) = label {
+  if let Some(l) = label {
     // Find and skip label prefix in string
     let label_part = format!("{}:", l);
     if rest.starts_with(&label_part) {
@@ -117,14 +346,13 @@ fn parse_instruction_decl(pair: Pair<Rule>) -> Line {
     // opcode is up to end
     opcode_end = rest.len();
   }
-  let opcode = &rest[..opcode_end].trim();
-  opcode_str = Some(opcode.to_string());
+  let opcode_str = rest[..opcode_end].trim();
 
   // The remaining pairs (if any) are arg_list
-  while let Some(pair) = inner.next() {
+  for pair in inner {
     match pair.as_rule() {
       Rule::arg_list => {
-        args = pair.into_inner().map(parse_arg).collect();
+        args = pair.into_inner().map(|arg| parse_arg(arg, file)).collect::<Result<_, _>>()?;
       }
       _ => {
         // Comments or similar, skip
@@ -132,75 +360,126 @@ fn parse_instruction_decl(pair: Pair<Rule>) -> Line {
     }
   }
 
-  Line::Instruction(Instruction {
-    label,
-    opcode: parse_opcode(&opcode_str.expect("opcode required")),
+  let opcode = OpCode::from_mnemonic(&opcode_str.to_ascii_uppercase())
+    .ok_or_else(|| invalid(&pair, file, "unknown-opcode", format!("Unknown opcode '{}'", opcode_str)))?;
+  Ok(Line::Instruction(Instruction {
+    label: label.map(Into::into),
+    opcode,
     args,
-  })
+  }))
 }
 
+/// An integer literal as an immediate: decimal, which has to fit an `i32`, or hexadecimal, binary
+/// or octal with a `0x`, `0b` or `0o` prefix, which may also spell out any 32-bit pattern, so
+/// `0xFFFFFFFF` is -1. With a `-` it has to fit an `i32` either way. Digits can be separated with
+/// `_`, as in `1_000_000`.
+pub fn parse_integer(text: &str) -> Option<i32> {
+  parse_sized(text, 32).map(|value| value as i32)
+}
 
+/// An integer literal as a `.word`: like `parse_integer`, but 64 bits wide.
+pub fn parse_word(text: &str) -> Option<i64> {
+  parse_sized(text, 64).map(|value| value as i64)
+}
 
-fn parse_opcode(s: &str) -> OpCode {
-  match s {
-    "ADD" => OpCode::Add,
-    "SUB" => OpCode::Sub,
-    "MUL" => OpCode::Mul,
-    "DIV" => OpCode::Div,
-    "AND" => OpCode::And,
-    "OR" => OpCode::Or,
-    "XOR" => OpCode::Xor,
-    "NOT" => OpCode::Not,
-    "LT" => OpCode::Lt,
-    "GT" => OpCode::Gt,
-    "EQ" => OpCode::Eq,
-    "JMP" => OpCode::Jmp,
-    "JZ" => OpCode::Jz,
-    "JNZ" => OpCode::Jnz,
-    "MOV" => OpCode::Mov,
-    "LOAD" => OpCode::Load,
-    "STORE" => OpCode::Store,
-    "MOVI" => OpCode::Movi,
-    "LOADI" => OpCode::Loadi,
-    "STOREI" => OpCode::Storei,
-    "CALL" => OpCode::Call,
-    "RET" => OpCode::Ret,
-    "PUSH" => OpCode::Push,
-    "POP" => OpCode::Pop,
-    "HALT" => OpCode::Halt,
-    "BREAK" => OpCode::Break,
-    "SYSCALL" => OpCode::Syscall,
-    "NOP" => OpCode::Nop,
-    _ => panic!("Unknown opcode: {s}"),
-  }
+/// `text` as a `bits`-wide integer, the way `parse_integer` reads one, with a bit pattern that
+/// does not fit a signed integer wrapped around to the negative value it stands for.
+fn parse_sized(text: &str, bits: u32) -> Option<i128> {
+  let text = strip_separators(text)?;
+  let text = text.as_str();
+  let (min, max) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text),
+  };
+  let radix = match digits.get(..2).map(str::to_ascii_lowercase).as_deref() {
+    Some("0x") => 16,
+    Some("0b") => 2,
+    Some("0o") => 8,
+    _ => return text.parse().ok().filter(|value| (min..=max).contains(value)),
+  };
+  i128::from_str_radix(&digits[2..], radix).ok()
+    .map(|value| if negative { -value } else { value })
+    .filter(|value| (min..1 << bits).contains(value))
+    .map(|value| if value > max { value - (1 << bits) } else { value })
 }
 
-fn parse_arg(pair: Pair<Rule>) -> Arg {
-  match pair.as_rule() {
-    Rule::num => {
-      let n: i32 = pair.as_str().parse().unwrap();
-      Arg::Immediate(n)
-    }
-    Rule::register => Arg::Register(pair.as_str().to_string()),
-    Rule::ident => Arg::Label(pair.as_str().to_string()),
+/// `text` without the `_`s that separate its digits, or `None` if one is not between two of them.
+pub fn strip_separators(text: &str) -> Option<String> {
+  let bytes = text.as_bytes();
+  let separates = |i: usize| i > 0 && bytes[i - 1].is_ascii_alphanumeric() && bytes.get(i + 1).is_some_and(u8::is_ascii_alphanumeric);
+  text.bytes().enumerate().all(|(i, b)| b != b'_' || separates(i)).then(|| text.replace('_', ""))
+}
+
+fn parse_number(pair: &Pair<Rule>, file: Option<&str>) -> Result<i32, Diagnostic> {
+  parse_integer(pair.as_str()).ok_or_else(|| {
+    invalid(pair, file, "invalid-immediate", format!("Immediate '{}' does not fit in 32 bits", pair.as_str()))
+  })
+}
+
+fn parse_arg<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<Arg<'src>, Diagnostic> {
+  Ok(match pair.as_rule() {
+    Rule::num => Arg::Immediate(parse_number(&pair, file)?),
+    Rule::register => Arg::Register(pair.as_str().into()),
+    Rule::ident | Rule::local_ref | Rule::here => Arg::Label(pair.as_str().into()),
+    Rule::relocated => parse_relocated(pair, file)?,
+    Rule::label_offset => parse_label_offset(pair, file)?,
     Rule::mem => {
       let inner = pair.into_inner().next().unwrap();
       match inner.as_rule() {
-        Rule::register => Arg::Mem(Box::new(Arg::Register(inner.as_str().to_string()))),
-        Rule::num => {
-          let n: i32 = inner.as_str().parse().unwrap();
-          Arg::Mem(Box::new(Arg::Immediate(n)))
+        Rule::register => Arg::Mem(Box::new(Arg::Register(inner.as_str().into()))),
+        Rule::num => Arg::Mem(Box::new(Arg::Immediate(parse_number(&inner, file)?))),
+        Rule::ident | Rule::local_ref | Rule::here => Arg::Mem(Box::new(Arg::Label(inner.as_str().into()))),
+        Rule::relocated => Arg::Mem(Box::new(parse_relocated(inner, file)?)),
+        Rule::label_offset => Arg::Mem(Box::new(parse_label_offset(inner, file)?)),
+        Rule::indexed => {
+          let mut parts = inner.clone().into_inner();
+          let (base, index) = (parts.next().unwrap().as_str(), parts.next().unwrap().as_str());
+          let scale = parts.next().map(|scale| parse_number(&scale, file)).transpose()?.unwrap_or(1);
+          Arg::Mem(Box::new(Arg::Indexed(base.into(), index.into(), scale)))
         }
-        Rule::ident => Arg::Mem(Box::new(Arg::Label(inner.as_str().to_string()))),
-        _ => panic!("Unexpected memory argument: {:?}", inner.as_rule()),
+        Rule::register_offset => {
+          let (register, offset) = parse_offset(inner, file)?;
+          Arg::Mem(Box::new(Arg::RegisterOffset(register, offset)))
+        }
+        _ => unreachable!("Unexpected memory argument: {:?}", inner.as_rule()),
       }
     }
-    _ => panic!("Unexpected rule in argument: {:?}", pair.as_rule()),
-  }
+    _ => unreachable!("Unexpected rule in argument: {:?}", pair.as_rule()),
+  })
+}
+
+fn parse_label_offset<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<Arg<'src>, Diagnostic> {
+  let (name, addend) = parse_offset(pair, file)?;
+  Ok(Arg::LabelOffset(name, addend))
+}
+
+/// The name and signed constant of `label+N` or `rN+N`.
+fn parse_offset<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<(Text<'src>, i32), Diagnostic> {
+  let mut inner = pair.clone().into_inner();
+  let name = inner.next().unwrap().as_str();
+  let negative = inner.next().unwrap().as_str() == "-";
+  let addend = parse_number(&inner.next().unwrap(), file)?;
+  let addend = if negative { addend.checked_neg() } else { Some(addend) }.ok_or_else(|| {
+    invalid(&pair, file, "invalid-immediate", format!("Offset in '{}' does not fit in 32 bits", pair.as_str()))
+  })?;
+  Ok((name.into(), addend))
+}
+
+fn parse_relocated<'src>(pair: Pair<'src, Rule>, file: Option<&str>) -> Result<Arg<'src>, Diagnostic> {
+  let mut inner = pair.clone().into_inner();
+  let operator = inner.next().unwrap().as_str();
+  let name = inner.next().unwrap().as_str();
+  let kind = RelocationType::from_operator(operator).ok_or_else(|| {
+    invalid(&pair, file, "unknown-relocation", format!("Unknown relocation operator '%{}'", operator))
+      .with_note("the operators are %hi, %lo and %secrel")
+  })?;
+  Ok(Arg::Relocated(kind, name.into()))
 }
 
 #[cfg(test)]
 mod tests {
+  use leaf_common::leaf_ast::to_source;
   use super::*;
 
   #[test]
@@ -212,9 +491,9 @@ mod tests {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Add);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
-          Arg::Register("r2".to_string()),
-          Arg::Register("r3".to_string()),
+          Arg::Register("r1".into()),
+          Arg::Register("r2".into()),
+          Arg::Register("r3".into()),
         ]);
         assert_eq!(instr.label, None);
       }
@@ -227,7 +506,7 @@ mod tests {
     let asm = "start:";
     let lines = parse_program(asm).unwrap();
     assert_eq!(lines, vec![
-      Line::LabelOnly("start".to_string())
+      Line::LabelOnly("start".into())
     ]);
   }
 
@@ -238,11 +517,11 @@ mod tests {
     assert_eq!(lines.len(), 1);
     match &lines[0] {
       Line::Instruction(instr) => {
-        assert_eq!(instr.label, Some("start".to_string()));
+        assert_eq!(instr.label, Some("start".into()));
         assert_eq!(instr.opcode, OpCode::Mov);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
-          Arg::Register("r2".to_string())
+          Arg::Register("r1".into()),
+          Arg::Register("r2".into())
         ]);
       }
       _ => panic!("Expected instruction"),
@@ -256,11 +535,11 @@ mod tests {
     assert_eq!(lines.len(), 1);
     match &lines[0] {
       Line::Instruction(instr) => {
-        assert_eq!(instr.label, Some("start".to_string()));
+        assert_eq!(instr.label, Some("start".into()));
         assert_eq!(instr.opcode, OpCode::Mov);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
-          Arg::Register("r2".to_string())
+          Arg::Register("r1".into()),
+          Arg::Register("r2".into())
         ]);
       }
       _ => panic!("Expected instruction"),
@@ -276,7 +555,7 @@ mod tests {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Add);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
+          Arg::Register("r1".into()),
           Arg::Immediate(-42),
           Arg::Immediate(99),
         ]);
@@ -285,6 +564,108 @@ mod tests {
     }
   }
 
+  #[test]
+  fn parse_hexadecimal_binary_and_octal_immediates() {
+    let lines = parse_program("MOVI r1, 0x1F\nMOVI r2, -0b1010\nMOVI r3, 0o17\nMOVI r4, 0XFFFFFFFF\n").unwrap();
+    let immediates: Vec<_> = lines.iter().map(|line| match line {
+      Line::Instruction(instr) => instr.args[1].clone(),
+      _ => panic!("Expected instruction"),
+    }).collect();
+    assert_eq!(immediates, vec![Arg::Immediate(31), Arg::Immediate(-10), Arg::Immediate(15), Arg::Immediate(-1)]);
+
+    let err = parse_program("MOVI r1, 0x100000000").unwrap_err();
+    assert_eq!(err.code, "invalid-immediate");
+    assert!(parse_program("MOVI r1, -0x80000001").is_err());
+    assert_eq!(parse_program("MOVI r1, -0x80000000").unwrap().len(), 1);
+  }
+
+  #[test]
+  fn parse_symbol_lists() {
+    let lines = parse_program(".extern foo, bar baz\n.global main,api\n").unwrap();
+    assert_eq!(lines, vec![
+      Line::Extern(vec!["foo".into(), "bar".into(), "baz".into()]),
+      Line::Global(vec!["main".into(), "api".into()]),
+    ]);
+    assert_eq!(lines[0].to_string(), ".extern foo, bar, baz");
+  }
+
+  #[test]
+  fn parse_digit_separators() {
+    let lines = parse_program("MOVI r1, 1_000_000\nMOVI r2, 0xFF_FF\nMOVI r3, -0b1_0\n").unwrap();
+    let immediates: Vec<_> = lines.iter().map(|line| match line {
+      Line::Instruction(instr) => instr.args[1].clone(),
+      _ => panic!("Expected instruction"),
+    }).collect();
+    assert_eq!(immediates, vec![Arg::Immediate(1_000_000), Arg::Immediate(0xFFFF), Arg::Immediate(-2)]);
+
+    assert_eq!(parse_program("MOVI r1, 1__0").unwrap_err().code, "syntax");
+    assert_eq!(parse_program("MOVI r1, 0x_FF").unwrap_err().code, "syntax");
+    assert_eq!((parse_integer("1_0"), parse_integer("1_"), parse_integer("_1")), (Some(10), None, None));
+  }
+
+  #[test]
+  fn parse_current_location() {
+    let program = parse_source("JMP $\nLOADI r1, [$+8]\nJMP .\n", None).unwrap();
+    assert_eq!(to_source(&program.lines), "JMP $\nLOADI r1, [$+8]\nJMP .\n");
+    assert_eq!(parse_program("JMP $x").unwrap_err().code, "syntax");
+  }
+
+  #[test]
+  fn parse_register_offsets_and_indexes() {
+    let program = parse_source("LOAD r1, [r7+8]\nSTORE r2, [R7 - 0x10]\n", None).unwrap();
+    let Line::Instruction(instr) = &program.lines[1] else { panic!("Expected instruction") };
+    assert_eq!(instr.args[1], Arg::Mem(Box::new(Arg::RegisterOffset("R7".into(), -16))));
+    assert_eq!(to_source(&program.lines), "LOAD r1, [r7+8]\nSTORE r2, [R7-16]\n");
+    // Only an address can have an offset
+    assert_eq!(parse_program("MOV r1, r2+8").unwrap_err().code, "syntax");
+
+    let program = parse_source("LOAD r1, [r2 + r3 * 8]\nSTORE r1, [r2+r3]\n", None).unwrap();
+    let Line::Instruction(instr) = &program.lines[0] else { panic!("Expected instruction") };
+    assert_eq!(instr.args[1], Arg::Mem(Box::new(Arg::Indexed("r2".into(), "r3".into(), 8))));
+    assert_eq!(to_source(&program.lines), "LOAD r1, [r2+r3*8]\nSTORE r1, [r2+r3]\n");
+  }
+
+  #[test]
+  fn parse_label_offsets() {
+    let lines = parse_program("JMP loop+8\nLOAD r1, [table - 0x10]\n").unwrap();
+    let args: Vec<_> = lines.iter().map(|line| match line {
+      Line::Instruction(instr) => instr.args.last().unwrap().clone(),
+      _ => panic!("Expected instruction"),
+    }).collect();
+    assert_eq!(args, vec![Arg::LabelOffset("loop".into(), 8), Arg::Mem(Box::new(Arg::LabelOffset("table".into(), -16)))]);
+    assert_eq!(to_source(&lines), "JMP loop+8\nLOAD r1, [table-16]\n");
+  }
+
+  #[test]
+  fn parse_local_labels() {
+    let lines = parse_program("1: SUB r1, r1, r2\n  JNZ r1, 1b\n  JMP 1f+4\n.Lnext:\n  LOADI r2, [0b]\n  MOVI r3, 0b1\n").unwrap();
+    assert!(matches!(&lines[0], Line::Instruction(instr) if instr.label.as_deref() == Some("1")));
+    assert_eq!(lines[3], Line::LabelOnly(".Lnext".into()));
+    let args: Vec<_> = [1, 2, 4, 5].iter().map(|&index| match &lines[index] {
+      Line::Instruction(instr) => instr.args.last().unwrap().clone(),
+      _ => panic!("Expected instruction"),
+    }).collect();
+    assert_eq!(args, vec![
+      Arg::Label("1b".into()),
+      Arg::LabelOffset("1f".into(), 4),
+      Arg::Mem(Box::new(Arg::Label("0b".into()))),
+      Arg::Immediate(1),
+    ]);
+  }
+
+  #[test]
+  fn parse_mnemonics_and_registers_in_any_case() {
+    let lines = parse_program("loop: movi R1, 5\n  Load r2, [R3]\n").unwrap();
+    assert_eq!(to_source(&lines), "loop: MOVI R1, 5\nLOAD r2, [R3]\n");
+
+    let err = parse_source_with("  HALT\n  movi r1, 5\n", None, true).unwrap_err();
+    assert_eq!((err.code, err.message.as_str()), ("case", "Mnemonic 'movi' is not written 'MOVI'"));
+    assert_eq!(err.span.unwrap().line, 2);
+    let err = parse_source_with("MOVI R1, 5\n", None, true).unwrap_err();
+    assert_eq!(err.message, "Register 'R1' is not written 'r1'");
+    assert!(parse_source_with("MOVI r1, 5\n", None, true).is_ok());
+  }
+
   #[test]
   fn parse_label_arg() {
     let asm = "JMP start";
@@ -294,7 +675,7 @@ mod tests {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Jmp);
         assert_eq!(instr.args, vec![
-          Arg::Label("start".to_string()),
+          Arg::Label("start".into()),
         ]);
       }
       _ => panic!("Expected instruction"),
@@ -310,14 +691,27 @@ mod tests {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Add);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
-          Arg::Register("r2".to_string()),
+          Arg::Register("r1".into()),
+          Arg::Register("r2".into()),
         ]);
       }
       _ => panic!("Expected instruction"),
     }
   }
 
+  #[test]
+  fn parse_c_style_comments() {
+    let asm = "/* header\n   over lines */\nmain: // entry\n  MOVI r1, /* five */ 5\n  .string \"a//b\" /* trailing */\n  HALT /* one\n  NOP */\n";
+    let program = parse_source(asm, None).unwrap();
+    assert_eq!(to_source(&program.lines), "main:\nMOVI r1, 5\n.string \"a//b\"\nHALT\n");
+    let lines: Vec<_> = program.spans.iter().map(|span| (span.line, span.column, span.length)).collect();
+    assert_eq!(lines, vec![(3, 1, 5), (4, 3, 21), (5, 3, 14), (6, 3, 4)]);
+
+    assert!(ends_in_block_comment("HALT /* one\n"));
+    assert!(!ends_in_block_comment("HALT /* one */ ; /* two\n"));
+    assert!(!ends_in_block_comment(".string \"/*\"\n"));
+  }
+
   #[test]
   fn parse_whitespace_and_empty_lines() {
     let asm = "\n  \nADD r1, r2\n\n  SUB r3, 1  \n\n";
@@ -355,7 +749,7 @@ mod tests {
     match &lines[0] {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Push);
-        assert_eq!(instr.args, vec![Arg::Register("r5".to_string())]);
+        assert_eq!(instr.args, vec![Arg::Register("r5".into())]);
       }
       _ => panic!("Expected instruction"),
     }
@@ -371,12 +765,12 @@ mod tests {
         ";
     let lines = parse_program(asm).unwrap();
     assert_eq!(lines.len(), 4);
-    assert_eq!(lines[0], Line::LabelOnly("start".to_string()));
+    assert_eq!(lines[0], Line::LabelOnly("start".into()));
     match &lines[1] {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Mov);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
+          Arg::Register("r1".into()),
           Arg::Immediate(5)
         ]);
       }
@@ -384,6 +778,130 @@ mod tests {
     }
   }
 
+  #[test]
+  fn records_statement_spans() {
+    let program = parse_source("main:\n  MOVI r1, 5 ; five\n", Some("main.leaf")).unwrap();
+    assert_eq!(program.spans, vec![
+      Span::new(1, 1, 5).in_file(Some("main.leaf")),
+      Span::new(2, 3, 10).in_file(Some("main.leaf")),
+    ]);
+  }
+
+  #[test]
+  fn syntax_error_has_location() {
+    let err = parse_program("MOVI r1, 5\n  ???\n").unwrap_err();
+    assert_eq!(err.code, "syntax");
+    let span = err.span.unwrap();
+    assert_eq!((span.line, span.column), (2, 3));
+  }
+
+  #[test]
+  fn recovering_reports_every_bad_line() {
+    let (program, errors) = parse_source_recovering("main:\n  MOVI r1, ,\n  HALT\n  FROB r1\n  movi r2, 1\n", Some("a.leaf"), true);
+    assert_eq!(program.lines.len(), 2);
+    assert_eq!(program.spans[1].line, 3);
+    let errors: Vec<_> = errors.iter().map(|e| (e.code, e.span.as_ref().unwrap().line)).collect();
+    assert_eq!(errors, vec![("syntax", 2), ("unknown-opcode", 4), ("case", 5)]);
+
+    let (program, errors) = parse_source_recovering("/* a\n,\n*/ HALT\nFROB\n", None, false);
+    assert_eq!((program.spans[0].line, errors[0].span.as_ref().unwrap().line), (3, 4));
+  }
+
+  #[test]
+  fn lossy_parse_keeps_what_it_can() {
+    let (program, errors) = parse_program_lossy("main:\n  MOVI r1,\n  LOAD r2, [r1+\n  HALT\n.wor");
+    assert_eq!(program.lines, vec![Line::LabelOnly("main".into()), Line::Instruction(Instruction { label: None, opcode: OpCode::Halt, args: vec![] }), Line::Directive(Directive { name: "wor".into(), args: None })]);
+    assert_eq!(program.spans.iter().map(|span| span.line).collect::<Vec<_>>(), vec![1, 4, 5]);
+    let errors: Vec<_> = errors.iter().map(|e| (e.code, e.span.as_ref().unwrap().line)).collect();
+    assert_eq!(errors, vec![("syntax", 2), ("syntax", 3)]);
+
+    let (program, errors) = parse_program_lossy("HALT\n");
+    assert_eq!((program.lines.len(), errors.len()), (1, 0));
+  }
+
+  #[test]
+  fn syntax_errors_say_what_was_expected() {
+    let err = parse_source("main:\n  MOVI r1, ,\n", Some("main.leaf")).unwrap_err();
+    assert_eq!(err.message, "Expected a memory operand, a label, a relocation such as `%hi(label)`, a register or a number, found ','");
+    assert_eq!(err.span, Some(Span::new(2, 12, 1).in_file(Some("main.leaf"))));
+
+    // The whole offending word is underlined
+    let err = parse_program("  JMP loop extra\n").unwrap_err();
+    assert_eq!(err.span.as_ref().map(|span| (span.column, span.length)), Some((12, 5)));
+    assert_eq!(err.render_human(Some("  JMP loop extra\n")).lines().nth(3), Some("1 |   JMP loop extra"));
+    assert_eq!(parse_program("main: MOVI r1 r2\n").unwrap_err().message, "Expected the end of the line, found 'r2'");
+  }
+
+  #[test]
+  fn parsed_text_borrows_from_source() {
+    use std::borrow::Cow;
+    let lines = parse_program("loop: JMP loop").unwrap();
+    match &lines[0] {
+      Line::Instruction(instr) => {
+        assert!(matches!(instr.label, Some(Cow::Borrowed("loop"))));
+        assert!(matches!(instr.args[0], Arg::Label(Cow::Borrowed("loop"))));
+      }
+      _ => panic!("Expected instruction"),
+    }
+    let owned: Vec<Line<'static>> = lines.into_iter().map(Line::into_owned).collect();
+    assert_eq!(owned, parse_program("loop: JMP loop").unwrap());
+  }
+
+  #[test]
+  fn parse_rodata_section() {
+    let lines = parse_program(".rodata").unwrap();
+    assert_eq!(lines, vec![Line::Section(".rodata".into())]);
+  }
+
+  #[test]
+  fn printed_program_reparses_to_equal_ast() {
+    let asm = "
+        .data
+        N: .word 10 ; count
+        .rodata
+        msg: .string \"hi\\n\"
+        .section .bss
+        .global main
+        .text
+        main:
+        loop: ADD r1, r2, r3
+        MOVI r1, -42
+        LOAD r2, [r1]
+        STOREI r3, [N]
+        JNZ r1, loop
+        HALT
+        ";
+    let lines = parse_program(asm).unwrap();
+    let printed = to_source(&lines);
+    assert_eq!(parse_program(&printed).unwrap(), lines);
+  }
+
+  #[test]
+  fn printed_fixtures_reparse_to_equal_ast() {
+    for src in [
+      include_str!("../fixtures/all.leaf"),
+      include_str!("../fixtures/fibonacci.leaf"),
+      include_str!("../fixtures/data_and_rodata.leaf"),
+      include_str!("../new_fixtures/09_complex_syscalls.leaf"),
+    ] {
+      let lines = parse_program(src).unwrap();
+      assert_eq!(parse_program(&to_source(&lines)).unwrap(), lines);
+    }
+  }
+
+  #[test]
+  fn builder_program_matches_parsed_source() {
+    use leaf_common::builder::{imm, mem, reg, sym, Program};
+    let built = Program::new()
+      .section(".text")
+      .label("main")
+      .instr(OpCode::Movi, [reg(1), imm(5)])
+      .instr(OpCode::Load, [reg(2), mem(reg(1))])
+      .instr(OpCode::Jmp, [sym("main")]);
+    let parsed = parse_program(".text\nmain:\nMOVI r1, 5\nLOAD r2, [r1]\nJMP main\n").unwrap();
+    assert_eq!(built.into_lines(), parsed);
+  }
+
   #[test]
   fn parse_mixed_labels_and_instructions_complex() {
     let asm = "
@@ -394,16 +912,36 @@ mod tests {
         ";
     let lines = parse_program(asm).unwrap();
     assert_eq!(lines.len(), 4);
-    assert_eq!(lines[0], Line::LabelOnly("start".to_string()));
+    assert_eq!(lines[0], Line::LabelOnly("start".into()));
     match &lines[1] {
       Line::Instruction(instr) => {
         assert_eq!(instr.opcode, OpCode::Mov);
         assert_eq!(instr.args, vec![
-          Arg::Register("r1".to_string()),
+          Arg::Register("r1".into()),
           Arg::Immediate(5)
         ]);
       }
       _ => panic!("Expected instruction"),
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn out_of_range_immediate_is_an_error() {
+    let err = parse_program("MOVI r1, 99999999999\n").unwrap_err();
+    assert_eq!(err.code, "invalid-immediate");
+    assert_eq!(err.span.unwrap().column, 10);
+  }
+
+  #[test]
+  fn parse_relocation_operators() {
+    let lines = parse_program("MOVI r1, %hi(table)\nLOADI r2, [%lo(table)]\n").unwrap();
+    assert_eq!(lines[0], Line::Instruction(Instruction {
+      label: None,
+      opcode: OpCode::Movi,
+      args: vec![Arg::Register("r1".into()), Arg::Relocated(RelocationType::Hi16, "table".into())],
+    }));
+    assert_eq!(to_source(&lines), "MOVI r1, %hi(table)\nLOADI r2, [%lo(table)]\n");
+    let err = parse_program("MOVI r1, %high(table)\n").unwrap_err();
+    assert_eq!(err.code, "unknown-relocation");
+  }
+}
@@ -0,0 +1,267 @@
+//! Interactive REPL: every entered instruction is assembled and executed immediately against a VM
+//! whose registers and memory persist between lines.
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use leaf_common::leaf_ast::{Arg, Line};
+use leaf_common::syscall;
+use leaf_vm::vm::VM;
+use crate::assembler::assemble::Assembler;
+use crate::parser::parse_program;
+
+const REPL_MEMORY_SIZE: usize = 0x10000;
+
+const HELP: &str = "\
+Enter instructions (e.g. `MOVI r1, 42`) to assemble and run them immediately.
+  name:               define a label at the current code position
+  :label NAME [ADDR]  define a label at ADDR (default: current code position)
+  :labels             list defined labels
+  :regs               dump registers and PC
+  :mem ADDR [LEN]     hex dump LEN bytes of memory from ADDR (default 64)
+  :reset              discard all state and start over
+  :help               show this message
+  :quit               leave the REPL
+";
+
+pub struct Repl {
+  vm: VM,
+  labels: HashMap<String, u32>,
+  /// Address the next entered instruction is written to.
+  cursor: usize,
+}
+
+impl Default for Repl {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Repl {
+  pub fn new() -> Self {
+    let mut vm = VM::new(REPL_MEMORY_SIZE);
+    vm.debug = false;
+    vm.registers[15] = vm.heap.len() as u64;
+    Self {
+      vm,
+      labels: HashMap::new(),
+      cursor: 0,
+    }
+  }
+
+  /// Evaluate one line of input, returning the text to show the user.
+  pub fn eval(&mut self, input: &str) -> Result<String, String> {
+    let input = input.trim();
+    if input.is_empty() {
+      return Ok(String::new());
+    }
+    if let Some(command) = input.strip_prefix(':') {
+      return self.command(command);
+    }
+
+    let mut out = String::new();
+    for line in parse_program(input).map_err(|d| d.message)? {
+      match line {
+        Line::LabelOnly(name) => self.define_label(&name, self.cursor as u32),
+        Line::Instruction(mut instr) => {
+          if let Some(name) = instr.label.take() {
+            self.define_label(&name, self.cursor as u32);
+          }
+          instr.args = instr.args.into_iter()
+            .map(|arg| self.resolve(arg))
+            .collect::<Result<_, _>>()?;
+          out.push_str(&self.execute(Line::Instruction(instr))?);
+        }
+        _ => return Err("only instructions and labels can be entered in the REPL".to_string()),
+      }
+    }
+    Ok(out)
+  }
+
+  pub fn vm(&self) -> &VM {
+    &self.vm
+  }
+
+  fn command(&mut self, command: &str) -> Result<String, String> {
+    let mut parts = command.split_whitespace();
+    match parts.next().unwrap_or("") {
+      "help" => Ok(HELP.to_string()),
+      "regs" => Ok(self.dump_registers()),
+      "mem" => {
+        let addr = parse_number(parts.next().ok_or("usage: :mem ADDR [LEN]")?)?;
+        let len = parts.next().map(parse_number).transpose()?.unwrap_or(64);
+        self.dump_memory(addr, len)
+      }
+      "label" => {
+        let name = parts.next().ok_or("usage: :label NAME [ADDR]")?;
+        let addr = parts.next().map(parse_number).transpose()?.unwrap_or(self.cursor);
+        self.define_label(name, addr as u32);
+        Ok(format!("{} = 0x{:04X}\n", name, addr))
+      }
+      "labels" => {
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|(_, addr)| **addr);
+        Ok(labels.iter().map(|(name, addr)| format!("0x{:04X} {}\n", addr, name)).collect())
+      }
+      "reset" => {
+        *self = Repl::new();
+        Ok("state reset\n".to_string())
+      }
+      other => Err(format!("unknown command ':{}' (try :help)", other)),
+    }
+  }
+
+  fn define_label(&mut self, name: &str, addr: u32) {
+    self.labels.insert(name.to_string(), addr);
+  }
+
+  /// Replace label operands by their address, since there is no linker to patch them later.
+  fn resolve<'a>(&self, arg: Arg<'a>) -> Result<Arg<'a>, String> {
+    match arg {
+      Arg::Label(name) => match self.labels.get(name.as_ref()) {
+        Some(addr) => Ok(Arg::Immediate(*addr as i32)),
+        None => match syscall::by_name(&name) {
+          Some(number) => Ok(Arg::Immediate(number as i32)),
+          None => Err(format!("unknown label '{}'", name)),
+        },
+      },
+      Arg::LabelOffset(name, addend) => match self.labels.get(name.as_ref()) {
+        Some(addr) => Ok(Arg::Immediate(addr.wrapping_add_signed(addend) as i32)),
+        None => Err(format!("unknown label '{}'", name)),
+      },
+      Arg::Relocated(kind, name) => match self.labels.get(name.as_ref()) {
+        Some(addr) => Ok(Arg::Immediate(kind.value(*addr, 0) as i32)),
+        None => Err(format!("unknown label '{}'", name)),
+      },
+      Arg::Mem(inner) => Ok(Arg::Mem(Box::new(self.resolve(*inner)?))),
+      other => Ok(other),
+    }
+  }
+
+  fn execute(&mut self, line: Line<'_>) -> Result<String, String> {
+    let object = Assembler::assemble(&[Line::Section(".text".into()), line], None)
+      .map_err(|diagnostics| diagnostics.iter().map(|d| d.message.as_str()).collect::<Vec<_>>().join("; "))?;
+    let code = object.bytecode;
+    let end = self.cursor + code.len();
+    if end > self.vm.registers[15] as usize {
+      return Err("out of code space".to_string());
+    }
+
+    self.vm.heap[self.cursor..end].copy_from_slice(&code);
+    self.vm.code_len = end;
+    self.vm.pc = self.cursor;
+    self.vm.step();
+    self.cursor = end;
+
+    let mut out = String::new();
+    if self.vm.halted {
+      self.vm.halted = false;
+      out.push_str("halted\n");
+    } else if self.vm.pc != end {
+      out.push_str(&format!("pc -> 0x{:04X}\n", self.vm.pc));
+    }
+    Ok(out)
+  }
+
+  fn dump_registers(&self) -> String {
+    let mut out = String::new();
+    for (i, value) in self.vm.registers.iter().enumerate() {
+      out.push_str(&format!("r{:<2} = 0x{:016X}", i, value));
+      out.push_str(if i % 4 == 3 { "\n" } else { "  " });
+    }
+    out.push_str(&format!("pc  = 0x{:04X}\n", self.vm.pc));
+    out
+  }
+
+  fn dump_memory(&self, addr: usize, len: usize) -> Result<String, String> {
+    let end = addr.checked_add(len)
+      .filter(|end| *end <= self.vm.heap.len())
+      .ok_or_else(|| format!("address range out of bounds (memory size 0x{:X})", self.vm.heap.len()))?;
+    let mut out = String::new();
+    for (row, chunk) in self.vm.heap[addr..end].chunks(16).enumerate() {
+      let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+      out.push_str(&format!("0x{:04X} | {}\n", addr + row * 16, bytes.join(" ")));
+    }
+    Ok(out)
+  }
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+  let parsed = match s.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16),
+    None => s.parse(),
+  };
+  parsed.map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// Run the REPL on stdin/stdout until `:quit` or end of input.
+pub fn run() -> std::io::Result<()> {
+  let mut repl = Repl::new();
+  let stdin = std::io::stdin();
+  let mut stdout = std::io::stdout();
+  println!("Leaf REPL - type :help for commands, :quit to exit");
+
+  loop {
+    print!("leaf> ");
+    stdout.flush()?;
+    let mut line = String::new();
+    if stdin.lock().read_line(&mut line)? == 0 {
+      break;
+    }
+    let line = line.trim();
+    if line == ":quit" || line == ":q" {
+      break;
+    }
+    match repl.eval(line) {
+      Ok(out) => print!("{}", out),
+      Err(e) => println!("error: {}", e),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn registers_persist_between_lines() {
+    let mut repl = Repl::new();
+    repl.eval("MOVI r1, 5").unwrap();
+    repl.eval("MOVI r2, 7").unwrap();
+    repl.eval("ADD r3, r1, r2").unwrap();
+    assert_eq!(repl.vm().registers[3], 12);
+  }
+
+  #[test]
+  fn labels_resolve_to_addresses() {
+    let mut repl = Repl::new();
+    repl.eval(":label buffer 0x8000").unwrap();
+    repl.eval("MOVI r4, buffer").unwrap();
+    assert_eq!(repl.vm().registers[4], 0x8000);
+
+    repl.eval("MOVI r5, 99").unwrap();
+    repl.eval("STOREI r5, [buffer]").unwrap();
+    assert_eq!(repl.vm().heap[0x8000], 99);
+  }
+
+  #[test]
+  fn halt_is_reported_and_cleared() {
+    let mut repl = Repl::new();
+    assert_eq!(repl.eval("HALT").unwrap(), "halted\n");
+    repl.eval("MOVI r1, 1").unwrap();
+    assert_eq!(repl.vm().registers[1], 1);
+  }
+
+  #[test]
+  fn unknown_label_is_an_error() {
+    let mut repl = Repl::new();
+    assert!(repl.eval("JMP nowhere").unwrap_err().contains("nowhere"));
+  }
+
+  #[test]
+  fn dumps_memory() {
+    let mut repl = Repl::new();
+    let dump = repl.eval(":mem 0 32").unwrap();
+    assert_eq!(dump.lines().count(), 2);
+    assert!(repl.eval(":mem 0xFFFFFF").is_err());
+  }
+}
@@ -0,0 +1,94 @@
+//! JavaScript bindings for the browser playground.
+//!
+//! Everything here works on in-memory buffers only; object files go in and out as byte arrays
+//! and nothing touches the filesystem, so the module builds for `wasm32-unknown-unknown`.
+use wasm_bindgen::prelude::*;
+use leaf_common::disassembler;
+use leaf_common::leaf_file::{LeafAsmFile, LeafAsmObject};
+use leaf_common::{ReadableResource, WriteableResource};
+use crate::linker::linker::link;
+use crate::{assemble_source, make_header};
+
+/// Assemble `source` and return the bytes of the resulting `.leafobj` file.
+#[wasm_bindgen]
+pub fn assemble(source: &str) -> Result<Vec<u8>, JsError> {
+  assemble_bytes(source).map_err(|e| JsError::new(&e))
+}
+
+/// Disassemble the `.text` section of a `.leafobj` or `.leafexe` file.
+#[wasm_bindgen]
+pub fn disassemble(bytes: &[u8]) -> Result<String, JsError> {
+  disassemble_bytes(bytes).map_err(|e| JsError::new(&e))
+}
+
+/// Collects object files handed over from JavaScript and links them into an executable.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Linker {
+  objects: Vec<LeafAsmObject>,
+  /// Newest ISA revision any of the objects needs.
+  isa_version: u16,
+}
+
+#[wasm_bindgen]
+impl Linker {
+  #[wasm_bindgen(constructor)]
+  pub fn new() -> Linker {
+    Linker::default()
+  }
+
+  /// Add the bytes of a `.leafobj` file to the link.
+  #[wasm_bindgen(js_name = addObject)]
+  pub fn add_object(&mut self, bytes: &[u8]) -> Result<(), JsError> {
+    let file = decode(bytes).map_err(|e| JsError::new(&e))?;
+    self.isa_version = self.isa_version.max(file.header.required_isa_version());
+    self.objects.push(file.object);
+    Ok(())
+  }
+
+  /// Link every object added so far, returning the bytes of the `.leafexe` file.
+  pub fn link(&self, entry: Option<String>) -> Result<Vec<u8>, JsError> {
+    link_bytes(&self.objects, self.isa_version, entry.as_deref().unwrap_or("main")).map_err(|e| JsError::new(&e))
+  }
+}
+
+fn assemble_bytes(source: &str) -> Result<Vec<u8>, String> {
+  let mut diagnostics = Vec::new();
+  match assemble_source(source, None, &mut diagnostics) {
+    Some(file) => encode(&file),
+    None => Err(diagnostics.iter().map(|d| d.render_human(Some(source))).collect()),
+  }
+}
+
+fn link_bytes(objects: &[LeafAsmObject], isa_version: u16, entry: &str) -> Result<Vec<u8>, String> {
+  let object = link(objects, entry).map_err(|d| d.to_string())?;
+  encode(&LeafAsmFile { header: make_header(isa_version.max(1)), object })
+}
+
+fn disassemble_bytes(bytes: &[u8]) -> Result<String, String> {
+  Ok(disassembler::disassemble(&decode(bytes)?.object.bytecode))
+}
+
+fn encode(file: &LeafAsmFile) -> Result<Vec<u8>, String> {
+  let mut out = Vec::new();
+  file.write_to(&mut out).map_err(|e| e.to_string())?;
+  Ok(out)
+}
+
+fn decode(mut bytes: &[u8]) -> Result<LeafAsmFile, String> {
+  LeafAsmFile::read_from(&mut bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn assembles_links_and_disassembles_in_memory() {
+    let obj = assemble_bytes(".text\nmain:\n  MOVI r1, 7\n  HALT\n").unwrap();
+    let exe = link_bytes(&[decode(&obj).unwrap().object], 1, "main").unwrap();
+    let listing = disassemble_bytes(&exe).unwrap();
+    assert!(listing.contains("MOVI r1, 7"));
+    assert!(listing.contains("HALT"));
+  }
+}
@@ -0,0 +1,144 @@
+//! Fluent construction of assembly programs, so code generators can build a `Vec<Line>` directly
+//! instead of formatting source text and parsing it again.
+//!
+//! ```
+//! use leaf_common::builder::{imm, reg, Program};
+//! use leaf_common::leaf_ast::OpCode::*;
+//!
+//! let program = Program::new()
+//!   .section(".text")
+//!   .label("main")
+//!   .instr(Add, [reg(1), reg(2), imm(3)])
+//!   .instr(Halt, []);
+//! assert_eq!(program.to_string(), ".text\nmain:\nADD r1, r2, 3\nHALT\n");
+//! ```
+use std::fmt;
+use crate::leaf_ast::{to_source, Arg, Directive, Instruction, Line, OpCode};
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Program {
+  lines: Vec<Line<'static>>,
+}
+
+impl Program {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Switch to a section, e.g. `.text`, `.data` or `.rodata`.
+  pub fn section(mut self, name: &str) -> Self {
+    self.lines.push(Line::Section(name.to_string().into()));
+    self
+  }
+
+  /// Define a label at the current position.
+  pub fn label(mut self, name: &str) -> Self {
+    self.lines.push(Line::LabelOnly(name.to_string().into()));
+    self
+  }
+
+  pub fn instr(mut self, opcode: OpCode, args: impl IntoIterator<Item = Arg<'static>>) -> Self {
+    self.lines.push(Line::Instruction(Instruction {
+      label: None,
+      opcode,
+      args: args.into_iter().collect(),
+    }));
+    self
+  }
+
+  /// Emit a directive such as `.word 1 2 3`; `args` is the raw argument text.
+  pub fn directive(mut self, name: &str, args: Option<&str>) -> Self {
+    self.lines.push(Line::Directive(Directive {
+      name: name.to_string().into(),
+      args: args.map(|a| a.to_string().into()),
+    }));
+    self
+  }
+
+  pub fn global(mut self, name: &str) -> Self {
+    self.lines.push(Line::Global(vec![name.to_string().into()]));
+    self
+  }
+
+  pub fn external(mut self, name: &str) -> Self {
+    self.lines.push(Line::Extern(vec![name.to_string().into()]));
+    self
+  }
+
+  /// Append an already constructed line.
+  pub fn line(mut self, line: Line<'_>) -> Self {
+    self.lines.push(line.into_owned());
+    self
+  }
+
+  pub fn lines(&self) -> &[Line<'static>] {
+    &self.lines
+  }
+
+  pub fn into_lines(self) -> Vec<Line<'static>> {
+    self.lines
+  }
+}
+
+impl fmt::Display for Program {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&to_source(&self.lines))
+  }
+}
+
+/// Register operand `rN`.
+pub fn reg(n: u8) -> Arg<'static> {
+  Arg::Register(format!("r{}", n).into())
+}
+
+/// Immediate operand.
+pub fn imm(value: i32) -> Arg<'static> {
+  Arg::Immediate(value)
+}
+
+/// Label/symbol operand, resolved by the assembler or linker.
+pub fn sym(name: &str) -> Arg<'static> {
+  Arg::Label(name.to_string().into())
+}
+
+/// Register plus offset `rN+offset`, written `mem(reg_offset(n, offset))` for the address
+/// `[rN+offset]`.
+pub fn reg_offset(n: u8, offset: i32) -> Arg<'static> {
+  Arg::RegisterOffset(format!("r{}", n).into(), offset)
+}
+
+/// Scaled index `rBase+rIndex*scale`, written `mem(indexed(base, index, scale))` for the address
+/// `[rBase+rIndex*scale]`.
+pub fn indexed(base: u8, index: u8, scale: i32) -> Arg<'static> {
+  Arg::Indexed(format!("r{}", base).into(), format!("r{}", index).into(), scale)
+}
+
+/// Memory operand `[inner]`.
+pub fn mem(inner: Arg<'static>) -> Arg<'static> {
+  Arg::Mem(Box::new(inner))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::leaf_ast::OpCode::*;
+
+  #[test]
+  fn builds_lines_in_order() {
+    let program = Program::new()
+      .section(".data")
+      .label("value")
+      .directive("word", Some("42"))
+      .section(".text")
+      .label("main")
+      .instr(Loadi, [reg(1), mem(sym("value"))])
+      .instr(Halt, []);
+
+    assert_eq!(program.lines().len(), 7);
+    assert_eq!(program.lines()[6], Line::Instruction(Instruction { label: None, opcode: Halt, args: vec![] }));
+    assert_eq!(
+      program.to_string(),
+      ".data\nvalue:\n.word 42\n.text\nmain:\nLOADI r1, [value]\nHALT\n"
+    );
+  }
+}
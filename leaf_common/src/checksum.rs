@@ -0,0 +1,211 @@
+//! Checksums for the object file header. CRC32 catches accidental corruption and is what older
+//! files carry; CRC32C and XXH64 are stronger checks that are just as cheap, and SHA-256 also
+//! guards binaries that are handed around against deliberate tampering with their contents.
+use std::fmt;
+use std::str::FromStr;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum ChecksumAlgorithm {
+  #[default]
+  Crc32,
+  Crc32c,
+  XxHash64,
+  Sha256,
+}
+
+/// A checksum together with the algorithm that produced it.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Checksum {
+  Crc32(u32),
+  Crc32c(u32),
+  XxHash64(u64),
+  Sha256([u8; 32]),
+}
+
+impl ChecksumAlgorithm {
+  pub const ALL: [ChecksumAlgorithm; 4] =
+    [ChecksumAlgorithm::Crc32, ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::XxHash64, ChecksumAlgorithm::Sha256];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      ChecksumAlgorithm::Crc32 => "crc32",
+      ChecksumAlgorithm::Crc32c => "crc32c",
+      ChecksumAlgorithm::XxHash64 => "xxhash64",
+      ChecksumAlgorithm::Sha256 => "sha256",
+    }
+  }
+
+  pub fn compute(self, bytes: &[u8]) -> Checksum {
+    match self {
+      ChecksumAlgorithm::Crc32 => Checksum::Crc32(crc32fast::hash(bytes)),
+      ChecksumAlgorithm::Crc32c => Checksum::Crc32c(crc32c(bytes)),
+      ChecksumAlgorithm::XxHash64 => Checksum::XxHash64(xxhash64(bytes, 0)),
+      ChecksumAlgorithm::Sha256 => Checksum::Sha256(Sha256::digest(bytes).into()),
+    }
+  }
+
+  /// The placeholder a header holds while its checksum is computed.
+  pub fn zero(self) -> Checksum {
+    match self {
+      ChecksumAlgorithm::Crc32 => Checksum::Crc32(0),
+      ChecksumAlgorithm::Crc32c => Checksum::Crc32c(0),
+      ChecksumAlgorithm::XxHash64 => Checksum::XxHash64(0),
+      ChecksumAlgorithm::Sha256 => Checksum::Sha256([0; 32]),
+    }
+  }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl FromStr for ChecksumAlgorithm {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Self, String> {
+    Self::ALL.into_iter().find(|algorithm| algorithm.name() == name).ok_or_else(|| {
+      let names: Vec<_> = Self::ALL.iter().map(|algorithm| algorithm.name()).collect();
+      format!("unknown checksum algorithm '{}', expected one of {}", name, names.join(", "))
+    })
+  }
+}
+
+impl Checksum {
+  pub fn algorithm(&self) -> ChecksumAlgorithm {
+    match self {
+      Checksum::Crc32(_) => ChecksumAlgorithm::Crc32,
+      Checksum::Crc32c(_) => ChecksumAlgorithm::Crc32c,
+      Checksum::XxHash64(_) => ChecksumAlgorithm::XxHash64,
+      Checksum::Sha256(_) => ChecksumAlgorithm::Sha256,
+    }
+  }
+}
+
+impl Default for Checksum {
+  fn default() -> Self {
+    ChecksumAlgorithm::default().zero()
+  }
+}
+
+impl fmt::Display for Checksum {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:", self.algorithm())?;
+    match self {
+      Checksum::Crc32(crc) | Checksum::Crc32c(crc) => write!(f, "{:08x}", crc),
+      Checksum::XxHash64(hash) => write!(f, "{:016x}", hash),
+      Checksum::Sha256(digest) => digest.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+    }
+  }
+}
+
+/// CRC32C lookup table, for the reflected Castagnoli polynomial.
+const CRC32C_TABLE: [u32; 256] = {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+  !bytes.iter().fold(!0u32, |crc, &byte| CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+  acc.wrapping_add(input.wrapping_mul(PRIME64_2)).rotate_left(31).wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, value: u64) -> u64 {
+  (acc ^ xxh64_round(0, value)).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+  u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// XXH64 of `bytes`, as specified at https://github.com/Cyan4973/xxHash.
+pub fn xxhash64(bytes: &[u8], seed: u64) -> u64 {
+  let stripes = bytes.chunks_exact(32);
+  let mut tail = stripes.remainder();
+  let mut hash = if bytes.len() >= 32 {
+    let mut acc = [
+      seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+      seed.wrapping_add(PRIME64_2),
+      seed,
+      seed.wrapping_sub(PRIME64_1),
+    ];
+    for stripe in stripes {
+      for (lane, acc) in acc.iter_mut().enumerate() {
+        *acc = xxh64_round(*acc, read_u64(&stripe[lane * 8..]));
+      }
+    }
+    let hash = acc[0].rotate_left(1)
+      .wrapping_add(acc[1].rotate_left(7))
+      .wrapping_add(acc[2].rotate_left(12))
+      .wrapping_add(acc[3].rotate_left(18));
+    acc.iter().fold(hash, |hash, &acc| xxh64_merge(hash, acc))
+  } else {
+    seed.wrapping_add(PRIME64_5)
+  };
+  hash = hash.wrapping_add(bytes.len() as u64);
+
+  while tail.len() >= 8 {
+    hash ^= xxh64_round(0, read_u64(tail));
+    hash = hash.rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+    tail = &tail[8..];
+  }
+  if tail.len() >= 4 {
+    hash ^= (u32::from_le_bytes(tail[..4].try_into().unwrap()) as u64).wrapping_mul(PRIME64_1);
+    hash = hash.rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+    tail = &tail[4..];
+  }
+  for &byte in tail {
+    hash ^= (byte as u64).wrapping_mul(PRIME64_5);
+    hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+  }
+
+  hash ^= hash >> 33;
+  hash = hash.wrapping_mul(PRIME64_2);
+  hash ^= hash >> 29;
+  hash = hash.wrapping_mul(PRIME64_3);
+  hash ^ (hash >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn algorithms_match_their_reference_values() {
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    assert_eq!(xxhash64(b"", 0), 0xEF46_DB37_51D8_E999);
+    assert_eq!(xxhash64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+    assert_eq!(xxhash64(b"Nobody inspects the spammish repetition", 0), 0xFBCE_A83C_8A37_8BF1);
+    assert_eq!(
+      ChecksumAlgorithm::Sha256.compute(b"abc").to_string(),
+      "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    );
+    assert_eq!("xxhash64".parse(), Ok(ChecksumAlgorithm::XxHash64));
+    assert!("md5".parse::<ChecksumAlgorithm>().is_err());
+  }
+}
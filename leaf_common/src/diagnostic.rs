@@ -0,0 +1,215 @@
+//! Diagnostics shared by the parser, assembler and linker, with human (rustc-style) and JSON renderers.
+use std::fmt;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Error,
+  Warning,
+  Note,
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Severity::Error => "error",
+      Severity::Warning => "warning",
+      Severity::Note => "note",
+    })
+  }
+}
+
+/// A location in a source file. Lines and columns are 1-based.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Span {
+  pub file: Option<String>,
+  pub line: usize,
+  pub column: usize,
+  /// Number of characters covered, used for the `^^^` underline.
+  pub length: usize,
+}
+
+impl Span {
+  pub fn new(line: usize, column: usize, length: usize) -> Self {
+    Self { file: None, line, column, length }
+  }
+
+  pub fn in_file(mut self, file: Option<&str>) -> Self {
+    self.file = file.map(str::to_string);
+    self
+  }
+}
+
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}:{}", self.file.as_deref().unwrap_or("<input>"), self.line, self.column)
+  }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  /// Stable, machine-readable identifier such as `unresolved-symbol`.
+  pub code: &'static str,
+  pub message: String,
+  pub span: Option<Span>,
+  pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+  pub fn new(severity: Severity, code: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      severity,
+      code,
+      message: message.into(),
+      span: None,
+      notes: Vec::new(),
+    }
+  }
+
+  pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+    Self::new(Severity::Error, code, message)
+  }
+
+  pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+    Self::new(Severity::Warning, code, message)
+  }
+
+  pub fn with_span(mut self, span: Option<Span>) -> Self {
+    self.span = span;
+    self
+  }
+
+  pub fn with_note(mut self, note: impl Into<String>) -> Self {
+    self.notes.push(note.into());
+    self
+  }
+
+  pub fn is_error(&self) -> bool {
+    self.severity == Severity::Error
+  }
+
+  /// Render in rustc style. When `source` is the text the span points into, the offending line is
+  /// quoted and underlined.
+  pub fn render_human(&self, source: Option<&str>) -> String {
+    let mut out = format!("{}[{}]: {}\n", self.severity, self.code, self.message);
+    if let Some(span) = &self.span {
+      let gutter = " ".repeat(span.line.to_string().len());
+      out.push_str(&format!("{}--> {}\n", gutter, span));
+      if let Some(text) = source.and_then(|s| s.lines().nth(span.line.saturating_sub(1))) {
+        let underline = format!(
+          "{}{}",
+          " ".repeat(span.column.saturating_sub(1)),
+          "^".repeat(span.length.max(1))
+        );
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", span.line, text));
+        out.push_str(&format!("{} | {}\n", gutter, underline));
+      }
+    }
+    for note in &self.notes {
+      out.push_str(&format!("  = note: {}\n", note));
+    }
+    out
+  }
+
+  /// Render as a single-line JSON object.
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("diagnostics always serialize")
+  }
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+    if let Some(span) = &self.span {
+      write!(f, " at {}", span)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// Turn every warning into an error, as `-Werror` does. Returns whether there were any.
+pub fn deny_warnings(diagnostics: &mut [Diagnostic]) -> bool {
+  let mut denied = false;
+  for diagnostic in diagnostics.iter_mut().filter(|d| d.severity == Severity::Warning) {
+    diagnostic.severity = Severity::Error;
+    denied = true;
+  }
+  denied
+}
+
+/// The candidate closest to `name` by edit distance, if it is close enough to be a likely typo.
+pub fn nearest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+  let limit = (name.chars().count() / 3).max(1);
+  candidates.into_iter()
+    .map(|candidate| (edit_distance(name, candidate), candidate))
+    .filter(|(distance, _)| *distance <= limit)
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance, counting a swap of two adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let a: Vec<char> = a.chars().collect();
+  let b: Vec<char> = b.chars().collect();
+  let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+  for i in 1..=a.len() {
+    let mut row = vec![i; b.len() + 1];
+    for j in 1..=b.len() {
+      let cost = usize::from(a[i - 1] != b[j - 1]);
+      row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+      if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+        row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+      }
+    }
+    rows.push(row);
+  }
+  rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn renders_caret_under_span() {
+    let diagnostic = Diagnostic::error("unknown-symbol", "Unknown symbol 'foo'")
+      .with_span(Some(Span::new(2, 5, 3).in_file(Some("main.leaf"))))
+      .with_note("declare it with .extern if it is defined elsewhere");
+    let rendered = diagnostic.render_human(Some("main:\nJMP foo\n"));
+    assert_eq!(
+      rendered,
+      "error[unknown-symbol]: Unknown symbol 'foo'\n \
+       --> main.leaf:2:5\n  \
+       |\n\
+       2 | JMP foo\n  \
+       |     ^^^\n  \
+       = note: declare it with .extern if it is defined elsewhere\n"
+    );
+  }
+
+  #[test]
+  fn renders_json() {
+    let diagnostic = Diagnostic::warning("unused-label", "Label 'x' is never used");
+    assert_eq!(
+      diagnostic.to_json(),
+      r#"{"severity":"warning","code":"unused-label","message":"Label 'x' is never used","span":null,"notes":[]}"#
+    );
+  }
+
+  #[test]
+  fn suggests_the_nearest_name() {
+    let known = ["word", "string", "ascii", "extern"];
+    assert_eq!(nearest("wrod", known), Some("word"));
+    assert_eq!(nearest("strng", known), Some("string"));
+    assert_eq!(nearest("bss", known), None);
+
+    let mut diagnostics = vec![Diagnostic::warning("unused-label", "Label 'x' is never used")];
+    assert!(deny_warnings(&mut diagnostics));
+    assert!(diagnostics[0].is_error());
+  }
+}
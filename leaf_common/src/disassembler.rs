@@ -1,2 +1,325 @@
-// use crate::leaf_file::LeafAsmFile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::isa::OperandKind;
+use crate::leaf_ast::{Arg, Directive, Instruction, Line, OpCode};
+use crate::leaf_file::{LeafAsmObject, RelocationType, SymbolEntry};
+use crate::opcode::AddressOperand;
+use crate::symbolicate::symbolicate;
 
+/// Render a listing of `code`, one instruction per line: offset, raw bytes and decoded text.
+pub fn disassemble(code: &[u8]) -> String {
+  let mut out = String::new();
+  let mut pc = 0usize;
+
+  while pc < code.len() {
+    pc += listing_line(code, pc, &mut out);
+  }
+  out
+}
+
+/// Like `disassemble`, for the `.text` of `object`, but each code symbol is written above the
+/// instruction it labels and, where the line table has a new source line, that line is written
+/// above the instructions assembled from it, as `objdump -S` does. `read` returns the contents of
+/// a source file named in the line table; a file it cannot find is shown by name and line only.
+pub fn disassemble_with_source(object: &LeafAsmObject, mut read: impl FnMut(&str) -> Option<String>) -> String {
+  let code = &object.bytecode;
+  let mut sources: HashMap<&str, Option<String>> = HashMap::new();
+  let mut out = String::new();
+  let mut pc = 0usize;
+  let mut last_line = None;
+
+  while pc < code.len() {
+    for symbol in object.symbols.iter().filter(|s| !s.external && s.section == 0 && s.offset == pc as u32) {
+      out.push_str(&format!("{}:\n", symbol.name));
+    }
+    let location = symbolicate(object, pc as u32);
+    if let (Some(file), Some(line)) = (location.file, location.line)
+      && last_line != Some((file, line))
+    {
+      last_line = Some((file, line));
+      let source = sources.entry(file).or_insert_with(|| read(file));
+      match source.as_deref().and_then(|text| text.lines().nth((line as usize).checked_sub(1)?)) {
+        Some(text) => out.push_str(&format!("; {}:{}: {}\n", file, line, text.trim())),
+        None => out.push_str(&format!("; {}:{}\n", file, line)),
+      }
+    }
+    pc += listing_line(code, pc, &mut out);
+  }
+  out
+}
+
+/// Write the listing line for the instruction at `pc`, returning its length.
+fn listing_line(code: &[u8], pc: usize, out: &mut String) -> usize {
+  let (disasm, instr_len) = disassemble_at(code, pc);
+  let instr_bytes: Vec<String> = code[pc..pc + instr_len.min(code.len() - pc)]
+    .iter()
+    .map(|b| format!("{:02X}", b))
+    .collect();
+
+  out.push_str(&format!("0x{:04X} | {:<40} | {}\n", pc, instr_bytes.join(" "), disasm));
+  instr_len
+}
+
+/// Decode the instruction at `pc`, returning its text and encoded length in bytes.
+pub fn disassemble_at(code: &[u8], pc: usize) -> (String, usize) {
+  if pc >= code.len() {
+    return ("<invalid PC>".to_string(), 1);
+  }
+
+  let Some(info) = OpCode::decode(code[pc]) else {
+    return ("<invalid>".to_string(), 1);
+  };
+  match decode_at(code, pc) {
+    Some((instruction, size)) => (instruction.to_string(), size),
+    None => (format!("{} <truncated>", info.opcode), code.len() - pc),
+  }
+}
+
+/// Decode the instruction at `pc` into the AST, with its encoded length in bytes. `None` for a
+/// byte that is no opcode and for an instruction cut off by the end of `code`.
+pub fn decode_at(code: &[u8], pc: usize) -> Option<(Instruction<'static>, usize)> {
+  let info = OpCode::decode(*code.get(pc)?)?;
+  let size = info.size();
+  let operands = code.get(pc + 1..pc + size)?;
+  // Operand i: register index (first byte) or 32-bit little-endian value, except that the address
+  // of LOAD and STORE may also have an offset
+  let args = info.opcode.operand_kinds().into_iter().zip(operands.chunks_exact(4)).enumerate()
+    .map(|(index, (kind, bytes))| {
+      let bytes: [u8; 4] = bytes.try_into().unwrap();
+      let arg = match kind {
+        OperandKind::Register if index == 1 && matches!(info.opcode, OpCode::Load | OpCode::Store) => address(AddressOperand::decode(bytes)),
+        OperandKind::Register => Arg::Register(format!("r{}", bytes[0]).into()),
+        OperandKind::Immediate => Arg::Immediate(i32::from_le_bytes(bytes)),
+      };
+      let address = index == 1 && matches!(info.opcode, OpCode::Load | OpCode::Store | OpCode::Loadi | OpCode::Storei);
+      if address { Arg::Mem(Box::new(arg)) } else { arg }
+    })
+    .collect();
+  Some((Instruction { label: None, opcode: info.opcode, args }, size))
+}
+
+/// The address operand of `LOAD` or `STORE` as it is written inside the brackets. An index and an
+/// offset together cannot be written, so the offset is left out then.
+fn address(operand: AddressOperand) -> Arg<'static> {
+  let register = |n: u8| format!("r{}", n).into();
+  match operand {
+    AddressOperand { base, index: Some((index, scale)), .. } => Arg::Indexed(register(base), register(index), scale.into()),
+    AddressOperand { base, offset: 0, .. } => Arg::Register(register(base)),
+    AddressOperand { base, offset, .. } => Arg::RegisterOffset(register(base), offset.into()),
+  }
+}
+
+/// `code` as a `.text` program. Code symbols in `symbols` become labels, and jumps and calls to a
+/// labelled address name the label instead of the address. A byte that does not decode becomes
+/// an `INVALID` instruction of its own.
+pub fn disassemble_lines(code: &[u8], symbols: &[SymbolEntry]) -> Vec<Line<'static>> {
+  let labels = |offset: usize| symbols.iter().filter(move |s| !s.external && s.section == 0 && s.offset as usize == offset);
+  let mut lines = vec![Line::Section(".text".into())];
+  let mut pc = 0;
+  while pc < code.len() {
+    lines.extend(labels(pc).map(|symbol| Line::LabelOnly(symbol.name.clone().into())));
+    let (mut instruction, size) = decode_at(code, pc)
+      .unwrap_or_else(|| (Instruction { label: None, opcode: OpCode::Invalid, args: Vec::new() }, 1));
+    if let Some(index) = instruction.opcode.branch_operand()
+      && let Some(&Arg::Immediate(target)) = instruction.args.get(index)
+      && let Some(symbol) = labels(target as u32 as usize).next()
+    {
+      instruction.args[index] = Arg::Label(symbol.name.clone().into());
+    }
+    lines.push(Line::Instruction(instruction));
+    pc += size;
+  }
+  lines.extend(labels(code.len()).map(|symbol| Line::LabelOnly(symbol.name.clone().into())));
+  lines
+}
+
+/// `object` as a program that assembles back to the same sections. Unlike `disassemble_lines`,
+/// operands keep their encoded values, except that a relocated operand the linker has not patched
+/// yet names its symbol, so the relocation comes back too. The data ranges of `.text`, bytes that
+/// do not decode and the other sections become `.ascii` directives, every defined symbol becomes
+/// a label and every other one an `.extern`. Names the parser would not read back are left out,
+/// and so are relocations against a section rather than a symbol, such as the ones for the labels
+/// `.if` generates, whose operands keep their encoded values.
+pub fn disassemble_object(object: &LeafAsmObject) -> Vec<Line<'static>> {
+  let mut lines = Vec::new();
+  let mut defined = HashSet::new();
+  let mut labels: [BTreeMap<u32, Vec<&str>>; 3] = Default::default();
+  for symbol in object.symbols.iter().filter(|s| !s.external && is_identifier(&s.name)) {
+    if let Some(section) = labels.get_mut(symbol.section as usize) && defined.insert(symbol.name.as_str()) {
+      section.entry(symbol.offset).or_default().push(&symbol.name);
+    }
+  }
+  for symbol in object.symbols.iter().filter(|s| s.external && is_identifier(&s.name)) {
+    if defined.insert(symbol.name.as_str()) {
+      lines.push(Line::Extern(vec![symbol.name.clone().into()]));
+    }
+  }
+  let relocated: HashMap<u32, (&str, RelocationType, i32)> = object.relocations.iter().enumerate()
+    .filter(|(index, reloc)| reloc.target_section == 0 && object.section_target(*index).is_none())
+    .filter_map(|(index, reloc)| {
+      let name = object.symbols.get(reloc.symbol_index as usize)?.name.as_str();
+      Some((reloc.offset, (name, reloc.reloc_type, object.addend(index))))
+    })
+    .filter(|(_, (name, _, _))| is_identifier(name))
+    .collect();
+
+  let code = &object.bytecode;
+  let [text_labels, data_labels, rodata_labels] = &labels;
+  let label_lines = |labels: &BTreeMap<u32, Vec<&str>>, offset: usize| -> Vec<Line<'static>> {
+    labels.get(&(offset as u32)).into_iter().flatten().map(|name| Line::LabelOnly(name.to_string().into())).collect()
+  };
+  // End of the data range `pc` is in, if it is in one
+  let ranges = &object.data_in_text;
+  let data_end = |pc: usize| ranges[..ranges.partition_point(|range| range.offset as usize <= pc)].last()
+    .map(|range| (range.offset + range.len) as usize)
+    .filter(|&end| pc < end);
+  lines.push(Line::Section(".text".into()));
+  let mut pc = 0;
+  while pc < code.len() {
+    lines.extend(label_lines(text_labels, pc));
+    let range_end = data_end(pc);
+    let decoded = if range_end.is_none() { decode_at(code, pc) } else { None };
+    let Some((mut instruction, size)) = decoded else {
+      // Data up to the end of its range or the next label, or a byte that does not decode
+      let mut end = pc + 1;
+      while end < range_end.unwrap_or(0).min(code.len()) && !text_labels.contains_key(&(end as u32)) {
+        end += 1;
+      }
+      lines.push(ascii(&code[pc..end]));
+      pc = end;
+      continue;
+    };
+    for (index, arg) in instruction.args.iter_mut().enumerate() {
+      let at = pc + 1 + 4 * index;
+      if let Some(&(name, kind, addend)) = relocated.get(&(at as u32)) && code[at..at + 4] == [0; 4] {
+        let label = match (kind.operator(), addend) {
+          (Some(_), _) => Arg::Relocated(kind, name.to_string().into()),
+          (None, 0) => Arg::Label(name.to_string().into()),
+          (None, addend) => Arg::LabelOffset(name.to_string().into(), addend),
+        };
+        *arg = match arg {
+          Arg::Mem(_) => Arg::Mem(Box::new(label)),
+          _ => label,
+        };
+      } else if let Arg::Mem(address) = arg && matches!(**address, Arg::Immediate(_)) {
+        // `[N]` does not parse, and `LOADI`/`STOREI` take a bare address just the same
+        *arg = (**address).clone();
+      }
+    }
+    lines.push(Line::Instruction(instruction));
+    pc += size;
+  }
+  lines.extend(label_lines(text_labels, code.len()));
+
+  for (name, bytes, labels) in [(".data", &object.data, data_labels), (".rodata", &object.rodata, rodata_labels)] {
+    if bytes.is_empty() && labels.is_empty() {
+      continue;
+    }
+    lines.push(Line::Section(name.into()));
+    let mut start = 0;
+    for (&offset, _) in labels.range(..=bytes.len() as u32) {
+      if offset as usize > start {
+        lines.push(ascii(&bytes[start..offset as usize]));
+        start = offset as usize;
+      }
+      lines.extend(label_lines(labels, start));
+    }
+    if start < bytes.len() {
+      lines.push(ascii(&bytes[start..]));
+    }
+  }
+  lines
+}
+
+/// A `.ascii` directive for `bytes`. A byte the escapes do not cover is written as the character
+/// with that code, which the assembler reads back as the byte.
+fn ascii(bytes: &[u8]) -> Line<'static> {
+  let mut text = String::from("\"");
+  for &byte in bytes {
+    match byte {
+      0 => text.push_str("\\0"),
+      b'\n' => text.push_str("\\n"),
+      b'\t' => text.push_str("\\t"),
+      b'\r' => text.push_str("\\r"),
+      b'\\' => text.push_str("\\\\"),
+      b'"' => text.push_str("\\\""),
+      _ => text.push(char::from(byte)),
+    }
+  }
+  text.push('"');
+  Line::Directive(Directive { name: "ascii".into(), args: Some(text.into()) })
+}
+
+/// Whether the parser reads `name` as a label.
+fn is_identifier(name: &str) -> bool {
+  let (base, version, _) = crate::symver::split(name);
+  let word = |text: &str| text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+  base.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+    && word(base)
+    && version.is_none_or(|version| !version.is_empty() && word(version))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn disassembles_each_instruction_once() {
+    // MOVI r1, 42 ; HALT
+    let code = [0x16, 1, 0, 0, 0, 42, 0, 0, 0, 0x13];
+    let listing = disassemble(&code);
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0x0000"));
+    assert!(lines[0].ends_with("MOVI r1, 42"));
+    assert!(lines[1].starts_with("0x0009"));
+    assert!(lines[1].ends_with("HALT"));
+  }
+
+  #[test]
+  fn interleaves_source_lines() {
+    use crate::leaf_file::{DebugInfo, LineEntry};
+    use crate::object_builder::LeafAsmObjectBuilder;
+    // MOVI r1, 42 ; MOVI r2, 1 ; HALT, the MOVIs from one source line
+    let code = vec![0x16, 1, 0, 0, 0, 42, 0, 0, 0, 0x16, 2, 0, 0, 0, 1, 0, 0, 0, 0x13];
+    let mut object = LeafAsmObjectBuilder::new().text(code).define("main", 0, 0).define("done", 0, 18).build().unwrap();
+    object.debug_info = Some(DebugInfo {
+      files: vec!["a.leaf".to_string(), "gone.leaf".to_string()],
+      lines: vec![LineEntry { offset: 0, file: 0, line: 2 }, LineEntry { offset: 18, file: 1, line: 7 }],
+    });
+    let listing = disassemble_with_source(&object, |file| (file == "a.leaf").then(|| "main:\n  SET2 r1, 42\n".to_string()));
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(lines[..2], ["main:", "; a.leaf:2: SET2 r1, 42"]);
+    assert!(lines[2].ends_with("MOVI r1, 42") && lines[3].ends_with("MOVI r2, 1"));
+    assert_eq!(lines[4..6], ["done:", "; gone.leaf:7"]);
+    assert!(lines[6].ends_with("HALT"));
+  }
+
+  #[test]
+  fn decodes_to_lines_with_labels() {
+    use crate::leaf_ast::to_source;
+    // main: MOVI r1, -1 ; CALL done ; LOADI r2, [64] ; done: HALT ; 0xFE
+    let mut code = vec![0x16, 1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 23, 0, 0, 0, 0x17, 2, 0, 0, 0, 64, 0, 0, 0, 0x13];
+    code.push(0xFE);
+    let symbol = |name: &str, offset| SymbolEntry { name: name.to_string(), offset, section: 0, kind: 0, external: false };
+    let lines = disassemble_lines(&code, &[symbol("main", 0), symbol("done", 23), symbol("end", 25)]);
+    assert_eq!(
+      to_source(&lines),
+      ".text\nmain:\nMOVI r1, -1\nCALL done\nLOADI r2, [64]\ndone:\nHALT\nINVALID\nend:\n",
+    );
+  }
+
+  #[test]
+  fn reports_truncated_instructions() {
+    let (text, len) = disassemble_at(&[0x09, 0x01], 0);
+    assert_eq!(text, "JMP <truncated>");
+    assert_eq!(len, 2);
+  }
+
+  #[test]
+  fn decodes_comparisons() {
+    let (text, len) = disassemble_at(&[0x19, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0], 0);
+    assert_eq!(text, "LT r1, r2, r3");
+    assert_eq!(len, 13);
+  }
+}
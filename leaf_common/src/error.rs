@@ -0,0 +1,129 @@
+//! The error type returned by the toolchain's library APIs, so consumers can match on what went
+//! wrong instead of inspecting strings.
+use std::fmt;
+use crate::checksum::Checksum;
+use crate::diagnostic::Diagnostic;
+use crate::object_builder::{ObjectError, ValidationReport};
+use crate::opcode::ISA_VERSION;
+
+#[derive(Debug)]
+pub enum LeafError {
+  /// Source text (assembly or Leaf) could not be parsed.
+  Parse(Box<Diagnostic>),
+  /// An object could not be serialized.
+  Encode(bincode::error::EncodeError),
+  /// Objects could not be linked together.
+  Link(Box<Diagnostic>),
+  Io(std::io::Error),
+  /// Bytes or an object that do not form a valid Leaf file.
+  Format(FormatError),
+  /// A program does not fit the VM's configured memory layout.
+  Layout(String),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum FormatError {
+  /// The bytes do not decode as a Leaf file (or linker script).
+  Decode(String),
+  BadMagic([u8; 4]),
+  UnsupportedVersion(u16),
+  /// The object needs a newer ISA revision than this toolchain implements.
+  UnsupportedIsaVersion(u16),
+  /// A length in the file is over its `DecodeLimits` limit.
+  TooLarge { what: &'static str, len: u64, limit: usize },
+  /// The file decoded but its contents are inconsistent.
+  Object(ObjectError),
+  /// The file decoded but failed `LeafAsmObject::check`.
+  Invalid(ValidationReport),
+  /// The header's checksum does not match the file's contents.
+  ChecksumMismatch { expected: Checksum, computed: Checksum },
+}
+
+impl fmt::Display for LeafError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LeafError::Parse(diagnostic) | LeafError::Link(diagnostic) => write!(f, "{}", diagnostic),
+      LeafError::Encode(e) => write!(f, "failed to encode object: {}", e),
+      LeafError::Io(e) => write!(f, "{}", e),
+      LeafError::Format(e) => write!(f, "{}", e),
+      LeafError::Layout(message) => write!(f, "invalid memory layout: {}", message),
+    }
+  }
+}
+
+impl fmt::Display for FormatError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FormatError::Decode(message) => write!(f, "malformed file: {}", message),
+      FormatError::BadMagic(magic) => write!(f, "bad magic number {:02X?}, expected \"LAF\\0\"", magic),
+      FormatError::UnsupportedVersion(version) => write!(f, "unsupported object file version {}", version),
+      FormatError::UnsupportedIsaVersion(version) =>
+        write!(f, "object needs ISA revision {}, but only revisions up to {} are supported", version, ISA_VERSION),
+      FormatError::TooLarge { what, len, limit } => write!(f, "{} has length {}, over the limit of {}", what, len, limit),
+      FormatError::Object(e) => write!(f, "invalid object: {}", e),
+      FormatError::Invalid(report) => write!(f, "invalid object: {}", report),
+      FormatError::ChecksumMismatch { expected, computed } =>
+        write!(f, "checksum mismatch: the header has {} but the contents sum to {}", expected, computed),
+    }
+  }
+}
+
+impl std::error::Error for LeafError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      LeafError::Parse(diagnostic) | LeafError::Link(diagnostic) => Some(diagnostic.as_ref()),
+      LeafError::Encode(e) => Some(e),
+      LeafError::Io(e) => Some(e),
+      LeafError::Format(FormatError::Object(e)) => Some(e),
+      LeafError::Format(FormatError::Invalid(report)) => Some(report),
+      LeafError::Format(_) | LeafError::Layout(_) => None,
+    }
+  }
+}
+
+impl std::error::Error for FormatError {}
+
+impl From<std::io::Error> for LeafError {
+  fn from(e: std::io::Error) -> Self {
+    LeafError::Io(e)
+  }
+}
+
+impl From<bincode::error::EncodeError> for LeafError {
+  fn from(e: bincode::error::EncodeError) -> Self {
+    LeafError::Encode(e)
+  }
+}
+
+impl From<bincode::error::DecodeError> for LeafError {
+  fn from(e: bincode::error::DecodeError) -> Self {
+    LeafError::Format(FormatError::Decode(e.to_string()))
+  }
+}
+
+impl From<FormatError> for LeafError {
+  fn from(e: FormatError) -> Self {
+    LeafError::Format(e)
+  }
+}
+
+impl From<ObjectError> for LeafError {
+  fn from(e: ObjectError) -> Self {
+    LeafError::Format(FormatError::Object(e))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn categories_can_be_matched() {
+    let io: LeafError = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+    assert!(matches!(io, LeafError::Io(ref e) if e.kind() == std::io::ErrorKind::NotFound));
+
+    let invalid: LeafError = ObjectError::InvalidSection(7).into();
+    assert!(matches!(invalid, LeafError::Format(FormatError::Object(ObjectError::InvalidSection(7)))));
+    assert_eq!(invalid.to_string(), "invalid object: invalid section 7");
+  }
+}
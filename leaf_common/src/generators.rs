@@ -0,0 +1,111 @@
+//! `Arbitrary` impls for the object model, behind the `arbitrary` feature. The plain format types
+//! derive theirs; `LeafAsmObject` is generated by hand so that every object passes `validate`,
+//! which lets fuzzers spend their time in the linker rather than on inputs it rejects up front.
+//! Feed raw bytes to `LeafAsmFile::read_from` to fuzz the reader itself.
+use arbitrary::{Arbitrary, Result, Unstructured};
+use crate::leaf_file::{DebugInfo, LeafAsmObject, LineEntry, RelocationEntry, SymbolEntry};
+
+/// Names are drawn from a small pool so that objects in one test often refer to each other.
+const NAMES: &[&str] = &["main", "start", "helper", "loop", "buffer", "message", "table", "exit"];
+
+fn section_bytes(u: &mut Unstructured<'_>) -> Result<Vec<u8>> {
+  let len = u.int_in_range(0..=64)?;
+  (0..len).map(|_| u.arbitrary()).collect()
+}
+
+impl<'a> Arbitrary<'a> for LeafAsmObject {
+  fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+    let mut object = LeafAsmObject {
+      bytecode: section_bytes(u)?,
+      data: section_bytes(u)?,
+      rodata: section_bytes(u)?,
+      ..LeafAsmObject::default()
+    };
+
+    // Each name is either defined once, declared extern, or absent
+    for name in NAMES {
+      match u.int_in_range(0..=2)? {
+        0 => {
+          let section = u.int_in_range(0..=2)?;
+          let len = object.section_len(section).unwrap_or(0) as u32;
+          object.symbols.push(SymbolEntry {
+            name: name.to_string(),
+            offset: u.int_in_range(0..=len)?,
+            section,
+            kind: section,
+            external: false,
+          });
+        }
+        1 => object.symbols.push(SymbolEntry {
+          name: name.to_string(),
+          offset: 0,
+          section: 0,
+          kind: 0,
+          external: true,
+        }),
+        _ => {}
+      }
+    }
+
+    if !object.symbols.is_empty() {
+      for _ in 0..u.int_in_range(0..=8)? {
+        // Only sections with room for a 4-byte field can be patched
+        let section: u8 = u.int_in_range(0..=2)?;
+        let len = object.section_len(section).unwrap_or(0) as u32;
+        if len < 4 {
+          continue;
+        }
+        // Some refer to a section rather than a symbol, as for local labels
+        let symbol_index = match u.arbitrary()? {
+          true => {
+            object.section_relocations.push(object.relocations.len() as u32);
+            u.int_in_range(0..=2)?
+          }
+          false => u.choose_index(object.symbols.len())? as u32,
+        };
+        object.relocations.push(RelocationEntry {
+          offset: u.int_in_range(0..=len - 4)?,
+          symbol_index,
+          reloc_type: u.arbitrary()?,
+          target_section: section,
+        });
+      }
+    }
+
+    let defined: Vec<&SymbolEntry> = object.symbols.iter().filter(|s| !s.external).collect();
+    if !defined.is_empty() && u.arbitrary()? {
+      object.entry_point = Some(u.choose(&defined)?.name.clone());
+    }
+
+    if u.arbitrary()? {
+      let mut lines = Vec::new();
+      let mut offset = 0;
+      while offset < object.bytecode.len() as u32 && u.arbitrary()? {
+        lines.push(LineEntry { offset, file: 0, line: u.int_in_range(1..=10_000)? });
+        offset += u.int_in_range(1..=13)?;
+      }
+      object.debug_info = Some(DebugInfo { files: vec!["fuzz.lasm".to_string()], lines });
+    }
+    Ok(object)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::leaf_file::LeafAsmFile;
+  use crate::{ReadableResource, WriteableResource};
+
+  #[test]
+  fn generated_objects_are_valid_and_round_trip() {
+    let seed: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let mut u = Unstructured::new(&seed);
+    for _ in 0..32 {
+      let file = LeafAsmFile::arbitrary(&mut u).unwrap();
+      file.object.validate().unwrap();
+      let mut buffer = Vec::new();
+      file.write_to(&mut buffer).unwrap();
+      assert_eq!(LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap().object, file.object);
+    }
+  }
+}
@@ -0,0 +1,70 @@
+//! String interning for label and symbol names: each distinct name is stored once and handed out
+//! as a `Symbol` id that is cheap to copy, hash and compare.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+  pub fn index(self) -> usize {
+    self.0 as usize
+  }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Interner {
+  ids: HashMap<Arc<str>, Symbol>,
+  names: Vec<Arc<str>>,
+}
+
+impl Interner {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn intern(&mut self, name: &str) -> Symbol {
+    if let Some(symbol) = self.ids.get(name) {
+      return *symbol;
+    }
+    let symbol = Symbol(self.names.len() as u32);
+    let name: Arc<str> = Arc::from(name);
+    self.names.push(name.clone());
+    self.ids.insert(name, symbol);
+    symbol
+  }
+
+  /// Look up a name without adding it.
+  pub fn get(&self, name: &str) -> Option<Symbol> {
+    self.ids.get(name).copied()
+  }
+
+  pub fn resolve(&self, symbol: Symbol) -> &str {
+    &self.names[symbol.index()]
+  }
+
+  pub fn len(&self) -> usize {
+    self.names.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn interns_each_name_once() {
+    let mut interner = Interner::new();
+    let main = interner.intern("main");
+    let helper = interner.intern("helper");
+    assert_eq!(interner.intern("main"), main);
+    assert_ne!(main, helper);
+    assert_eq!(interner.len(), 2);
+    assert_eq!(interner.resolve(helper), "helper");
+    assert_eq!(interner.get("missing"), None);
+  }
+}
@@ -0,0 +1,170 @@
+//! Experimental instructions described in a TOML or JSON file instead of `OPCODES`, so they can
+//! be tried out in the assembler and disassembler without touching `OpCode`. An installed
+//! instruction is `OpCode::Ext(byte)` and is looked up like a built-in one by byte or mnemonic.
+//! The VM decodes extension instructions but has no semantics for them, so running one faults.
+//!
+//! ```toml
+//! [[instruction]]
+//! mnemonic = "SQRT"
+//! byte = 0x40
+//! operands = ["reg", "reg"]
+//! ```
+use std::fmt;
+use std::io::Read;
+use std::sync::RwLock;
+use serde::Deserialize;
+use crate::error::{FormatError, LeafError};
+use crate::opcode::{OpCode, OpInfo, ISA_VERSION, OPCODES};
+use crate::ReadableResource;
+
+/// Most operands an extension instruction can take, as for the built-in three-register ops.
+pub const MAX_OPERANDS: usize = 3;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize)]
+pub enum OperandKind {
+  /// Encoded as `[reg, 0, 0, 0]`.
+  #[serde(rename = "reg")]
+  Register,
+  /// An immediate or label, as a 4-byte little-endian word.
+  #[serde(rename = "imm")]
+  Immediate,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+pub struct InstructionDef {
+  pub mnemonic: String,
+  pub byte: u8,
+  #[serde(default)]
+  pub operands: Vec<OperandKind>,
+}
+
+/// A set of extension instructions, as read from an ISA description file.
+#[derive(Debug, Eq, PartialEq, Clone, Default, Deserialize)]
+pub struct IsaExtension {
+  #[serde(rename = "instruction", default)]
+  pub instructions: Vec<InstructionDef>,
+}
+
+/// An installed extension instruction.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Extension {
+  pub info: OpInfo,
+  pub operands: Vec<OperandKind>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum IsaError {
+  /// Mnemonics are upper case letters, digits and `_`, starting with a letter.
+  BadMnemonic(String),
+  MnemonicInUse(String),
+  ByteInUse { mnemonic: String, byte: u8 },
+  TooManyOperands { mnemonic: String, operands: usize },
+}
+
+impl fmt::Display for IsaError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      IsaError::BadMnemonic(mnemonic) => write!(f, "'{}' is not a valid mnemonic", mnemonic),
+      IsaError::MnemonicInUse(mnemonic) => write!(f, "{} is already an instruction", mnemonic),
+      IsaError::ByteInUse { mnemonic, byte } => write!(f, "byte 0x{:02X} for {} is already an opcode", byte, mnemonic),
+      IsaError::TooManyOperands { mnemonic, operands } =>
+        write!(f, "{} has {} operands, at most {} are allowed", mnemonic, operands, MAX_OPERANDS),
+    }
+  }
+}
+
+impl std::error::Error for IsaError {}
+
+static EXTENSIONS: RwLock<Vec<&'static Extension>> = RwLock::new(Vec::new());
+
+impl ReadableResource for IsaExtension {
+  /// JSON if the text starts with `{`, TOML otherwise.
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    let parsed = if content.trim_start().starts_with('{') {
+      serde_json::from_str(&content).map_err(|e| e.to_string())
+    } else {
+      toml::from_str(&content).map_err(|e| e.to_string())
+    };
+    parsed.map_err(|e| FormatError::Decode(e).into())
+  }
+}
+
+impl IsaExtension {
+  /// Make these instructions known to the whole process. Nothing is installed unless all of them
+  /// are valid and clash with neither built-in nor already installed instructions.
+  pub fn install(&self) -> Result<(), IsaError> {
+    let mut extensions = EXTENSIONS.write().unwrap_or_else(|e| e.into_inner());
+    for (index, def) in self.instructions.iter().enumerate() {
+      let valid = def.mnemonic.starts_with(|c: char| c.is_ascii_uppercase())
+        && def.mnemonic.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+      if !valid {
+        return Err(IsaError::BadMnemonic(def.mnemonic.clone()));
+      }
+      if def.operands.len() > MAX_OPERANDS {
+        return Err(IsaError::TooManyOperands { mnemonic: def.mnemonic.clone(), operands: def.operands.len() });
+      }
+      // Not through `OpCode`, which would take the lock again to look at installed instructions
+      let earlier = &self.instructions[..index];
+      let mnemonic_in_use = OPCODES.iter().any(|info| info.mnemonic == def.mnemonic)
+        || extensions.iter().any(|ext| ext.info.mnemonic == def.mnemonic)
+        || earlier.iter().any(|other| other.mnemonic == def.mnemonic);
+      if mnemonic_in_use {
+        return Err(IsaError::MnemonicInUse(def.mnemonic.clone()));
+      }
+      let byte_in_use = OPCODES.iter().any(|info| info.byte == def.byte)
+        || extensions.iter().any(|ext| ext.info.byte == def.byte)
+        || earlier.iter().any(|other| other.byte == def.byte);
+      if byte_in_use {
+        return Err(IsaError::ByteInUse { mnemonic: def.mnemonic.clone(), byte: def.byte });
+      }
+    }
+    for def in &self.instructions {
+      // Installed for the life of the process, like the built-in table
+      let mnemonic: &'static str = Box::leak(def.mnemonic.clone().into_boxed_str());
+      let info = OpInfo { opcode: OpCode::Ext(def.byte), byte: def.byte, mnemonic, operands: def.operands.len() as u8, since: ISA_VERSION };
+      extensions.push(Box::leak(Box::new(Extension { info, operands: def.operands.clone() })));
+    }
+    Ok(())
+  }
+}
+
+/// Every installed extension instruction, in the order they were installed.
+pub fn installed() -> Vec<&'static Extension> {
+  EXTENSIONS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The installed extension instruction with this byte.
+pub fn by_byte(byte: u8) -> Option<&'static Extension> {
+  EXTENSIONS.read().unwrap_or_else(|e| e.into_inner()).iter().find(|ext| ext.info.byte == byte).copied()
+}
+
+/// The installed extension instruction with this mnemonic.
+pub fn by_mnemonic(mnemonic: &str) -> Option<&'static Extension> {
+  EXTENSIONS.read().unwrap_or_else(|e| e.into_inner()).iter().find(|ext| ext.info.mnemonic == mnemonic).copied()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::disassembler::disassemble_at;
+
+  #[test]
+  fn installed_instructions_decode_and_disassemble() {
+    let toml = "[[instruction]]\nmnemonic = \"ROTL\"\nbyte = 0xE0\noperands = [\"reg\", \"reg\", \"imm\"]\n";
+    let isa = IsaExtension::read_from(&mut toml.as_bytes()).unwrap();
+    isa.install().unwrap();
+    assert_eq!(OpCode::from_mnemonic("ROTL"), Some(OpCode::Ext(0xE0)));
+    assert_eq!(OpCode::Ext(0xE0).operand_count(), 3);
+    let code = [0xE0, 1, 0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0];
+    assert_eq!(disassemble_at(&code, 0), ("ROTL r1, r2, 7".to_string(), 13));
+
+    let json = r#"{"instruction": [{"mnemonic": "ADD", "byte": 225}]}"#;
+    let clash = IsaExtension::read_from(&mut json.as_bytes()).unwrap();
+    assert_eq!(clash.install(), Err(IsaError::MnemonicInUse("ADD".to_string())));
+    let clash = IsaExtension { instructions: vec![InstructionDef { mnemonic: "ROTR".to_string(), byte: 0x01, operands: vec![] }] };
+    assert_eq!(clash.install(), Err(IsaError::ByteInUse { mnemonic: "ROTR".to_string(), byte: 0x01 }));
+    assert_eq!(OpCode::from_mnemonic("ROTR"), None);
+  }
+}
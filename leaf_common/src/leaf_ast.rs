@@ -1,115 +1,150 @@
-#[derive(Debug, Eq, PartialEq, Clone)]
-pub enum OpCode {
-  Add, Mul, Sub, Div,
-  And, Or, Xor, Not,
-  Lt, Gt, Eq,
-  Jmp, Jz, Jnz,
-  Mov, Load, Store,
-  Movi, Loadi, Storei,
-  Call, Ret,
-  Push, Pop,
-  Halt, Break,
-  Syscall, Nop,
-  Invalid,
-}
+use std::borrow::Cow;
+use std::fmt;
+
+pub use crate::opcode::OpCode;
+use crate::leaf_file::RelocationType;
+
+/// Text in the AST. The parser borrows it from the source, so assembling a large file does not
+/// allocate per token; programs built in code own their strings. `into_owned` detaches a program
+/// from its source.
+pub type Text<'src> = Cow<'src, str>;
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum Arg {
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Arg<'src> {
   Immediate(i32),
-  Register(String),
-  Label(String),
-  Mem(Box<Arg>),
+  Register(Text<'src>),
+  Label(Text<'src>),
+  Mem(Box<Arg<'src>>),
+  /// A label patched with a relocation other than an absolute address, written `%hi(name)`.
+  Relocated(RelocationType, Text<'src>),
+  /// A label plus a constant, written `loop+8` or `table-4`.
+  LabelOffset(Text<'src>, i32),
+  /// A register plus a constant, written `[r1+8]` or `[r7-4]` as the address of `LOAD` or `STORE`.
+  RegisterOffset(Text<'src>, i32),
+  /// A base register plus an index register times a scale of 1, 2, 4 or 8, written `[r1+r2*8]`
+  /// as the address of `LOAD` or `STORE`.
+  Indexed(Text<'src>, Text<'src>, i32),
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Instruction {
-  pub label: Option<String>,
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Instruction<'src> {
+  pub label: Option<Text<'src>>,
   pub opcode: OpCode,
-  pub args: Vec<Arg>,
+  pub args: Vec<Arg<'src>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Directive<'src> {
+  pub name: Text<'src>,
+  pub args: Option<Text<'src>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Line<'src> {
+  Instruction(Instruction<'src>),
+  LabelOnly(Text<'src>),
+  Directive(Directive<'src>),
+  Section(Text<'src>),
+  /// The names listed by `.global`, written `.global a, b` or `.global a b`.
+  Global(Vec<Text<'src>>),
+  /// The names listed by `.extern`, written like those of `.global`.
+  Extern(Vec<Text<'src>>),
+}
+
+fn owned(text: Text<'_>) -> Text<'static> {
+  Cow::Owned(text.into_owned())
+}
+
+impl Arg<'_> {
+  pub fn into_owned(self) -> Arg<'static> {
+    match self {
+      Arg::Immediate(n) => Arg::Immediate(n),
+      Arg::Register(name) => Arg::Register(owned(name)),
+      Arg::Label(name) => Arg::Label(owned(name)),
+      Arg::Mem(inner) => Arg::Mem(Box::new(inner.into_owned())),
+      Arg::Relocated(kind, name) => Arg::Relocated(kind, owned(name)),
+      Arg::LabelOffset(name, addend) => Arg::LabelOffset(owned(name), addend),
+      Arg::RegisterOffset(name, offset) => Arg::RegisterOffset(owned(name), offset),
+      Arg::Indexed(base, index, scale) => Arg::Indexed(owned(base), owned(index), scale),
+    }
+  }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Directive {
-  pub name: String,
-  pub args: Option<String>,
+impl Line<'_> {
+  pub fn into_owned(self) -> Line<'static> {
+    match self {
+      Line::Instruction(instr) => Line::Instruction(Instruction {
+        label: instr.label.map(owned),
+        opcode: instr.opcode,
+        args: instr.args.into_iter().map(Arg::into_owned).collect(),
+      }),
+      Line::LabelOnly(name) => Line::LabelOnly(owned(name)),
+      Line::Directive(d) => Line::Directive(Directive { name: owned(d.name), args: d.args.map(owned) }),
+      Line::Section(name) => Line::Section(owned(name)),
+      Line::Global(names) => Line::Global(names.into_iter().map(owned).collect()),
+      Line::Extern(names) => Line::Extern(names.into_iter().map(owned).collect()),
+    }
+  }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum Line {
-  Instruction(Instruction),
-  LabelOnly(String),
-  Directive(Directive),
-  Section(String),
-  Global(String),
-  Extern(String),
+impl fmt::Display for Arg<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Arg::Immediate(n) => write!(f, "{}", n),
+      Arg::Register(name) | Arg::Label(name) => f.write_str(name),
+      Arg::Mem(inner) => write!(f, "[{}]", inner),
+      Arg::Relocated(kind, name) => match kind.operator() {
+        Some(operator) => write!(f, "%{}({})", operator, name),
+        None => f.write_str(name),
+      },
+      Arg::LabelOffset(name, addend) | Arg::RegisterOffset(name, addend) if *addend < 0 => write!(f, "{}-{}", name, addend.unsigned_abs()),
+      Arg::LabelOffset(name, addend) | Arg::RegisterOffset(name, addend) => write!(f, "{}+{}", name, addend),
+      Arg::Indexed(base, index, 1) => write!(f, "{}+{}", base, index),
+      Arg::Indexed(base, index, scale) => write!(f, "{}+{}*{}", base, index, scale),
+    }
+  }
 }
 
-impl OpCode {
-  pub fn opcode_to_byte(opcode: &OpCode) -> u8 {
-    match opcode {
-      OpCode::Nop => 0x00,
-      OpCode::Add => 0x01,
-      OpCode::Sub => 0x02,
-      OpCode::Mul => 0x03,
-      OpCode::Div => 0x04,
-      OpCode::And => 0x05,
-      OpCode::Or => 0x06,
-      OpCode::Xor => 0x07,
-      OpCode::Not => 0x08,
-      OpCode::Lt => 0x19,
-      OpCode::Gt => 0x1A,
-      OpCode::Eq => 0x1B,
-      OpCode::Jmp => 0x09,
-      OpCode::Jz => 0x0A,
-      OpCode::Jnz => 0x0B,
-      OpCode::Mov => 0x0C,
-      OpCode::Load => 0x0D,
-      OpCode::Store => 0x0E,
-      OpCode::Call => 0x0F,
-      OpCode::Ret => 0x10,
-      OpCode::Push => 0x11,
-      OpCode::Pop => 0x12,
-      OpCode::Halt => 0x13,
-      OpCode::Break => 0x14,
-      OpCode::Syscall => 0x15,
-      OpCode::Movi => 0x16,
-      OpCode::Loadi => 0x17,
-      OpCode::Storei => 0x18,
-      OpCode::Invalid => 0xFF,
+impl fmt::Display for Instruction<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(label) = &self.label {
+      write!(f, "{}: ", label)?;
+    }
+    write!(f, "{}", self.opcode)?;
+    for (i, arg) in self.args.iter().enumerate() {
+      write!(f, "{}{}", if i == 0 { " " } else { ", " }, arg)?;
     }
+    Ok(())
   }
+}
 
-  pub fn byte_to_opcode(byte: u8) -> Option<OpCode> {
-    match byte {
-      0x00 => Some(OpCode::Nop),
-      0x01 => Some(OpCode::Add),
-      0x02 => Some(OpCode::Sub),
-      0x03 => Some(OpCode::Mul),
-      0x04 => Some(OpCode::Div),
-      0x05 => Some(OpCode::And),
-      0x06 => Some(OpCode::Or),
-      0x07 => Some(OpCode::Xor),
-      0x08 => Some(OpCode::Not),
-      0x09 => Some(OpCode::Jmp),
-      0x0A => Some(OpCode::Jz),
-      0x0B => Some(OpCode::Jnz),
-      0x0C => Some(OpCode::Mov),
-      0x0D => Some(OpCode::Load),
-      0x0E => Some(OpCode::Store),
-      0x0F => Some(OpCode::Call),
-      0x10 => Some(OpCode::Ret),
-      0x11 => Some(OpCode::Push),
-      0x12 => Some(OpCode::Pop),
-      0x13 => Some(OpCode::Halt),
-      0x14 => Some(OpCode::Break),
-      0x15 => Some(OpCode::Syscall),
-      0x16 => Some(OpCode::Movi),
-      0x17 => Some(OpCode::Loadi),
-      0x18 => Some(OpCode::Storei),
-      0x19 => Some(OpCode::Lt),
-      0x1A => Some(OpCode::Gt),
-      0x1B => Some(OpCode::Eq),
-      _ => None,
+impl fmt::Display for Directive<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.args {
+      Some(args) => write!(f, ".{} {}", self.name, args),
+      None => write!(f, ".{}", self.name),
     }
   }
 }
+
+/// Renders a line as canonical assembly text, which parses back to an equal `Line`.
+impl fmt::Display for Line<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Line::Instruction(instr) => write!(f, "{}", instr),
+      Line::LabelOnly(label) => write!(f, "{}:", label),
+      Line::Directive(d) => write!(f, "{}", d),
+      Line::Section(s) => match s.as_ref() {
+        ".text" | ".data" | ".rodata" => f.write_str(s),
+        _ => write!(f, ".section {}", s),
+      },
+      Line::Global(names) => write!(f, ".global {}", names.join(", ")),
+      Line::Extern(names) => write!(f, ".extern {}", names.join(", ")),
+    }
+  }
+}
+
+/// Render a whole program as assembly source, one line per `Line`.
+pub fn to_source(program: &[Line]) -> String {
+  program.iter().map(|line| format!("{}\n", line)).collect()
+}
@@ -1,15 +1,24 @@
 use std::io::{Read, Write};
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
 use bincode::{Decode, Encode};
 use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use crate::{ReadableResource, WriteableResource};
+use crate::checksum::{Checksum, ChecksumAlgorithm};
+use crate::error::{FormatError, LeafError};
+use crate::limits::{check_limits, DecodeLimits};
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SymbolEntry {
   /// The name of the symbol, e.g. "main", "data_buffer", etc.
   pub name: String,
   /// The offset of the symbol in the section it belongs to.
   pub offset: u32,
-  /// The size of the symbol in bytes: 0 = .text, 1 = .data, 2 = .rodata
+  /// The size of the symbol in bytes: 0 = .text, 1 = .data, 2 = .rodata, or `ABSOLUTE_SECTION`
   pub section: u8,
   /// The kind of symbol: 0 = label, 1 = data, 2 = rodata
   pub kind: u8,
@@ -17,13 +26,54 @@ pub struct SymbolEntry {
   pub external: bool,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+/// The `section` of a symbol that is in no section: its offset is an address, wherever the sections
+/// are loaded. The linker defines such symbols for the bounds of overlaid sections.
+pub const ABSOLUTE_SECTION: u8 = 3;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RelocationType {
   Absolute,
-  Relative
+  Relative,
+  /// The symbol's offset in its section, wherever the section is placed.
+  SectionRelative,
+  /// The top 16 bits of the symbol's address, for code that builds an address in two halves.
+  Hi16,
+  /// The bottom 16 bits of the symbol's address.
+  Lo16,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+impl RelocationType {
+  /// The word a relocation of this type writes at address `patch` for a symbol at `address`. For
+  /// `SectionRelative`, `address` is the symbol's offset in its section instead.
+  pub fn value(self, address: u32, patch: u32) -> u32 {
+    match self {
+      RelocationType::Absolute | RelocationType::SectionRelative => address,
+      RelocationType::Relative => address.wrapping_sub(patch.wrapping_add(4)),
+      RelocationType::Hi16 => address >> 16,
+      RelocationType::Lo16 => address & 0xFFFF,
+    }
+  }
+
+  /// The `%name(symbol)` operator the assembler writes this type with, if it is not the default.
+  pub fn operator(self) -> Option<&'static str> {
+    match self {
+      RelocationType::SectionRelative => Some("secrel"),
+      RelocationType::Hi16 => Some("hi"),
+      RelocationType::Lo16 => Some("lo"),
+      RelocationType::Absolute | RelocationType::Relative => None,
+    }
+  }
+
+  pub fn from_operator(name: &str) -> Option<Self> {
+    [RelocationType::SectionRelative, RelocationType::Hi16, RelocationType::Lo16]
+      .into_iter()
+      .find(|kind| kind.operator() == Some(name))
+  }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct RelocationEntry {
   pub offset: u32,
   pub symbol_index: u32,
@@ -31,15 +81,288 @@ pub struct RelocationEntry {
   pub target_section: u8, // 0=text, 1=data, 2=rodata
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+/// A constant added to the address relocation `relocation` patches in, for operands such as
+/// `loop+8`: the patch is worked out as if the symbol were `value` bytes further on.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Addend {
+  /// Index into the relocation table.
+  pub relocation: u32,
+  pub value: i32,
+}
+
+impl Addend {
+  /// The table for relocations with these addends, in order: an entry for each that is not zero.
+  pub fn table(addends: impl IntoIterator<Item = i32>) -> Vec<Addend> {
+    addends.into_iter().enumerate()
+      .filter(|(_, value)| *value != 0)
+      .map(|(relocation, value)| Addend { relocation: relocation as u32, value })
+      .collect()
+  }
+}
+
+/// One row of the line table: code from `offset` up to the next row was assembled from `line` of `files[file]`.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct LineEntry {
+  pub offset: u32,
+  pub file: u32,
+  pub line: u32,
+}
+
+/// Source locations for `.text`, emitted when the assembler knows where each line came from.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct DebugInfo {
+  pub files: Vec<String>,
+  /// Sorted by offset.
+  pub lines: Vec<LineEntry>,
+}
+
+impl DebugInfo {
+  /// Index of `file` in the file table, adding it if needed.
+  pub fn file_index(&mut self, file: &str) -> u32 {
+    match self.files.iter().position(|f| f == file) {
+      Some(index) => index as u32,
+      None => {
+        self.files.push(file.to_string());
+        (self.files.len() - 1) as u32
+      }
+    }
+  }
+
+  /// The row covering a code offset, if any.
+  pub fn line_for(&self, offset: u32) -> Option<&LineEntry> {
+    let index = self.lines.partition_point(|entry| entry.offset <= offset);
+    index.checked_sub(1).map(|i| &self.lines[i])
+  }
+}
+
+/// A function the host can call, as listed in an executable's export table.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ExportEntry {
+  pub name: String,
+  /// Offset of the function in `.text`, which the VM loads at address 0.
+  pub address: u32,
+  /// Free-form description of the arguments and result, e.g. `(r1: len, r2: ptr) -> r0`.
+  pub signature: Option<String>,
+}
+
+/// Bytes of `.text` that are data, such as a `.word` between functions, rather than instructions.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct DataRange {
+  pub offset: u32,
+  pub len: u32,
+}
+
+/// Read, write and execute permissions of a section, as bits.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SectionFlags(pub u8);
+
+impl SectionFlags {
+  pub const READ: SectionFlags = SectionFlags(1);
+  pub const WRITE: SectionFlags = SectionFlags(2);
+  pub const EXECUTE: SectionFlags = SectionFlags(4);
+
+  /// What a section is unless its object says otherwise: `.text` is readable and executable,
+  /// `.data` readable and writable and `.rodata` only readable. Leaf has no `.bss`; zeroed space
+  /// goes in `.data`.
+  pub fn default_for(section: u8) -> SectionFlags {
+    match section {
+      0 => SectionFlags::READ | SectionFlags::EXECUTE,
+      1 => SectionFlags::READ | SectionFlags::WRITE,
+      _ => SectionFlags::READ,
+    }
+  }
+
+  pub fn contains(self, flags: SectionFlags) -> bool {
+    self.0 & flags.0 == flags.0
+  }
+
+  /// Flags written as letters, such as `rx`, in any order; `None` for any other letter.
+  pub fn parse(letters: &str) -> Option<SectionFlags> {
+    letters.chars().try_fold(SectionFlags(0), |flags, letter| match letter {
+      'r' => Some(flags | SectionFlags::READ),
+      'w' => Some(flags | SectionFlags::WRITE),
+      'x' => Some(flags | SectionFlags::EXECUTE),
+      _ => None,
+    })
+  }
+}
+
+impl std::ops::BitOr for SectionFlags {
+  type Output = SectionFlags;
+
+  fn bitor(self, other: SectionFlags) -> SectionFlags {
+    SectionFlags(self.0 | other.0)
+  }
+}
+
+impl std::fmt::Display for SectionFlags {
+  /// `r-x` style, as `ls` shows file modes.
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (flag, letter) in [(SectionFlags::READ, 'r'), (SectionFlags::WRITE, 'w'), (SectionFlags::EXECUTE, 'x')] {
+      write!(f, "{}", if self.contains(flag) { letter } else { '-' })?;
+    }
+    Ok(())
+  }
+}
+
+/// A loadable region of an executable: the bytes of one section, where they go in memory and
+/// what may be done with them.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Segment {
+  /// 0 = .text, 1 = .data, 2 = .rodata
+  pub section: u8,
+  /// Load address.
+  pub address: u32,
+  /// Where the section runs, which its symbols' addresses are relative to: the load address unless
+  /// an overlay puts the section somewhere else for a runtime to copy it to.
+  pub run_address: u32,
+  /// Where the section's bytes start, counted from the end of the header; see
+  /// `LeafAsmObject::file_offset`.
+  pub file_offset: u32,
+  pub file_size: u32,
+  /// Bytes the segment takes in memory; any past `file_size` are zero.
+  pub mem_size: u32,
+  pub flags: SectionFlags,
+}
+
+/// Newest file format version this toolchain reads and writes.
+pub const FORMAT_VERSION: u16 = 7;
+/// First format version whose checksum records its algorithm; before it, the checksum is a CRC32.
+const TAGGED_CHECKSUM_VERSION: u16 = 2;
+/// First format version that records its byte order; before it, files are little-endian.
+const BYTE_ORDER_VERSION: u16 = 3;
+/// First format version that can carry a build-id.
+const BUILD_ID_VERSION: u16 = 4;
+/// First format version whose objects go on after the debug info, with the export, data range,
+/// section flag, segment and addend tables; before it, objects have none.
+const OBJECT_TABLES_VERSION: u16 = 5;
+/// First format version whose objects end in the section relocation table.
+const SECTION_RELOCATIONS_VERSION: u16 = 6;
+/// First format version whose segments record a run address, and whose symbols can be absolute.
+const RUN_ADDRESS_VERSION: u16 = 7;
+
+/// Byte order of the multi-byte integers in a file's encoding. Instruction operands inside
+/// `.text` are always little-endian (LDR-003); this only covers the container around them.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ByteOrder {
+  #[default]
+  Little,
+  Big,
+}
+
+impl ByteOrder {
+  /// The byte order an encoded file records. The marker follows the four-byte magic and the
+  /// version, a varint that is one byte below 251 and three bytes from then on, in either order.
+  pub fn of(bytes: &[u8]) -> ByteOrder {
+    let marker = match bytes.get(4) {
+      Some(&version) if (BYTE_ORDER_VERSION as u8..251).contains(&version) => bytes.get(5),
+      Some(251) => bytes.get(7),
+      _ => None,
+    };
+    match marker {
+      Some(1) => ByteOrder::Big,
+      _ => ByteOrder::Little,
+    }
+  }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LeafAsmObjectHeader {
   pub magic: [u8; 4],
   pub version: u16,
-  pub reserved: u16,
-  pub checksum: u32,
+  /// How the rest of the file is encoded. `write_to` writes big-endian files as format version 3
+  /// or later, and the reader decodes either order.
+  pub byte_order: ByteOrder,
+  /// Oldest ISA revision that can run the object (`opcode::ISA_VERSION`). Files written before
+  /// revisions were recorded have 0 here, and need revision 1.
+  pub isa_version: u16,
+  /// Over the whole encoded file with this field zeroed. `write_to` fills it in with whichever
+  /// algorithm it already names.
+  pub checksum: Checksum,
+  /// Identifies the contents, see `LeafAsmFile::compute_build_id`. `write_to` fills in a zeroed
+  /// one and keeps any other, so a file split off another can carry the original's; files with
+  /// a build-id are written as format version 4 or later.
+  pub build_id: Option<BuildId>,
+}
+
+/// A hash of a file's contents that does not depend on how the file was written: two builds of
+/// the same program have the same build-id, and an executable and the debug info split off it
+/// can be matched up by theirs.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Encode, Decode, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct BuildId(pub [u8; 16]);
+
+impl std::fmt::Display for BuildId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+  }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+impl LeafAsmObjectHeader {
+  /// The ISA revision the object needs.
+  pub fn required_isa_version(&self) -> u16 {
+    self.isa_version.max(1)
+  }
+}
+
+/// Version 1 headers end in a bare CRC32, so a file that uses the default algorithm still reads
+/// with older toolchains.
+impl Encode for LeafAsmObjectHeader {
+  fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+    self.magic.encode(encoder)?;
+    self.version.encode(encoder)?;
+    match self.byte_order {
+      byte_order if self.version >= BYTE_ORDER_VERSION => byte_order.encode(encoder)?,
+      ByteOrder::Little => {}
+      ByteOrder::Big => return Err(EncodeError::Other("only format version 3 and later can be big-endian")),
+    }
+    self.isa_version.encode(encoder)?;
+    match self.checksum {
+      checksum if self.version >= TAGGED_CHECKSUM_VERSION => checksum.encode(encoder)?,
+      Checksum::Crc32(crc) => crc.encode(encoder)?,
+      _ => return Err(EncodeError::Other("only format version 2 and later can record a checksum other than CRC32")),
+    }
+    match self.build_id {
+      build_id if self.version >= BUILD_ID_VERSION => build_id.encode(encoder),
+      None => Ok(()),
+      Some(_) => Err(EncodeError::Other("only format version 4 and later can carry a build-id")),
+    }
+  }
+}
+
+impl<Context> Decode<Context> for LeafAsmObjectHeader {
+  fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+    let magic = Decode::decode(decoder)?;
+    let version = u16::decode(decoder)?;
+    let byte_order = match version {
+      BYTE_ORDER_VERSION.. => ByteOrder::decode(decoder)?,
+      _ => ByteOrder::Little,
+    };
+    let isa_version = Decode::decode(decoder)?;
+    let checksum = match version {
+      TAGGED_CHECKSUM_VERSION.. => Checksum::decode(decoder)?,
+      _ => Checksum::Crc32(Decode::decode(decoder)?),
+    };
+    let build_id = match version {
+      BUILD_ID_VERSION.. => Decode::decode(decoder)?,
+      _ => None,
+    };
+    Ok(LeafAsmObjectHeader { magic, version, byte_order, isa_version, checksum, build_id })
+  }
+}
+
+bincode::impl_borrow_decode!(LeafAsmObjectHeader);
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Encode, Decode, Serialize, Deserialize)]
 pub struct LeafAsmObject {
   pub bytecode: Vec<u8>,
   pub data: Vec<u8>,
@@ -47,52 +370,237 @@ pub struct LeafAsmObject {
   pub symbols: Vec<SymbolEntry>,
   pub entry_point: Option<String>,
   pub relocations: Vec<RelocationEntry>,
-  pub debug_info: Option<String>,
+  pub debug_info: Option<DebugInfo>,
+  /// Entry points for embedders, written by `leaf_asm link --export`.
+  #[serde(default)]
+  pub exports: Vec<ExportEntry>,
+  /// Where `.text` holds data, in order, so a disassembler does not decode it as instructions.
+  #[serde(default)]
+  pub data_in_text: Vec<DataRange>,
+  /// Permissions of `.text`, `.data` and `.rodata`, in that order; see `LeafAsmObject::flags` for
+  /// sections this leaves out.
+  #[serde(default)]
+  pub section_flags: Vec<SectionFlags>,
+  /// How to load a linked executable, one segment per non-empty section in address order; empty
+  /// in objects and shared objects.
+  #[serde(default)]
+  pub segments: Vec<Segment>,
+  /// Addends of the relocations that have one, by relocation; see `LeafAsmObject::addend`.
+  #[serde(default)]
+  pub addends: Vec<Addend>,
+  /// Relocations, by index in order, that refer to one of the object's own sections rather than a
+  /// symbol: their `symbol_index` is the section and their addend the offset in it. Labels no other
+  /// object can use, such as local labels, are kept out of the symbol table this way; see
+  /// `LeafAsmObject::section_target`.
+  #[serde(default)]
+  pub section_relocations: Vec<u32>,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+impl LeafAsmObject {
+  /// Whether the object has anything in the tables that follow the debug info.
+  fn has_tables(&self) -> bool {
+    !(self.exports.is_empty()
+      && self.data_in_text.is_empty()
+      && self.section_flags.is_empty()
+      && self.segments.is_empty()
+      && self.addends.is_empty()
+      && self.section_relocations.is_empty())
+  }
+
+  /// Whether the object has a section that runs away from where it is loaded, or an absolute
+  /// symbol, which only format version 7 can record.
+  fn has_run_addresses(&self) -> bool {
+    self.segments.iter().any(|segment| segment.run_address != segment.address)
+      || self.symbols.iter().any(|symbol| symbol.section == ABSOLUTE_SECTION)
+  }
+}
+
+/// A segment as files before version 7 record it, without a run address.
+type UnmovedSegment = (u8, u32, u32, u32, u32, SectionFlags);
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LeafAsmFile {
   pub header: LeafAsmObjectHeader,
   pub object: LeafAsmObject,
 }
 
-impl WriteableResource for LeafAsmFile {
-  fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
-    let config = bincode::config::standard();
-
-    info!("Generating checksum...");
-    let mut file_with_zero_checksum = self.clone();
-    file_with_zero_checksum.header.checksum = 0;
+/// Objects in files before version 5 end after the debug info, so those files still read with
+/// older toolchains.
+impl Encode for LeafAsmFile {
+  fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+    let object = &self.object;
+    self.header.encode(encoder)?;
+    object.bytecode.encode(encoder)?;
+    object.data.encode(encoder)?;
+    object.rodata.encode(encoder)?;
+    object.symbols.encode(encoder)?;
+    object.entry_point.encode(encoder)?;
+    object.relocations.encode(encoder)?;
+    object.debug_info.encode(encoder)?;
+    if self.header.version < RUN_ADDRESS_VERSION && object.has_run_addresses() {
+      return Err(EncodeError::Other("only format version 7 and later can carry run addresses or absolute symbols"));
+    }
+    if self.header.version < OBJECT_TABLES_VERSION {
+      return match object.has_tables() {
+        false => Ok(()),
+        true => Err(EncodeError::Other("only format version 5 and later can carry exports, data ranges, section flags, segments or addends")),
+      };
+    }
+    object.exports.encode(encoder)?;
+    object.data_in_text.encode(encoder)?;
+    object.section_flags.encode(encoder)?;
+    match self.header.version {
+      RUN_ADDRESS_VERSION.. => object.segments.encode(encoder)?,
+      _ => object.segments.iter()
+        .map(|s| (s.section, s.address, s.file_offset, s.file_size, s.mem_size, s.flags))
+        .collect::<Vec<UnmovedSegment>>()
+        .encode(encoder)?,
+    }
+    object.addends.encode(encoder)?;
+    match self.header.version {
+      SECTION_RELOCATIONS_VERSION.. => object.section_relocations.encode(encoder),
+      _ if object.section_relocations.is_empty() => Ok(()),
+      _ => Err(EncodeError::Other("only format version 6 and later can carry section relocations")),
+    }
+  }
+}
 
-    let encoded_without_checksum = bincode::encode_to_vec(&file_with_zero_checksum, config)
-      .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+impl<Context> Decode<Context> for LeafAsmFile {
+  fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+    let header = LeafAsmObjectHeader::decode(decoder)?;
+    let mut object = LeafAsmObject {
+      bytecode: Decode::decode(decoder)?,
+      data: Decode::decode(decoder)?,
+      rodata: Decode::decode(decoder)?,
+      symbols: Decode::decode(decoder)?,
+      entry_point: Decode::decode(decoder)?,
+      relocations: Decode::decode(decoder)?,
+      debug_info: Decode::decode(decoder)?,
+      ..LeafAsmObject::default()
+    };
+    if header.version >= OBJECT_TABLES_VERSION {
+      object.exports = Decode::decode(decoder)?;
+      object.data_in_text = Decode::decode(decoder)?;
+      object.section_flags = Decode::decode(decoder)?;
+      object.segments = match header.version {
+        RUN_ADDRESS_VERSION.. => Decode::decode(decoder)?,
+        _ => Vec::<UnmovedSegment>::decode(decoder)?.into_iter()
+          .map(|(section, address, file_offset, file_size, mem_size, flags)| {
+            Segment { section, address, run_address: address, file_offset, file_size, mem_size, flags }
+          })
+          .collect(),
+      };
+      object.addends = Decode::decode(decoder)?;
+    }
+    if header.version >= SECTION_RELOCATIONS_VERSION {
+      object.section_relocations = Decode::decode(decoder)?;
+    }
+    Ok(LeafAsmFile { header, object })
+  }
+}
 
-    let checksum = crc32fast::hash(&encoded_without_checksum);
+bincode::impl_borrow_decode!(LeafAsmFile);
 
-    info!("Checksum generated: {}, writing to writer...", checksum);
+impl WriteableResource for LeafAsmFile {
+  /// Write the file with its checksum and any build-id filled in, using the algorithm the header
+  /// names, in the byte order it names. The version is raised to the first one that can record
+  /// all three, and the object's tables after the debug info.
+  fn write_to(&self, writer: &mut dyn Write) -> Result<(), LeafError> {
     let mut final_file = self.clone();
+    let algorithm = self.header.checksum.algorithm();
+    if algorithm != ChecksumAlgorithm::Crc32 {
+      final_file.header.version = final_file.header.version.max(TAGGED_CHECKSUM_VERSION);
+    }
+    if self.header.byte_order != ByteOrder::Little {
+      final_file.header.version = final_file.header.version.max(BYTE_ORDER_VERSION);
+    }
+    if let Some(build_id) = self.header.build_id {
+      final_file.header.version = final_file.header.version.max(BUILD_ID_VERSION);
+      if build_id == BuildId::default() {
+        final_file.header.build_id = Some(self.compute_build_id()?);
+      }
+    }
+    if self.object.has_tables() {
+      final_file.header.version = final_file.header.version.max(OBJECT_TABLES_VERSION);
+    }
+    if !self.object.section_relocations.is_empty() {
+      final_file.header.version = final_file.header.version.max(SECTION_RELOCATIONS_VERSION);
+    }
+    if self.object.has_run_addresses() {
+      final_file.header.version = final_file.header.version.max(RUN_ADDRESS_VERSION);
+    }
+    info!("Generating {} checksum...", algorithm);
+    let checksum = final_file.compute_checksum()?;
+    info!("Checksum generated: {}, writing to writer...", checksum);
     final_file.header.checksum = checksum;
 
-    let final_encoded = bincode::encode_to_vec(&final_file, config)
-      .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    writer.write_all(&final_encoded)?;
+    writer.write_all(&final_file.encode()?)?;
+    Ok(())
+  }
+}
+
+impl LeafAsmFile {
+  /// The file in bincode's standard encoding, with integers in the header's byte order.
+  fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+    let config = bincode::config::standard();
+    match self.header.byte_order {
+      ByteOrder::Little => bincode::encode_to_vec(self, config),
+      ByteOrder::Big => bincode::encode_to_vec(self, config.with_big_endian()),
+    }
+  }
+
+  /// The build-id of the file: the start of a SHA-256 over the little-endian encoding of the
+  /// object and the ISA revision it needs. The rest of the header only says how the file was
+  /// written, so neither the version, the byte order nor the checksum changes it.
+  pub fn compute_build_id(&self) -> Result<BuildId, LeafError> {
+    let config = bincode::config::standard();
+    let mut hasher = Sha256::new();
+    hasher.update(bincode::encode_to_vec(self.header.isa_version, config)?);
+    hasher.update(bincode::encode_to_vec(&self.object, config)?);
+    Ok(BuildId(hasher.finalize()[..16].try_into().unwrap()))
+  }
+
+  /// The checksum of the file's encoding with the checksum zeroed, by the algorithm it names.
+  pub fn compute_checksum(&self) -> Result<Checksum, LeafError> {
+    let algorithm = self.header.checksum.algorithm();
+    let mut zeroed = self.clone();
+    zeroed.header.checksum = algorithm.zero();
+    Ok(algorithm.compute(&zeroed.encode()?))
+  }
+
+  /// Check the header's checksum against the file's contents.
+  pub fn verify_checksum(&self) -> Result<(), LeafError> {
+    let computed = self.compute_checksum()?;
+    if computed != self.header.checksum {
+      return Err(FormatError::ChecksumMismatch { expected: self.header.checksum, computed }.into());
+    }
     Ok(())
   }
+
+  /// Read a file, rejecting it if it is larger than `limits` allow instead of allocating whatever
+  /// its lengths claim.
+  pub fn read_with_limits(reader: &mut dyn Read, limits: &DecodeLimits) -> Result<Self, LeafError> {
+    let mut buffer = Vec::new();
+    reader.take(limits.max_file_size.saturating_add(1) as u64).read_to_end(&mut buffer)?;
+    check_limits(&buffer, limits)?;
+
+    let config = bincode::config::standard();
+    let (file, _) = match ByteOrder::of(&buffer) {
+      ByteOrder::Little => bincode::decode_from_slice(&buffer, config)?,
+      ByteOrder::Big => bincode::decode_from_slice(&buffer, config.with_big_endian())?,
+    };
+    Ok(file)
+  }
 }
 
 impl ReadableResource for LeafAsmFile {
-  fn read_from(reader: &mut dyn Read) -> std::io::Result<Self>
+  /// Read a file with the default `DecodeLimits`.
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError>
   where
     Self: Sized
   {
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-
-    let config = bincode::config::standard();
-    match bincode::decode_from_slice(&buffer, config) {
-      Ok((obj, _)) => Ok(obj),
-      Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-    }
+    Self::read_with_limits(reader, &DecodeLimits::default())
   }
 }
 
@@ -124,14 +632,25 @@ mod tests {
       rodata: vec![],
       entry_point: Some("main".to_string()),
       relocations: vec![reloc],
-      debug_info: Some("Debug info".to_string()),
+      debug_info: Some(DebugInfo {
+        files: vec!["main.leaf".to_string()],
+        lines: vec![LineEntry { offset: 0, file: 0, line: 3 }],
+      }),
+      exports: vec![ExportEntry { name: "main".to_string(), address: 0, signature: None }],
+      data_in_text: vec![DataRange { offset: 1, len: 2 }],
+      section_flags: vec![SectionFlags::READ | SectionFlags::EXECUTE, SectionFlags::READ],
+      segments: vec![Segment { section: 0, address: 0, run_address: 0, file_offset: 1, file_size: 3, mem_size: 3, flags: SectionFlags::READ | SectionFlags::EXECUTE }],
+      addends: vec![Addend { relocation: 0, value: -8 }],
+      section_relocations: vec![],
     };
 
     let header = LeafAsmObjectHeader {
       magic: *b"LAF\0",
       version: 1,
-      reserved: 0,
-      checksum: 12345678,
+      byte_order: ByteOrder::Little,
+      isa_version: 1,
+      checksum: Checksum::Crc32(12345678),
+      build_id: None,
     };
 
     let header_clone = header.clone();
@@ -149,8 +668,166 @@ mod tests {
 
     assert_eq!(decoded.object, object_clone);
     assert_eq!(decoded.header.magic, header_clone.magic);
-    assert_eq!(decoded.header.version, header_clone.version);
-    assert_eq!(decoded.header.reserved, header_clone.reserved);
-    assert_eq!(decoded.header.checksum, 310412118);
+    // Raised to the first version that has the tables after the debug info
+    assert_eq!(decoded.header.version, 5);
+    assert_eq!(decoded.header.isa_version, header_clone.isa_version);
+    // The checksum covers the whole encoding with the checksum field zeroed
+    assert_eq!(decoded.header.checksum, Checksum::Crc32(2018847939));
+    decoded.verify_checksum().unwrap();
+  }
+
+  #[test]
+  fn test_checksum_algorithm_is_recorded() {
+    let object = LeafAsmObject { bytecode: vec![0x09, 0, 0, 0, 0], ..LeafAsmObject::default() };
+    for algorithm in ChecksumAlgorithm::ALL {
+      let header = LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Little, isa_version: 1, checksum: algorithm.zero(), build_id: None };
+      let mut buffer = Vec::new();
+      LeafAsmFile { header, object: object.clone() }.write_to(&mut buffer).unwrap();
+      let mut decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+      assert_eq!(decoded.header.checksum.algorithm(), algorithm);
+      // CRC32 files keep the version 1 layout that older readers understand
+      assert_eq!(decoded.header.version, if algorithm == ChecksumAlgorithm::Crc32 { 1 } else { 2 });
+      decoded.verify_checksum().unwrap();
+
+      decoded.object.bytecode[0] = 0x10;
+      let err = decoded.verify_checksum().unwrap_err();
+      assert!(matches!(err, LeafError::Format(FormatError::ChecksumMismatch { expected, .. }) if expected.algorithm() == algorithm));
+    }
+  }
+
+  #[test]
+  fn test_json_round_trip() {
+    let file = LeafAsmFile {
+      header: LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Little, isa_version: 1, checksum: Checksum::Crc32(0), build_id: None },
+      object: LeafAsmObject {
+        bytecode: vec![0x09, 0, 0, 0, 0],
+        data: vec![42],
+        rodata: vec![],
+        symbols: vec![SymbolEntry { name: "main".to_string(), offset: 0, section: 0, kind: 0, external: false }],
+        entry_point: Some("main".to_string()),
+        relocations: vec![RelocationEntry {
+          offset: 1,
+          symbol_index: 0,
+          reloc_type: RelocationType::Relative,
+          target_section: 0,
+        }],
+        debug_info: None,
+        exports: vec![],
+        data_in_text: vec![],
+        section_flags: vec![],
+        segments: vec![],
+        addends: vec![],
+        section_relocations: vec![],
+      },
+    };
+
+    let json = serde_json::to_string(&file).unwrap();
+    assert!(json.contains("\"reloc_type\":\"Relative\""));
+    assert_eq!(serde_json::from_str::<LeafAsmFile>(&json).unwrap(), file);
+  }
+
+  #[test]
+  fn test_big_endian_files_read_transparently() {
+    let object = LeafAsmObject {
+      bytecode: vec![0x09, 0, 0, 0, 0],
+      symbols: vec![SymbolEntry { name: "main".to_string(), offset: 0x1234, section: 0, kind: 0, external: false }],
+      ..LeafAsmObject::default()
+    };
+    let header = LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Big, isa_version: 1, checksum: Checksum::Crc32(0), build_id: None };
+    let mut buffer = Vec::new();
+    LeafAsmFile { header, object: object.clone() }.write_to(&mut buffer).unwrap();
+    assert_eq!((buffer[4], buffer[5]), (3, 1));
+    // The symbol offset is a 2-byte varint, most significant byte first
+    assert!(buffer.windows(3).any(|bytes| bytes == [251, 0x12, 0x34]));
+
+    let decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!((decoded.header.byte_order, decoded.header.version), (ByteOrder::Big, 3));
+    assert_eq!(decoded.object, object);
+    decoded.verify_checksum().unwrap();
+  }
+
+  #[test]
+  fn test_build_id_depends_only_on_the_contents() {
+    let object = LeafAsmObject { bytecode: vec![0x09, 0, 0, 0, 0], ..LeafAsmObject::default() };
+    let write = |byte_order, checksum, object: &LeafAsmObject| {
+      let header = LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order, isa_version: 1, checksum, build_id: Some(BuildId::default()) };
+      let mut buffer = Vec::new();
+      LeafAsmFile { header, object: object.clone() }.write_to(&mut buffer).unwrap();
+      LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap()
+    };
+    let file = write(ByteOrder::Little, Checksum::Crc32(0), &object);
+    assert_eq!(file.header.version, 4);
+    let build_id = file.header.build_id.unwrap();
+    assert_ne!(build_id, BuildId::default());
+    assert_eq!(build_id, file.compute_build_id().unwrap());
+    assert_eq!(write(ByteOrder::Big, ChecksumAlgorithm::Sha256.zero(), &object).header.build_id, Some(build_id));
+    let changed = LeafAsmObject { bytecode: vec![0x09, 1, 0, 0, 0], ..object };
+    assert_ne!(write(ByteOrder::Little, Checksum::Crc32(0), &changed).header.build_id, Some(build_id));
+
+    // A build-id that is already there is kept, so split-off files can carry the original's
+    let mut split = LeafAsmFile { header: file.header.clone(), object: changed };
+    let mut buffer = Vec::new();
+    split.write_to(&mut buffer).unwrap();
+    split = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!(split.header.build_id, Some(build_id));
+    split.verify_checksum().unwrap();
+  }
+
+  #[test]
+  fn test_tables_after_the_debug_info_depend_on_the_version() {
+    let object = LeafAsmObject { bytecode: vec![0x09, 0, 0, 0, 0], entry_point: Some("main".to_string()), ..LeafAsmObject::default() };
+    let mut file = LeafAsmFile { header: LeafAsmObjectHeader { magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Little, isa_version: 1, checksum: Checksum::Crc32(0), build_id: None }, object };
+    let mut buffer = Vec::new();
+    file.write_to(&mut buffer).unwrap();
+    // An absent debug info is the last byte
+    assert_eq!(buffer.last(), Some(&0));
+    let decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!((decoded.header.version, &decoded.object), (1, &file.object));
+    decoded.verify_checksum().unwrap();
+    // Trailing bytes are not read as tables
+    let mut longer = buffer.clone();
+    longer.extend_from_slice(&[1, 0]);
+    assert_eq!(LeafAsmFile::read_from(&mut longer.as_slice()).unwrap().object, file.object);
+
+    file.object.section_flags = vec![SectionFlags::READ];
+    let mut buffer = Vec::new();
+    file.write_to(&mut buffer).unwrap();
+    let decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!((decoded.header.version, &decoded.object), (5, &file.object));
+    decoded.verify_checksum().unwrap();
+    // Without the version to carry them, the tables are an error rather than dropped
+    assert!(bincode::encode_to_vec(&file, bincode::config::standard()).is_err());
+
+    // Section relocations need version 6
+    file.object.relocations = vec![RelocationEntry { offset: 1, symbol_index: 0, reloc_type: RelocationType::Absolute, target_section: 0 }];
+    file.object.section_relocations = vec![0];
+    let mut buffer = Vec::new();
+    file.write_to(&mut buffer).unwrap();
+    let decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!((decoded.header.version, &decoded.object), (6, &file.object));
+    file.header.version = 5;
+    assert!(bincode::encode_to_vec(&file, bincode::config::standard()).is_err());
+
+    // A run address apart from the load address needs version 7, and one that is not adds nothing
+    file.object.data = vec![0; 4];
+    file.object.segments = vec![Segment { section: 1, address: 5, run_address: 5, file_offset: 7, file_size: 4, mem_size: 4, flags: SectionFlags::READ }];
+    let encode = |file: &LeafAsmFile| bincode::encode_to_vec(file, bincode::config::standard()).unwrap();
+    file.header.version = 6;
+    let unmoved = encode(&file);
+    file.header.version = 7;
+    assert_eq!(encode(&file).len(), unmoved.len() + 1);
+    file.object.segments[0].run_address = 0x100;
+    let mut buffer = Vec::new();
+    file.write_to(&mut buffer).unwrap();
+    let decoded = LeafAsmFile::read_from(&mut buffer.as_slice()).unwrap();
+    assert_eq!((decoded.header.version, &decoded.object), (7, &file.object));
+    file.header.version = 6;
+    assert!(bincode::encode_to_vec(&file, bincode::config::standard()).is_err());
+  }
+
+  #[test]
+  fn test_reading_garbage_is_a_format_error() {
+    let err = LeafAsmFile::read_from(&mut [0xFFu8, 0xFF, 0xFF].as_slice()).unwrap_err();
+    assert!(matches!(err, LeafError::Format(crate::error::FormatError::Decode(_))));
   }
 }
@@ -1,22 +1,44 @@
 pub mod leaf_file;
 pub mod leaf_ast;
+pub mod opcode;
+pub mod isa;
 pub mod disassembler;
+pub mod builder;
+pub mod object_builder;
+pub mod diagnostic;
+pub mod interner;
+pub mod symbolicate;
+pub mod error;
+pub mod syscall;
+pub mod limits;
+pub mod checksum;
+pub mod symver;
+pub mod source_map;
+pub mod library;
+#[cfg(feature = "arbitrary")]
+pub mod generators;
+
+/// All filesystem access in the toolchain libraries goes through these traits, so the `*_path`
+/// helpers are the only place `std::fs` is touched. They are left out of wasm builds.
+use crate::error::LeafError;
 
 pub trait WriteableResource {
-  fn write_to(&self, writer: &mut dyn std::io::Write) -> std::io::Result<()>;
+  fn write_to(&self, writer: &mut dyn std::io::Write) -> Result<(), LeafError>;
 
-  fn write_to_path<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+  #[cfg(not(target_arch = "wasm32"))]
+  fn write_to_path<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), LeafError> {
     let mut file = std::fs::File::create(path)?;
     self.write_to(&mut file)
   }
 }
 
 pub trait ReadableResource {
-  fn read_from(reader: &mut dyn std::io::Read) -> std::io::Result<Self>
+  fn read_from(reader: &mut dyn std::io::Read) -> Result<Self, LeafError>
   where
     Self: Sized;
 
-  fn read_from_path<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self>
+  #[cfg(not(target_arch = "wasm32"))]
+  fn read_from_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self, LeafError>
   where
     Self: Sized,
   {
@@ -0,0 +1,150 @@
+//! Libraries (`.leaflib`): object files gathered into one file under member names, like a Unix
+//! `.a` archive. The linker searches them for the symbols a program uses but does not define and
+//! links in only the members that define them, so a program pays for the routines it calls and
+//! nothing else.
+//!
+//! The file is the magic `LLIB`, a little-endian `u16` version and a `u32` member count, then for
+//! each member a `u32` name length, the name in UTF-8, a `u32` object length and the member's
+//! `.leafobj` bytes, checksum and all.
+use std::io::{Read, Write};
+use crate::error::{FormatError, LeafError};
+use crate::leaf_file::LeafAsmFile;
+use crate::limits::DecodeLimits;
+use crate::{ReadableResource, WriteableResource};
+
+pub const LIBRARY_MAGIC: [u8; 4] = *b"LLIB";
+
+/// The library format version this toolchain reads and writes.
+pub const LIBRARY_VERSION: u16 = 1;
+
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct LeafLibrary {
+  pub members: Vec<LibraryMember>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct LibraryMember {
+  /// Usually the source file the member was assembled from, without its extension.
+  pub name: String,
+  pub file: LeafAsmFile,
+}
+
+impl LibraryMember {
+  /// Whether the member defines `symbol` for other objects to use.
+  pub fn defines(&self, symbol: &str) -> bool {
+    self.file.object.symbols.iter().any(|s| !s.external && s.name == symbol)
+  }
+}
+
+impl LeafLibrary {
+  pub fn member(&self, name: &str) -> Option<&LibraryMember> {
+    self.members.iter().find(|member| member.name == name)
+  }
+
+  /// Read a library, and each member in it, within `limits`.
+  pub fn read_with_limits(reader: &mut dyn Read, limits: &DecodeLimits) -> Result<Self, LeafError> {
+    let mut buffer = Vec::new();
+    reader.take(limits.max_file_size.saturating_add(1) as u64).read_to_end(&mut buffer)?;
+    if buffer.len() > limits.max_file_size {
+      return Err(FormatError::TooLarge { what: "library", len: buffer.len() as u64, limit: limits.max_file_size }.into());
+    }
+    let mut bytes = buffer.as_slice();
+    let magic: [u8; 4] = take(&mut bytes, 4)?.try_into().unwrap();
+    if magic != LIBRARY_MAGIC {
+      return Err(FormatError::BadMagic(magic).into());
+    }
+    let version = u16::from_le_bytes(take(&mut bytes, 2)?.try_into().unwrap());
+    if version != LIBRARY_VERSION {
+      return Err(FormatError::UnsupportedVersion(version).into());
+    }
+    let count = read_u32(&mut bytes)?;
+    let mut members = Vec::new();
+    for _ in 0..count {
+      let len = read_u32(&mut bytes)? as usize;
+      if len > limits.max_name_len {
+        return Err(FormatError::TooLarge { what: "member name", len: len as u64, limit: limits.max_name_len }.into());
+      }
+      let name = String::from_utf8(take(&mut bytes, len)?.to_vec())
+        .map_err(|_| FormatError::Decode("library member name is not UTF-8".to_string()))?;
+      let len = read_u32(&mut bytes)? as usize;
+      let file = LeafAsmFile::read_with_limits(&mut take(&mut bytes, len)?, limits)
+        .map_err(|e| FormatError::Decode(format!("library member '{}': {}", name, e)))?;
+      members.push(LibraryMember { name, file });
+    }
+    if !bytes.is_empty() {
+      return Err(FormatError::Decode(format!("{} bytes after the last library member", bytes.len())).into());
+    }
+    Ok(LeafLibrary { members })
+  }
+}
+
+/// The next `len` bytes of `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], LeafError> {
+  if bytes.len() < len {
+    return Err(FormatError::Decode("library ends in the middle of a member".to_string()).into());
+  }
+  let (taken, rest) = bytes.split_at(len);
+  *bytes = rest;
+  Ok(taken)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, LeafError> {
+  Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+impl ReadableResource for LeafLibrary {
+  /// Read a library with the default `DecodeLimits`.
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError> {
+    Self::read_with_limits(reader, &DecodeLimits::default())
+  }
+}
+
+impl WriteableResource for LeafLibrary {
+  fn write_to(&self, writer: &mut dyn Write) -> Result<(), LeafError> {
+    let mut bytes = Vec::new();
+    bytes.extend(LIBRARY_MAGIC);
+    bytes.extend(LIBRARY_VERSION.to_le_bytes());
+    bytes.extend((self.members.len() as u32).to_le_bytes());
+    for member in &self.members {
+      let mut object = Vec::new();
+      member.file.write_to(&mut object)?;
+      bytes.extend((member.name.len() as u32).to_le_bytes());
+      bytes.extend(member.name.as_bytes());
+      bytes.extend((object.len() as u32).to_le_bytes());
+      bytes.extend(object);
+    }
+    writer.write_all(&bytes)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::checksum::Checksum;
+  use crate::leaf_file::{ByteOrder, LeafAsmObject, LeafAsmObjectHeader, SymbolEntry};
+
+  #[test]
+  fn libraries_round_trip_their_members() {
+    let header = LeafAsmObjectHeader {
+      magic: *b"LAF\0", version: 1, byte_order: ByteOrder::Little, isa_version: 1, checksum: Checksum::default(), build_id: None,
+    };
+    let mut file = LeafAsmFile { header, object: LeafAsmObject { bytecode: vec![0x10], ..LeafAsmObject::default() } };
+    file.object.symbols.push(SymbolEntry { name: "memcpy".to_string(), offset: 0, section: 0, kind: 0, external: false });
+    file.object.symbols.push(SymbolEntry { name: "strlen".to_string(), offset: 0, section: 0, kind: 0, external: true });
+    let library = LeafLibrary { members: vec![LibraryMember { name: "memcpy".to_string(), file }] };
+    assert!(library.member("memcpy").unwrap().defines("memcpy"));
+    assert!(!library.member("memcpy").unwrap().defines("strlen"));
+
+    let mut written = Vec::new();
+    library.write_to(&mut written).unwrap();
+    let read = LeafLibrary::read_from(&mut written.as_slice()).unwrap();
+    assert_eq!(read.members.len(), 1);
+    assert_eq!(read.members[0].name, "memcpy");
+    assert_eq!(read.members[0].file.object, library.members[0].file.object);
+
+    assert!(LeafLibrary::read_from(&mut &written[..written.len() - 1]).is_err());
+    written[0] = b'X';
+    assert!(matches!(LeafLibrary::read_from(&mut written.as_slice()), Err(LeafError::Format(FormatError::BadMagic(_)))));
+  }
+}
@@ -0,0 +1,290 @@
+//! Resource limits for decoding untrusted object files. Bincode allocates whatever a length prefix
+//! claims before reading a single element, so a few crafted bytes could ask for gigabytes. Before
+//! decoding, `check_limits` walks the encoded lengths of a `LeafAsmFile` and rejects any that is
+//! over its limit or longer than the rest of the input.
+use crate::error::{FormatError, LeafError};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DecodeLimits {
+  /// Largest whole file, in bytes.
+  pub max_file_size: usize,
+  /// Largest `.text`, `.data` or `.rodata`, in bytes.
+  pub max_section_size: usize,
+  /// Most symbols, and most exports.
+  pub max_symbols: usize,
+  pub max_relocations: usize,
+  /// Longest symbol, entry point or source file name, in bytes.
+  pub max_name_len: usize,
+  /// Most files or lines in the line table, and most data ranges in `.text`.
+  pub max_debug_entries: usize,
+}
+
+impl Default for DecodeLimits {
+  /// Far beyond anything the toolchain produces, but small enough to decode without trouble.
+  fn default() -> Self {
+    DecodeLimits {
+      max_file_size: 256 << 20,
+      max_section_size: 64 << 20,
+      max_symbols: 1 << 20,
+      max_relocations: 1 << 22,
+      max_name_len: 4096,
+      max_debug_entries: 1 << 22,
+    }
+  }
+}
+
+impl DecodeLimits {
+  /// No limits beyond the input itself: lengths must still fit in what is left of it.
+  pub fn unlimited() -> Self {
+    DecodeLimits {
+      max_file_size: usize::MAX,
+      max_section_size: usize::MAX,
+      max_symbols: usize::MAX,
+      max_relocations: usize::MAX,
+      max_name_len: usize::MAX,
+      max_debug_entries: usize::MAX,
+    }
+  }
+}
+
+/// Check the lengths in an encoded `LeafAsmFile` against `limits`. This mirrors the field order of
+/// `LeafAsmFile` in bincode's standard (varint) encoding, so it has to change with those types.
+pub fn check_limits(bytes: &[u8], limits: &DecodeLimits) -> Result<(), LeafError> {
+  if bytes.len() > limits.max_file_size {
+    return Err(too_large("file", bytes.len() as u64, limits.max_file_size));
+  }
+  let mut scan = Scanner { bytes, pos: 0, big_endian: false };
+  // Header: magic, version, byte order from version 3, ISA version, checksum, build-id from
+  // version 4; from version 2 the checksum is tagged with its algorithm, and a SHA-256 is 32 bytes
+  // rather than an integer
+  scan.skip(4)?;
+  let version = scan.varint()?;
+  if version >= 3 {
+    scan.big_endian = match scan.byte()? {
+      0 => false,
+      1 => true,
+      order => return Err(FormatError::Decode(format!("invalid byte order {}", order)).into()),
+    };
+  }
+  scan.varint()?;
+  match version {
+    0 | 1 => scan.varint().map(drop)?,
+    _ => match scan.varint()? {
+      0..=2 => scan.varint().map(drop)?,
+      3 => scan.skip(32)?,
+      tag => return Err(FormatError::Decode(format!("unknown checksum algorithm {}", tag)).into()),
+    },
+  }
+  if version >= 4 && scan.option()? {
+    scan.skip(16)?;
+  }
+  for section in [".text", ".data", ".rodata"] {
+    let len = scan.len(section, limits.max_section_size)?;
+    scan.skip(len)?;
+  }
+  for _ in 0..scan.len("symbol table", limits.max_symbols)? {
+    scan.string("symbol name", limits.max_name_len)?;
+    scan.varint()?;
+    // section, kind, external
+    scan.skip(3)?;
+  }
+  if scan.option()? {
+    scan.string("entry point", limits.max_name_len)?;
+  }
+  for _ in 0..scan.len("relocation table", limits.max_relocations)? {
+    // offset, symbol index, type
+    for _ in 0..3 {
+      scan.varint()?;
+    }
+    scan.skip(1)?;
+  }
+  if scan.option()? {
+    for _ in 0..scan.len("debug file table", limits.max_debug_entries)? {
+      scan.string("source file name", limits.max_name_len)?;
+    }
+    for _ in 0..scan.len("line table", limits.max_debug_entries)? {
+      for _ in 0..3 {
+        scan.varint()?;
+      }
+    }
+  }
+  // Objects before version 5 end here
+  if version < 5 {
+    return Ok(());
+  }
+  for _ in 0..scan.len("export table", limits.max_symbols)? {
+    scan.string("export name", limits.max_name_len)?;
+    scan.varint()?;
+    if scan.option()? {
+      scan.string("export signature", limits.max_name_len)?;
+    }
+  }
+  for _ in 0..scan.len("data range table", limits.max_debug_entries)? {
+    // offset, length
+    scan.varint()?;
+    scan.varint()?;
+  }
+  // One byte each for at most .text, .data and .rodata
+  let flags = scan.len("section flag table", 3)?;
+  scan.skip(flags)?;
+  // At most one segment per section
+  for _ in 0..scan.len("segment table", 3)? {
+    scan.skip(1)?;
+    // address, run address from version 7, file offset, file size, memory size
+    for _ in 0..if version >= 7 { 5 } else { 4 } {
+      scan.varint()?;
+    }
+    scan.skip(1)?;
+  }
+  for _ in 0..scan.len("addend table", limits.max_relocations)? {
+    // relocation, value
+    scan.varint()?;
+    scan.varint()?;
+  }
+  if version >= 6 {
+    for _ in 0..scan.len("section relocation table", limits.max_relocations)? {
+      scan.varint()?;
+    }
+  }
+  Ok(())
+}
+
+fn too_large(what: &'static str, len: u64, limit: usize) -> LeafError {
+  FormatError::TooLarge { what, len, limit }.into()
+}
+
+struct Scanner<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  /// Integers wider than a byte are big-endian.
+  big_endian: bool,
+}
+
+impl Scanner<'_> {
+  fn remaining(&self) -> usize {
+    self.bytes.len() - self.pos
+  }
+
+  fn skip(&mut self, n: usize) -> Result<(), LeafError> {
+    if n > self.remaining() {
+      return Err(FormatError::Decode(format!("unexpected end of input at byte {}", self.bytes.len())).into());
+    }
+    self.pos += n;
+    Ok(())
+  }
+
+  fn byte(&mut self) -> Result<u8, LeafError> {
+    self.skip(1)?;
+    Ok(self.bytes[self.pos - 1])
+  }
+
+  fn varint(&mut self) -> Result<u64, LeafError> {
+    let width = match self.byte()? {
+      byte @ 0..=250 => return Ok(byte as u64),
+      251 => 2,
+      252 => 4,
+      253 => 8,
+      byte => return Err(FormatError::Decode(format!("invalid integer tag {} at byte {}", byte, self.pos - 1)).into()),
+    };
+    self.skip(width)?;
+    let mut value = [0; 8];
+    let bytes = &self.bytes[self.pos - width..self.pos];
+    if self.big_endian {
+      value[8 - width..].copy_from_slice(bytes);
+      return Ok(u64::from_be_bytes(value));
+    }
+    value[..width].copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+  }
+
+  /// A length prefix: at most `limit`, and no more than the bytes left since every element takes
+  /// at least one.
+  fn len(&mut self, what: &'static str, limit: usize) -> Result<usize, LeafError> {
+    let len = self.varint()?;
+    if len > limit as u64 {
+      return Err(too_large(what, len, limit));
+    }
+    if len > self.remaining() as u64 {
+      return Err(FormatError::Decode(format!("{} claims {} entries but only {} bytes remain", what, len, self.remaining())).into());
+    }
+    Ok(len as usize)
+  }
+
+  fn string(&mut self, what: &'static str, limit: usize) -> Result<(), LeafError> {
+    let len = self.len(what, limit)?;
+    self.skip(len)
+  }
+
+  fn option(&mut self) -> Result<bool, LeafError> {
+    match self.byte()? {
+      0 => Ok(false),
+      1 => Ok(true),
+      tag => Err(FormatError::Decode(format!("invalid option tag {} at byte {}", tag, self.pos - 1)).into()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::checksum::Checksum;
+  use crate::leaf_file::{Addend, ByteOrder, DataRange, DebugInfo, ExportEntry, LeafAsmFile, LeafAsmObject, LeafAsmObjectHeader, LineEntry, RelocationEntry, RelocationType, SectionFlags, Segment, SymbolEntry};
+
+  fn encode(file: &LeafAsmFile) -> Vec<u8> {
+    bincode::encode_to_vec(file, bincode::config::standard()).unwrap()
+  }
+
+  #[test]
+  fn walks_every_field_and_rejects_oversized_lengths() {
+    let file = LeafAsmFile {
+      header: LeafAsmObjectHeader { magic: *b"LAF\0", version: 7, byte_order: ByteOrder::Little, isa_version: 1, checksum: Checksum::Crc32(0xDEADBEEF), build_id: None },
+      object: LeafAsmObject {
+        bytecode: vec![0x90; 300],
+        data: vec![1; 70000],
+        rodata: vec![],
+        symbols: vec![SymbolEntry { name: "main".to_string(), offset: 1 << 20, section: 0, kind: 0, external: false }],
+        entry_point: Some("main".to_string()),
+        relocations: vec![RelocationEntry { offset: 5, symbol_index: 0, reloc_type: RelocationType::Relative, target_section: 0 }],
+        debug_info: Some(DebugInfo {
+          files: vec!["main.leaf".to_string()],
+          lines: vec![LineEntry { offset: 0, file: 0, line: 300 }],
+        }),
+        exports: vec![ExportEntry { name: "main".to_string(), address: 1 << 20, signature: Some("() -> r0".to_string()) }],
+        data_in_text: vec![DataRange { offset: 1 << 20, len: 300 }],
+        section_flags: vec![SectionFlags::READ; 3],
+        segments: vec![Segment { section: 1, address: 300, run_address: 1 << 16, file_offset: 305, file_size: 70000, mem_size: 1 << 20, flags: SectionFlags::READ }],
+        addends: vec![Addend { relocation: 0, value: -70000 }],
+        section_relocations: vec![0],
+      },
+    };
+    let bytes = encode(&file);
+    assert!(check_limits(&bytes, &DecodeLimits::default()).is_ok());
+    // Any truncation is caught before bincode sees it
+    assert!((0..bytes.len()).all(|len| check_limits(&bytes[..len], &DecodeLimits::unlimited()).is_err()));
+
+    let limits = DecodeLimits { max_section_size: 0x1000, ..DecodeLimits::default() };
+    let err = check_limits(&bytes, &limits).unwrap_err();
+    assert!(matches!(err, LeafError::Format(FormatError::TooLarge { what: ".data", len: 70000, limit: 0x1000 })));
+
+    // A .text that claims 4 GiB right after the 14-byte header
+    let mut forged = bytes[..14].to_vec();
+    forged.extend_from_slice(&[252, 0xFF, 0xFF, 0xFF, 0xFF]);
+    let err = check_limits(&forged, &DecodeLimits::unlimited()).unwrap_err();
+    assert!(matches!(err, LeafError::Format(FormatError::Decode(_))));
+
+    // Before version 7 segments have no run address
+    let mut unmoved = file.clone();
+    unmoved.header.version = 6;
+    unmoved.object.segments[0].run_address = 300;
+    let bytes = encode(&unmoved);
+    assert!(check_limits(&bytes, &DecodeLimits::default()).is_ok());
+    assert!((0..bytes.len()).all(|len| check_limits(&bytes[..len], &DecodeLimits::unlimited()).is_err()));
+
+    // Before version 5 the object ends after the debug info
+    let mut file = LeafAsmFile { object: LeafAsmObject { exports: vec![], data_in_text: vec![], section_flags: vec![], segments: vec![], addends: vec![], section_relocations: vec![], ..file.object }, ..file };
+    file.header.version = 4;
+    let bytes = encode(&file);
+    assert!(check_limits(&bytes, &DecodeLimits::default()).is_ok());
+    assert!((0..bytes.len()).all(|len| check_limits(&bytes[..len], &DecodeLimits::unlimited()).is_err()));
+  }
+}
@@ -0,0 +1,511 @@
+//! Checked construction of `LeafAsmObject`s, so hand-built objects (tests, code generators) cannot
+//! be silently malformed.
+//!
+//! ```
+//! use leaf_common::object_builder::LeafAsmObjectBuilder;
+//!
+//! let object = LeafAsmObjectBuilder::new()
+//!   .text(vec![0x0F, 0, 0, 0, 0, 0x13]) // CALL helper; HALT
+//!   .define("main", 0, 0)
+//!   .external("helper")
+//!   .absolute_relocation(1, "helper", 0)
+//!   .entry_point("main")
+//!   .build()
+//!   .unwrap();
+//! assert_eq!(object.relocations[0].symbol_index, 1);
+//! ```
+use std::fmt;
+use std::collections::HashSet;
+use crate::leaf_file::{Addend, ABSOLUTE_SECTION, DebugInfo, LeafAsmObject, RelocationEntry, RelocationType, SectionFlags, Segment, SymbolEntry};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ObjectError {
+  /// A section number other than 0 (.text), 1 (.data) or 2 (.rodata).
+  InvalidSection(u8),
+  SymbolOutOfBounds { name: String, offset: u32, section_len: usize },
+  DuplicateSymbol(String),
+  /// A relocation names a symbol that is not in the symbol table.
+  UnknownSymbol(String),
+  BadSymbolIndex { index: u32, symbols: usize },
+  RelocationOutOfBounds { offset: u32, section: u8, section_len: usize },
+  /// A data range that runs past the end of `.text`, or starts before the one before it ends.
+  BadDataRange { offset: u32, len: u32 },
+  /// A segment that does not cover exactly its section's bytes with its flags, repeats a section
+  /// or starts before the one before it ends.
+  BadSegment { section: u8 },
+  /// An addend for a relocation that does not exist, or out of relocation order.
+  BadAddend { relocation: u32 },
+  /// A section relocation that does not exist, or out of relocation order.
+  BadSectionRelocation { relocation: u32 },
+}
+
+impl fmt::Display for ObjectError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ObjectError::InvalidSection(section) => write!(f, "invalid section {}", section),
+      ObjectError::SymbolOutOfBounds { name, offset, section_len } =>
+        write!(f, "symbol '{}' at offset {} is outside its section (size {})", name, offset, section_len),
+      ObjectError::DuplicateSymbol(name) => write!(f, "symbol '{}' is defined more than once", name),
+      ObjectError::UnknownSymbol(name) => write!(f, "relocation refers to unknown symbol '{}'", name),
+      ObjectError::BadSymbolIndex { index, symbols } =>
+        write!(f, "relocation symbol index {} out of range ({} symbols)", index, symbols),
+      ObjectError::RelocationOutOfBounds { offset, section, section_len } =>
+        write!(f, "relocation at offset {} does not fit in section {} (size {})", offset, section, section_len),
+      ObjectError::BadDataRange { offset, len } =>
+        write!(f, "data range at .text offset {} of {} bytes overlaps another or ends outside .text", offset, len),
+      ObjectError::BadSegment { section } =>
+        write!(f, "segment for section {} does not match the section or overlaps another", section),
+      ObjectError::BadAddend { relocation } =>
+        write!(f, "addend for relocation {} is out of order or has no relocation", relocation),
+      ObjectError::BadSectionRelocation { relocation } =>
+        write!(f, "section relocation {} is out of order or has no relocation", relocation),
+    }
+  }
+}
+
+impl std::error::Error for ObjectError {}
+
+/// Everything wrong with an object, from `LeafAsmObject::check`.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct ValidationReport {
+  pub problems: Vec<ObjectError>,
+}
+
+impl ValidationReport {
+  pub fn is_valid(&self) -> bool {
+    self.problems.is_empty()
+  }
+
+  /// `Ok` if there are no problems, else the report as the error.
+  pub fn into_result(self) -> Result<(), ValidationReport> {
+    if self.is_valid() { Ok(()) } else { Err(self) }
+  }
+}
+
+impl fmt::Display for ValidationReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let count = self.problems.len();
+    write!(f, "{} problem{}", count, if count == 1 { "" } else { "s" })?;
+    for (i, problem) in self.problems.iter().enumerate() {
+      write!(f, "{} {}", if i == 0 { ":" } else { ";" }, problem)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for ValidationReport {}
+
+impl LeafAsmObject {
+  /// Length of section 0, 1 or 2.
+  pub fn section_len(&self, section: u8) -> Option<usize> {
+    match section {
+      0 => Some(self.bytecode.len()),
+      1 => Some(self.data.len()),
+      2 => Some(self.rodata.len()),
+      _ => None,
+    }
+  }
+
+  /// Permissions of section 0, 1 or 2: what the object records, or `SectionFlags::default_for` the
+  /// section if it records nothing, as objects from before section flags do.
+  pub fn flags(&self, section: u8) -> SectionFlags {
+    self.section_flags.get(section as usize).copied().unwrap_or_else(|| SectionFlags::default_for(section))
+  }
+
+  /// The addend of relocation `relocation`, or 0 if it has none.
+  pub fn addend(&self, relocation: usize) -> i32 {
+    self.addends.binary_search_by_key(&(relocation as u32), |addend| addend.relocation)
+      .map_or(0, |index| self.addends[index].value)
+  }
+
+  /// The section of this object relocation `relocation` refers to, if it is one of the
+  /// `section_relocations`, or `None` if it refers to a symbol.
+  pub fn section_target(&self, relocation: usize) -> Option<u8> {
+    self.section_relocations.binary_search(&(relocation as u32)).ok()
+      .and_then(|_| self.relocations.get(relocation))
+      .map(|reloc| reloc.symbol_index.min(u8::MAX as u32) as u8)
+  }
+
+  /// Offset of the bytes of section 0, 1 or 2 in the encoding of the object, which follows the file
+  /// header. Each section is its length, as a varint, and then its bytes.
+  pub fn file_offset(&self, section: u8) -> Option<u32> {
+    let mut offset = 0;
+    for before in 0..section {
+      let len = self.section_len(before)?;
+      offset += varint_len(len) + len;
+    }
+    Some((offset + varint_len(self.section_len(section)?)) as u32)
+  }
+
+  /// The segments of the layout linked executables are patched for: `.text` at 0, then `.data`,
+  /// then `.rodata`, with nothing between them.
+  pub fn packed_segments(&self) -> Vec<Segment> {
+    let mut address = 0;
+    let mut segments = Vec::new();
+    for section in 0..3 {
+      let len = self.section_len(section).unwrap_or(0) as u32;
+      if len > 0 {
+        let file_offset = self.file_offset(section).unwrap_or(0);
+        segments.push(Segment { section, address, run_address: address, file_offset, file_size: len, mem_size: len, flags: self.flags(section) });
+      }
+      address += len;
+    }
+    segments
+  }
+
+  /// Check that every symbol lies inside its section or is absolute, defined names are unique and every relocation
+  /// refers to an existing symbol or section and patches 4 bytes inside its section, that addends
+  /// and section relocations are in relocation order and each has a relocation, that data ranges
+  /// are ordered and inside `.text`, that there are flags for no more than three sections and that
+  /// each segment loads one whole section, in address order. Returns the first problem; `check`
+  /// finds them all.
+  pub fn validate(&self) -> Result<(), ObjectError> {
+    self.check().problems.into_iter().next().map_or(Ok(()), Err)
+  }
+
+  /// Every problem `validate` looks for, in symbol table, relocation table then data range order.
+  pub fn check(&self) -> ValidationReport {
+    let mut problems = Vec::new();
+    let mut defined = HashSet::new();
+    for symbol in self.symbols.iter().filter(|s| !s.external) {
+      match self.section_len(symbol.section) {
+        None if symbol.section == ABSOLUTE_SECTION => {}
+        None => problems.push(ObjectError::InvalidSection(symbol.section)),
+        Some(section_len) if symbol.offset as usize > section_len => problems.push(ObjectError::SymbolOutOfBounds {
+          name: symbol.name.clone(),
+          offset: symbol.offset,
+          section_len,
+        }),
+        Some(_) => {}
+      }
+      if !defined.insert(symbol.name.as_str()) {
+        problems.push(ObjectError::DuplicateSymbol(symbol.name.clone()));
+      }
+    }
+
+    for (index, reloc) in self.relocations.iter().enumerate() {
+      match self.section_target(index) {
+        Some(section) if self.section_len(section).is_none() => problems.push(ObjectError::InvalidSection(section)),
+        Some(_) => {}
+        None if reloc.symbol_index as usize >= self.symbols.len() => {
+          problems.push(ObjectError::BadSymbolIndex { index: reloc.symbol_index, symbols: self.symbols.len() });
+        }
+        None => {}
+      }
+      match self.section_len(reloc.target_section) {
+        None => problems.push(ObjectError::InvalidSection(reloc.target_section)),
+        Some(section_len) if reloc.offset as usize + 4 > section_len => problems.push(ObjectError::RelocationOutOfBounds {
+          offset: reloc.offset,
+          section: reloc.target_section,
+          section_len,
+        }),
+        Some(_) => {}
+      }
+    }
+
+    let mut next = 0;
+    for addend in &self.addends {
+      if addend.relocation < next || addend.relocation as usize >= self.relocations.len() {
+        problems.push(ObjectError::BadAddend { relocation: addend.relocation });
+      }
+      next = addend.relocation.saturating_add(1);
+    }
+    let mut next = 0;
+    for &relocation in &self.section_relocations {
+      if relocation < next || relocation as usize >= self.relocations.len() {
+        problems.push(ObjectError::BadSectionRelocation { relocation });
+      }
+      next = relocation.saturating_add(1);
+    }
+
+    let mut end = 0;
+    for range in &self.data_in_text {
+      let fits = range.offset.checked_add(range.len).is_some_and(|range_end| range_end as usize <= self.bytecode.len());
+      if range.offset < end || !fits {
+        problems.push(ObjectError::BadDataRange { offset: range.offset, len: range.len });
+      }
+      end = range.offset.saturating_add(range.len);
+    }
+    if self.section_flags.len() > 3 {
+      problems.push(ObjectError::InvalidSection(3));
+    }
+    self.check_segments(&mut problems);
+    ValidationReport { problems }
+  }
+
+  /// The segment checks of `validate` alone, for loaders that trust the rest of the object.
+  pub fn validate_segments(&self) -> Result<(), ObjectError> {
+    let mut problems = Vec::new();
+    self.check_segments(&mut problems);
+    problems.into_iter().next().map_or(Ok(()), Err)
+  }
+
+  fn check_segments(&self, problems: &mut Vec<ObjectError>) {
+    let mut end = 0u64;
+    let mut loaded = HashSet::new();
+    for segment in &self.segments {
+      let matches = self.section_len(segment.section).is_some_and(|len| len as u32 == segment.file_size)
+        && self.file_offset(segment.section) == Some(segment.file_offset)
+        && segment.mem_size >= segment.file_size
+        && self.flags(segment.section) == segment.flags;
+      if !matches || !loaded.insert(segment.section) || (segment.address as u64) < end {
+        problems.push(ObjectError::BadSegment { section: segment.section });
+      }
+      end = segment.address as u64 + segment.mem_size as u64;
+    }
+  }
+}
+
+/// Bytes bincode's varint encoding takes for `n`.
+fn varint_len(n: usize) -> usize {
+  match n {
+    0..=250 => 1,
+    251..=0xFFFF => 3,
+    0x1_0000..=0xFFFF_FFFF => 5,
+    _ => 9,
+  }
+}
+
+/// Relocation recorded by symbol name; resolved to an index in `build`.
+#[derive(Debug, Clone)]
+struct PendingRelocation {
+  offset: u32,
+  symbol: String,
+  reloc_type: RelocationType,
+  target_section: u8,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LeafAsmObjectBuilder {
+  object: LeafAsmObject,
+  pending: Vec<PendingRelocation>,
+}
+
+impl LeafAsmObjectBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn text(mut self, bytes: Vec<u8>) -> Self {
+    self.object.bytecode = bytes;
+    self
+  }
+
+  pub fn data(mut self, bytes: Vec<u8>) -> Self {
+    self.object.data = bytes;
+    self
+  }
+
+  pub fn rodata(mut self, bytes: Vec<u8>) -> Self {
+    self.object.rodata = bytes;
+    self
+  }
+
+  /// Add a raw symbol table entry.
+  pub fn symbol(mut self, symbol: SymbolEntry) -> Self {
+    self.object.symbols.push(symbol);
+    self
+  }
+
+  /// Define `name` at `offset` in `section`.
+  pub fn define(self, name: &str, section: u8, offset: u32) -> Self {
+    self.symbol(SymbolEntry { name: name.to_string(), offset, section, kind: section, external: false })
+  }
+
+  /// Declare `name` as defined in another object.
+  pub fn external(self, name: &str) -> Self {
+    self.symbol(SymbolEntry { name: name.to_string(), offset: 0, section: 0, kind: 0, external: true })
+  }
+
+  /// Add a raw relocation entry, referring to a symbol by index.
+  pub fn relocation(mut self, reloc: RelocationEntry) -> Self {
+    self.object.relocations.push(reloc);
+    self
+  }
+
+  /// Add `value` to the address relocation `relocation`, counted from 0 in the order relocations
+  /// end up in the object, patches in. Addends have to be added in that order.
+  pub fn addend(mut self, relocation: u32, value: i32) -> Self {
+    self.object.addends.push(Addend { relocation, value });
+    self
+  }
+
+  /// Patch the 4 bytes at `offset` of `target_section` with a relocation of this object's own
+  /// `section`, as the assembler writes for labels no other object sees. The offset in `section`
+  /// is the relocation's addend.
+  pub fn section_relocation(mut self, offset: u32, section: u8, reloc_type: RelocationType, target_section: u8) -> Self {
+    self.object.section_relocations.push(self.object.relocations.len() as u32);
+    self.object.relocations.push(RelocationEntry { offset, symbol_index: section as u32, reloc_type, target_section });
+    self
+  }
+
+  /// Patch the 4 bytes at `offset` of `section` with the absolute address of `symbol`.
+  pub fn absolute_relocation(self, offset: u32, symbol: &str, section: u8) -> Self {
+    self.named_relocation(offset, symbol, RelocationType::Absolute, section)
+  }
+
+  /// Patch the 4 bytes at `offset` of `section` with the distance from the end of the field to `symbol`.
+  pub fn relative_relocation(self, offset: u32, symbol: &str, section: u8) -> Self {
+    self.named_relocation(offset, symbol, RelocationType::Relative, section)
+  }
+
+  /// Patch the 4 bytes at `offset` of `section` with a relocation of `symbol` of any type.
+  pub fn named_relocation(mut self, offset: u32, symbol: &str, reloc_type: RelocationType, target_section: u8) -> Self {
+    self.pending.push(PendingRelocation { offset, symbol: symbol.to_string(), reloc_type, target_section });
+    self
+  }
+
+  /// Give `section` permissions other than its defaults.
+  pub fn section_flags(mut self, section: u8, flags: SectionFlags) -> Self {
+    let object = &mut self.object;
+    while object.section_flags.len() <= section as usize {
+      object.section_flags.push(SectionFlags::default_for(object.section_flags.len() as u8));
+    }
+    object.section_flags[section as usize] = flags;
+    self
+  }
+
+  pub fn entry_point(mut self, name: &str) -> Self {
+    self.object.entry_point = Some(name.to_string());
+    self
+  }
+
+  pub fn debug_info(mut self, debug_info: DebugInfo) -> Self {
+    self.object.debug_info = Some(debug_info);
+    self
+  }
+
+  pub fn build(self) -> Result<LeafAsmObject, ObjectError> {
+    let mut object = self.object;
+    for reloc in self.pending {
+      let index = object.symbols.iter()
+        .position(|s| s.name == reloc.symbol)
+        .ok_or_else(|| ObjectError::UnknownSymbol(reloc.symbol.clone()))?;
+      object.relocations.push(RelocationEntry {
+        offset: reloc.offset,
+        symbol_index: index as u32,
+        reloc_type: reloc.reloc_type,
+        target_section: reloc.target_section,
+      });
+    }
+    object.validate()?;
+    Ok(object)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rejects_malformed_objects() {
+    let duplicate = LeafAsmObjectBuilder::new().text(vec![0; 4]).define("a", 0, 0).define("a", 0, 2).build();
+    assert_eq!(duplicate.unwrap_err(), ObjectError::DuplicateSymbol("a".to_string()));
+
+    let outside = LeafAsmObjectBuilder::new().data(vec![0; 8]).define("buf", 1, 9).build();
+    assert!(matches!(outside.unwrap_err(), ObjectError::SymbolOutOfBounds { offset: 9, .. }));
+
+    let unknown = LeafAsmObjectBuilder::new().text(vec![0; 5]).absolute_relocation(1, "nope", 0).build();
+    assert_eq!(unknown.unwrap_err(), ObjectError::UnknownSymbol("nope".to_string()));
+
+    let past_end = LeafAsmObjectBuilder::new().text(vec![0; 5]).external("f").absolute_relocation(2, "f", 0).build();
+    assert!(matches!(past_end.unwrap_err(), ObjectError::RelocationOutOfBounds { offset: 2, section: 0, .. }));
+
+    let bad_index = LeafAsmObjectBuilder::new()
+      .text(vec![0; 5])
+      .relocation(RelocationEntry { offset: 1, symbol_index: 3, reloc_type: RelocationType::Absolute, target_section: 0 })
+      .build();
+    assert_eq!(bad_index.unwrap_err(), ObjectError::BadSymbolIndex { index: 3, symbols: 0 });
+
+    let fourth = LeafAsmObjectBuilder::new().section_flags(3, SectionFlags::READ).build();
+    assert_eq!(fourth.unwrap_err(), ObjectError::InvalidSection(3));
+  }
+
+  #[test]
+  fn sections_without_flags_get_the_defaults() {
+    let object = LeafAsmObjectBuilder::new().section_flags(1, SectionFlags::READ).build().unwrap();
+    assert_eq!(object.section_flags.len(), 2);
+    assert_eq!(object.flags(0).to_string(), "r-x");
+    assert_eq!(object.flags(1).to_string(), "r--");
+    assert_eq!(object.flags(2).to_string(), "r--");
+    assert_eq!(LeafAsmObject::default().flags(1).to_string(), "rw-");
+    assert_eq!(SectionFlags::parse("xr"), Some(SectionFlags::READ | SectionFlags::EXECUTE));
+    assert_eq!(SectionFlags::parse("rq"), None);
+  }
+
+  #[test]
+  fn packed_segments_point_at_the_encoded_sections() {
+    let object = LeafAsmObjectBuilder::new().text(vec![0x13; 300]).rodata(b"hi".to_vec()).build().unwrap();
+    let segments = object.packed_segments();
+    let text = Segment { section: 0, address: 0, run_address: 0, file_offset: 3, file_size: 300, mem_size: 300, flags: object.flags(0) };
+    let rodata = Segment { section: 2, address: 300, run_address: 300, file_offset: 3 + 300 + 1 + 1, file_size: 2, mem_size: 2, flags: object.flags(2) };
+    assert_eq!(segments, vec![text.clone(), rodata.clone()]);
+    let encoded = bincode::encode_to_vec(&object, bincode::config::standard()).unwrap();
+    assert_eq!(&encoded[rodata.file_offset as usize..][..2], b"hi");
+
+    let mut object = LeafAsmObject { segments, ..object };
+    assert!(object.validate().is_ok());
+    object.segments[1].address = 200;
+    assert_eq!(object.validate(), Err(ObjectError::BadSegment { section: 2 }));
+    object.segments[1] = Segment { file_size: 1, ..rodata };
+    assert_eq!(object.validate(), Err(ObjectError::BadSegment { section: 2 }));
+  }
+
+  #[test]
+  fn addends_belong_to_relocations_in_order() {
+    let builder = LeafAsmObjectBuilder::new()
+      .text(vec![0; 10])
+      .define("loop", 0, 0)
+      .absolute_relocation(1, "loop", 0)
+      .absolute_relocation(6, "loop", 0);
+    let object = builder.clone().addend(1, 8).build().unwrap();
+    assert_eq!((object.addend(0), object.addend(1)), (0, 8));
+
+    let missing = builder.clone().addend(2, 8).build();
+    assert_eq!(missing.unwrap_err(), ObjectError::BadAddend { relocation: 2 });
+    let unordered = builder.addend(1, 8).addend(0, 4).build();
+    assert_eq!(unordered.unwrap_err(), ObjectError::BadAddend { relocation: 0 });
+  }
+
+  #[test]
+  fn section_relocations_refer_to_sections() {
+    let builder = LeafAsmObjectBuilder::new()
+      .text(vec![0; 10])
+      .data(vec![0; 8])
+      .section_relocation(1, 1, RelocationType::Absolute, 0)
+      .addend(0, 4);
+    let object = builder.clone().build().unwrap();
+    assert_eq!((object.section_target(0), object.addend(0)), (Some(1), 4));
+    assert!(object.symbols.is_empty());
+
+    let unknown = builder.section_relocation(6, 3, RelocationType::Absolute, 0).build();
+    assert_eq!(unknown.unwrap_err(), ObjectError::InvalidSection(3));
+    let mut object = LeafAsmObjectBuilder::new().text(vec![0; 10]).define("a", 0, 0).absolute_relocation(1, "a", 0).build().unwrap();
+    assert_eq!(object.section_target(0), None);
+    object.section_relocations = vec![1];
+    assert_eq!(object.validate(), Err(ObjectError::BadSectionRelocation { relocation: 1 }));
+  }
+
+  #[test]
+  fn check_reports_every_problem() {
+    let mut object = LeafAsmObjectBuilder::new().text(vec![0; 5]).define("a", 0, 0).build().unwrap();
+    object.symbols.push(SymbolEntry { name: "a".to_string(), offset: 9, section: 0, kind: 0, external: false });
+    object.relocations.push(RelocationEntry { offset: 3, symbol_index: 7, reloc_type: RelocationType::Absolute, target_section: 4 });
+    let report = object.check();
+    assert_eq!(report.problems, vec![
+      ObjectError::SymbolOutOfBounds { name: "a".to_string(), offset: 9, section_len: 5 },
+      ObjectError::DuplicateSymbol("a".to_string()),
+      ObjectError::BadSymbolIndex { index: 7, symbols: 2 },
+      ObjectError::InvalidSection(4),
+    ]);
+    assert!(report.to_string().starts_with("4 problems: symbol 'a' at offset 9"));
+    assert_eq!(object.validate(), Err(report.problems[0].clone()));
+  }
+
+  #[test]
+  fn extern_and_definition_of_same_name_are_allowed() {
+    // An extern and a definition of the same name in one object are allowed (the definition wins at link time)
+    let object = LeafAsmObjectBuilder::new().text(vec![0; 5]).external("f").define("f", 0, 0).build();
+    assert!(object.is_ok());
+  }
+}
@@ -0,0 +1,242 @@
+//! The single source of truth for opcode encodings (LDR-003): every opcode's byte, mnemonic and
+//! operand count. The parser, assembler, disassembler and VM all go through this table, and fall
+//! back to the extension instructions installed from an ISA description (`isa`).
+use std::fmt;
+use crate::isa::{self, OperandKind};
+
+/// The newest ISA revision, which the toolchain and VM implement. Revision 1 is the original
+/// instruction set; revision 2 added `LT`, `GT` and `EQ`, and revision 3 addresses with an offset,
+/// `[r1+8]`, or a scaled index, `[r1+r2*8]`, for `LOAD` and `STORE` (see `AddressOperand`).
+pub const ISA_VERSION: u16 = 3;
+
+/// ISA revision that introduced the offset and index of `AddressOperand`.
+pub const ADDRESS_MODES_SINCE: u16 = 3;
+
+/// The address operand of `LOAD` and `STORE`: a base register, plus an index register times a
+/// scale of 1, 2, 4 or 8, plus an offset.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct AddressOperand {
+  pub base: u8,
+  /// Index register and scale.
+  pub index: Option<(u8, u8)>,
+  pub offset: i16,
+}
+
+impl AddressOperand {
+  /// Read the four operand bytes. The base register is the first, as for any register operand. The
+  /// second is 0, or the index register with the top bit set and the log2 of its scale in the two
+  /// bits below, and the last two are the signed offset.
+  pub fn decode(bytes: [u8; 4]) -> Self {
+    let index = (bytes[1] & 0x80 != 0).then(|| (bytes[1] & 0x1F, 1 << ((bytes[1] >> 5) & 3)));
+    AddressOperand { base: bytes[0], index, offset: i16::from_le_bytes([bytes[2], bytes[3]]) }
+  }
+
+  /// The bytes `decode` reads back. The scale has to be 1, 2, 4 or 8.
+  pub fn encode(self) -> [u8; 4] {
+    let index = self.index.map_or(0, |(index, scale)| 0x80 | (scale.trailing_zeros() as u8) << 5 | index);
+    let [low, high] = self.offset.to_le_bytes();
+    [self.base, index, low, high]
+  }
+}
+
+impl fmt::Display for AddressOperand {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "r{}", self.base)?;
+    match self.index {
+      Some((index, 1)) => write!(f, "+r{}", index)?,
+      Some((index, scale)) => write!(f, "+r{}*{}", index, scale)?,
+      None => {}
+    }
+    if self.offset != 0 {
+      write!(f, "{:+}", self.offset)?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum OpCode {
+  Add, Mul, Sub, Div,
+  And, Or, Xor, Not,
+  Lt, Gt, Eq,
+  Jmp, Jz, Jnz,
+  Mov, Load, Store,
+  Movi, Loadi, Storei,
+  Call, Ret,
+  Push, Pop,
+  Halt, Break,
+  Syscall, Nop,
+  Invalid,
+  /// An extension instruction installed with `IsaExtension::install`, by its byte.
+  Ext(u8),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct OpInfo {
+  pub opcode: OpCode,
+  pub byte: u8,
+  pub mnemonic: &'static str,
+  /// Number of 4-byte operands following the opcode byte.
+  pub operands: u8,
+  /// ISA revision that introduced the instruction.
+  pub since: u16,
+}
+
+impl OpInfo {
+  /// Encoded size of the instruction in bytes.
+  pub const fn size(&self) -> usize {
+    1 + 4 * self.operands as usize
+  }
+
+  const fn since(self, since: u16) -> OpInfo {
+    OpInfo { since, ..self }
+  }
+}
+
+const fn op(opcode: OpCode, byte: u8, mnemonic: &'static str, operands: u8) -> OpInfo {
+  OpInfo { opcode, byte, mnemonic, operands, since: 1 }
+}
+
+/// Every valid opcode, in encoding order. `Invalid` has no entry.
+pub const OPCODES: &[OpInfo] = &[
+  op(OpCode::Nop, 0x00, "NOP", 0),
+  op(OpCode::Add, 0x01, "ADD", 3),
+  op(OpCode::Sub, 0x02, "SUB", 3),
+  op(OpCode::Mul, 0x03, "MUL", 3),
+  op(OpCode::Div, 0x04, "DIV", 3),
+  op(OpCode::And, 0x05, "AND", 3),
+  op(OpCode::Or, 0x06, "OR", 3),
+  op(OpCode::Xor, 0x07, "XOR", 3),
+  op(OpCode::Not, 0x08, "NOT", 2),
+  op(OpCode::Jmp, 0x09, "JMP", 1),
+  op(OpCode::Jz, 0x0A, "JZ", 2),
+  op(OpCode::Jnz, 0x0B, "JNZ", 2),
+  op(OpCode::Mov, 0x0C, "MOV", 2),
+  op(OpCode::Load, 0x0D, "LOAD", 2),
+  op(OpCode::Store, 0x0E, "STORE", 2),
+  op(OpCode::Call, 0x0F, "CALL", 1),
+  op(OpCode::Ret, 0x10, "RET", 0),
+  op(OpCode::Push, 0x11, "PUSH", 1),
+  op(OpCode::Pop, 0x12, "POP", 1),
+  op(OpCode::Halt, 0x13, "HALT", 0),
+  op(OpCode::Break, 0x14, "BREAK", 0),
+  op(OpCode::Syscall, 0x15, "SYSCALL", 0),
+  op(OpCode::Movi, 0x16, "MOVI", 2),
+  op(OpCode::Loadi, 0x17, "LOADI", 2),
+  op(OpCode::Storei, 0x18, "STOREI", 2),
+  op(OpCode::Lt, 0x19, "LT", 3).since(2),
+  op(OpCode::Gt, 0x1A, "GT", 3).since(2),
+  op(OpCode::Eq, 0x1B, "EQ", 3).since(2),
+];
+
+/// Byte to table entry, so decoding in the VM's hot loop is a single index.
+const DECODE: [Option<&OpInfo>; 256] = {
+  let mut table = [None; 256];
+  let mut i = 0;
+  while i < OPCODES.len() {
+    table[OPCODES[i].byte as usize] = Some(&OPCODES[i]);
+    i += 1;
+  }
+  table
+};
+
+impl OpCode {
+  /// Table entry for this opcode; `None` for `Invalid` and extension bytes that are not installed.
+  pub fn info(&self) -> Option<&'static OpInfo> {
+    match self {
+      OpCode::Ext(byte) => isa::by_byte(*byte).map(|ext| &ext.info),
+      _ => OPCODES.iter().find(|info| info.opcode == *self),
+    }
+  }
+
+  pub fn decode(byte: u8) -> Option<&'static OpInfo> {
+    DECODE[byte as usize].or_else(|| isa::by_byte(byte).map(|ext| &ext.info))
+  }
+
+  pub fn opcode_to_byte(opcode: &OpCode) -> u8 {
+    opcode.info().map_or(0xFF, |info| info.byte)
+  }
+
+  pub fn byte_to_opcode(byte: u8) -> Option<OpCode> {
+    Self::decode(byte).map(|info| info.opcode)
+  }
+
+  pub fn from_mnemonic(mnemonic: &str) -> Option<OpCode> {
+    OPCODES.iter().find(|info| info.mnemonic == mnemonic).map(|info| info.opcode)
+      .or_else(|| isa::by_mnemonic(mnemonic).map(|ext| ext.info.opcode))
+  }
+
+  pub fn mnemonic(&self) -> &'static str {
+    self.info().map_or("INVALID", |info| info.mnemonic)
+  }
+
+  pub fn operand_count(&self) -> usize {
+    self.info().map_or(0, |info| info.operands as usize)
+  }
+
+  /// What each operand is. The address of `LOAD` and `STORE` is a register, and that of `LOADI`
+  /// and `STOREI` an immediate.
+  pub fn operand_kinds(&self) -> Vec<OperandKind> {
+    use OperandKind::{Immediate as I, Register as R};
+    match self {
+      OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div |
+      OpCode::And | OpCode::Or | OpCode::Xor |
+      OpCode::Lt | OpCode::Gt | OpCode::Eq => vec![R, R, R],
+      OpCode::Mov | OpCode::Not | OpCode::Load | OpCode::Store => vec![R, R],
+      OpCode::Movi | OpCode::Jz | OpCode::Jnz | OpCode::Loadi | OpCode::Storei => vec![R, I],
+      OpCode::Jmp | OpCode::Call => vec![I],
+      OpCode::Push | OpCode::Pop => vec![R],
+      OpCode::Ret | OpCode::Break | OpCode::Halt | OpCode::Syscall | OpCode::Nop | OpCode::Invalid => vec![],
+      OpCode::Ext(byte) => isa::by_byte(*byte).map(|ext| ext.operands.clone()).unwrap_or_default(),
+    }
+  }
+
+  /// Which operand is a code address the instruction may jump to, for `JMP`, `JZ`, `JNZ` and `CALL`.
+  pub fn branch_operand(&self) -> Option<usize> {
+    match self {
+      OpCode::Jmp | OpCode::Call => Some(0),
+      OpCode::Jz | OpCode::Jnz => Some(1),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for OpCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.mnemonic())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn table_round_trips() {
+    for info in OPCODES {
+      assert_eq!(OpCode::byte_to_opcode(info.byte), Some(info.opcode));
+      assert_eq!(OpCode::opcode_to_byte(&info.opcode), info.byte);
+      assert_eq!(OpCode::from_mnemonic(info.mnemonic), Some(info.opcode));
+    }
+    assert_eq!(OpCode::byte_to_opcode(0xFF), None);
+    assert_eq!(OpCode::opcode_to_byte(&OpCode::Invalid), 0xFF);
+    for info in OPCODES {
+      assert_eq!(info.opcode.operand_kinds().len(), info.operands as usize, "{}", info.mnemonic);
+    }
+  }
+
+  #[test]
+  fn address_operands_round_trip() {
+    for operand in [
+      AddressOperand { base: 7, index: None, offset: 0 },
+      AddressOperand { base: 7, index: None, offset: -4 },
+      AddressOperand { base: 1, index: Some((31, 8)), offset: 0 },
+      AddressOperand { base: 1, index: Some((2, 1)), offset: 16 },
+    ] {
+      assert_eq!(AddressOperand::decode(operand.encode()), operand);
+    }
+    // A plain register operand, as revision 1 encodes it
+    assert_eq!(AddressOperand::decode([3, 0, 0, 0]), AddressOperand { base: 3, index: None, offset: 0 });
+    assert_eq!(AddressOperand { base: 1, index: Some((2, 4)), offset: -8 }.to_string(), "r1+r2*4-8");
+  }
+}
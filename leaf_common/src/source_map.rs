@@ -0,0 +1,127 @@
+//! Source maps from compilers that target leaf, so the line table of an assembled object can point
+//! at the compiler's own source instead of the assembly it generated. A source map is JSON:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "mappings": [
+//!     { "asm_line": 1, "file": "fib.lf", "line": 1 },
+//!     { "asm_line": 9, "file": "fib.lf", "line": 3 },
+//!     { "asm_line": 20 }
+//!   ]
+//! }
+//! ```
+//!
+//! A mapping covers its assembly line (1-based) and every line after it up to the next mapping,
+//! which is how compilers emit code: a statement's assembly directly follows the mapping for it.
+//! A mapping without a `file` hands the lines back to the assembly, as do lines before the first
+//! mapping. Mappings are in order of `asm_line`. Version 1 is the only version so far, and fields
+//! it does not define are ignored.
+use std::io::{Read, Write};
+use serde::{Deserialize, Serialize};
+use crate::error::{FormatError, LeafError};
+use crate::leaf_file::{DebugInfo, LineEntry};
+use crate::{ReadableResource, WriteableResource};
+
+/// The source map version this toolchain reads and writes.
+pub const SOURCE_MAP_VERSION: u32 = 1;
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SourceMap {
+  pub version: u32,
+  pub mappings: Vec<Mapping>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Mapping {
+  pub asm_line: u32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub file: Option<String>,
+  #[serde(default)]
+  pub line: u32,
+}
+
+impl SourceMap {
+  pub fn new(mappings: Vec<Mapping>) -> Self {
+    SourceMap { version: SOURCE_MAP_VERSION, mappings }
+  }
+
+  /// Where assembly line `asm_line` came from, or `None` if it stands for itself.
+  pub fn lookup(&self, asm_line: u32) -> Option<(&str, u32)> {
+    let index = self.mappings.partition_point(|mapping| mapping.asm_line <= asm_line);
+    let mapping = self.mappings[..index].last()?;
+    mapping.file.as_deref().map(|file| (file, mapping.line))
+  }
+
+  /// `debug` with the rows for lines of `asm_file` moved to where the map says they came from.
+  /// Rows that end up on the same line as the one before are merged.
+  pub fn apply(&self, debug: &DebugInfo, asm_file: &str) -> DebugInfo {
+    let asm_index = debug.files.iter().position(|file| file == asm_file).map(|index| index as u32);
+    let mut mapped = DebugInfo { files: debug.files.clone(), lines: Vec::new() };
+    for entry in &debug.lines {
+      let (file, line) = match self.lookup(entry.line).filter(|_| Some(entry.file) == asm_index) {
+        Some((file, line)) => (mapped.file_index(file), line),
+        None => (entry.file, entry.line),
+      };
+      if mapped.lines.last().is_none_or(|last| (last.file, last.line) != (file, line)) {
+        mapped.lines.push(LineEntry { offset: entry.offset, file, line });
+      }
+    }
+    mapped
+  }
+}
+
+impl ReadableResource for SourceMap {
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    let map: SourceMap = serde_json::from_str(&content).map_err(|e| FormatError::Decode(e.to_string()))?;
+    if map.version != SOURCE_MAP_VERSION {
+      return Err(FormatError::Decode(format!("unsupported source map version {}", map.version)).into());
+    }
+    if let Some(pair) = map.mappings.windows(2).find(|pair| pair[1].asm_line <= pair[0].asm_line) {
+      return Err(FormatError::Decode(format!(
+        "source map mappings are out of order at assembly line {}", pair[1].asm_line,
+      )).into());
+    }
+    Ok(map)
+  }
+}
+
+impl WriteableResource for SourceMap {
+  fn write_to(&self, writer: &mut dyn Write) -> Result<(), LeafError> {
+    let json = serde_json::to_string_pretty(self).map_err(|e| FormatError::Decode(e.to_string()))?;
+    writer.write_all(json.as_bytes())?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maps_assembly_lines_to_the_compilers_source() {
+    let json = r#"{"version": 1, "mappings": [{"asm_line": 2, "file": "fib.lf", "line": 7}, {"asm_line": 4, "file": "fib.lf", "line": 8}, {"asm_line": 6}]}"#;
+    let map = SourceMap::read_from(&mut json.as_bytes()).unwrap();
+    assert_eq!(map.lookup(1), None);
+    assert_eq!(map.lookup(3), Some(("fib.lf", 7)));
+    assert_eq!(map.lookup(9), None);
+
+    let row = |offset, file, line| LineEntry { offset, file, line };
+    let debug = DebugInfo {
+      files: vec!["fib.leaf".to_string()],
+      lines: vec![row(0, 0, 1), row(5, 0, 2), row(10, 0, 3), row(15, 0, 4), row(20, 0, 6)],
+    };
+    let mapped = map.apply(&debug, "fib.leaf");
+    assert_eq!(mapped.files, vec!["fib.leaf".to_string(), "fib.lf".to_string()]);
+    assert_eq!(mapped.lines, vec![row(0, 0, 1), row(5, 1, 7), row(15, 1, 8), row(20, 0, 6)]);
+
+    let mut written = Vec::new();
+    map.write_to(&mut written).unwrap();
+    assert_eq!(SourceMap::read_from(&mut written.as_slice()).unwrap(), map);
+    let unordered = r#"{"version": 1, "mappings": [{"asm_line": 4}, {"asm_line": 4}]}"#;
+    assert!(SourceMap::read_from(&mut unordered.as_bytes()).is_err());
+    assert!(SourceMap::read_from(&mut r#"{"version": 2, "mappings": []}"#.as_bytes()).is_err());
+  }
+}
@@ -0,0 +1,93 @@
+//! Map code offsets back to symbols and source lines, for trap messages, profiles and coverage.
+use std::fmt;
+use crate::leaf_file::{LeafAsmObject, SymbolEntry};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Location<'a> {
+  pub offset: u32,
+  /// The closest code symbol at or before `offset`.
+  pub symbol: Option<&'a SymbolEntry>,
+  pub file: Option<&'a str>,
+  pub line: Option<u32>,
+}
+
+impl Location<'_> {
+  /// Distance from the start of the enclosing symbol.
+  pub fn symbol_offset(&self) -> Option<u32> {
+    self.symbol.map(|s| self.offset - s.offset)
+  }
+}
+
+impl fmt::Display for Location<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.symbol {
+      Some(symbol) => write!(f, "{}+0x{:X}", symbol.name, self.offset - symbol.offset)?,
+      None => write!(f, "0x{:04X}", self.offset)?,
+    }
+    if let (Some(file), Some(line)) = (self.file, self.line) {
+      write!(f, " ({}:{})", file, line)?;
+    }
+    Ok(())
+  }
+}
+
+/// Describe the `.text` offset `offset` of `object`.
+pub fn symbolicate(object: &LeafAsmObject, offset: u32) -> Location<'_> {
+  let symbol = object.symbols.iter()
+    .filter(|s| !s.external && s.section == 0 && s.offset <= offset)
+    .max_by_key(|s| s.offset);
+  let entry = object.debug_info.as_ref()
+    .and_then(|debug| debug.line_for(offset).map(|entry| (debug, entry)));
+
+  Location {
+    offset,
+    symbol,
+    file: entry.and_then(|(debug, entry)| debug.files.get(entry.file as usize)).map(String::as_str),
+    line: entry.map(|(_, entry)| entry.line),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::leaf_file::{DebugInfo, LineEntry};
+
+  fn symbol(name: &str, offset: u32) -> SymbolEntry {
+    SymbolEntry { name: name.to_string(), offset, section: 0, kind: 0, external: false }
+  }
+
+  #[test]
+  fn finds_enclosing_symbol_and_line() {
+    let object = LeafAsmObject {
+      bytecode: vec![0; 20],
+      data: vec![],
+      rodata: vec![],
+      symbols: vec![symbol("main", 0), symbol("helper", 10)],
+      entry_point: None,
+      relocations: vec![],
+      debug_info: Some(DebugInfo {
+        files: vec!["main.leaf".to_string()],
+        lines: vec![
+          LineEntry { offset: 0, file: 0, line: 2 },
+          LineEntry { offset: 10, file: 0, line: 7 },
+          LineEntry { offset: 15, file: 0, line: 8 },
+        ],
+      }),
+      exports: vec![],
+      data_in_text: vec![],
+      section_flags: vec![],
+      segments: vec![],
+      addends: vec![],
+      section_relocations: vec![],
+    };
+
+    let location = symbolicate(&object, 12);
+    assert_eq!(location.symbol.map(|s| s.name.as_str()), Some("helper"));
+    assert_eq!(location.symbol_offset(), Some(2));
+    assert_eq!(location.line, Some(7));
+    assert_eq!(location.to_string(), "helper+0x2 (main.leaf:7)");
+
+    let stripped = LeafAsmObject { debug_info: None, ..object };
+    assert_eq!(symbolicate(&stripped, 16).to_string(), "helper+0x6");
+  }
+}
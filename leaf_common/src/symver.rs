@@ -0,0 +1,63 @@
+//! Versioned symbol names, so a shared library can change a function's ABI while programs built
+//! against the old one keep working. `name@VERSION` is one version of `name`; `name@@VERSION` is
+//! its default version, which also answers references to plain `name`. A library that changes
+//! `parse` renames the old code `parse@V1` and defines the new code as `parse@@V2`: programs that
+//! call `parse@V1` still get the old behaviour, and everything else gets the new one.
+//!
+//! The linker and the loader resolve a reference the same way: a definition with exactly the same
+//! name wins, and failing that the default version of the same name and version (if any) does.
+
+/// The parts of a symbol name: `parse@@V2` is `("parse", Some("V2"), true)`.
+pub fn split(name: &str) -> (&str, Option<&str>, bool) {
+  match name.split_once('@') {
+    Some((base, version)) => match version.strip_prefix('@') {
+      Some(version) => (base, Some(version), true),
+      None => (base, Some(version), false),
+    },
+    None => (name, None, false),
+  }
+}
+
+/// Whether `definition` is the default version that a reference to `reference` falls back to:
+/// `parse@@V2` for both `parse` and `parse@V2`.
+pub fn is_default_for(definition: &str, reference: &str) -> bool {
+  let (base, version, default) = split(definition);
+  let (wanted, wanted_version, wanted_default) = split(reference);
+  default && !wanted_default && base == wanted && wanted_version.is_none_or(|wanted| Some(wanted) == version)
+}
+
+/// The definition `reference` binds to among `(name, value)` pairs: the one with exactly that name,
+/// else the default version for it.
+pub fn resolve<'a, T>(reference: &str, definitions: impl IntoIterator<Item = (&'a str, T)>) -> Option<T> {
+  let mut fallback = None;
+  for (name, value) in definitions {
+    if name == reference {
+      return Some(value);
+    }
+    if fallback.is_none() && is_default_for(name, reference) {
+      fallback = Some(value);
+    }
+  }
+  fallback
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn references_prefer_exact_names_then_the_default_version() {
+    assert_eq!(split("parse@@V2"), ("parse", Some("V2"), true));
+    assert_eq!(split("parse@V1"), ("parse", Some("V1"), false));
+    assert_eq!(split("parse"), ("parse", None, false));
+
+    let library = [("parse@V1", 1), ("parse@@V2", 2), ("print", 3)];
+    assert_eq!(resolve("parse@V1", library), Some(1));
+    assert_eq!(resolve("parse@V2", library), Some(2));
+    assert_eq!(resolve("parse", library), Some(2));
+    assert_eq!(resolve("parse@V3", library), None);
+    assert_eq!(resolve("print@V1", library), None);
+    // A plain definition beats the default version
+    assert_eq!(resolve("parse", [("parse@@V2", 2), ("parse", 0)]), Some(0));
+  }
+}
@@ -0,0 +1,75 @@
+//! Syscall numbers (LDR-006). A program puts the number in r0 and arguments in r1..r6, then runs
+//! `SYSCALL`; the result comes back in r0, with -1 meaning failure. The assembler accepts each
+//! `SYS_*` name below wherever an immediate is expected, e.g. `MOVI r0, SYS_WRITE`.
+
+/// Print the null-terminated string at r1.
+pub const SYS_PRINT_STR: u64 = 1;
+/// Print r1 as a decimal integer followed by a newline.
+pub const SYS_PRINT_INT: u64 = 2;
+/// Stop the program with status r1.
+pub const SYS_EXIT: u64 = 3;
+/// Read up to r3 bytes from fd r1 into the buffer at r2; returns the number read.
+pub const SYS_READ: u64 = 4;
+/// Write r3 bytes from the buffer at r2 to fd r1; returns the number written.
+pub const SYS_WRITE: u64 = 5;
+/// Open the file named by the string at r1 with flags r2 (0 read, 1 write, 2 both); returns an fd.
+/// Names are paths inside the VM's filesystem root, and without one every `OPEN` fails.
+pub const SYS_OPEN: u64 = 6;
+/// Close fd r1.
+pub const SYS_CLOSE: u64 = 7;
+/// Allocate r1 bytes; returns their address.
+pub const SYS_ALLOC: u64 = 8;
+/// Release memory from `SYS_ALLOC`. Allocation is a bump allocator, so this currently does nothing.
+pub const SYS_FREE: u64 = 9;
+/// Current Unix time in seconds.
+pub const SYS_TIME: u64 = 10;
+/// Let interrupts be taken; returns 1 if they already were, else 0.
+pub const SYS_INT_ENABLE: u64 = 11;
+/// Hold interrupts back until `SYS_INT_ENABLE`; returns the previous state like `SYS_INT_ENABLE`.
+pub const SYS_INT_DISABLE: u64 = 12;
+/// Return from an interrupt handler: restore r0 and the PC from the interrupt frame and re-enable
+/// interrupts.
+pub const SYS_IRET: u64 = 13;
+/// Raise the timer interrupt every r1 instructions; 0 stops the timer.
+pub const SYS_TIMER: u64 = 14;
+/// Start a task at address r1 with r2 in its r1; returns the task id.
+pub const SYS_SPAWN: u64 = 15;
+/// Let the next ready task run; this one continues when its turn comes again.
+pub const SYS_YIELD: u64 = 16;
+/// End the running task; ending the last one stops the program.
+pub const SYS_TASK_EXIT: u64 = 17;
+/// Move fd r1 to offset r2 from the start (r3 = 0), the current position (1) or the end (2);
+/// returns the new offset from the start.
+pub const SYS_SEEK: u64 = 18;
+
+/// Every syscall by assembler name.
+pub const SYSCALLS: &[(&str, u64)] = &[
+  ("SYS_PRINT_STR", SYS_PRINT_STR),
+  ("SYS_PRINT_INT", SYS_PRINT_INT),
+  ("SYS_EXIT", SYS_EXIT),
+  ("SYS_READ", SYS_READ),
+  ("SYS_WRITE", SYS_WRITE),
+  ("SYS_OPEN", SYS_OPEN),
+  ("SYS_CLOSE", SYS_CLOSE),
+  ("SYS_ALLOC", SYS_ALLOC),
+  ("SYS_FREE", SYS_FREE),
+  ("SYS_TIME", SYS_TIME),
+  ("SYS_INT_ENABLE", SYS_INT_ENABLE),
+  ("SYS_INT_DISABLE", SYS_INT_DISABLE),
+  ("SYS_IRET", SYS_IRET),
+  ("SYS_TIMER", SYS_TIMER),
+  ("SYS_SPAWN", SYS_SPAWN),
+  ("SYS_YIELD", SYS_YIELD),
+  ("SYS_TASK_EXIT", SYS_TASK_EXIT),
+  ("SYS_SEEK", SYS_SEEK),
+];
+
+/// Number of the syscall called `name`, e.g. `"SYS_WRITE"`.
+pub fn by_name(name: &str) -> Option<u64> {
+  SYSCALLS.iter().find(|(n, _)| *n == name).map(|(_, number)| *number)
+}
+
+/// Standard file descriptors.
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
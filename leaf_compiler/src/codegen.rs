@@ -9,6 +9,12 @@ pub struct CodeGenerator {
     current_function: Option<String>,
 }
 
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CodeGenerator {
     pub fn new() -> Self {
         Self {
@@ -199,19 +205,19 @@ impl CodeGenerator {
                     BinaryOp::Eq => self.asm.push_str(&format!("    EQ r{}, r30, r{}\n", reg, reg)),
                     BinaryOp::Ne => {
                         self.asm.push_str(&format!("    EQ r{}, r30, r{}\n", reg, reg));
-                        self.asm.push_str(&format!("    MOVI r31, 1\n"));
+                        self.asm.push_str("    MOVI r31, 1\n");
                         self.asm.push_str(&format!("    XOR r{}, r{}, r31\n", reg, reg));
                     }
                     BinaryOp::Le => {
                         // a <= b  <=>  !(a > b)
                         self.asm.push_str(&format!("    GT r{}, r30, r{}\n", reg, reg));
-                        self.asm.push_str(&format!("    MOVI r31, 1\n"));
+                        self.asm.push_str("    MOVI r31, 1\n");
                         self.asm.push_str(&format!("    XOR r{}, r{}, r31\n", reg, reg));
                     }
                     BinaryOp::Ge => {
                         // a >= b  <=>  !(a < b)
                         self.asm.push_str(&format!("    LT r{}, r30, r{}\n", reg, reg));
-                        self.asm.push_str(&format!("    MOVI r31, 1\n"));
+                        self.asm.push_str("    MOVI r31, 1\n");
                         self.asm.push_str(&format!("    XOR r{}, r{}, r31\n", reg, reg));
                     }
                     BinaryOp::And => self.asm.push_str(&format!("    AND r{}, r30, r{}\n", reg, reg)),
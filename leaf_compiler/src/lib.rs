@@ -7,26 +7,37 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use pest::Parser as PestParser;
 use pest_derive::Parser;
+use leaf_common::diagnostic::{Diagnostic, Span};
+use leaf_common::error::LeafError;
 use crate::ast::Program;
 
 #[derive(Parser)]
 #[grammar = "grammar/leaf.pest"]
 pub struct LeafParser;
 
-pub fn compile_file(path: &Path, visited: &mut HashSet<PathBuf>) -> Program {
-    let absolute_path = fs::canonicalize(path).expect(&format!("Failed to canonicalize path: {:?}", path));
+/// Parse `path` and, recursively, everything it includes into one program.
+pub fn compile_file(path: &Path, visited: &mut HashSet<PathBuf>) -> Result<Program, LeafError> {
+    let absolute_path = fs::canonicalize(path)?;
     if visited.contains(&absolute_path) {
-        return Program {
+        return Ok(Program {
             includes: Vec::new(),
             globals: Vec::new(),
             functions: Vec::new(),
-        };
+        });
     }
     visited.insert(absolute_path.clone());
 
-    let content = fs::read_to_string(&absolute_path).expect(&format!("Failed to read file: {:?}", absolute_path));
+    let content = fs::read_to_string(&absolute_path)?;
+    let file = absolute_path.display().to_string();
     let pair = LeafParser::parse(Rule::program, &content)
-        .expect(&format!("Failed to parse file: {:?}", absolute_path))
+        .map_err(|e| {
+            let (line, column) = match e.line_col {
+                pest::error::LineColLocation::Pos(pos) => pos,
+                pest::error::LineColLocation::Span(start, _) => start,
+            };
+            LeafError::Parse(Box::new(Diagnostic::error("syntax", e.variant.message())
+                .with_span(Some(Span::new(line, column, 1).in_file(Some(&file))))))
+        })?
         .next()
         .unwrap();
 
@@ -37,8 +48,7 @@ pub fn compile_file(path: &Path, visited: &mut HashSet<PathBuf>) -> Program {
     let parent_dir = absolute_path.parent().unwrap();
 
     for include in &program.includes {
-        let include_path = if include.starts_with("@std/") {
-            let std_name = &include[5..];
+        let include_path = if let Some(std_name) = include.strip_prefix("@std/") {
             // Look for std in leaf_compiler/resources/std
             let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
             p.push("resources");
@@ -49,7 +59,7 @@ pub fn compile_file(path: &Path, visited: &mut HashSet<PathBuf>) -> Program {
             parent_dir.join(include)
         };
 
-        let included_program = compile_file(&include_path, visited);
+        let included_program = compile_file(&include_path, visited)?;
         all_globals.extend(included_program.globals);
         all_functions.extend(included_program.functions);
     }
@@ -61,7 +71,7 @@ pub fn compile_file(path: &Path, visited: &mut HashSet<PathBuf>) -> Program {
     program.globals = all_globals;
     program.functions = all_functions;
 
-    program
+    Ok(program)
 }
 
 #[cfg(test)]
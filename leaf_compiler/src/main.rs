@@ -1,8 +1,6 @@
 use std::fs;
 use std::path::PathBuf;
 use clap::Parser;
-use leaf_compiler::{LeafParser, Rule};
-use pest::Parser as PestParser;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -21,7 +19,13 @@ fn main() {
     let args = Args::parse();
 
     let mut visited = std::collections::HashSet::new();
-    let program = leaf_compiler::compile_file(&args.input, &mut visited);
+    let program = match leaf_compiler::compile_file(&args.input, &mut visited) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
     
     let mut codegen = leaf_compiler::codegen::CodeGenerator::new();
     let asm = codegen.generate(&program);
@@ -51,7 +51,7 @@ fn parse_function(pair: Pair<Rule>) -> Function {
     // Check if next is parameters or return type or block
     if next.as_rule() == Rule::parameter {
          params.push(parse_parameter(next.clone()));
-         while let Some(p) = inner.next() {
+         for p in inner.by_ref() {
              if p.as_rule() == Rule::parameter {
                  params.push(parse_parameter(p));
              } else {
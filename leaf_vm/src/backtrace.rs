@@ -0,0 +1,131 @@
+//! Symbolized backtraces for faults. Leaf has no frame pointers, so the running task's stack is
+//! scanned for return addresses: words that point just past a `CALL` in loaded code. Each one is
+//! named with the enclosing symbol and, for the program's own code, its source line.
+use std::fmt;
+use leaf_common::leaf_ast::OpCode;
+use crate::vm::VM;
+
+/// Most frames `VM::backtrace` reports.
+pub const MAX_FRAMES: usize = 64;
+
+/// Size of a `CALL` instruction, and so the distance from a call site to its return address.
+const CALL_SIZE: usize = 5;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Frame {
+  /// The faulting instruction in the innermost frame, the `CALL` in the others.
+  pub pc: usize,
+  /// The closest code symbol at or before `pc` and the distance from it.
+  pub symbol: Option<(String, usize)>,
+  pub file: Option<String>,
+  pub line: Option<u32>,
+}
+
+impl fmt::Display for Frame {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.symbol {
+      Some((name, offset)) => write!(f, "0x{:04X} {}+0x{:X}", self.pc, name, offset)?,
+      None => write!(f, "0x{:04X}", self.pc)?,
+    }
+    if let (Some(file), Some(line)) = (&self.file, self.line) {
+      write!(f, " ({}:{})", file, line)?;
+    }
+    Ok(())
+  }
+}
+
+impl VM {
+  /// The current PC followed by the call site of every `CALL` still on the running task's stack,
+  /// innermost first.
+  pub fn backtrace(&self) -> Vec<Frame> {
+    let call = OpCode::opcode_to_byte(&OpCode::Call);
+    let top = self.stack_top.min(self.heap.len());
+    let return_addresses = (self.registers[15] as usize..top.saturating_sub(7)).step_by(8)
+      .map(|sp| u64::from_le_bytes(self.heap[sp..sp + 8].try_into().unwrap()) as usize)
+      .filter(|addr| *addr >= CALL_SIZE && self.code_end(addr - CALL_SIZE).is_some_and(|end| *addr <= end))
+      .filter(|addr| self.heap[addr - CALL_SIZE] == call);
+    std::iter::once(self.pc)
+      .chain(return_addresses.map(|addr| addr - CALL_SIZE))
+      .take(MAX_FRAMES)
+      .map(|pc| self.frame(pc))
+      .collect()
+  }
+
+  fn frame(&self, pc: usize) -> Frame {
+    let symbol = if pc < self.code_len {
+      self.symbol_at(pc).map(|(name, offset)| (name.to_string(), offset))
+    } else {
+      self.modules.iter()
+        .find(|module| module.text.contains(&pc) || module.retired.iter().any(|code| code.contains(&pc)))
+        .and_then(|module| module.exports.iter()
+          .filter(|(_, export)| **export <= pc && module.text.contains(export))
+          .max_by_key(|(_, export)| **export)
+          .map(|(name, export)| (format!("{}::{}", module.name, name), pc - export)))
+    };
+    let entry = self.debug_info.as_ref()
+      .filter(|_| pc < self.code_len)
+      .and_then(|debug| debug.line_for(pc as u32).map(|entry| (debug, entry)));
+    Frame {
+      pc,
+      symbol,
+      file: entry.and_then(|(debug, entry)| debug.files.get(entry.file as usize)).cloned(),
+      line: entry.map(|(_, entry)| entry.line),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::leaf_file::{DebugInfo, LineEntry};
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::vm::{ExitStatus, VM};
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn fault_backtrace_names_every_caller() {
+    // main: PUSH r1; CALL outer; HALT / outer: CALL inner; RET / inner: LOADI r1, [0xFFFFFF]
+    let code = [
+      instr(OpCode::Push, &[1]),
+      instr(OpCode::Call, &[11]),
+      instr(OpCode::Halt, &[]),
+      instr(OpCode::Call, &[17]),
+      instr(OpCode::Ret, &[]),
+      instr(OpCode::Loadi, &[1, 0xFFFFFF]),
+    ].concat();
+    let mut object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .define("main", 0, 0)
+      .define("outer", 0, 11)
+      .define("inner", 0, 17)
+      .build()
+      .unwrap();
+    object.debug_info = Some(DebugInfo {
+      files: vec!["calls.leaf".to_string()],
+      lines: vec![
+        LineEntry { offset: 5, file: 0, line: 3 },
+        LineEntry { offset: 11, file: 0, line: 6 },
+        LineEntry { offset: 17, file: 0, line: 9 },
+      ],
+    });
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert!(matches!(vm.status, Some(ExitStatus::Fault(_))));
+
+    let frames: Vec<String> = vm.backtrace().iter().map(|frame| frame.to_string()).collect();
+    assert_eq!(frames, vec![
+      "0x0011 inner+0x0 (calls.leaf:9)",
+      "0x000B outer+0x0 (calls.leaf:6)",
+      "0x0005 main+0x5 (calls.leaf:3)",
+    ]);
+  }
+}
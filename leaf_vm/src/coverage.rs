@@ -0,0 +1,91 @@
+//! Source-line coverage from the VM's execution counts and the object's line table, written in
+//! lcov's tracefile format so existing tooling (genhtml, CI coverage gates) can consume it.
+use std::collections::BTreeMap;
+use leaf_common::leaf_file::LeafAsmObject;
+
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Coverage {
+  /// File name -> line -> times executed. Every line with code is present, executed or not.
+  pub files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl Coverage {
+  /// Build coverage from `counts[address]` (see `VM::profile`). Objects without debug info have
+  /// no lines to report.
+  pub fn new(object: &LeafAsmObject, counts: &[u64]) -> Self {
+    let mut coverage = Coverage::default();
+    let Some(debug) = &object.debug_info else {
+      return coverage;
+    };
+    for entry in &debug.lines {
+      let Some(file) = debug.files.get(entry.file as usize) else {
+        continue;
+      };
+      let hits = counts.get(entry.offset as usize).copied().unwrap_or(0);
+      let line = coverage.files.entry(file.clone()).or_default().entry(entry.line).or_default();
+      *line = (*line).max(hits);
+    }
+    coverage
+  }
+
+  /// Fold in another run of the same program.
+  pub fn merge(&mut self, other: &Coverage) {
+    for (file, lines) in &other.files {
+      let target = self.files.entry(file.clone()).or_default();
+      for (line, hits) in lines {
+        *target.entry(*line).or_default() += hits;
+      }
+    }
+  }
+
+  /// Lines with code and lines executed at least once, over all files.
+  pub fn summary(&self) -> (usize, usize) {
+    let lines = self.files.values().flat_map(|lines| lines.values());
+    lines.fold((0, 0), |(found, hit), hits| (found + 1, hit + (*hits > 0) as usize))
+  }
+
+  pub fn to_lcov(&self) -> String {
+    let mut out = String::from("TN:\n");
+    for (file, lines) in &self.files {
+      out.push_str(&format!("SF:{}\n", file));
+      for (line, hits) in lines {
+        out.push_str(&format!("DA:{},{}\n", line, hits));
+      }
+      let hit = lines.values().filter(|hits| **hits > 0).count();
+      out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+    }
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::leaf_file::{DebugInfo, LineEntry};
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+
+  #[test]
+  fn reports_executed_and_skipped_lines() {
+    let debug = DebugInfo {
+      files: vec!["a.lasm".to_string()],
+      lines: vec![
+        LineEntry { offset: 0, file: 0, line: 2 },
+        LineEntry { offset: 9, file: 0, line: 3 },
+        LineEntry { offset: 14, file: 0, line: 5 },
+      ],
+    };
+    let object = LeafAsmObjectBuilder::new().text(vec![0; 15]).debug_info(debug).build().unwrap();
+    let mut counts = vec![0; 15];
+    counts[0] = 1;
+    counts[9] = 4;
+
+    let mut coverage = Coverage::new(&object, &counts);
+    assert_eq!(coverage.summary(), (3, 2));
+    assert_eq!(coverage.to_lcov(), "TN:\nSF:a.lasm\nDA:2,1\nDA:3,4\nDA:5,0\nLF:3\nLH:2\nend_of_record\n");
+
+    counts[14] = 1;
+    coverage.merge(&Coverage::new(&object, &counts));
+    assert_eq!(coverage.files["a.lasm"][&3], 8);
+    assert_eq!(coverage.summary(), (3, 3));
+  }
+}
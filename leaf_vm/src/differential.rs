@@ -0,0 +1,151 @@
+//! Differential testing: run the same program on two VMs in lockstep, typically the interpreter
+//! and the JIT, and compare their state every few instructions. Checkpoints use the instruction
+//! budget, which both engines honour exactly, so the two runs are always compared at the same
+//! point.
+use std::fmt;
+use crate::vm::{ExitStatus, VM};
+
+/// The first checkpoint at which two runs disagreed.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Divergence {
+  /// Instructions both runs had executed at the last checkpoint where they still agreed.
+  pub agreed_until: u64,
+  /// Instructions executed when the difference was found.
+  pub found_at: u64,
+  pub difference: Difference,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Difference {
+  Status(Option<ExitStatus>, Option<ExitStatus>),
+  Pc(usize, usize),
+  Register { index: usize, left: u64, right: u64 },
+  MemorySize(usize, usize),
+  Memory { addr: usize, left: u8, right: u8 },
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "runs diverged between instructions {} and {}: ", self.agreed_until, self.found_at)?;
+    match &self.difference {
+      Difference::Status(left, right) => write!(f, "status {:?} vs {:?}", left, right),
+      Difference::Pc(left, right) => write!(f, "pc 0x{:04X} vs 0x{:04X}", left, right),
+      Difference::Register { index, left, right } => write!(f, "r{} = 0x{:X} vs 0x{:X}", index, left, right),
+      Difference::MemorySize(left, right) => write!(f, "memory size 0x{:X} vs 0x{:X}", left, right),
+      Difference::Memory { addr, left, right } => write!(f, "memory at 0x{:04X} = 0x{:02X} vs 0x{:02X}", addr, left, right),
+    }
+  }
+}
+
+impl std::error::Error for Divergence {}
+
+/// The first difference between the state of two VMs, if any.
+pub fn compare(left: &VM, right: &VM) -> Option<Difference> {
+  if left.status != right.status {
+    return Some(Difference::Status(left.status.clone(), right.status.clone()));
+  }
+  if left.pc != right.pc {
+    return Some(Difference::Pc(left.pc, right.pc));
+  }
+  if let Some(index) = (0..left.registers.len()).find(|i| left.registers[*i] != right.registers[*i]) {
+    return Some(Difference::Register { index, left: left.registers[index], right: right.registers[index] });
+  }
+  if left.heap.len() != right.heap.len() {
+    return Some(Difference::MemorySize(left.heap.len(), right.heap.len()));
+  }
+  let addr = left.heap.iter().zip(&right.heap).position(|(a, b)| a != b)?;
+  Some(Difference::Memory { addr, left: left.heap[addr], right: right.heap[addr] })
+}
+
+/// Run two VMs with the same program loaded until the program stops, comparing them every
+/// `interval` instructions. Returns the number of instructions executed, or the first divergence.
+/// Any fuel set on the VMs is replaced.
+pub fn run_differential(left: &mut VM, right: &mut VM, interval: u64) -> Result<u64, Divergence> {
+  let interval = interval.max(1);
+  let mut executed = 0;
+  loop {
+    for vm in [&mut *left, &mut *right] {
+      vm.fuel = Some(interval);
+      vm.run();
+    }
+    let agreed_until = executed;
+    executed += interval - left.fuel.unwrap_or(0);
+    if let Some(difference) = compare(left, right) {
+      return Err(Divergence { agreed_until, found_at: executed, difference });
+    }
+    if left.status != Some(ExitStatus::OutOfFuel) {
+      left.fuel = None;
+      right.fuel = None;
+      return Ok(executed);
+    }
+    for vm in [&mut *left, &mut *right] {
+      vm.halted = false;
+      vm.status = None;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::leaf_file::LeafAsmObject;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  fn answer(value: u64) -> impl FnMut(&mut [u64; 32], &mut [u8]) -> Result<(), String> {
+    move |registers, _| {
+      registers[0] = value;
+      Ok(())
+    }
+  }
+
+  fn vm(object: &LeafAsmObject) -> VM {
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(object).unwrap();
+    vm
+  }
+
+  #[test]
+  fn reports_the_checkpoint_where_runs_diverge() {
+    // r1 = 100; loop: r2 += r1; r1 -= 1; r4 = r1 > r0; JNZ r4, loop; r0 = SYSCALL 99; HALT
+    let code = [
+      instr(OpCode::Movi, &[1, 100]),
+      instr(OpCode::Movi, &[3, 1]),
+      instr(OpCode::Add, &[2, 2, 1]),
+      instr(OpCode::Sub, &[1, 1, 3]),
+      instr(OpCode::Gt, &[4, 1, 0]),
+      instr(OpCode::Jnz, &[4, 18]),
+      instr(OpCode::Movi, &[0, 99]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let object = LeafAsmObjectBuilder::new().text(code).build().unwrap();
+    let engines = || {
+      let (mut left, mut right) = (vm(&object), vm(&object));
+      left.register_syscall(99, answer(1));
+      right.register_syscall(99, answer(1));
+      (left, right)
+    };
+
+    let (mut left, mut right) = engines();
+    #[cfg(feature = "jit")]
+    assert!(right.enable_jit(10));
+    assert_eq!(run_differential(&mut left, &mut right, 7), Ok(405));
+    assert_eq!(right.registers[2], 5050);
+
+    let (mut left, mut right) = engines();
+    right.register_syscall(99, answer(2));
+    let divergence = run_differential(&mut left, &mut right, 100).unwrap_err();
+    assert_eq!(divergence.difference, Difference::Register { index: 0, left: 1, right: 2 });
+    assert_eq!((divergence.agreed_until, divergence.found_at), (400, 405));
+  }
+}
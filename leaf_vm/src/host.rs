@@ -0,0 +1,399 @@
+//! Embedding API for Rust hosts that run leaf programs as scripts: load a linked object, call its
+//! functions with arguments and inspect registers and memory.
+//!
+//! ```
+//! use leaf_common::object_builder::LeafAsmObjectBuilder;
+//! use leaf_vm::host::{Vm, VmConfig};
+//! use leaf_vm::vm::ExitStatus;
+//!
+//! // double: ADD r0, r1, r1; RET
+//! let object = LeafAsmObjectBuilder::new()
+//!   .text(vec![0x01, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0x10])
+//!   .define("double", 0, 0)
+//!   .build()
+//!   .unwrap();
+//! let mut vm = Vm::new(&object, VmConfig::default()).unwrap();
+//! assert_eq!(vm.call("double", &[21]).unwrap(), ExitStatus::Returned(42));
+//! ```
+use leaf_common::error::LeafError;
+use leaf_common::leaf_file::{ExportEntry, LeafAsmObject, SymbolEntry};
+use leaf_common::object_builder::ObjectError;
+use crate::loader::LoadedModule;
+use crate::mmio::Device;
+use crate::snapshot::Snapshot;
+use crate::vm::{BreakAction, BreakContext, ExitStatus, MemoryLayout, VM};
+
+/// Register holding the stack pointer (LDR-004).
+pub const SP: usize = 15;
+/// Arguments are passed in r1..=r14, below the stack pointer.
+pub const MAX_ARGS: usize = SP - 1;
+
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+  /// Initial memory size in bytes; grown if the program does not fit.
+  pub memory_size: usize,
+  /// Log every executed instruction.
+  pub trace: bool,
+  /// Instruction budget shared by all runs and calls; see `VM::fuel`.
+  pub fuel: Option<u64>,
+  /// Refuse clock, stdin and filesystem syscalls other than `allowed_syscalls`; see
+  /// `VM::deterministic`. Host syscalls are always allowed.
+  pub deterministic: bool,
+  pub allowed_syscalls: Vec<u64>,
+  /// Enforce section flags, so writes to `.text` and `.rodata` fault; see `VM::memory_protection`.
+  pub memory_protection: bool,
+  /// Check return addresses on `RET`; see `VM::stack_canaries`.
+  pub stack_canaries: bool,
+  /// Section addresses, stack size and `ALLOC` limit.
+  pub layout: MemoryLayout,
+}
+
+impl Default for VmConfig {
+  fn default() -> Self {
+    VmConfig {
+      memory_size: 0x10000,
+      trace: false,
+      fuel: None,
+      deterministic: false,
+      allowed_syscalls: Vec::new(),
+      memory_protection: true,
+      stack_canaries: false,
+      layout: MemoryLayout::default(),
+    }
+  }
+}
+
+/// Command-line flags for `MemoryLayout`, shared by the `leaf_vm` and `leaf_asm run` binaries.
+#[derive(Debug, Clone, clap::Args)]
+pub struct LayoutArgs {
+  /// Bytes reserved for the stack at the top of memory; deeper calls fault as stack overflow
+  #[arg(long, value_parser = parse_size)]
+  pub stack_size: Option<usize>,
+
+  /// Maximum total bytes the ALLOC syscall hands out
+  #[arg(long, value_parser = parse_size)]
+  pub heap_size: Option<usize>,
+
+  /// Load address of .data (default: right after .text)
+  #[arg(long, value_parser = parse_size)]
+  pub data_base: Option<usize>,
+
+  /// Load address of .rodata (default: right after .data)
+  #[arg(long, value_parser = parse_size)]
+  pub rodata_base: Option<usize>,
+}
+
+impl LayoutArgs {
+  pub fn layout(&self) -> MemoryLayout {
+    MemoryLayout {
+      data_base: self.data_base,
+      rodata_base: self.rodata_base,
+      stack_size: self.stack_size,
+      heap_size: self.heap_size,
+    }
+  }
+}
+
+/// A decimal or `0x` hexadecimal size or address, with an optional `k`/`m` suffix for KiB/MiB.
+pub fn parse_size(text: &str) -> Result<usize, String> {
+  let (digits, unit) = match text.strip_suffix(['k', 'K']) {
+    Some(digits) => (digits, 1 << 10),
+    None => match text.strip_suffix(['m', 'M']) {
+      Some(digits) => (digits, 1 << 20),
+      None => (text, 1),
+    },
+  };
+  let value = match digits.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16),
+    None => digits.parse(),
+  };
+  value.ok().and_then(|value| value.checked_mul(unit)).ok_or_else(|| format!("invalid size '{}'", text))
+}
+
+pub struct Vm {
+  vm: VM,
+  symbols: Vec<SymbolEntry>,
+  exports: Vec<ExportEntry>,
+}
+
+impl Vm {
+  /// Load a linked object, with the PC at its entry point (if any).
+  pub fn new(object: &LeafAsmObject, config: VmConfig) -> Result<Self, LeafError> {
+    let mut vm = VM::new(config.memory_size);
+    vm.debug = config.trace;
+    vm.fuel = config.fuel;
+    vm.deterministic = config.deterministic;
+    vm.memory_protection = config.memory_protection;
+    vm.stack_canaries = config.stack_canaries;
+    vm.layout = config.layout;
+    for number in config.allowed_syscalls {
+      vm.allow_syscall(number);
+    }
+    vm.load_object(object)?;
+    Ok(Vm { symbols: object.symbols.clone(), exports: object.exports.clone(), vm })
+  }
+
+  /// Run from the current PC until the program stops.
+  pub fn run(&mut self) -> ExitStatus {
+    self.vm.halted = false;
+    self.vm.status = None;
+    self.vm.run();
+    self.exit_status().cloned().unwrap_or(ExitStatus::Halted)
+  }
+
+  /// Call the function at `symbol` using the compiler's convention: arguments in r1.., result in
+  /// r0. Returns `Returned(r0)` when the function returns, or whatever else stopped the program.
+  /// Panics if given more than `MAX_ARGS` arguments.
+  pub fn call(&mut self, symbol: &str, args: &[u64]) -> Result<ExitStatus, LeafError> {
+    let target = self.address_of(symbol).ok_or_else(|| ObjectError::UnknownSymbol(symbol.to_string()))?;
+    assert!(args.len() <= MAX_ARGS, "{} called with {} arguments, at most {} are supported", symbol, args.len(), MAX_ARGS);
+    self.vm.registers[1..=args.len()].copy_from_slice(args);
+
+    // Return to an address past the end of .text, which stops the VM once the callee executes RET
+    let sentinel = self.vm.heap.len() as u64;
+    let sp = self.vm.registers[SP] as usize;
+    if sp < self.vm.stack_limit + 8 || sp > self.vm.heap.len() {
+      return Ok(ExitStatus::Fault(format!("Stack pointer 0x{:X} cannot hold a return address", sp)));
+    }
+    self.vm.heap[sp - 8..sp].copy_from_slice(&sentinel.to_le_bytes());
+    self.vm.registers[SP] = (sp - 8) as u64;
+    self.vm.pc = target;
+
+    let status = self.run();
+    if status == ExitStatus::Halted && self.vm.pc as u64 == sentinel {
+      return Ok(ExitStatus::Returned(self.vm.registers[0]));
+    }
+    Ok(status)
+  }
+
+  /// Expose a native capability to the program as syscall `number`; see `SyscallHandler`.
+  pub fn register_syscall<F>(&mut self, number: u64, handler: F)
+  where
+    F: FnMut(&mut [u64; 32], &mut [u8]) -> Result<(), String> + 'static,
+  {
+    self.vm.register_syscall(number, handler);
+  }
+
+  /// Map a shared object at `base` and bind its imports; its exports can then be `call`ed. See
+  /// `VM::load_shared_object`.
+  pub fn load_shared(&mut self, name: &str, object: &LeafAsmObject, base: usize) -> Result<&LoadedModule, LeafError> {
+    self.vm.load_shared_object(name, object, base)
+  }
+
+  /// Swap in a new build of a loaded shared object, keeping its data; see `VM::reload_module`.
+  pub fn reload(&mut self, name: &str, object: &LeafAsmObject) -> Result<&LoadedModule, LeafError> {
+    self.vm.reload_module(name, object)
+  }
+
+  /// Map a device (console, timer, framebuffer, ...) at `base..base + len`; see `mmio`.
+  pub fn map_device<D: Device + 'static>(&mut self, base: usize, len: usize, device: D) -> Result<(), LeafError> {
+    self.vm.map_device(base, len, device)
+  }
+
+  /// Call `handler` on every `BREAK`; it can inspect and change registers and memory, and decide
+  /// whether to continue or pause (returning `ExitStatus::Breakpoint` from `run` or `call`).
+  pub fn on_break<F>(&mut self, handler: F)
+  where
+    F: FnMut(&mut BreakContext) -> BreakAction + 'static,
+  {
+    self.vm.on_break(handler);
+  }
+
+  /// The code symbol containing `pc` and the offset into it, e.g. to report where a pause happened.
+  pub fn symbol_at(&self, pc: usize) -> Option<(&str, usize)> {
+    self.vm.symbol_at(pc)
+  }
+
+  /// Absolute address of a symbol defined by the program, listed in its export table or exported
+  /// by a loaded module.
+  pub fn address_of(&self, symbol: &str) -> Option<usize> {
+    match self.symbols.iter().find(|s| s.name == symbol && !s.external) {
+      Some(entry) => Some(self.vm.run_base(entry.section)? + entry.offset as usize),
+      None => match self.exports.iter().find(|export| export.name == symbol) {
+        Some(export) => Some(self.vm.section_base(0)? + export.address as usize),
+        None => self.vm.export(symbol),
+      },
+    }
+  }
+
+  /// The functions the program's export table offers to `call`; see `leaf_asm link --export`.
+  pub fn exports(&self) -> &[ExportEntry] {
+    &self.exports
+  }
+
+  /// Why the program last stopped, or `None` if it has not run yet.
+  pub fn exit_status(&self) -> Option<&ExitStatus> {
+    self.vm.status.as_ref()
+  }
+
+  /// Process exit code for how the program last stopped; see `ExitStatus::exit_code`.
+  pub fn exit_code(&self) -> Option<i32> {
+    self.vm.exit_code()
+  }
+
+  pub fn register(&self, index: usize) -> Option<u64> {
+    self.vm.registers.get(index).copied()
+  }
+
+  /// Set register `index`; returns false if there is no such register.
+  pub fn set_register(&mut self, index: usize, value: u64) -> bool {
+    match self.vm.registers.get_mut(index) {
+      Some(register) => {
+        *register = value;
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn pc(&self) -> usize {
+    self.vm.pc
+  }
+
+  pub fn set_pc(&mut self, pc: usize) {
+    self.vm.pc = pc;
+  }
+
+  /// `len` bytes at `addr`, or `None` if the range is outside memory.
+  pub fn memory(&self, addr: usize, len: usize) -> Option<&[u8]> {
+    self.vm.heap.get(addr..addr.checked_add(len)?)
+  }
+
+  pub fn memory_mut(&mut self, addr: usize, len: usize) -> Option<&mut [u8]> {
+    self.vm.heap.get_mut(addr..addr.checked_add(len)?)
+  }
+
+  /// Read the 64-bit little-endian word at `addr`.
+  pub fn read_word(&self, addr: usize) -> Option<u64> {
+    self.memory(addr, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+  }
+
+  /// Write a 64-bit little-endian word; returns false if it does not fit in memory.
+  pub fn write_word(&mut self, addr: usize, value: u64) -> bool {
+    match self.memory_mut(addr, 8) {
+      Some(bytes) => {
+        bytes.copy_from_slice(&value.to_le_bytes());
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Capture registers, PC and memory; see `snapshot::Snapshot` for saving it to a file.
+  pub fn snapshot(&self) -> Snapshot {
+    self.vm.snapshot()
+  }
+
+  pub fn restore(&mut self, snapshot: &Snapshot) {
+    self.vm.restore(snapshot);
+  }
+
+  /// Instructions left in the budget, if there is one.
+  pub fn fuel(&self) -> Option<u64> {
+    self.vm.fuel
+  }
+
+  /// Top up or remove the budget, e.g. before calling again after `ExitStatus::OutOfFuel`.
+  pub fn set_fuel(&mut self, fuel: Option<u64>) {
+    self.vm.fuel = fuel;
+  }
+
+  /// The underlying interpreter, for anything this API does not cover.
+  pub fn inner(&mut self) -> &mut VM {
+    &mut self.vm
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  /// `main` exits with the value of `counter`; `bump(n)` adds n to `counter` and returns the total.
+  fn script() -> LeafAsmObject {
+    let code = [
+      // main (0): LOADI r1, [counter]; MOVI r0, 3; SYSCALL
+      instr(OpCode::Loadi, &[1, 0]),
+      instr(OpCode::Movi, &[0, 3]),
+      instr(OpCode::Syscall, &[]),
+      // bump (19): LOADI r0, [counter]; ADD r0, r0, r1; STOREI r0, [counter]; RET
+      instr(OpCode::Loadi, &[0, 0]),
+      instr(OpCode::Add, &[0, 0, 1]),
+      instr(OpCode::Storei, &[0, 0]),
+      instr(OpCode::Ret, &[]),
+    ].concat();
+    LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(vec![0; 8])
+      .define("main", 0, 0)
+      .define("bump", 0, 19)
+      .define("counter", 1, 0)
+      .absolute_relocation(5, "counter", 0)
+      .absolute_relocation(24, "counter", 0)
+      .absolute_relocation(46, "counter", 0)
+      .entry_point("main")
+      .build()
+      .unwrap()
+  }
+
+  #[test]
+  fn calls_functions_and_shares_memory_between_calls() {
+    let mut vm = Vm::new(&script(), VmConfig::default()).unwrap();
+    assert_eq!(vm.call("bump", &[5]).unwrap(), ExitStatus::Returned(5));
+    assert_eq!(vm.call("bump", &[3]).unwrap(), ExitStatus::Returned(8));
+
+    let counter = vm.address_of("counter").unwrap();
+    assert_eq!(vm.read_word(counter), Some(8));
+    assert!(vm.write_word(counter, 40));
+
+    vm.set_pc(vm.address_of("main").unwrap());
+    assert_eq!(vm.run(), ExitStatus::Exited(40));
+    assert_eq!(vm.exit_status(), Some(&ExitStatus::Exited(40)));
+    assert_eq!(vm.register(SP), Some(0x10000));
+  }
+
+  #[test]
+  fn exported_functions_can_be_listed_and_called() {
+    let mut object = script();
+    object.exports = vec![ExportEntry { name: "bump".to_string(), address: 19, signature: Some("(r1: n) -> r0".to_string()) }];
+    // Callable through the export table even without a symbol table
+    object.symbols.retain(|symbol| symbol.name == "counter");
+    object.entry_point = None;
+    object.relocations.iter_mut().for_each(|reloc| reloc.symbol_index = 0);
+    let mut vm = Vm::new(&object, VmConfig::default()).unwrap();
+    let names: Vec<&str> = vm.exports().iter().map(|export| export.name.as_str()).collect();
+    assert_eq!(names, ["bump"]);
+    assert_eq!(vm.call("bump", &[2]).unwrap(), ExitStatus::Returned(2));
+    assert!(vm.call("main", &[]).is_err());
+  }
+
+  #[test]
+  fn reports_unknown_symbols_and_faults() {
+    let mut vm = Vm::new(&script(), VmConfig::default()).unwrap();
+    assert!(matches!(vm.call("missing", &[]), Err(LeafError::Format(_))));
+    assert!(vm.set_register(1, 1));
+    assert!(!vm.set_register(32, 1));
+    assert_eq!(vm.memory(0x10000 - 4, 8), None);
+
+    vm.inner().heap[19] = 0xEE; // corrupt the first instruction of `bump`
+    assert!(matches!(vm.call("bump", &[1]).unwrap(), ExitStatus::Fault(_)));
+  }
+
+  #[test]
+  fn parses_sizes() {
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("0x100"), Ok(256));
+    assert_eq!(parse_size("64k"), Ok(64 * 1024));
+    assert_eq!(parse_size("2M"), Ok(2 << 20));
+    assert!(parse_size("lots").is_err());
+  }
+}
@@ -0,0 +1,285 @@
+//! Optional JIT (feature `jit`). The interpreter counts how often it reaches each address; once an
+//! address gets hot, the straight-line run of register instructions starting there (up to and
+//! including a jump) is compiled with Cranelift into a native function taking the register file
+//! and returning the next PC. Anything else - memory access, calls, syscalls, faults - stays in the
+//! interpreter, which also takes over whenever a block cannot be used.
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlagsData, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use leaf_common::leaf_ast::OpCode;
+
+/// Times an address is reached before the code starting there is compiled.
+pub const DEFAULT_THRESHOLD: u32 = 1000;
+
+const REGISTERS: usize = 32;
+
+type BlockFn = unsafe extern "C" fn(*mut u64) -> u64;
+
+struct Block {
+  function: BlockFn,
+  /// The bytecode the block was compiled from, to notice when it has been overwritten.
+  bytes: Vec<u8>,
+  instructions: u64,
+}
+
+enum Slot {
+  Counting(u32),
+  /// Nothing at this address can be compiled.
+  Unsupported,
+  Compiled(Block),
+}
+
+/// A decoded instruction the JIT can translate; register operands are already validated.
+struct Instruction {
+  opcode: OpCode,
+  addr: usize,
+  operands: Vec<u32>,
+}
+
+pub struct Jit {
+  module: JITModule,
+  builder_context: FunctionBuilderContext,
+  threshold: u32,
+  /// One slot per `.text` address.
+  slots: Vec<Slot>,
+}
+
+impl Jit {
+  /// `None` if Cranelift does not support the host.
+  pub fn new(threshold: u32) -> Option<Self> {
+    let builder = JITBuilder::new(default_libcall_names()).ok()?;
+    Some(Jit {
+      module: JITModule::new(builder),
+      builder_context: FunctionBuilderContext::new(),
+      threshold: threshold.max(1),
+      slots: Vec::new(),
+    })
+  }
+
+  /// Number of blocks compiled so far.
+  pub fn compiled_blocks(&self) -> usize {
+    self.slots.iter().filter(|slot| matches!(slot, Slot::Compiled(_))).count()
+  }
+
+  /// Run the compiled block at `pc`, compiling it first if it just got hot. Returns the next PC and
+  /// the number of instructions executed, or `None` if the interpreter should execute `pc` itself,
+  /// e.g. because the block is longer than the remaining `fuel`.
+  pub fn run_block(&mut self, code: &[u8], pc: usize, registers: &mut [u64; 32], fuel: Option<u64>) -> Option<(usize, u64)> {
+    if self.slots.len() != code.len() {
+      self.slots = (0..code.len()).map(|_| Slot::Counting(0)).collect();
+    }
+    let slot = self.slots.get_mut(pc)?;
+    if let Slot::Counting(count) = slot {
+      *count += 1;
+      if *count < self.threshold {
+        return None;
+      }
+      self.slots[pc] = match self.compile(code, pc) {
+        Some(block) => Slot::Compiled(block),
+        None => Slot::Unsupported,
+      };
+    }
+    let Slot::Compiled(block) = &self.slots[pc] else {
+      return None;
+    };
+    if code.get(pc..pc + block.bytes.len()) != Some(block.bytes.as_slice()) {
+      // Self-modifying code: start counting again for the new bytes
+      self.slots[pc] = Slot::Counting(0);
+      return None;
+    }
+    if fuel.is_some_and(|fuel| fuel < block.instructions) {
+      return None;
+    }
+    // SAFETY: the block only reads and writes the 32 registers behind the pointer
+    let next = unsafe { (block.function)(registers.as_mut_ptr()) };
+    Some((next as usize, block.instructions))
+  }
+
+  /// Instructions from `pc` that can be compiled, ending after the first jump.
+  fn decode(code: &[u8], mut pc: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    while let Some(info) = code.get(pc).and_then(|byte| OpCode::decode(*byte)) {
+      let registers = match info.opcode {
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::And | OpCode::Or | OpCode::Xor
+        | OpCode::Lt | OpCode::Gt | OpCode::Eq => 3,
+        OpCode::Mov | OpCode::Not => 2,
+        OpCode::Movi | OpCode::Jz | OpCode::Jnz => 1,
+        OpCode::Jmp | OpCode::Nop => 0,
+        _ => break,
+      };
+      let Some(bytes) = code.get(pc..pc + info.size()) else {
+        break;
+      };
+      let operands: Vec<u32> = bytes[1..].chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+      // The interpreter reads a register from the operand's first byte and faults if it is invalid
+      if bytes[1..].chunks(4).take(registers).any(|b| b[0] as usize >= REGISTERS) {
+        break;
+      }
+      let jump = matches!(info.opcode, OpCode::Jmp | OpCode::Jz | OpCode::Jnz);
+      instructions.push(Instruction { opcode: info.opcode, addr: pc, operands });
+      pc += info.size();
+      if jump {
+        break;
+      }
+    }
+    instructions
+  }
+
+  fn compile(&mut self, code: &[u8], pc: usize) -> Option<Block> {
+    let instructions = Self::decode(code, pc);
+    let last = instructions.last()?;
+    let end = last.addr + last.opcode.info()?.size();
+
+    let mut context = self.module.make_context();
+    let target = self.module.target_config();
+    let pointer = target.pointer_type();
+    context.func.signature.params.push(AbiParam::new(pointer));
+    context.func.signature.returns.push(AbiParam::new(types::I64));
+    let id = self.module.declare_anonymous_function(&context.func.signature).ok()?;
+
+    let mut b = FunctionBuilder::new(&mut context.func, &mut self.builder_context);
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    let file = b.block_params(entry)[0];
+    let flags = MemFlagsData::trusted();
+
+    // Keep registers in SSA variables, loaded on entry and written back on every exit
+    let vars: Vec<Variable> = (0..REGISTERS).map(|_| b.declare_var(types::I64)).collect();
+    for (reg, var) in vars.iter().enumerate() {
+      let value = b.ins().load(types::I64, flags, file, (reg * 8) as i32);
+      b.def_var(*var, value);
+    }
+    let exit = |b: &mut FunctionBuilder, next: u64| {
+      for (reg, var) in vars.iter().enumerate() {
+        let value = b.use_var(*var);
+        b.ins().store(flags, value, file, (reg * 8) as i32);
+      }
+      let next = b.ins().iconst(types::I64, next as i64);
+      b.ins().return_(&[next]);
+    };
+
+    for instr in &instructions {
+      let reg = |i: usize| vars[instr.operands[i] as usize];
+      let next = (instr.addr + instr.opcode.info()?.size()) as u64;
+      match instr.opcode {
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::And | OpCode::Or | OpCode::Xor
+        | OpCode::Lt | OpCode::Gt | OpCode::Eq => {
+          let x = b.use_var(reg(1));
+          let y = b.use_var(reg(2));
+          let value: Value = match instr.opcode {
+            OpCode::Add => b.ins().iadd(x, y),
+            OpCode::Sub => b.ins().isub(x, y),
+            OpCode::Mul => b.ins().imul(x, y),
+            OpCode::And => b.ins().band(x, y),
+            OpCode::Or => b.ins().bor(x, y),
+            OpCode::Xor => b.ins().bxor(x, y),
+            compare => {
+              let cc = match compare {
+                OpCode::Lt => IntCC::SignedLessThan,
+                OpCode::Gt => IntCC::SignedGreaterThan,
+                _ => IntCC::Equal,
+              };
+              let flag = b.ins().icmp(cc, x, y);
+              b.ins().uextend(types::I64, flag)
+            }
+          };
+          b.def_var(reg(0), value);
+        }
+        OpCode::Mov => {
+          let value = b.use_var(reg(1));
+          b.def_var(reg(0), value);
+        }
+        OpCode::Not => {
+          let x = b.use_var(reg(1));
+          let value = b.ins().bnot(x);
+          b.def_var(reg(0), value);
+        }
+        OpCode::Movi => {
+          let value = b.ins().iconst(types::I64, instr.operands[1] as i64);
+          b.def_var(reg(0), value);
+        }
+        OpCode::Jmp => exit(&mut b, instr.operands[0] as u64),
+        OpCode::Jz | OpCode::Jnz => {
+          let taken = b.create_block();
+          let fallthrough = b.create_block();
+          let value = b.use_var(reg(0));
+          match instr.opcode {
+            OpCode::Jnz => b.ins().brif(value, taken, &[], fallthrough, &[]),
+            _ => b.ins().brif(value, fallthrough, &[], taken, &[]),
+          };
+          b.switch_to_block(taken);
+          exit(&mut b, instr.operands[1] as u64);
+          b.switch_to_block(fallthrough);
+          exit(&mut b, next);
+        }
+        _ => {}
+      }
+    }
+    if !matches!(last.opcode, OpCode::Jmp | OpCode::Jz | OpCode::Jnz) {
+      exit(&mut b, end as u64);
+    }
+    b.seal_all_blocks();
+    b.finalize(target);
+
+    self.module.define_function(id, &mut context).ok()?;
+    self.module.clear_context(&mut context);
+    self.module.finalize_definitions().ok()?;
+    // SAFETY: the function was just defined with the `BlockFn` signature
+    let function = unsafe { std::mem::transmute::<*const u8, BlockFn>(self.module.get_finalized_function(id)) };
+    Some(Block { function, bytes: code[pc..end].to_vec(), instructions: instructions.len() as u64 })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::vm::{ExitStatus, VM};
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn compiled_loop_matches_the_interpreter() {
+    // r1 = 1000; loop: r2 += r1; r1 -= 1; r4 = r1 > r0; JNZ r4, loop; HALT
+    let code = [
+      instr(OpCode::Movi, &[1, 1000]),
+      instr(OpCode::Movi, &[3, 1]),
+      instr(OpCode::Add, &[2, 2, 1]),
+      instr(OpCode::Sub, &[1, 1, 3]),
+      instr(OpCode::Gt, &[4, 1, 0]),
+      instr(OpCode::Jnz, &[4, 18]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let object = LeafAsmObjectBuilder::new().text(code).build().unwrap();
+    let run = |jit: bool, fuel: Option<u64>| {
+      let mut vm = VM::new(0x1000);
+      vm.debug = false;
+      vm.fuel = fuel;
+      if jit {
+        assert!(vm.enable_jit(10));
+      }
+      vm.load_object(&object).unwrap();
+      vm.run();
+      vm
+    };
+
+    let (interpreted, compiled) = (run(false, None), run(true, None));
+    assert_eq!(compiled.registers, interpreted.registers);
+    assert_eq!(compiled.registers[2], 500500);
+    assert_eq!(compiled.status, Some(ExitStatus::Halted));
+    assert_eq!(compiled.jit.as_ref().unwrap().compiled_blocks(), 1);
+
+    let (interpreted, compiled) = (run(false, Some(2003)), run(true, Some(2003)));
+    assert_eq!((compiled.registers, compiled.pc), (interpreted.registers, interpreted.pc));
+    assert_eq!(compiled.status, Some(ExitStatus::OutOfFuel));
+  }
+}
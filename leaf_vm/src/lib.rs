@@ -0,0 +1,14 @@
+pub mod vm;
+pub mod host;
+pub mod profile;
+pub mod coverage;
+pub mod mmio;
+pub mod snapshot;
+pub mod loader;
+pub mod tasks;
+pub mod differential;
+pub mod backtrace;
+pub mod stack;
+pub mod sandbox;
+#[cfg(feature = "jit")]
+pub mod jit;
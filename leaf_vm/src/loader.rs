@@ -0,0 +1,381 @@
+//! Runtime loader for shared objects (`.leafso`, made by `leaf_asm link --shared`). A module is
+//! mapped at a base address with its `.text`, `.data` and `.rodata` one after another, its imports
+//! are bound to the exports of the program and of modules loaded before it, and its relocations
+//! are applied. Every symbol a module defines is exported; its sections are protected by their
+//! flags like the program's.
+use std::collections::BTreeMap;
+use std::ops::Range;
+use bincode::{Decode, Encode};
+use leaf_common::diagnostic::Diagnostic;
+use leaf_common::error::LeafError;
+use leaf_common::leaf_file::{LeafAsmFile, LeafAsmObject, RelocationType, SectionFlags};
+use leaf_common::object_builder::ObjectError;
+use leaf_common::symver;
+use crate::vm::{check_header, VM};
+
+/// A shared object mapped into memory.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub struct LoadedModule {
+  pub name: String,
+  pub text: Range<usize>,
+  pub data: Range<usize>,
+  pub rodata: Range<usize>,
+  /// Permissions of `.text`, `.data` and `.rodata`.
+  pub flags: [SectionFlags; 3],
+  /// Address of every symbol the module defines.
+  pub exports: BTreeMap<String, usize>,
+  /// Where the module refers to symbols of the program or other modules.
+  pub imports: Vec<Import>,
+  /// Code of versions replaced by `reload_module`, still executable so that calls already in
+  /// progress can return through it.
+  pub retired: Vec<Range<usize>>,
+}
+
+/// A relocation bound to a symbol outside the module.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub struct Import {
+  pub symbol: String,
+  /// Address of the patched operand.
+  pub patch: usize,
+  pub kind: RelocationType,
+  /// Address the operand was bound to.
+  pub address: usize,
+  /// The relocation's addend, added to `address`.
+  pub addend: i32,
+}
+
+impl Import {
+  fn value(&self) -> u32 {
+    relocated(self.address, 0, self.patch, self.kind, self.addend)
+  }
+}
+
+impl LoadedModule {
+  /// Address range taken by all three sections.
+  pub fn extent(&self) -> Range<usize> {
+    self.text.start..self.rodata.end
+  }
+
+  /// Address `name` binds to in this module, taking symbol versions into account; see `symver`.
+  pub fn lookup(&self, name: &str) -> Option<usize> {
+    symver::resolve(name, self.exports.iter().map(|(export, &address)| (export.as_str(), address)))
+  }
+
+  pub(crate) fn section(&self, section: u8) -> Option<&Range<usize>> {
+    match section {
+      0 => Some(&self.text),
+      1 => Some(&self.data),
+      2 => Some(&self.rodata),
+      _ => None,
+    }
+  }
+}
+
+/// The operand value of a relocation at `patch` against a symbol at `address`, `offset` into its
+/// section, with `addend` added to either.
+pub(crate) fn relocated(address: usize, offset: u32, patch: usize, kind: RelocationType, addend: i32) -> u32 {
+  match kind {
+    RelocationType::SectionRelative => kind.value(offset.wrapping_add_signed(addend), patch as u32),
+    _ => kind.value((address as u32).wrapping_add_signed(addend), patch as u32),
+  }
+}
+
+/// Data words stay aligned like the linker keeps them within a section.
+fn align(addr: usize) -> usize {
+  addr.div_ceil(8) * 8
+}
+
+impl VM {
+  /// Check the file header and load the shared object it contains; see `load_shared_object`.
+  pub fn load_shared(&mut self, name: &str, file: &LeafAsmFile, base: usize) -> Result<&LoadedModule, LeafError> {
+    check_header(file)?;
+    self.load_shared_object(name, &file.object, base)
+  }
+
+  /// Map `object` at `base`, growing memory if needed, bind its imports and apply its relocations.
+  /// Fails without changing the VM if the module would overlap the program, the reserved stack or
+  /// another module, or if an import is not exported by anything loaded so far.
+  pub fn load_shared_object(&mut self, name: &str, object: &LeafAsmObject, base: usize) -> Result<&LoadedModule, LeafError> {
+    if let Some(message) = self.writable_and_executable(object, &format!(" of module '{}'", name)) {
+      return Err(LeafError::Layout(message));
+    }
+    let text = base..base + object.bytecode.len();
+    let data = align(text.end)..align(text.end) + object.data.len();
+    let rodata = align(data.end)..align(data.end) + object.rodata.len();
+    let extent = text.start..rodata.end;
+    let overlaps = |range: &Range<usize>| range.start < extent.end && extent.start < range.end;
+    let program = self.code_len.max(self.data_base + self.data_len).max(self.rodata_base + self.rodata_len)
+      .max(self.data_run + self.data_len).max(self.rodata_run + self.rodata_len);
+    let stack = self.layout.stack_size.map(|size| self.heap.len() - size..self.heap.len());
+    let taken = [Some((".text, .data and .rodata".to_string(), 0..program)), stack.map(|stack| ("the stack".to_string(), stack))]
+      .into_iter()
+      .flatten()
+      .chain(self.modules.iter().map(|other| (format!("module '{}'", other.name), other.extent())));
+    for (what, range) in taken {
+      if overlaps(&range) {
+        return Err(LeafError::Layout(format!(
+          "module '{}' at 0x{:X}..0x{:X} overlaps {} at 0x{:X}..0x{:X}", name, extent.start, extent.end, what, range.start, range.end)));
+      }
+    }
+
+    let (module, patches) = self.bind(name, object, text, data, rodata)?;
+    if extent.end > self.heap.len() {
+      self.heap.resize(extent.end, 0);
+    }
+    self.heap[extent].fill(0);
+    self.heap[module.data.clone()].copy_from_slice(&object.data);
+    self.install(&module, object, patches);
+    self.modules.push(module);
+    Ok(self.modules.last().unwrap())
+  }
+
+  /// Replace module `name` with a new build of it, without restarting the program. The new code
+  /// and `.rodata` go at the end of memory; `.data` keeps its address and current contents, so the
+  /// new build must have a `.data` section of the same size. Imports of other modules that were
+  /// bound to the old version are re-pointed at the new one. The old code stays in memory, so calls
+  /// into it that are still in progress return normally.
+  pub fn reload_module(&mut self, name: &str, object: &LeafAsmObject) -> Result<&LoadedModule, LeafError> {
+    let index = self.modules.iter().position(|module| module.name == name).ok_or_else(|| LeafError::Link(Box::new(
+      Diagnostic::error("unknown-module", format!("No module named '{}' is loaded", name)))))?;
+    if let Some(message) = self.writable_and_executable(object, &format!(" of module '{}'", name)) {
+      return Err(LeafError::Layout(message));
+    }
+    let old = &self.modules[index];
+    if object.data.len() != old.data.len() {
+      return Err(LeafError::Layout(format!(
+        "module '{}' .data changed size ({} -> {} bytes), so its data cannot be preserved", name, old.data.len(), object.data.len())));
+    }
+    let data = old.data.clone();
+    let text = align(self.heap.len())..align(self.heap.len()) + object.bytecode.len();
+    let rodata = align(text.end)..align(text.end) + object.rodata.len();
+
+    let (mut module, patches) = self.bind(name, object, text, data, rodata.clone())?;
+    let old = self.modules[index].clone();
+    module.retired = old.retired.iter().cloned().chain([old.text.clone()]).collect();
+    self.heap.resize(rodata.end, 0);
+    self.install(&module, object, patches);
+
+    // Re-point other modules' imports of the old version's symbols
+    for other in &mut self.modules {
+      for import in &mut other.imports {
+        if old.lookup(&import.symbol) == Some(import.address)
+          && let Some(address) = module.lookup(&import.symbol) {
+          import.address = address;
+          self.heap[import.patch..import.patch + 4].copy_from_slice(&import.value().to_le_bytes());
+        }
+      }
+    }
+    self.modules[index] = module;
+    Ok(&self.modules[index])
+  }
+
+  /// Work out the exports, imports and relocated operands of `object` loaded at the given ranges.
+  /// Nothing is written to memory yet.
+  fn bind(&self, name: &str, object: &LeafAsmObject, text: Range<usize>, data: Range<usize>, rodata: Range<usize>)
+    -> Result<(LoadedModule, Vec<(usize, u32)>), LeafError> {
+    let mut module = LoadedModule {
+      name: name.to_string(),
+      text,
+      data,
+      rodata,
+      flags: [0, 1, 2].map(|section| object.flags(section)),
+      exports: BTreeMap::new(),
+      imports: Vec::new(),
+      retired: Vec::new(),
+    };
+    let mut addresses = Vec::with_capacity(object.symbols.len());
+    for symbol in &object.symbols {
+      let address = if symbol.external {
+        self.export(&symbol.name).ok_or_else(|| LeafError::Link(Box::new(
+          Diagnostic::error("unresolved-import", format!("Unresolved import: {}", symbol.name))
+            .with_note(format!("needed by module '{}', but nothing loaded exports it", name)))))?
+      } else {
+        let section = module.section(symbol.section).ok_or(ObjectError::InvalidSection(symbol.section))?;
+        let address = section.start + symbol.offset as usize;
+        module.exports.insert(symbol.name.clone(), address);
+        address
+      };
+      addresses.push(address);
+    }
+
+    let mut patches = Vec::with_capacity(object.relocations.len());
+    for (reloc_index, reloc) in object.relocations.iter().enumerate() {
+      let section = module.section(reloc.target_section).ok_or(ObjectError::InvalidSection(reloc.target_section))?;
+      let patch = section.start + reloc.offset as usize;
+      if patch + 4 > section.end {
+        return Err(ObjectError::RelocationOutOfBounds {
+          offset: reloc.offset,
+          section: reloc.target_section,
+          section_len: section.len(),
+        }.into());
+      }
+      let addend = object.addend(reloc_index);
+      // A label the module keeps to itself is the start of its section plus the addend
+      if let Some(target) = object.section_target(reloc_index) {
+        let start = module.section(target).ok_or(ObjectError::InvalidSection(target))?.start;
+        patches.push((patch, relocated(start, 0, patch, reloc.reloc_type, addend)));
+        continue;
+      }
+      let index = reloc.symbol_index as usize;
+      let address = *addresses.get(index).ok_or(ObjectError::BadSymbolIndex {
+        index: reloc.symbol_index,
+        symbols: object.symbols.len(),
+      })?;
+      let symbol = &object.symbols[index];
+      if symbol.external {
+        module.imports.push(Import { symbol: symbol.name.clone(), patch, kind: reloc.reloc_type, address, addend });
+      }
+      patches.push((patch, relocated(address, symbol.offset, patch, reloc.reloc_type, addend)));
+    }
+    Ok((module, patches))
+  }
+
+  /// Copy the module's code and `.rodata` into place and apply its relocations.
+  fn install(&mut self, module: &LoadedModule, object: &LeafAsmObject, patches: Vec<(usize, u32)>) {
+    self.heap[module.text.clone()].copy_from_slice(&object.bytecode);
+    self.heap[module.rodata.clone()].copy_from_slice(&object.rodata);
+    for (patch, value) in patches {
+      self.heap[patch..patch + 4].copy_from_slice(&value.to_le_bytes());
+    }
+  }
+
+  /// Modules loaded with `load_shared`, in load order.
+  pub fn modules(&self) -> &[LoadedModule] {
+    &self.modules
+  }
+
+  /// Address of a symbol defined by the program or, failing that, exported by a loaded module.
+  /// Versioned names resolve as in `symver`.
+  pub fn export(&self, name: &str) -> Option<usize> {
+    match symver::resolve(name, self.symbols.iter().map(|s| (s.name.as_str(), s))) {
+      Some(symbol) => Some(self.run_base(symbol.section)? + symbol.offset as usize),
+      None => self.modules.iter().find_map(|module| module.lookup(name)),
+    }
+  }
+
+  /// End of the executable section (of the program or a module) or retired module code containing `pc`.
+  pub(crate) fn code_end(&self, pc: usize) -> Option<usize> {
+    let program = self.section_flags.into_iter().zip(self.sections());
+    let modules = self.modules.iter().flat_map(|module| module.flags.into_iter()
+      .zip([module.text.clone(), module.data.clone(), module.rodata.clone()])
+      .chain(module.retired.iter().map(|code| (SectionFlags::EXECUTE, code.clone()))));
+    program.chain(modules)
+      .find(|(flags, code)| flags.contains(SectionFlags::EXECUTE) && code.contains(&pc))
+      .map(|(_, code)| code.end)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::leaf_file::RelocationType;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::host::{Vm, VmConfig};
+  use crate::vm::ExitStatus;
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn modules_bind_imports_to_earlier_exports() {
+    // program: square: MUL r0, r1, r1; RET
+    let program = LeafAsmObjectBuilder::new()
+      .text([instr(OpCode::Mul, &[0, 1, 1]), instr(OpCode::Ret, &[])].concat())
+      .define("square", 0, 0)
+      .build()
+      .unwrap();
+    // libquad: quad: CALL square; MOV r1, r0; CALL square; RET
+    let lib = LeafAsmObjectBuilder::new()
+      .text([
+        instr(OpCode::Call, &[0]),
+        instr(OpCode::Mov, &[1, 0]),
+        instr(OpCode::Call, &[0]),
+        instr(OpCode::Ret, &[]),
+      ].concat())
+      .define("quad", 0, 0)
+      .external("square")
+      .absolute_relocation(1, "square", 0)
+      .absolute_relocation(15, "square", 0)
+      .build()
+      .unwrap();
+
+    let mut vm = Vm::new(&program, VmConfig { memory_size: 0x1000, ..VmConfig::default() }).unwrap();
+    let module = vm.load_shared("libquad", &lib, 0x2000).unwrap();
+    assert_eq!((module.text.clone(), module.exports["quad"]), (0x2000..0x2000 + 20, 0x2000));
+    assert_eq!(vm.call("quad", &[3]).unwrap(), ExitStatus::Returned(81));
+
+    assert!(vm.load_shared("again", &lib, 0x2008).is_err());
+    let orphan = LeafAsmObjectBuilder::new()
+      .text(instr(OpCode::Call, &[0]))
+      .external("missing")
+      .absolute_relocation(1, "missing", 0)
+      .build()
+      .unwrap();
+    assert!(vm.load_shared("orphan", &orphan, 0x3000).unwrap_err().to_string().contains("Unresolved import: missing"));
+  }
+
+  #[test]
+  fn section_relocations_point_into_the_module() {
+    let program = LeafAsmObjectBuilder::new().text(instr(OpCode::Halt, &[])).build().unwrap();
+    // two: JMP .text+6; HALT; MOVI r0, 2; RET
+    let lib = LeafAsmObjectBuilder::new()
+      .text([instr(OpCode::Jmp, &[0]), instr(OpCode::Halt, &[]), instr(OpCode::Movi, &[0, 2]), instr(OpCode::Ret, &[])].concat())
+      .define("two", 0, 0)
+      .section_relocation(1, 0, RelocationType::Absolute, 0)
+      .addend(0, 6)
+      .build()
+      .unwrap();
+    let mut vm = Vm::new(&program, VmConfig { memory_size: 0x1000, ..VmConfig::default() }).unwrap();
+    vm.load_shared("libtwo", &lib, 0x2000).unwrap();
+    assert_eq!(vm.call("two", &[]).unwrap(), ExitStatus::Returned(2));
+  }
+
+  #[test]
+  fn reloading_keeps_data_and_repoints_importers() {
+    let program = LeafAsmObjectBuilder::new().text(instr(OpCode::Halt, &[])).build().unwrap();
+    // bump: counter += r1 (twice as fast in version 2); r0 = counter; RET
+    let counter = |adds: usize| {
+      let add = instr(OpCode::Add, &[0, 0, 1]);
+      let text = [instr(OpCode::Loadi, &[0, 0]), add.repeat(adds), instr(OpCode::Storei, &[0, 0]), instr(OpCode::Ret, &[])].concat();
+      LeafAsmObjectBuilder::new()
+        .text(text)
+        .data(vec![0; 8])
+        .define("bump", 0, 0)
+        .define("counter", 1, 0)
+        .absolute_relocation(5, "counter", 0)
+        .absolute_relocation(9 + 13 * adds as u32 + 5, "counter", 0)
+        .build()
+        .unwrap()
+    };
+    // user: twice: CALL bump; CALL bump; RET
+    let user = LeafAsmObjectBuilder::new()
+      .text([instr(OpCode::Call, &[0]), instr(OpCode::Call, &[0]), instr(OpCode::Ret, &[])].concat())
+      .define("twice", 0, 0)
+      .external("bump")
+      .absolute_relocation(1, "bump", 0)
+      .absolute_relocation(6, "bump", 0)
+      .build()
+      .unwrap();
+
+    let mut vm = Vm::new(&program, VmConfig { memory_size: 0x1000, ..VmConfig::default() }).unwrap();
+    vm.load_shared("counter", &counter(1), 0x2000).unwrap();
+    vm.load_shared("user", &user, 0x3000).unwrap();
+    assert_eq!(vm.call("bump", &[5]).unwrap(), ExitStatus::Returned(5));
+
+    let reloaded = vm.reload("counter", &counter(2)).unwrap();
+    assert_eq!(reloaded.data, 0x2020..0x2028);
+    assert_eq!(reloaded.retired.first(), Some(&(0x2000..0x2020)));
+    assert_ne!(vm.address_of("bump"), Some(0x2000));
+    // The counter kept its value, and the user module now calls the new code
+    assert_eq!(vm.call("twice", &[1]).unwrap(), ExitStatus::Returned(9));
+
+    let mut grown = counter(2);
+    grown.data.extend([0; 8]);
+    assert!(vm.reload("counter", &grown).is_err());
+  }
+}
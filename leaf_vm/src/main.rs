@@ -1,28 +1,130 @@
+use clap::Parser;
+use log::error;
 use leaf_common::leaf_file::LeafAsmFile;
 use leaf_common::ReadableResource;
-use crate::vm::VM;
+use leaf_vm::host::{parse_size, LayoutArgs};
+use leaf_vm::mmio::{Console, Timer};
+use leaf_vm::vm::{ExitStatus, VM};
 
-mod vm;
+#[derive(Parser)]
+#[command(author, version, about = "Run a linked .leafexe", long_about = None)]
+struct Args {
+  /// Linked executable to run
+  program: String,
+
+  /// Initial memory size in bytes; the stack starts at the top
+  #[arg(short, long, default_value_t = 0x10000)]
+  memory: usize,
+
+  /// Log every executed instruction
+  #[arg(long)]
+  trace: bool,
+
+  /// Stop after executing this many instructions
+  #[arg(long)]
+  fuel: Option<u64>,
+
+  /// Directory the program can open files in; without it, the OPEN syscall always fails
+  #[arg(long, value_name = "DIR")]
+  fs_root: Option<std::path::PathBuf>,
+
+  /// Refuse the TIME, READ and OPEN syscalls unless allowed with --allow-syscall
+  #[arg(long)]
+  deterministic: bool,
+
+  /// Syscall number to allow in deterministic mode (repeatable)
+  #[arg(long = "allow-syscall", requires = "deterministic")]
+  allow_syscalls: Vec<u64>,
+
+  #[command(flatten)]
+  layout: LayoutArgs,
+
+  /// Fault when a RET pops a return address other than the one its CALL pushed
+  #[arg(long)]
+  stack_canaries: bool,
+
+  /// Map a console at this address: storing a word there prints its low byte
+  #[arg(long, value_parser = parse_size)]
+  console: Option<usize>,
+
+  /// Map an instruction-counting timer at this address
+  #[arg(long, value_parser = parse_size)]
+  timer: Option<usize>,
+
+  /// Compile hot code to native code
+  #[cfg(feature = "jit")]
+  #[arg(long)]
+  jit: bool,
+
+  /// Run the program on both the interpreter and the JIT, comparing their state every this many
+  /// instructions and reporting the first divergence. Output from the program appears twice.
+  #[cfg(feature = "jit")]
+  #[arg(long, value_name = "INTERVAL", conflicts_with_all = ["jit", "fuel"])]
+  differential: Option<u64>,
+}
 
 fn main() {
+  let args = Args::parse();
+
   // Set up logging level
-  let log_level = "info";
+  let log_level = if args.trace { "info" } else { "warn" };
   unsafe {
     std::env::set_var("RUST_LOG", log_level);
   }
   env_logger::init();
 
-  let args: Vec<String> = std::env::args().collect();
-  let exe_path = if args.len() > 1 {
-    &args[1]
-  } else {
-    // "C:\\Users\\bucin\\RustroverProjects\\leaf\\leaf_asm\\fixtures\\out\\exe\\fibonacci.leafexe"
-    "C:\\Users\\bucin\\RustroverProjects\\leaf\\leaf_asm\\new_fixtures\\09_complex_syscalls.leafexe"
-  };
-
-  let mut vm = VM::new(0x10000);
-  let x = LeafAsmFile::read_from_path(exe_path)
-    .expect("Failed to read ELF file");
-  vm.load_program(&x);
+  #[cfg(feature = "jit")]
+  if let Some(interval) = args.differential {
+    let (mut interpreted, mut compiled) = (new_vm(&args), new_vm(&args));
+    if !compiled.enable_jit(leaf_vm::jit::DEFAULT_THRESHOLD) {
+      error!("The JIT does not support this host");
+      std::process::exit(1);
+    }
+    match leaf_vm::differential::run_differential(&mut interpreted, &mut compiled, interval) {
+      Ok(executed) => println!("No divergence in {} instructions", executed),
+      Err(divergence) => {
+        error!("{}", divergence);
+        std::process::exit(1);
+      }
+    }
+    return;
+  }
+  let mut vm = new_vm(&args);
   vm.run();
+  if vm.status == Some(ExitStatus::OutOfFuel) {
+    error!("Out of fuel after {} instructions", args.fuel.unwrap_or_default());
+  }
+  std::process::exit(vm.exit_code().unwrap_or(0));
+}
+
+/// A VM set up from the command line, with the program loaded.
+fn new_vm(args: &Args) -> VM {
+  let mut vm = VM::new(args.memory);
+  vm.debug = args.trace;
+  vm.layout = args.layout.layout();
+  vm.stack_canaries = args.stack_canaries;
+  vm.fs_root = args.fs_root.clone();
+  let devices = [
+    args.console.map(|addr| vm.map_device(addr, Console::<std::io::Stdout>::SIZE, Console::stdout())),
+    args.timer.map(|addr| vm.map_device(addr, Timer::SIZE, Timer::new())),
+  ];
+  if let Some(Err(e)) = devices.into_iter().flatten().find(|result| result.is_err()) {
+    error!("{}", e);
+    std::process::exit(1);
+  }
+  vm.fuel = args.fuel;
+  vm.deterministic = args.deterministic;
+  for number in &args.allow_syscalls {
+    vm.allow_syscall(*number);
+  }
+  #[cfg(feature = "jit")]
+  if args.jit && !vm.enable_jit(leaf_vm::jit::DEFAULT_THRESHOLD) {
+    error!("The JIT does not support this host; interpreting");
+  }
+  let result = LeafAsmFile::read_from_path(&args.program).and_then(|file| vm.load_program(&file));
+  if let Err(e) = result {
+    error!("Failed to load {}: {}", args.program, e);
+    std::process::exit(1);
+  }
+  vm
 }
@@ -0,0 +1,190 @@
+//! Memory-mapped devices. A device claims an address range with `VM::map_device`; `LOAD`/`STORE`
+//! (and their immediate forms) inside it call the device instead of touching memory, a 64-bit word
+//! at a time. Hosts keep access to a device after mapping it by mapping an `Rc<RefCell<_>>`.
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+pub trait Device {
+  /// Read the word at `offset` from the start of the device's range.
+  fn read(&mut self, offset: usize) -> u64;
+
+  fn write(&mut self, offset: usize, value: u64);
+
+  /// Called as the VM executes instructions, for devices that keep time.
+  fn tick(&mut self, _instructions: u64) {}
+}
+
+pub(crate) struct MappedDevice {
+  pub(crate) range: std::ops::Range<usize>,
+  pub(crate) device: Box<dyn Device>,
+}
+
+impl<D: Device> Device for Rc<RefCell<D>> {
+  fn read(&mut self, offset: usize) -> u64 {
+    self.borrow_mut().read(offset)
+  }
+
+  fn write(&mut self, offset: usize, value: u64) {
+    self.borrow_mut().write(offset, value)
+  }
+
+  fn tick(&mut self, instructions: u64) {
+    self.borrow_mut().tick(instructions)
+  }
+}
+
+/// A character output port: storing a word at offset 0 writes its low byte. Reads return 0.
+pub struct Console<W: Write> {
+  out: W,
+}
+
+impl<W: Write> Console<W> {
+  /// Size of the console's address range.
+  pub const SIZE: usize = 8;
+
+  pub fn new(out: W) -> Self {
+    Console { out }
+  }
+
+  pub fn into_inner(self) -> W {
+    self.out
+  }
+}
+
+impl Console<std::io::Stdout> {
+  pub fn stdout() -> Self {
+    Console::new(std::io::stdout())
+  }
+}
+
+impl<W: Write> Device for Console<W> {
+  fn read(&mut self, _offset: usize) -> u64 {
+    0
+  }
+
+  fn write(&mut self, offset: usize, value: u64) {
+    if offset == 0 {
+      // Output is best effort; a closed pipe should not stop the program
+      let _ = self.out.write_all(&[value as u8]).and_then(|_| self.out.flush());
+    }
+  }
+}
+
+/// Counts executed instructions, so programs can measure time deterministically. Offset 0 reads the
+/// count; storing there resets it to the stored value.
+#[derive(Debug, Default)]
+pub struct Timer {
+  ticks: u64,
+}
+
+impl Timer {
+  pub const SIZE: usize = 8;
+
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn ticks(&self) -> u64 {
+    self.ticks
+  }
+}
+
+impl Device for Timer {
+  fn read(&mut self, offset: usize) -> u64 {
+    if offset == 0 { self.ticks } else { 0 }
+  }
+
+  fn write(&mut self, offset: usize, value: u64) {
+    if offset == 0 {
+      self.ticks = value;
+    }
+  }
+
+  fn tick(&mut self, instructions: u64) {
+    self.ticks = self.ticks.wrapping_add(instructions);
+  }
+}
+
+/// A `width` x `height` display with one word per pixel (row-major, `0x00RRGGBB` in the low bits),
+/// so pixel `(x, y)` is at offset `(y * width + x) * 8`.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Vec<u32>,
+}
+
+impl Framebuffer {
+  pub fn new(width: usize, height: usize) -> Self {
+    Framebuffer { width, height, pixels: vec![0; width * height] }
+  }
+
+  /// Size of the framebuffer's address range.
+  pub fn size(&self) -> usize {
+    self.pixels.len() * 8
+  }
+
+  pub fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+    (x < self.width).then(|| self.pixels.get(y * self.width + x).copied()).flatten()
+  }
+}
+
+impl Device for Framebuffer {
+  fn read(&mut self, offset: usize) -> u64 {
+    self.pixels.get(offset / 8).map_or(0, |pixel| *pixel as u64)
+  }
+
+  fn write(&mut self, offset: usize, value: u64) {
+    if let Some(pixel) = self.pixels.get_mut(offset / 8) {
+      *pixel = value as u32;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::vm::{ExitStatus, VM};
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn loads_and_stores_reach_mapped_devices() {
+    let code = [
+      instr(OpCode::Movi, &[1, b'h' as u32]),
+      instr(OpCode::Storei, &[1, 0xF000]),
+      instr(OpCode::Movi, &[1, b'i' as u32]),
+      instr(OpCode::Storei, &[1, 0xF000]),
+      instr(OpCode::Loadi, &[2, 0xF008]),
+      instr(OpCode::Movi, &[3, 0xFF0000]),
+      instr(OpCode::Movi, &[4, 0x10000 + 3 * 8]),
+      instr(OpCode::Store, &[3, 4]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let console = Rc::new(RefCell::new(Console::new(Vec::new())));
+    let screen = Rc::new(RefCell::new(Framebuffer::new(2, 2)));
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.map_device(0xF000, Console::<Vec<u8>>::SIZE, console.clone()).unwrap();
+    vm.map_device(0xF008, Timer::SIZE, Timer::new()).unwrap();
+    vm.map_device(0x10000, screen.borrow().size(), screen.clone()).unwrap();
+    assert!(vm.map_device(0xF004, 8, Timer::new()).is_err());
+
+    vm.load_object(&LeafAsmObjectBuilder::new().text(code).build().unwrap()).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+    assert_eq!(console.borrow().out, b"hi");
+    // The timer has counted the four instructions before the LOADI, and the LOADI itself
+    assert_eq!(vm.registers[2], 5);
+    assert_eq!(screen.borrow().pixel(1, 1), Some(0xFF0000));
+  }
+}
@@ -0,0 +1,128 @@
+//! Turn the VM's per-address execution counts into a report, aggregated by symbol.
+use serde::{Deserialize, Serialize};
+use leaf_common::disassembler::disassemble_at;
+use leaf_common::error::{FormatError, LeafError};
+use leaf_common::leaf_file::LeafAsmObject;
+use leaf_common::symbolicate::symbolicate;
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct InstructionCount {
+  pub address: u32,
+  pub count: u64,
+  pub instruction: String,
+  pub symbol: Option<String>,
+  pub file: Option<String>,
+  pub line: Option<u32>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SymbolCount {
+  pub name: String,
+  pub count: u64,
+}
+
+/// Execution counts for one run, hottest first.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Profile {
+  /// Instructions executed in total.
+  pub total: u64,
+  /// Every instruction that ran at least once.
+  pub instructions: Vec<InstructionCount>,
+  /// Counts summed over each code symbol; code before the first symbol is under "<unknown>".
+  pub symbols: Vec<SymbolCount>,
+}
+
+impl Profile {
+  /// Build a profile from `counts[address]`, as returned by `VM::profile`, for the loaded `object`.
+  pub fn new(object: &LeafAsmObject, counts: &[u64]) -> Self {
+    let mut instructions = Vec::new();
+    let mut symbols: Vec<SymbolCount> = Vec::new();
+    for (address, &count) in counts.iter().enumerate().filter(|(_, count)| **count > 0) {
+      let location = symbolicate(object, address as u32);
+      let symbol = location.symbol.map(|s| s.name.clone());
+      let name = symbol.clone().unwrap_or_else(|| "<unknown>".to_string());
+      match symbols.iter_mut().find(|s| s.name == name) {
+        Some(entry) => entry.count += count,
+        None => symbols.push(SymbolCount { name, count }),
+      }
+      instructions.push(InstructionCount {
+        address: address as u32,
+        count,
+        instruction: disassemble_at(&object.bytecode, address).0,
+        symbol,
+        file: location.file.map(str::to_string),
+        line: location.line,
+      });
+    }
+    // Stable sorts keep address order among equal counts
+    instructions.sort_by_key(|i| std::cmp::Reverse(i.count));
+    symbols.sort_by_key(|s| std::cmp::Reverse(s.count));
+    Profile { total: counts.iter().sum(), instructions, symbols }
+  }
+
+  /// Human-readable summary: time per symbol, then the `top` hottest instructions.
+  pub fn report(&self, top: usize) -> String {
+    let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+    let mut out = format!("{} instructions executed\n\n  count      %  symbol\n", self.total);
+    for symbol in &self.symbols {
+      out.push_str(&format!("{:>7} {:>6.2}  {}\n", symbol.count, percent(symbol.count), symbol.name));
+    }
+    out.push_str("\n  count      %  address  instruction\n");
+    for instr in self.instructions.iter().take(top) {
+      let location = match (&instr.symbol, &instr.file, instr.line) {
+        (_, Some(file), Some(line)) => format!("  ({}:{})", file, line),
+        (Some(symbol), _, _) => format!("  ({})", symbol),
+        _ => String::new(),
+      };
+      out.push_str(&format!("{:>7} {:>6.2}  0x{:04X}   {}{}\n",
+        instr.count, percent(instr.count), instr.address, instr.instruction, location));
+    }
+    out
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("profile serializes")
+  }
+
+  /// Read back a profile written by `to_json`, as the linker does to order code.
+  pub fn from_json(json: &str) -> Result<Self, LeafError> {
+    serde_json::from_str(json).map_err(|e| FormatError::Decode(e.to_string()).into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::vm::VM;
+
+  #[test]
+  fn counts_loop_iterations_by_symbol() {
+    // main: MOVI r1, 3    loop: SUB r1, r1, r2; JNZ r1, loop; HALT   (r2 = 1)
+    let code = vec![
+      0x16, 1, 0, 0, 0, 3, 0, 0, 0,
+      0x16, 2, 0, 0, 0, 1, 0, 0, 0,
+      0x02, 1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0,
+      0x0B, 1, 0, 0, 0, 18, 0, 0, 0,
+      0x13,
+    ];
+    let object = LeafAsmObjectBuilder::new().text(code).define("main", 0, 0).define("loop", 0, 18).build().unwrap();
+    let mut vm = VM::new(0x100);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    vm.enable_profiling();
+    vm.run();
+
+    let profile = Profile::new(&object, vm.profile().unwrap());
+    assert_eq!(profile.total, 9);
+    assert_eq!(profile.symbols, vec![
+      SymbolCount { name: "loop".to_string(), count: 7 },
+      SymbolCount { name: "main".to_string(), count: 2 },
+    ]);
+    assert_eq!(profile.instructions[0].address, 18);
+    assert_eq!(profile.instructions[0].instruction, "SUB r1, r1, r2");
+    assert!(profile.report(3).contains("      7  77.78  loop"));
+    assert!(profile.to_json().contains("\"total\": 9"));
+    assert_eq!(Profile::from_json(&profile.to_json()).unwrap(), profile);
+  }
+}
@@ -0,0 +1,52 @@
+//! The filesystem a program sees through `OPEN`: one directory on the host, set with `VM::fs_root`
+//! or `--fs-root`. Names are paths inside it, `/` is its top and `..` cannot climb above it, and a
+//! symbolic link that leads out of it is refused, so a program only reaches the files it was given.
+use std::path::{Component, Path, PathBuf};
+
+/// The host path of the file a program calls `name`, inside `root`, or why it may not open it.
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, String> {
+  let mut relative = PathBuf::new();
+  for component in Path::new(name).components() {
+    match component {
+      Component::Normal(part) => relative.push(part),
+      Component::ParentDir if !relative.pop() => return Err(format!("'{}' is outside the filesystem root", name)),
+      Component::ParentDir | Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+    }
+  }
+  let root = root.canonicalize().map_err(|e| format!("filesystem root {}: {}", root.display(), e))?;
+  let path = root.join(&relative);
+  // The file itself may not exist yet, so check the deepest part of the path that does. A link
+  // that leads nowhere is refused too, since creating the file would follow it.
+  let existing = path.ancestors().find(|ancestor| ancestor.symlink_metadata().is_ok()).unwrap_or(&root);
+  match existing.canonicalize() {
+    Ok(target) if target.starts_with(&root) => Ok(path),
+    _ => Err(format!("'{}' leads outside the filesystem root", name)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn names_stay_inside_the_root() {
+    let root = std::env::temp_dir().join(format!("leaf-sandbox-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("a.txt"), "a").unwrap();
+    let canonical = root.canonicalize().unwrap();
+
+    assert_eq!(resolve(&root, "a.txt"), Ok(canonical.join("a.txt")));
+    assert_eq!(resolve(&root, "/a.txt"), Ok(canonical.join("a.txt")));
+    assert_eq!(resolve(&root, "sub/../new.txt"), Ok(canonical.join("new.txt")));
+    assert!(resolve(&root, "../a.txt").is_err());
+    assert!(resolve(&root, "sub/../../a.txt").is_err());
+    #[cfg(unix)]
+    {
+      std::os::unix::fs::symlink(std::env::temp_dir(), root.join("out")).unwrap();
+      std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+      assert!(resolve(&root, "out/a.txt").is_err());
+      assert!(resolve(&root, "dangling").is_err());
+    }
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
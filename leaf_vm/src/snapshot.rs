@@ -0,0 +1,180 @@
+//! Checkpoints of a running VM: registers, PC, memory (which includes the stack) and the program
+//! layout, saved as a bincode file so a run can be resumed later or a state replayed in a test.
+//! Interrupt and task state are included; open file descriptors and host handlers are not.
+use std::io::{Read, Write};
+use bincode::{Decode, Encode};
+use leaf_common::error::{FormatError, LeafError};
+use leaf_common::leaf_file::{DebugInfo, SectionFlags, SymbolEntry};
+use leaf_common::{ReadableResource, WriteableResource};
+use crate::loader::LoadedModule;
+use crate::stack::CallFrame;
+use crate::tasks::Task;
+use crate::vm::{ExitStatus, VM};
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"LSN\0";
+pub const SNAPSHOT_VERSION: u16 = 10;
+
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub struct Snapshot {
+  pub magic: [u8; 4],
+  pub version: u16,
+  pub registers: [u64; 32],
+  pub pc: usize,
+  pub memory: Vec<u8>,
+  pub halted: bool,
+  pub status: Option<ExitStatus>,
+  pub code_len: usize,
+  pub data_len: usize,
+  pub rodata_len: usize,
+  pub data_base: usize,
+  pub rodata_base: usize,
+  pub data_run: usize,
+  pub rodata_run: usize,
+  pub section_flags: [SectionFlags; 3],
+  pub stack_limit: usize,
+  pub stack_top: usize,
+  pub frames: Vec<CallFrame>,
+  /// Defined symbols of the loaded program.
+  pub symbols: Vec<SymbolEntry>,
+  pub debug_info: Option<DebugInfo>,
+  pub interrupts_enabled: bool,
+  pub pending_interrupts: u64,
+  pub vectors: Option<usize>,
+  pub timer_period: u64,
+  pub timer_countdown: u64,
+  pub current_task: u64,
+  /// Tasks waiting to run, in scheduling order.
+  pub tasks: Vec<Task>,
+  pub next_task_id: u64,
+  /// Shared objects mapped into `memory`.
+  pub modules: Vec<LoadedModule>,
+}
+
+impl VM {
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      magic: SNAPSHOT_MAGIC,
+      version: SNAPSHOT_VERSION,
+      registers: self.registers,
+      pc: self.pc,
+      memory: self.heap.clone(),
+      halted: self.halted,
+      status: self.status.clone(),
+      code_len: self.code_len,
+      data_len: self.data_len,
+      rodata_len: self.rodata_len,
+      data_base: self.data_base,
+      rodata_base: self.rodata_base,
+      data_run: self.data_run,
+      rodata_run: self.rodata_run,
+      section_flags: self.section_flags,
+      stack_limit: self.stack_limit,
+      stack_top: self.stack_top,
+      frames: self.frames.clone(),
+      symbols: self.symbols.clone(),
+      debug_info: self.debug_info.clone(),
+      interrupts_enabled: self.interrupts_enabled,
+      pending_interrupts: self.pending_interrupts,
+      vectors: self.vectors,
+      timer_period: self.timer_period,
+      timer_countdown: self.timer_countdown,
+      current_task: self.current_task,
+      tasks: self.tasks.iter().cloned().collect(),
+      next_task_id: self.next_task_id,
+      modules: self.modules.clone(),
+    }
+  }
+
+  /// Put the VM back into the state of `snapshot`, replacing its memory. Host syscalls, the `BREAK`
+  /// handler and profiling counts are kept.
+  pub fn restore(&mut self, snapshot: &Snapshot) {
+    self.registers = snapshot.registers;
+    self.pc = snapshot.pc;
+    self.heap = snapshot.memory.clone();
+    self.halted = snapshot.halted;
+    self.status = snapshot.status.clone();
+    self.code_len = snapshot.code_len;
+    self.data_len = snapshot.data_len;
+    self.rodata_len = snapshot.rodata_len;
+    self.data_base = snapshot.data_base;
+    self.rodata_base = snapshot.rodata_base;
+    self.data_run = snapshot.data_run;
+    self.rodata_run = snapshot.rodata_run;
+    self.section_flags = snapshot.section_flags;
+    self.stack_limit = snapshot.stack_limit;
+    self.stack_top = snapshot.stack_top;
+    self.frames = snapshot.frames.clone();
+    self.symbols = snapshot.symbols.clone();
+    self.debug_info = snapshot.debug_info.clone();
+    self.interrupts_enabled = snapshot.interrupts_enabled;
+    self.pending_interrupts = snapshot.pending_interrupts;
+    self.vectors = snapshot.vectors;
+    self.timer_period = snapshot.timer_period;
+    self.timer_countdown = snapshot.timer_countdown;
+    self.current_task = snapshot.current_task;
+    self.tasks = snapshot.tasks.iter().cloned().collect();
+    self.next_task_id = snapshot.next_task_id;
+    self.modules = snapshot.modules.clone();
+  }
+}
+
+impl WriteableResource for Snapshot {
+  fn write_to(&self, writer: &mut dyn Write) -> Result<(), LeafError> {
+    writer.write_all(&bincode::encode_to_vec(self, bincode::config::standard())?)?;
+    Ok(())
+  }
+}
+
+impl ReadableResource for Snapshot {
+  fn read_from(reader: &mut dyn Read) -> Result<Self, LeafError> {
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
+    let (snapshot, _): (Snapshot, _) = bincode::decode_from_slice(&buffer, bincode::config::standard())?;
+    if snapshot.magic != SNAPSHOT_MAGIC {
+      return Err(FormatError::BadMagic(snapshot.magic).into());
+    }
+    if snapshot.version != SNAPSHOT_VERSION {
+      return Err(FormatError::UnsupportedVersion(snapshot.version).into());
+    }
+    Ok(snapshot)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+
+  #[test]
+  fn restored_vm_resumes_where_the_snapshot_was_taken() {
+    // MOVI r1, 5; MOVI r2, 1; ADD r1, r1, r2; HALT
+    let movi = OpCode::opcode_to_byte(&OpCode::Movi);
+    let mut code = vec![movi, 1, 0, 0, 0, 5, 0, 0, 0, movi, 2, 0, 0, 0, 1, 0, 0, 0];
+    code.extend([OpCode::opcode_to_byte(&OpCode::Add), 1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    code.push(OpCode::opcode_to_byte(&OpCode::Halt));
+    let object = LeafAsmObjectBuilder::new().text(code).build().unwrap();
+    let mut vm = VM::new(0x100);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    vm.step();
+    vm.step();
+
+    let mut file = Vec::new();
+    vm.snapshot().write_to(&mut file).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[1], 6);
+
+    let snapshot = Snapshot::read_from(&mut file.as_slice()).unwrap();
+    let mut resumed = VM::new(0);
+    resumed.debug = false;
+    resumed.restore(&snapshot);
+    assert_eq!((resumed.pc, resumed.registers[1], resumed.registers[15]), (18, 5, 0x100));
+    resumed.run();
+    assert_eq!(resumed.registers[1], 6);
+    assert_eq!(resumed.status, Some(ExitStatus::Halted));
+
+    file[0] = b'X';
+    assert!(Snapshot::read_from(&mut file.as_slice()).is_err());
+  }
+}
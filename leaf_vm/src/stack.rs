@@ -0,0 +1,140 @@
+//! Stack bounds checks for `PUSH`, `POP`, `CALL`, `RET` and interrupt frames, and optional stack
+//! canaries. A fault names the function that overflowed or underflowed the stack rather than
+//! letting it run into `.data` or read past the top.
+//!
+//! With `VM::stack_canaries` on, `CALL` remembers where it put the return address and what it was,
+//! and `RET` checks that word is unchanged before jumping through it, so a buffer overrun that
+//! smashes a return address faults in the function that returns instead of jumping somewhere
+//! random. Frames that were never popped (a task switch that replaced `r15`, say) are dropped
+//! once the stack unwinds past them.
+use bincode::{Decode, Encode};
+use crate::vm::VM;
+
+/// The return address a `CALL` pushed, checked again by the matching `RET`.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub struct CallFrame {
+  /// Where the return address was written.
+  pub slot: usize,
+  pub return_addr: u64,
+}
+
+impl VM {
+  /// Check that `len` more bytes can be pushed on the stack, faulting if not.
+  pub(crate) fn check_push(&mut self, op: &str, len: usize) -> bool {
+    let sp = self.registers[15] as usize;
+    if sp >= self.stack_limit + len {
+      return true;
+    }
+    let message = format!("Stack overflow in {} at {}: sp=0x{:04X}, stack limit 0x{:04X}", op, self.function(), sp, self.stack_limit);
+    self.fault(message);
+    false
+  }
+
+  /// Check that `len` bytes can be popped off the stack, faulting if that would read past the end
+  /// of memory or, for the task's own stack, above its top.
+  pub(crate) fn check_pop(&mut self, op: &str, len: usize) -> bool {
+    let sp = self.registers[15] as usize;
+    let on_stack = (self.stack_limit..=self.stack_top).contains(&sp);
+    let end = sp.checked_add(len);
+    if end.is_some_and(|end| end <= self.heap.len() && (!on_stack || end <= self.stack_top)) {
+      return true;
+    }
+    let message = format!("Stack underflow in {} at {}: sp=0x{:04X}, stack top 0x{:04X}", op, self.function(), sp, self.stack_top);
+    self.fault(message);
+    false
+  }
+
+  /// Remember the return address `CALL` just pushed at `slot`.
+  pub(crate) fn push_frame(&mut self, slot: usize, return_addr: u64) {
+    if self.stack_canaries {
+      self.frames.retain(|frame| frame.slot > slot);
+      self.frames.push(CallFrame { slot, return_addr });
+    }
+  }
+
+  /// Check the return address `RET` is about to pop from `slot` is the one `CALL` pushed. Frames
+  /// not made by `CALL`, such as `Vm::call`'s, are not checked.
+  pub(crate) fn check_frame(&mut self, slot: usize, return_addr: u64) -> bool {
+    if !self.stack_canaries {
+      return true;
+    }
+    while self.frames.last().is_some_and(|frame| frame.slot < slot) {
+      self.frames.pop();
+    }
+    match self.frames.last() {
+      Some(frame) if frame.slot == slot => {
+        let expected = frame.return_addr;
+        self.frames.pop();
+        if expected != return_addr {
+          let message = format!(
+            "Stack corruption in RET at {}: return address at 0x{:04X} is 0x{:X}, CALL pushed 0x{:X}",
+            self.function(), slot, return_addr, expected);
+          self.fault(message);
+          return false;
+        }
+        true
+      }
+      _ => true,
+    }
+  }
+
+  /// The function containing the PC, as `symbol+0xN`, or the bare address.
+  fn function(&self) -> String {
+    match self.symbol_at(self.pc) {
+      Some((name, offset)) => format!("{}+0x{:X}", name, offset),
+      None => format!("0x{:04X}", self.pc),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use crate::vm::{ExitStatus, VM};
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  fn run(code: Vec<u8>, canaries: bool) -> VM {
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .define("main", 0, 0)
+      .define("smash", 0, 6)
+      .build()
+      .unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.stack_canaries = canaries;
+    vm.fuel = Some(100);
+    vm.load_object(&object).unwrap();
+    vm.run();
+    vm
+  }
+
+  #[test]
+  fn underflow_and_smashed_return_addresses_fault() {
+    // main: POP r1 with nothing pushed
+    let vm = run([instr(OpCode::Pop, &[1]), instr(OpCode::Halt, &[])].concat(), false);
+    assert_eq!(vm.status, Some(ExitStatus::Fault("Stack underflow in POP at main+0x0: sp=0x1000, stack top 0x1000".to_string())));
+
+    // main: CALL smash; HALT / smash: STOREI r0, [0xFF8]; RET, overwriting its return address
+    let code = [
+      instr(OpCode::Call, &[6]),
+      instr(OpCode::Halt, &[]),
+      instr(OpCode::Storei, &[0, 0xFF8]),
+      instr(OpCode::Ret, &[]),
+    ].concat();
+    let vm = run(code.clone(), true);
+    assert_eq!(vm.status, Some(ExitStatus::Fault(
+      "Stack corruption in RET at smash+0x9: return address at 0x0FF8 is 0x0, CALL pushed 0x5".to_string())));
+    // Without canaries the RET jumps back to main, which calls smash again forever
+    let vm = run(code, false);
+    assert_eq!(vm.status, Some(ExitStatus::OutOfFuel));
+  }
+}
@@ -0,0 +1,137 @@
+//! Green threads. `SYS_SPAWN` starts a task with its own register file and stack, `SYS_YIELD` parks
+//! the running task and resumes the next ready one round-robin, and `SYS_TASK_EXIT` ends it. Tasks
+//! share memory and only switch inside those syscalls, so there are no host threads involved.
+use bincode::{Decode, Encode};
+use crate::stack::CallFrame;
+use crate::vm::VM;
+
+/// Stack size of a spawned task when the layout does not reserve a stack size.
+pub const TASK_STACK_SIZE: usize = 0x1000;
+
+/// A task that is not running: everything needed to resume it.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub struct Task {
+  pub id: u64,
+  pub registers: [u64; 32],
+  pub pc: usize,
+  /// Bottom of the task's stack.
+  pub stack_limit: usize,
+  /// Top of the task's stack.
+  pub stack_top: usize,
+  /// Return addresses checked by stack canaries.
+  pub frames: Vec<CallFrame>,
+  pub interrupts_enabled: bool,
+}
+
+impl VM {
+  /// Id of the running task; the program starts as task 0.
+  pub fn current_task(&self) -> u64 {
+    self.current_task
+  }
+
+  /// Tasks waiting to run, in the order they will.
+  pub fn ready_tasks(&self) -> impl Iterator<Item = &Task> {
+    self.tasks.iter()
+  }
+
+  /// Queue a task at `entry` with `arg` in r1 and a fresh stack at the end of memory.
+  pub(crate) fn spawn_task(&mut self, entry: usize, arg: u64) -> u64 {
+    let size = self.layout.stack_size.unwrap_or(TASK_STACK_SIZE);
+    let base = self.heap.len();
+    self.heap.resize(base + size, 0);
+    let mut registers = [0; 32];
+    registers[1] = arg;
+    registers[15] = (base + size) as u64;
+    let id = self.next_task_id;
+    self.next_task_id += 1;
+    self.tasks.push_back(Task { id, registers, pc: entry, stack_limit: base, stack_top: base + size, frames: Vec::new(), interrupts_enabled: self.interrupts_enabled });
+    id
+  }
+
+  /// Move the running task to the back of the queue and resume the first ready one, if any. The PC
+  /// must already point past the `SYSCALL`.
+  pub(crate) fn yield_task(&mut self) {
+    if let Some(next) = self.tasks.pop_front() {
+      let current = self.switch_to(next);
+      self.tasks.push_back(current);
+    }
+  }
+
+  /// Drop the running task and resume the first ready one; false if it was the last task.
+  pub(crate) fn exit_task(&mut self) -> bool {
+    match self.tasks.pop_front() {
+      Some(next) => {
+        self.switch_to(next);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Make `task` the running one, returning the state of the task it replaces.
+  fn switch_to(&mut self, task: Task) -> Task {
+    let current = Task {
+      id: self.current_task,
+      registers: self.registers,
+      pc: self.pc,
+      stack_limit: self.stack_limit,
+      stack_top: self.stack_top,
+      frames: std::mem::take(&mut self.frames),
+      interrupts_enabled: self.interrupts_enabled,
+    };
+    self.current_task = task.id;
+    self.registers = task.registers;
+    self.pc = task.pc;
+    self.stack_limit = task.stack_limit;
+    self.stack_top = task.stack_top;
+    self.frames = task.frames;
+    self.interrupts_enabled = task.interrupts_enabled;
+    current
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use leaf_common::leaf_ast::OpCode;
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
+  use leaf_common::syscall::*;
+  use crate::vm::{ExitStatus, VM};
+
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn tasks_keep_their_own_registers_and_take_turns() {
+    // main: spawn worker(7); r3 = 100; yield; r4 = [0x800]; HALT
+    // worker: [0x800] = r1; exit
+    let code = [
+      instr(OpCode::Movi, &[0, SYS_SPAWN as u32]),
+      instr(OpCode::Movi, &[1, 57]),
+      instr(OpCode::Movi, &[2, 7]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Movi, &[3, 100]),
+      instr(OpCode::Movi, &[0, SYS_YIELD as u32]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Loadi, &[4, 0x800]),
+      instr(OpCode::Halt, &[]),
+      instr(OpCode::Storei, &[1, 0x800]),
+      instr(OpCode::Movi, &[0, SYS_TASK_EXIT as u32]),
+      instr(OpCode::Syscall, &[]),
+    ].concat();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&LeafAsmObjectBuilder::new().text(code).build().unwrap()).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+    assert_eq!((vm.registers[3], vm.registers[4]), (100, 7));
+    // Back on task 0's own stack, with the worker's stack added above the original memory
+    assert_eq!((vm.current_task(), vm.registers[15]), (0, 0x1000));
+    assert_eq!(vm.heap.len(), 0x2000);
+    assert_eq!(vm.ready_tasks().count(), 0);
+  }
+}
@@ -1,6 +1,18 @@
+use std::ops::Range;
+use bincode::{Decode, Encode};
 use log::{debug, error, info};
 use leaf_common::leaf_ast::OpCode;
-use leaf_common::leaf_file::LeafAsmFile;
+use leaf_common::leaf_file::{DebugInfo, LeafAsmFile, LeafAsmObject, SectionFlags, SymbolEntry, ABSOLUTE_SECTION, FORMAT_VERSION};
+use leaf_common::disassembler::disassemble;
+use leaf_common::error::{FormatError, LeafError};
+use leaf_common::object_builder::ObjectError;
+use leaf_common::opcode::{AddressOperand, ISA_VERSION};
+use leaf_common::syscall::*;
+use crate::mmio::{Device, MappedDevice};
+use crate::loader::{relocated, LoadedModule};
+use crate::sandbox;
+use crate::stack::CallFrame;
+use crate::tasks::Task;
 
 pub struct VM {
   pub registers: [u64; 32],
@@ -10,9 +22,162 @@ pub struct VM {
   pub code_len: usize,
   pub data_len: usize,
   pub rodata_len: usize,
+  /// Load addresses of `.data` and `.rodata`; `.text` is always loaded at 0.
+  pub data_base: usize,
+  pub rodata_base: usize,
+  /// Run addresses of `.data` and `.rodata`, which their symbols refer to: the load addresses
+  /// unless an overlay runs them elsewhere, where the program copies them.
+  pub data_run: usize,
+  pub rodata_run: usize,
+  /// Permissions of `.text`, `.data` and `.rodata`, from the loaded object.
+  pub section_flags: [SectionFlags; 3],
+  /// Lowest address the stack may grow down to; `PUSH` or `CALL` below it faults.
+  pub stack_limit: usize,
+  /// Top of the running task's stack, where r15 starts.
+  pub stack_top: usize,
+  /// Where sections go and how much stack and `ALLOC` space there is, applied by `load_object`.
+  pub layout: MemoryLayout,
+  /// Bytes handed out by `ALLOC` so far.
+  allocated: usize,
   pub debug: bool,
   pub file_descriptors: std::collections::HashMap<u64, std::fs::File>,
   pub next_fd: u64,
+  /// Directory `OPEN` finds files in, see `sandbox`; without one, `OPEN` always fails.
+  pub fs_root: Option<std::path::PathBuf>,
+  /// Why the VM stopped; `None` while it is running.
+  pub status: Option<ExitStatus>,
+  /// Host-provided syscalls, consulted before the built-in ones.
+  syscalls: std::collections::HashMap<u64, SyscallHandler>,
+  on_break: Option<BreakHandler>,
+  /// Defined symbols of the loaded program, for naming the current function and faulting writes.
+  pub(crate) symbols: Vec<SymbolEntry>,
+  /// Source lines of the loaded program's `.text`, for backtraces.
+  pub(crate) debug_info: Option<DebugInfo>,
+  /// Executions of each `.text` address, when profiling.
+  profile: Option<Vec<u64>>,
+  /// Instructions left before the program is stopped with `ExitStatus::OutOfFuel`; unlimited if
+  /// `None`.
+  pub fuel: Option<u64>,
+  /// Refuse the built-in syscalls whose result depends on the host (`NONDETERMINISTIC_SYSCALLS`)
+  /// unless allowed with `allow_syscall`, so a run depends only on the program and its inputs.
+  pub deterministic: bool,
+  /// Fault on writes to sections that are not writable and reads from ones that are not readable
+  /// (by default, writes to `.text` and `.rodata`), and refuse to load a section that is both
+  /// writable and executable; on by default. Only executable sections are ever run, so with this
+  /// on no address is both writable and executable.
+  pub memory_protection: bool,
+  /// Check that `RET` pops the return address the matching `CALL` pushed; see `stack`.
+  pub stack_canaries: bool,
+  /// Return addresses pushed by `CALL` on the running task's stack, when checking canaries.
+  pub(crate) frames: Vec<CallFrame>,
+  allowed_syscalls: std::collections::HashSet<u64>,
+  devices: Vec<MappedDevice>,
+  /// Whether a pending interrupt may be taken before the next instruction (LDR-008).
+  pub interrupts_enabled: bool,
+  /// Bit `n` is set while interrupt `n` waits to be taken.
+  pub(crate) pending_interrupts: u64,
+  /// Address of the `__vectors` table; without one, interrupts are never taken.
+  pub(crate) vectors: Option<usize>,
+  /// Instructions between timer interrupts, or 0 if the timer is off.
+  pub(crate) timer_period: u64,
+  /// Instructions left until the next timer interrupt.
+  pub(crate) timer_countdown: u64,
+  /// Id of the running task and the tasks waiting for their turn.
+  pub(crate) current_task: u64,
+  pub(crate) tasks: std::collections::VecDeque<Task>,
+  pub(crate) next_task_id: u64,
+  /// Shared objects mapped with `load_shared`.
+  pub(crate) modules: Vec<LoadedModule>,
+  #[cfg(feature = "jit")]
+  pub(crate) jit: Option<crate::jit::Jit>,
+}
+
+/// Memory layout options. The defaults pack `.text`, `.data` and `.rodata` from address 0 and let
+/// the stack (which starts at the top of memory) grow down to the end of the sections.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct MemoryLayout {
+  /// Load address of `.data`; right after `.text` if `None`.
+  pub data_base: Option<usize>,
+  /// Load address of `.rodata`; right after `.data` if `None`.
+  pub rodata_base: Option<usize>,
+  /// Bytes reserved for the stack at the top of memory, below which it overflows.
+  pub stack_size: Option<usize>,
+  /// Total bytes `ALLOC` may hand out before it starts returning -1.
+  pub heap_size: Option<usize>,
+}
+
+/// Built-in syscalls that read the clock, stdin or the host filesystem.
+pub const NONDETERMINISTIC_SYSCALLS: [u64; 3] = [SYS_READ, SYS_OPEN, SYS_TIME];
+
+/// Symbol of the interrupt vector table; entry `n` is the instruction at `__vectors + VECTOR_SIZE * n`.
+/// Section names by number, for messages.
+pub(crate) const SECTION_NAMES: [&str; 3] = [".text", ".data", ".rodata"];
+
+pub const VECTOR_TABLE_SYMBOL: &str = "__vectors";
+/// Size of a vector table entry, which fits one `JMP`.
+pub const VECTOR_SIZE: usize = 5;
+/// Interrupt raised by the `SYS_TIMER` timer.
+pub const TIMER_INTERRUPT: u32 = 0;
+
+/// What a `BREAK` handler wants the VM to do next.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BreakAction {
+  Continue,
+  /// Stop with `ExitStatus::Breakpoint`; running again resumes after the `BREAK`.
+  Pause,
+}
+
+/// VM state handed to the `BREAK` handler.
+pub struct BreakContext<'a> {
+  /// Address of the `BREAK` instruction.
+  pub pc: usize,
+  pub registers: &'a mut [u64; 32],
+  pub memory: &'a mut [u8],
+  /// The code symbol at or before `pc` and the distance from it, if the program has one.
+  pub symbol: Option<(&'a str, usize)>,
+}
+
+pub type BreakHandler = Box<dyn FnMut(&mut BreakContext) -> BreakAction>;
+
+/// A host syscall. It gets the registers (number in r0, arguments from r1, result back in r0) and
+/// memory, which it may modify but not resize. Returning `Err` stops the program with a fault.
+pub type SyscallHandler = Box<dyn FnMut(&mut [u64; 32], &mut [u8]) -> Result<(), String>>;
+
+/// How a program stopped running.
+#[derive(Debug, Eq, PartialEq, Clone, Encode, Decode)]
+pub enum ExitStatus {
+  /// `HALT`, or execution ran off the end of `.text`.
+  Halted,
+  /// The `EXIT` syscall, with its code.
+  Exited(u64),
+  /// `BREAK` paused the program; the PC is just past it.
+  Breakpoint,
+  /// A host call (`Vm::call`) returned, with the value left in r0.
+  Returned(u64),
+  /// Execution stopped on an error, e.g. an invalid opcode or out-of-bounds access.
+  Fault(String),
+  /// The instruction budget (`VM::fuel`) ran out; the PC is at the next instruction.
+  OutOfFuel,
+}
+
+/// Process exit code of a program that faulted (`EX_SOFTWARE`).
+pub const FAULT_EXIT_CODE: i32 = 70;
+/// Process exit code of a program that ran out of fuel, as `timeout` uses.
+pub const OUT_OF_FUEL_EXIT_CODE: i32 = 124;
+
+impl ExitStatus {
+  /// The process exit code for a program that stopped like this, given its final r0 (LDR-009):
+  /// the low byte of r0 after `HALT`, of the `EXIT` code, or of the value a host call returned.
+  /// `None` for a program paused at a breakpoint.
+  pub fn exit_code(&self, r0: u64) -> Option<i32> {
+    match self {
+      ExitStatus::Halted => Some(r0 as u8 as i32),
+      ExitStatus::Exited(code) | ExitStatus::Returned(code) => Some(*code as u8 as i32),
+      ExitStatus::Fault(_) => Some(FAULT_EXIT_CODE),
+      ExitStatus::OutOfFuel => Some(OUT_OF_FUEL_EXIT_CODE),
+      ExitStatus::Breakpoint => None,
+    }
+  }
 }
 
 impl VM {
@@ -25,135 +190,442 @@ impl VM {
       code_len: 0,
       data_len: 0,
       rodata_len: 0,
+      data_base: 0,
+      rodata_base: 0,
+      data_run: 0,
+      rodata_run: 0,
+      section_flags: [0, 1, 2].map(SectionFlags::default_for),
+      stack_limit: 0,
+      stack_top: 0,
+      layout: MemoryLayout::default(),
+      allocated: 0,
       debug: true,
       file_descriptors: std::collections::HashMap::new(),
       next_fd: 3,
+      fs_root: None,
+      status: None,
+      syscalls: std::collections::HashMap::new(),
+      on_break: None,
+      symbols: Vec::new(),
+      debug_info: None,
+      profile: None,
+      fuel: None,
+      deterministic: false,
+      memory_protection: true,
+      stack_canaries: false,
+      frames: Vec::new(),
+      allowed_syscalls: std::collections::HashSet::new(),
+      devices: Vec::new(),
+      interrupts_enabled: false,
+      pending_interrupts: 0,
+      vectors: None,
+      timer_period: 0,
+      timer_countdown: 0,
+      current_task: 0,
+      tasks: std::collections::VecDeque::new(),
+      next_task_id: 1,
+      modules: Vec::new(),
+      #[cfg(feature = "jit")]
+      jit: None,
     }
   }
 
-  pub fn load_program(&mut self, object: &LeafAsmFile) {
+  /// Check the file header and load the object it contains.
+  pub fn load_program(&mut self, object: &LeafAsmFile) -> Result<(), LeafError> {
 
-    disassembly_dump(&object, &self);
+    disassembly_dump(object);
 
-    // TODO: assert the CRC32 checksum
-    if object.header.magic != *b"LAF\0" {
-      error!("Magic flag does not match");
-      panic!("Invalid magic number in object file");
-    }
-    if object.header.version != 1 {
-      error!("Unsupported object file version: {}", object.header.version);
-      panic!("Unsupported object file version: {}", object.header.version);
-    }
+    check_header(object).inspect_err(|e| error!("{}", e))?;
+    self.load_object(&object.object)
+  }
 
-    let code_len = object.object.bytecode.len();
-    let data_len = object.object.data.len();
-    let rodata_len = object.object.rodata.len();
+  /// Lay out a linked object in memory as `.text`, `.data`, `.rodata` (at the addresses in `layout`,
+  /// else where its segments say, else packed from 0), apply its relocations for where the sections
+  /// run, which is where they are loaded unless the segments run them elsewhere, point the stack
+  /// pointer (r15) at the top of memory and the PC at the entry point (or 0). Memory grows if the
+  /// sections, plus any reserved stack, do not fit.
+  pub fn load_object(&mut self, object: &LeafAsmObject) -> Result<(), LeafError> {
+    object.validate_segments()?;
+    let segment = |section| object.segments.iter().find(|segment| segment.section == section);
+    if let Some(text) = segment(0).filter(|text| text.address != 0) {
+      return Err(LeafError::Layout(format!(".text segment is at 0x{:X}, but .text always loads at 0", text.address)));
+    }
+    self.symbols = object.symbols.iter().filter(|s| !s.external).cloned().collect();
+    self.debug_info = object.debug_info.clone();
+    self.modules.clear();
+    let len = |section| segment(section).map_or(object.section_len(section).unwrap_or(0), |segment| segment.mem_size as usize);
+    let code_len = len(0);
+    let data_len = len(1);
+    let rodata_len = len(2);
     self.code_len = code_len;
     self.data_len = data_len;
     self.rodata_len = rodata_len;
+    let address = |section| segment(section).map(|segment| segment.address as usize);
+    self.data_base = self.layout.data_base.or(address(1)).unwrap_or(code_len);
+    self.rodata_base = self.layout.rodata_base.or(address(2)).unwrap_or(self.data_base + data_len);
+    // The linker fixed the addresses of the bounds of a section that runs elsewhere
+    let moved = |section| segment(section).filter(|segment| segment.run_address != segment.address);
+    let overridden = [(1, self.layout.data_base), (2, self.layout.rodata_base)];
+    if let Some((section, _)) = overridden.iter().find(|(section, base)| base.is_some() && moved(*section).is_some()) {
+      return Err(LeafError::Layout(format!("{} runs away from where it is loaded, so the layout cannot move it", SECTION_NAMES[*section as usize])));
+    }
+    self.data_run = moved(1).map_or(self.data_base, |segment| segment.run_address as usize);
+    self.rodata_run = moved(2).map_or(self.rodata_base, |segment| segment.run_address as usize);
 
     info!("Loading program with code length: {}, data length: {}, rodata length: {}", code_len, data_len, rodata_len);
-    
-    // Ensure heap is large enough
-    let total_required = code_len + data_len + rodata_len;
+
+    let data = self.data_base..self.data_base + data_len;
+    let rodata = self.rodata_base..self.rodata_base + rodata_len;
+    let sections = [(".text", 0, 0..code_len), (".data", 1, data.clone()), (".rodata", 2, rodata.clone())];
+    if let Some(message) = section_overflow(&object.symbols, &sections) {
+      return Err(LeafError::Layout(message));
+    }
+    if let Some(message) = self.writable_and_executable(object, "") {
+      return Err(LeafError::Layout(message));
+    }
+    self.section_flags = [0, 1, 2].map(|section| object.flags(section));
+
+    // Ensure heap is large enough, also where the sections run
+    let sections_end = code_len.max(data.end).max(rodata.end).max(self.data_run + data_len).max(self.rodata_run + rodata_len);
+    let total_required = sections_end + self.layout.stack_size.unwrap_or(0);
     if total_required > self.heap.len() {
-        self.heap.resize(total_required + 0x1000, 0); // Add some padding for stack if needed
-    } else {
-        // Zero out the portion of the heap we will use
-        for i in 0..total_required {
-            self.heap[i] = 0;
-        }
+        // Without a reserved stack, add some padding for it
+        let padding = if self.layout.stack_size.is_some() { 0 } else { 0x1000 };
+        self.heap.resize(total_required + padding, 0);
     }
+    // Zero out the portion of the heap we will use
+    self.heap[..sections_end].fill(0);
 
-    self.heap[..code_len].copy_from_slice(object.object.bytecode.as_slice());
-    self.heap[code_len..code_len + data_len].copy_from_slice(object.object.data.as_slice());
-    self.heap[code_len + data_len..code_len + data_len + rodata_len].copy_from_slice(object.object.rodata.as_slice());
+    // A segment may be longer in memory than in the file; the rest stays zero
+    self.heap[..object.bytecode.len()].copy_from_slice(object.bytecode.as_slice());
+    self.heap[data.start..data.start + object.data.len()].copy_from_slice(object.data.as_slice());
+    self.heap[rodata.start..rodata.start + object.rodata.len()].copy_from_slice(object.rodata.as_slice());
 
     // Apply relocations
-    for reloc in &object.object.relocations {
-      let symbol = &object.object.symbols[reloc.symbol_index as usize];
-      let section_offset = match symbol.section {
-        0 => 0,
-        1 => code_len,
-        2 => code_len + data_len,
-        _ => panic!("Invalid symbol section: {}", symbol.section),
+    for (index, reloc) in object.relocations.iter().enumerate() {
+      // A label the object keeps to itself is the start of its section plus the addend
+      let (section, offset) = match object.section_target(index) {
+        Some(section) => (section, 0),
+        None => {
+          let symbol = object.symbols.get(reloc.symbol_index as usize).ok_or(ObjectError::BadSymbolIndex {
+            index: reloc.symbol_index,
+            symbols: object.symbols.len(),
+          })?;
+          (symbol.section, symbol.offset)
+        }
       };
-      let target_addr = (section_offset + symbol.offset as usize) as u32;
+      let section_offset = self.run_base(section).ok_or(ObjectError::InvalidSection(section))?;
+      let target_addr = (section_offset + offset as usize) as u32;
 
-      let patch_section_offset = match reloc.target_section {
-        0 => 0,
-        1 => code_len,
-        2 => code_len + data_len,
-        _ => panic!("Invalid relocation target section: {}", reloc.target_section),
-      };
+      let patch_section_offset = self.section_base(reloc.target_section).ok_or(ObjectError::InvalidSection(reloc.target_section))?;
       let patch_addr = patch_section_offset + reloc.offset as usize;
 
       if patch_addr + 4 > self.heap.len() {
         error!("Relocation out of bounds: patch_addr={}", patch_addr);
-        panic!("Relocation out of bounds: patch_addr={}", patch_addr);
+        return Err(ObjectError::RelocationOutOfBounds {
+          offset: reloc.offset,
+          section: reloc.target_section,
+          section_len: self.heap.len() - patch_section_offset,
+        }.into());
       }
 
-      info!("Applying relocation at {:04X}: symbol '{}' at section {} offset {} (target_addr={:04X})",
-        patch_addr, symbol.name, symbol.section, symbol.offset, target_addr);
+      info!("Applying relocation at {:04X}: section {} offset {} (target_addr={:04X})", patch_addr, section, offset, target_addr);
 
-      let bytes = target_addr.to_le_bytes();
+      let bytes = relocated(target_addr as usize, offset, patch_addr, reloc.reloc_type, object.addend(index)).to_le_bytes();
       self.heap[patch_addr..patch_addr + 4].copy_from_slice(&bytes);
     }
 
     self.pc = 0;
+    self.halted = false;
+    self.status = None;
+    self.allocated = 0;
+    self.registers = [0; 32];
+    self.registers[15] = self.heap.len() as u64;
+    self.stack_top = self.heap.len();
+    self.frames.clear();
+    self.stack_limit = match self.layout.stack_size {
+      Some(size) => self.heap.len() - size,
+      None => sections_end,
+    };
+    self.interrupts_enabled = false;
+    self.pending_interrupts = 0;
+    self.timer_period = 0;
+    self.timer_countdown = 0;
+    self.current_task = 0;
+    self.tasks.clear();
+    self.next_task_id = 1;
+    let vectors = self.code_symbols().find(|s| s.name == VECTOR_TABLE_SYMBOL).map(|s| s.offset as usize);
+    self.vectors = vectors;
 
-    if let Some(entry) = &object.object.entry_point {
-      if let Some(symbol) = object.object.symbols.iter().find(|s| s.name == *entry) {
-        let section_offset = match symbol.section {
-          0 => 0,
-          1 => code_len,
-          2 => code_len + data_len,
-          _ => 0,
-        };
-        self.pc = section_offset + symbol.offset as usize;
+    if let Some(entry) = &object.entry_point {
+      if let Some(symbol) = object.symbols.iter().find(|s| s.name == *entry) {
+        self.pc = self.run_base(symbol.section).unwrap_or(0) + symbol.offset as usize;
       } else {
         error!("Entry point '{}' not found in symbols", entry);
-        panic!("Entry point '{}' not found in symbols", entry);
+        return Err(ObjectError::UnknownSymbol(entry.clone()).into());
       }
     }
+    Ok(())
+  }
+
+  /// With memory protection on, why `object` may not be loaded because one of its sections is both
+  /// writable and executable. `what` follows the section name in the message.
+  pub(crate) fn writable_and_executable(&self, object: &LeafAsmObject, what: &str) -> Option<String> {
+    let both = SectionFlags::WRITE | SectionFlags::EXECUTE;
+    let section = (0..3).find(|&section| self.memory_protection && object.flags(section).contains(both))?;
+    Some(format!("{}{} is both writable and executable", SECTION_NAMES[section as usize], what))
+  }
+
+  /// Address ranges of `.text`, `.data` and `.rodata`.
+  pub(crate) fn sections(&self) -> [Range<usize>; 3] {
+    [0..self.code_len, self.data_base..self.data_base + self.data_len, self.rodata_base..self.rodata_base + self.rodata_len]
+  }
+
+  /// Load address of section 0 (.text), 1 (.data) or 2 (.rodata).
+  pub fn section_base(&self, section: u8) -> Option<usize> {
+    match section {
+      0 => Some(0),
+      1 => Some(self.data_base),
+      2 => Some(self.rodata_base),
+      _ => None,
+    }
+  }
+
+  /// Address section 0 (.text), 1 (.data) or 2 (.rodata) runs at, which its symbols are relative
+  /// to, or 0 for an absolute symbol.
+  pub fn run_base(&self, section: u8) -> Option<usize> {
+    match section {
+      1 => Some(self.data_run),
+      2 => Some(self.rodata_run),
+      ABSOLUTE_SECTION => Some(0),
+      _ => self.section_base(section),
+    }
+  }
+
+  /// Handle syscall `number` with `handler`, replacing any built-in or earlier handler for it.
+  pub fn register_syscall<F>(&mut self, number: u64, handler: F)
+  where
+    F: FnMut(&mut [u64; 32], &mut [u8]) -> Result<(), String> + 'static,
+  {
+    self.syscalls.insert(number, Box::new(handler));
+  }
+
+  /// Map `device` at `base..base + len`. Loads and stores there go to the device instead of memory;
+  /// the range may lie beyond the end of memory but not overlap another device.
+  pub fn map_device<D: Device + 'static>(&mut self, base: usize, len: usize, device: D) -> Result<(), LeafError> {
+    let range = base..base.checked_add(len).ok_or_else(|| LeafError::Layout(format!("device at 0x{:X} wraps around", base)))?;
+    if let Some(other) = self.devices.iter().find(|mapped| mapped.range.start < range.end && range.start < mapped.range.end) {
+      return Err(LeafError::Layout(format!(
+        "device at 0x{:X}..0x{:X} overlaps the one at 0x{:X}..0x{:X}", range.start, range.end, other.range.start, other.range.end)));
+    }
+    self.devices.push(MappedDevice { range, device: Box::new(device) });
+    Ok(())
+  }
+
+  /// Advance every mapped device's clock by `instructions`.
+  fn tick_devices(&mut self, instructions: u64) {
+    for mapped in &mut self.devices {
+      mapped.device.tick(instructions);
+    }
+  }
+
+  /// Mark interrupt `n` (0..64) pending; it is taken once interrupts are enabled.
+  pub fn raise_interrupt(&mut self, n: u32) {
+    if n < u64::BITS {
+      self.pending_interrupts |= 1 << n;
+    }
+  }
+
+  /// The interrupt to take before the next instruction, if any.
+  fn ready_interrupt(&self) -> Option<u32> {
+    let ready = self.interrupts_enabled && self.pending_interrupts != 0 && self.vectors.is_some();
+    ready.then(|| self.pending_interrupts.trailing_zeros())
+  }
+
+  /// Enter the handler for interrupt `n`: push the PC and r0, disable interrupts and jump to the
+  /// vector table entry.
+  fn take_interrupt(&mut self, n: u32) {
+    self.pending_interrupts &= !(1 << n);
+    let sp = self.registers[15] as usize;
+    if sp < self.stack_limit + 16 {
+      self.fault(format!("Stack overflow taking interrupt {}: sp=0x{:04X}, stack limit 0x{:04X}", n, sp, self.stack_limit));
+      return;
+    }
+    if !self.check_write("interrupt", sp - 16, 16) {
+      return;
+    }
+    info!("Interrupt {} at PC={:04X}", n, self.pc);
+    self.heap[sp - 8..sp].copy_from_slice(&(self.pc as u64).to_le_bytes());
+    self.heap[sp - 16..sp - 8].copy_from_slice(&self.registers[0].to_le_bytes());
+    self.registers[15] = (sp - 16) as u64;
+    self.interrupts_enabled = false;
+    self.pc = self.vectors.unwrap_or(0) + VECTOR_SIZE * n as usize;
   }
 
+  /// Count `instructions` towards the next timer interrupt.
+  fn advance_timer(&mut self, instructions: u64) {
+    if self.timer_period == 0 {
+      return;
+    }
+    self.timer_countdown = self.timer_countdown.saturating_sub(instructions);
+    if self.timer_countdown == 0 {
+      self.raise_interrupt(TIMER_INTERRUPT);
+      self.timer_countdown = self.timer_period;
+    }
+  }
+
+  /// Permit a nondeterministic built-in syscall in deterministic mode.
+  pub fn allow_syscall(&mut self, number: u64) {
+    self.allowed_syscalls.insert(number);
+  }
+
+  /// Call `handler` whenever the program executes `BREAK`. Without a handler, `BREAK` pauses.
+  pub fn on_break<F>(&mut self, handler: F)
+  where
+    F: FnMut(&mut BreakContext) -> BreakAction + 'static,
+  {
+    self.on_break = Some(Box::new(handler));
+  }
+
+  /// The code symbol at or before `pc` and the distance from it.
+  pub fn symbol_at(&self, pc: usize) -> Option<(&str, usize)> {
+    symbol_at(self.code_symbols(), pc)
+  }
+
+  /// Process exit code for how the program stopped; see `ExitStatus::exit_code`.
+  pub fn exit_code(&self) -> Option<i32> {
+    self.status.as_ref()?.exit_code(self.registers[0])
+  }
+
+  fn code_symbols(&self) -> impl Iterator<Item = &SymbolEntry> {
+    self.symbols.iter().filter(|s| s.section == 0)
+  }
+
+  /// Start counting how often each instruction of the loaded program executes.
+  pub fn enable_profiling(&mut self) {
+    self.profile = Some(vec![0; self.code_len]);
+  }
+
+  /// Execution count per `.text` address, if profiling is enabled.
+  pub fn profile(&self) -> Option<&[u64]> {
+    self.profile.as_deref()
+  }
+
+  /// Compile code reached `threshold` times to native code during `run`. Returns false if the JIT
+  /// is not available on this host. Tracing and profiling keep everything interpreted.
+  #[cfg(feature = "jit")]
+  pub fn enable_jit(&mut self, threshold: u32) -> bool {
+    self.jit = crate::jit::Jit::new(threshold);
+    self.jit.is_some()
+  }
+
+  /// Execute from the current PC until the program halts.
   pub fn run(&mut self) {
     info!("Heap initialized, size={}", self.heap.len());
-    self.registers[15] = self.heap.len() as u64;
     while !self.halted {
+      #[cfg(feature = "jit")]
+      if self.run_compiled() {
+        continue;
+      }
       self.step();
     }
   }
 
+  /// Run the compiled block at the PC, if there is one; false means interpret the next instruction.
+  #[cfg(feature = "jit")]
+  fn run_compiled(&mut self) -> bool {
+    if self.debug || self.profile.is_some() {
+      return false;
+    }
+    if self.ready_interrupt().is_some() {
+      return false;
+    }
+    if !self.section_flags[0].contains(SectionFlags::EXECUTE) {
+      return false;
+    }
+    let Some(jit) = &mut self.jit else {
+      return false;
+    };
+    // Stop blocks short of the next timer interrupt, as the interpreter would take it there
+    let timer = (self.timer_period > 0).then_some(self.timer_countdown);
+    let limit = match (self.fuel, timer) {
+      (Some(fuel), Some(timer)) => Some(fuel.min(timer)),
+      (fuel, timer) => fuel.or(timer),
+    };
+    match jit.run_block(&self.heap[..self.code_len], self.pc, &mut self.registers, limit) {
+      Some((next, instructions)) => {
+        self.pc = next;
+        if let Some(fuel) = &mut self.fuel {
+          *fuel -= instructions;
+        }
+        self.tick_devices(instructions);
+        self.advance_timer(instructions);
+        true
+      }
+      None => false,
+    }
+  }
+
   pub fn step(&mut self) {
 
-    if self.pc >= self.code_len {
-      info!("Reached end of code section at PC={:04X}. Halting.", self.pc);
-      self.halted = true;
+    if let Some(n) = self.ready_interrupt() {
+      self.take_interrupt(n);
       return;
     }
 
+    let Some(code_end) = self.code_end(self.pc) else {
+      // Running off the end of .text still halts, even into the section after it
+      if self.pc != self.code_len && let Some(section) = self.sections().iter().position(|range| range.contains(&self.pc)) {
+        self.fault(format!("Execute from {} at pc={:04X}, which is not executable", SECTION_NAMES[section], self.pc));
+        return;
+      }
+      info!("Reached end of code section at PC={:04X}. Halting.", self.pc);
+      self.stop(ExitStatus::Halted);
+      return;
+    };
+
     let opcode_byte = self.heap[self.pc];
-    let opcode = match OpCode::byte_to_opcode(opcode_byte) {
-      Some(op) => op,
+    let opcode = match OpCode::decode(opcode_byte) {
+      Some(info) if self.pc + info.size() > code_end => {
+        self.fault(format!("Truncated {} at pc={:04X} -- halting", info.opcode, self.pc));
+        return;
+      }
+      Some(info) => info.opcode,
       None => {
-        error!("Invalid opcode: {:02X} at pc={:04X} -- halting", opcode_byte, self.pc);
-        self.halted = true;
+        self.fault(format!("Invalid opcode: {:02X} at pc={:04X} -- halting", opcode_byte, self.pc));
         return;
       }
     };
+    match &mut self.fuel {
+      Some(0) => {
+        info!("Out of fuel at PC={:04X}", self.pc);
+        self.stop(ExitStatus::OutOfFuel);
+        return;
+      }
+      Some(fuel) => *fuel -= 1,
+      None => {}
+    }
+    if !self.devices.is_empty() {
+      self.tick_devices(1);
+    }
+    self.advance_timer(1);
+    if let Some(counts) = &mut self.profile
+      && let Some(count) = counts.get_mut(self.pc) {
+      *count += 1;
+    }
 
     if self.debug {
       // Debug dump
       info!("PC={:04X}: byte={:02X} op={:?} disasm={}", self.pc, opcode_byte, opcode, self.disassemble());
     }
-    // self.halted = true; if opcode error
     debug!("Executing opcode {:?} at pc={}", opcode, self.pc);
     match opcode {
       OpCode::Invalid => {
         self.pc += 1;
       }
+      OpCode::Ext(_) => {
+        self.fault(format!("Extension instruction {} has no implementation in the VM at pc={:04X}", opcode, self.pc));
+      }
       OpCode::Add => {
         // ADD r1, r2, r3  --> r1 = r2 + r3
         let r1 = self.fetch_reg(self.pc + 1);
@@ -189,8 +661,7 @@ impl VM {
         let v2 = self.registers[r2];
         let v3 = self.registers[r3];
         if v3 == 0 {
-          error!("Division by zero at pc={}", self.pc);
-          self.halted = true;
+          self.fault(format!("Division by zero at pc={}", self.pc));
           return;
         }
         self.set_reg(r1, v2 / v3);
@@ -291,34 +762,22 @@ impl VM {
         self.pc += 9;
       }
       OpCode::Load => {
-        // LOAD r1, [r2]
+        // LOAD r1, [r2+offset]
         let r1 = self.fetch_reg(self.pc + 1);
-        let r2 = self.fetch_reg(self.pc + 5);
-        let addr = self.registers[r2] as usize;
-        if addr + 8 > self.heap.len() {
-          error!("LOAD out of bounds: addr={} (heap len={})", addr, self.heap.len());
-          self.halted = true;
+        let addr = self.fetch_address(self.pc + 5);
+        let Some(value) = self.load_word("LOAD", addr) else {
           return;
-        }
-        let value = u64::from_le_bytes([
-          self.heap[addr], self.heap[addr + 1], self.heap[addr + 2], self.heap[addr + 3],
-          self.heap[addr + 4], self.heap[addr + 5], self.heap[addr + 6], self.heap[addr + 7],
-        ]);
+        };
         self.set_reg(r1, value);
         self.pc += 9;
       }
       OpCode::Store => {
-        // STORE r1, [r2]
+        // STORE r1, [r2+offset]
         let r1 = self.fetch_reg(self.pc + 1);
-        let r2 = self.fetch_reg(self.pc + 5);
-        let addr = self.registers[r2] as usize;
-        if addr + 8 > self.heap.len() {
-          error!("STORE out of bounds: addr={} (heap len={})", addr, self.heap.len());
-          self.halted = true;
+        let addr = self.fetch_address(self.pc + 5);
+        if !self.store_word("STORE", addr, self.registers[r1]) {
           return;
         }
-        let value = self.registers[r1].to_le_bytes();
-        self.heap[addr..addr + 8].copy_from_slice(&value);
         self.pc += 9;
       }
       OpCode::Movi => {
@@ -332,15 +791,9 @@ impl VM {
         // LOADI r1, addr  --> r1 = [addr]
         let r1 = self.fetch_reg(self.pc + 1);
         let addr = self.fetch_u32(self.pc + 5) as usize;
-        if addr + 8 > self.heap.len() {
-          error!("LOADI out of bounds: addr={} (heap len={})", addr, self.heap.len());
-          self.halted = true;
+        let Some(value) = self.load_word("LOADI", addr) else {
           return;
-        }
-        let value = u64::from_le_bytes([
-          self.heap[addr], self.heap[addr + 1], self.heap[addr + 2], self.heap[addr + 3],
-          self.heap[addr + 4], self.heap[addr + 5], self.heap[addr + 6], self.heap[addr + 7],
-        ]);
+        };
         self.set_reg(r1, value);
         self.pc += 9;
       }
@@ -348,36 +801,29 @@ impl VM {
         // STOREI r1, addr  --> [addr] = r1
         let r1 = self.fetch_reg(self.pc + 1);
         let addr = self.fetch_u32(self.pc + 5) as usize;
-        if addr + 8 > self.heap.len() {
-          error!("STOREI out of bounds: addr={} (heap len={})", addr, self.heap.len());
-          self.halted = true;
+        if !self.store_word("STOREI", addr, self.registers[r1]) {
           return;
         }
-        let value = self.registers[r1].to_le_bytes();
-        self.heap[addr..addr + 8].copy_from_slice(&value);
         self.pc += 9;
       }
       OpCode::Call => {
         // CALL addr: push next_pc, then jump
         let addr = self.fetch_u32(self.pc + 1) as usize;
         let sp = self.registers[15] as usize;
-        if sp < 8 {
-          error!("Stack overflow in CALL!");
-          self.halted = true;
+        if !self.check_push("CALL", 8) || !self.check_write("CALL", sp - 8, 8) {
           return;
         }
         let return_addr = (self.pc + 5) as u64;
         info!("CALL at PC={:04X}: target={:04X}, pushing return_addr={:04X}, sp={:04X}", self.pc, addr, return_addr, sp);
         self.heap[sp - 8..sp].copy_from_slice(&return_addr.to_le_bytes());
+        self.push_frame(sp - 8, return_addr);
         self.registers[15] = (sp - 8) as u64;
         self.pc = addr;
       }
       OpCode::Ret => {
         // RET: pop PC from stack
         let sp = self.registers[15] as usize;
-        if sp + 8 > self.heap.len() {
-          error!("Stack underflow in RET!");
-          self.halted = true;
+        if !self.check_pop("RET", 8) {
           return;
         }
         let return_addr = u64::from_le_bytes([
@@ -385,6 +831,9 @@ impl VM {
           self.heap[sp + 4], self.heap[sp + 5], self.heap[sp + 6], self.heap[sp + 7],
         ]);
         info!("RET at PC={:04X}: popping return_addr={:04X}, sp={:04X}", self.pc, return_addr, sp);
+        if !self.check_frame(sp, return_addr) {
+          return;
+        }
         self.registers[15] = (sp + 8) as u64;
         self.pc = return_addr as usize;
       }
@@ -392,9 +841,7 @@ impl VM {
         // PUSH r1  --> [SP] = r1; SP -= 8
         let r1 = self.fetch_reg(self.pc + 1);
         let sp = self.registers[15] as usize;
-        if sp < 8 {
-          error!("Stack overflow!");
-          self.halted = true;
+        if !self.check_push("PUSH", 8) || !self.check_write("PUSH", sp - 8, 8) {
           return;
         }
         let value = self.registers[r1].to_le_bytes();
@@ -406,9 +853,7 @@ impl VM {
         // POP r1  --> r1 = [SP]; SP += 8
         let r1 = self.fetch_reg(self.pc + 1);
         let sp = self.registers[15] as usize;
-        if sp + 8 > self.heap.len() {
-          error!("Stack underflow!");
-          self.halted = true;
+        if !self.check_pop("POP", 8) {
           return;
         }
         let value = u64::from_le_bytes([
@@ -420,21 +865,43 @@ impl VM {
         self.pc += 5;
       }
       OpCode::Halt => {
-        self.halted = true;
+        self.stop(ExitStatus::Halted);
         self.pc += 1;
         info!("Halting execution");
       }
       OpCode::Break => {
         info!("Breakpoint reached at PC={}", self.pc);
+        let action = match &mut self.on_break {
+          Some(handler) => handler(&mut BreakContext {
+            pc: self.pc,
+            symbol: symbol_at(self.symbols.iter().filter(|s| s.section == 0), self.pc),
+            registers: &mut self.registers,
+            memory: &mut self.heap,
+          }),
+          None => BreakAction::Pause,
+        };
         self.pc += 1;
-        self.halted = true; // or pause depending on the design
+        if action == BreakAction::Pause {
+          self.stop(ExitStatus::Breakpoint);
+        }
       }
       OpCode::Syscall => {
         debug!("SYSCALL called at PC={}", self.pc);
         debug!("Registers: {:?}", self.registers);
         let syscall_num = self.registers[0];
+        if let Some(handler) = self.syscalls.get_mut(&syscall_num) {
+          if let Err(message) = handler(&mut self.registers, &mut self.heap) {
+            self.fault(format!("Syscall {} failed: {}", syscall_num, message));
+          }
+          self.pc += 1;
+          return;
+        }
+        if self.deterministic && NONDETERMINISTIC_SYSCALLS.contains(&syscall_num) && !self.allowed_syscalls.contains(&syscall_num) {
+          self.fault(format!("Syscall {} is not allowed in deterministic mode", syscall_num));
+          return;
+        }
         match syscall_num {
-          1 => {
+          SYS_PRINT_STR => {
             let ptr = self.registers[1] as usize;
             let mut s = Vec::new();
             let mut i = ptr;
@@ -446,26 +913,28 @@ impl VM {
             let s = String::from_utf8_lossy(&s);
             print!("{}", s); // Use print! instead of println! to respect \n in string
           }
-          2 => {
+          SYS_PRINT_INT => {
             println!("{}", self.registers[1]);
           }
-          3 => {
+          SYS_EXIT => {
             let code = self.registers[1];
             info!("Exiting with code {}", code);
-            self.halted = true;
+            self.stop(ExitStatus::Exited(code));
           }
-          4 => {
+          SYS_READ => {
             // READ fd, buf_ptr, count
             let fd = self.registers[1];
             let buf_ptr = self.registers[2] as usize;
             let count = self.registers[3] as usize;
             
-            if buf_ptr.checked_add(count).map_or(true, |end| end > self.heap.len()) {
+            if buf_ptr.checked_add(count).is_none_or(|end| end > self.heap.len()) {
               error!("READ out of bounds or overflow: buf_ptr={}, count={}, heap_len={}", buf_ptr, count, self.heap.len());
               self.registers[0] = (-1i64) as u64; // Return -1 on error
+            } else if !self.check_write("READ", buf_ptr, count) {
+              return;
             } else {
               match fd {
-                0 => {
+                STDIN => {
                   use std::io::Read;
                   let mut buf = vec![0u8; count];
                   match std::io::stdin().read(&mut buf) {
@@ -501,21 +970,23 @@ impl VM {
               }
             }
           }
-          5 => {
+          SYS_WRITE => {
             // WRITE fd, buf_ptr, count
             let fd = self.registers[1];
             let buf_ptr = self.registers[2] as usize;
             let count = self.registers[3] as usize;
 
-            if buf_ptr.checked_add(count).map_or(true, |end| end > self.heap.len()) {
+            if buf_ptr.checked_add(count).is_none_or(|end| end > self.heap.len()) {
               error!("WRITE out of bounds or overflow: buf_ptr={}, count={}, heap_len={}", buf_ptr, count, self.heap.len());
               self.registers[0] = (-1i64) as u64;
+            } else if !self.check_access("WRITE", buf_ptr, count, SectionFlags::READ) {
+              return;
             } else {
               match fd {
-                1 | 2 => {
+                STDOUT | STDERR => {
                   use std::io::Write;
                   let buf = &self.heap[buf_ptr..buf_ptr + count];
-                  let result = if fd == 1 {
+                  let result = if fd == STDOUT {
                     std::io::stdout().write(buf)
                   } else {
                     std::io::stderr().write(buf)
@@ -547,7 +1018,7 @@ impl VM {
               }
             }
           }
-          6 => {
+          SYS_OPEN => {
             // OPEN name_ptr, flags, mode
             let name_ptr = self.registers[1] as usize;
             // Read null-terminated name
@@ -570,7 +1041,11 @@ impl VM {
               _ => options.read(true),
             };
 
-            match options.open(&name) {
+            let path = match &self.fs_root {
+              Some(root) => sandbox::resolve(root, &name),
+              None => Err("no filesystem root is set".to_string()),
+            };
+            match path.and_then(|path| options.open(path).map_err(|e| e.to_string())) {
               Ok(file) => {
                 let fd = self.next_fd;
                 self.file_descriptors.insert(fd, file);
@@ -583,7 +1058,31 @@ impl VM {
               }
             }
           }
-          7 => {
+          SYS_SEEK => {
+            // SEEK fd, offset, whence
+            use std::io::{Seek, SeekFrom};
+            let fd = self.registers[1];
+            let offset = self.registers[2];
+            let position = match self.registers[3] {
+              0 => Some(SeekFrom::Start(offset)),
+              1 => Some(SeekFrom::Current(offset as i64)),
+              2 => Some(SeekFrom::End(offset as i64)),
+              _ => None,
+            };
+            let result = match (self.file_descriptors.get_mut(&fd), position) {
+              (Some(file), Some(position)) => file.seek(position).map_err(|e| e.to_string()),
+              (None, _) => Err("not an open file".to_string()),
+              (_, None) => Err(format!("unknown origin {}", self.registers[3])),
+            };
+            match result {
+              Ok(offset) => self.registers[0] = offset,
+              Err(e) => {
+                error!("Error seeking fd {}: {}", fd, e);
+                self.registers[0] = (-1i64) as u64;
+              }
+            }
+          }
+          SYS_CLOSE => {
             // CLOSE fd
             let fd = self.registers[1];
             if self.file_descriptors.remove(&fd).is_some() {
@@ -593,16 +1092,23 @@ impl VM {
               self.registers[0] = (-1i64) as u64;
             }
           }
-          8 => {
+          SYS_ALLOC => {
             // ALLOC size
             let size = self.registers[1] as usize;
             let current_len = self.heap.len();
+            if self.layout.heap_size.is_some_and(|limit| self.allocated.saturating_add(size) > limit) {
+              error!("ALLOC of {} bytes exceeds the heap size", size);
+              self.registers[0] = (-1i64) as u64;
+              self.pc += 1;
+              return;
+            }
+            self.allocated += size;
             // Simple bump allocation at the end of the heap
             self.heap.resize(current_len + size, 0);
             self.registers[0] = current_len as u64;
             info!("ALLOCated {} bytes at {:04X}, new heap size={}", size, current_len, self.heap.len());
           }
-          10 => {
+          SYS_TIME => {
             // TIME
             use std::time::{SystemTime, UNIX_EPOCH};
             let start = SystemTime::now();
@@ -610,8 +1116,58 @@ impl VM {
                 .expect("Time went backwards");
             self.registers[0] = since_the_epoch.as_secs();
           }
+          SYS_FREE => {
+            // Bump allocation never reuses memory, so there is nothing to release
+            self.registers[0] = 0;
+          }
+          SYS_INT_ENABLE | SYS_INT_DISABLE => {
+            self.registers[0] = self.interrupts_enabled as u64;
+            self.interrupts_enabled = syscall_num == SYS_INT_ENABLE;
+          }
+          SYS_IRET => {
+            // Pop the frame pushed by take_interrupt: r0, then the interrupted PC
+            let sp = self.registers[15] as usize;
+            if !self.check_pop("IRET", 16) {
+              return;
+            }
+            self.registers[0] = u64::from_le_bytes(self.heap[sp..sp + 8].try_into().unwrap());
+            self.pc = u64::from_le_bytes(self.heap[sp + 8..sp + 16].try_into().unwrap()) as usize;
+            self.registers[15] = (sp + 16) as u64;
+            self.interrupts_enabled = true;
+            return;
+          }
+          SYS_TIMER => {
+            self.timer_period = self.registers[1];
+            self.timer_countdown = self.timer_period;
+            self.registers[0] = 0;
+          }
+          SYS_SPAWN => {
+            let entry = self.registers[1] as usize;
+            self.registers[0] = if self.code_end(entry).is_some() {
+              self.spawn_task(entry, self.registers[2])
+            } else {
+              error!("SPAWN entry point out of .text: {:04X}", entry);
+              (-1i64) as u64
+            };
+          }
+          SYS_YIELD => {
+            self.registers[0] = 0;
+            self.pc += 1;
+            self.yield_task();
+            return;
+          }
+          SYS_TASK_EXIT => {
+            self.pc += 1;
+            if !self.exit_task() {
+              // Like HALT, with exit code 0
+              self.registers[0] = 0;
+              self.stop(ExitStatus::Halted);
+            }
+            return;
+          }
           _ => {
             error!("Unknown syscall number: {}", syscall_num);
+            self.registers[0] = (-1i64) as u64;
           }
         }
         self.pc += 1;
@@ -622,6 +1178,102 @@ impl VM {
     }
   }
 
+  /// The word at `addr` for `op`, from a mapped device or memory; faults and returns `None` if it is
+  /// out of bounds.
+  fn load_word(&mut self, op: &str, addr: usize) -> Option<u64> {
+    if let Some((device, offset)) = self.device_at(addr) {
+      return Some(device.read(offset));
+    }
+    if addr.checked_add(8).is_none_or(|end| end > self.heap.len()) {
+      self.fault(format!("{} out of bounds: addr={} (heap len={})", op, addr, self.heap.len()));
+      return None;
+    }
+    if !self.check_access(op, addr, 8, SectionFlags::READ) {
+      return None;
+    }
+    Some(u64::from_le_bytes(self.heap[addr..addr + 8].try_into().unwrap()))
+  }
+
+  /// Write the word at `addr` for `op`, to a mapped device or memory; false if that faulted.
+  fn store_word(&mut self, op: &str, addr: usize, value: u64) -> bool {
+    if let Some((device, offset)) = self.device_at(addr) {
+      device.write(offset, value);
+      return true;
+    }
+    if addr.checked_add(8).is_none_or(|end| end > self.heap.len()) {
+      self.fault(format!("{} out of bounds: addr={} (heap len={})", op, addr, self.heap.len()));
+      return false;
+    }
+    if !self.check_access(op, addr, 8, SectionFlags::WRITE) {
+      return false;
+    }
+    self.heap[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+    true
+  }
+
+  fn device_at(&mut self, addr: usize) -> Option<(&mut (dyn Device + 'static), usize)> {
+    self.devices.iter_mut()
+      .find(|mapped| mapped.range.contains(&addr))
+      .map(|mapped| (mapped.device.as_mut(), addr - mapped.range.start))
+  }
+
+  /// With memory protection on, fault unless `addr..addr + len` is writable.
+  fn check_write(&mut self, op: &str, addr: usize, len: usize) -> bool {
+    self.check_access(op, addr, len, SectionFlags::WRITE)
+  }
+
+  /// With memory protection on, fault unless the sections `addr..addr + len` overlaps allow
+  /// `access`, `SectionFlags::READ` or `SectionFlags::WRITE`. `op` names the instruction or
+  /// syscall in the message, which points at the first protected byte.
+  fn check_access(&mut self, op: &str, addr: usize, len: usize, access: SectionFlags) -> bool {
+    if !self.memory_protection || len == 0 {
+      return true;
+    }
+    let end = addr.saturating_add(len);
+    let hits = |range: &Range<usize>| addr < range.end && range.start < end;
+    let denied = |flags: &SectionFlags| !flags.contains(access);
+    let program = (0..3).zip(self.sections()).filter(|(section, _)| denied(&self.section_flags[*section as usize]));
+    let (name, target, location) = if let Some((section, range)) = program.into_iter().find(|(_, range)| hits(range)) {
+      let target = addr.max(range.start);
+      let location = symbol_at(self.symbols.iter().filter(|s| s.section == section), target - range.start)
+        .map(|(symbol, offset)| (symbol.to_string(), offset));
+      (SECTION_NAMES[section as usize].to_string(), target, location)
+    } else if let Some((module, section, range)) = self.modules.iter()
+      .flat_map(|module| (0..3).map(move |section| (module, section, module.section(section).unwrap().clone())))
+      .filter(|(module, section, _)| denied(&module.flags[*section as usize]))
+      .find(|(_, _, range)| hits(range)) {
+      let target = addr.max(range.start);
+      // The closest export at or before the target in the section that was hit
+      let location = module.exports.iter()
+        .filter(|(_, export)| range.contains(export) && **export <= target)
+        .max_by_key(|(_, export)| **export)
+        .map(|(symbol, export)| (symbol.clone(), target - export));
+      (format!("{} of module '{}'", SECTION_NAMES[section as usize], module.name), target, location)
+    } else {
+      return true;
+    };
+    let location = match location {
+      Some((symbol, offset)) => format!(" ({}+0x{:X})", symbol, offset),
+      None => String::new(),
+    };
+    let direction = if access == SectionFlags::WRITE { "to" } else { "from" };
+    self.fault(format!("{} {} {} at 0x{:04X}{} at pc={:04X}", op, direction, name, target, location, self.pc));
+    false
+  }
+
+  fn stop(&mut self, status: ExitStatus) {
+    self.halted = true;
+    self.status = Some(status);
+  }
+
+  pub(crate) fn fault(&mut self, message: String) {
+    error!("{}", message);
+    for frame in self.backtrace() {
+      error!("  at {}", frame);
+    }
+    self.stop(ExitStatus::Fault(message));
+  }
+
   fn fetch_u32(&self, offset: usize) -> u32 {
     u32::from_le_bytes([
       self.heap[offset],
@@ -631,24 +1283,37 @@ impl VM {
     ])
   }
 
-  // Helper: Fetch a register index (from the first byte of a 4-byte arg)
-  fn fetch_reg(&self, offset: usize) -> usize {
+  // Helper: Fetch a register index (from the first byte of a 4-byte arg). An invalid index faults
+  // and reads as r0 so the current instruction can finish without indexing out of bounds.
+  fn fetch_reg(&mut self, offset: usize) -> usize {
     let reg = self.heap[offset] as usize;
-    if reg >= 32 {
-        error!("Invalid register index: {} at pc={}", reg, self.pc);
+    if reg >= self.registers.len() {
+      self.fault(format!("Invalid register index: {} at pc={}", reg, self.pc));
+      return 0;
     }
     reg
   }
+  /// The address the `LOAD` or `STORE` operand at `offset` points at: its base register, plus its
+  /// index register times the scale, plus its offset.
+  fn fetch_address(&mut self, offset: usize) -> usize {
+    let operand = AddressOperand::decode(self.fetch_u32(offset).to_le_bytes());
+    let base = self.fetch_reg(offset);
+    let index = operand.index.map_or(0, |(index, scale)| self.registers[index as usize].wrapping_mul(scale as u64));
+    self.registers[base].wrapping_add(index).wrapping_add_signed(operand.offset as i64) as usize
+  }
   // Helper: Write to a register
   fn set_reg(&mut self, reg: usize, value: u64) {
     if reg < self.registers.len() {
       self.registers[reg] = value;
     } else {
-      error!("Invalid register: {}", reg);
-      self.halted = true;
+      self.fault(format!("Invalid register: {}", reg));
     }
   }
 
+  fn heap_byte(&self, offset: usize) -> usize {
+    self.heap[offset] as usize
+  }
+
   fn disassemble(&self) -> String {
     let pc = self.pc;
     if pc >= self.heap.len() { return "<invalid pc>".to_string(); }
@@ -657,204 +1322,636 @@ impl VM {
     match op {
       OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div |
       OpCode::And | OpCode::Or | OpCode::Xor => {
-        if pc + 13 > self.heap.len() { return format!("{:?} <truncated>", op); }
-        let r1 = self.fetch_reg(pc + 1);
-        let r2 = self.fetch_reg(pc + 5);
-        let r3 = self.fetch_reg(pc + 9);
-        format!("{:?} r{}, r{}, r{}", op, r1, r2, r3)
+        if pc + 13 > self.heap.len() { return format!("{} <truncated>", op); }
+        let r1 = self.heap_byte(pc + 1);
+        let r2 = self.heap_byte(pc + 5);
+        let r3 = self.heap_byte(pc + 9);
+        format!("{} r{}, r{}, r{}", op, r1, r2, r3)
       }
       OpCode::Mov | OpCode::Load | OpCode::Store | OpCode::Not | OpCode::Jz | OpCode::Jnz | OpCode::Movi | OpCode::Loadi | OpCode::Storei => {
-        if pc + 9 > self.heap.len() { return format!("{:?} <truncated>", op); }
-        let r1 = self.fetch_reg(pc + 1);
+        if pc + 9 > self.heap.len() { return format!("{} <truncated>", op); }
+        let r1 = self.heap_byte(pc + 1);
         let arg2 = self.fetch_u32(pc + 5);
         match op {
-            OpCode::Mov | OpCode::Load | OpCode::Store | OpCode::Not => {
-                format!("{:?} r{}, r{}", op, r1, arg2)
+            OpCode::Mov | OpCode::Not => {
+                format!("{} r{}, r{}", op, r1, arg2)
+            }
+            OpCode::Load | OpCode::Store => {
+                format!("{} r{}, [{}]", op, r1, AddressOperand::decode(arg2.to_le_bytes()))
             }
             OpCode::Jz | OpCode::Jnz => {
                 let what = self.describe_addr(arg2 as usize);
-                format!("{:?} r{}, {} ({})", op, r1, arg2, what)
+                format!("{} r{}, {} ({})", op, r1, arg2, what)
             }
             OpCode::Movi => {
                 format!("MOVI r{}, {}", r1, arg2)
             }
             OpCode::Loadi | OpCode::Storei => {
                 let what = self.describe_addr(arg2 as usize);
-                format!("{:?} r{}, [{}] ({})", op, r1, arg2, what)
+                format!("{} r{}, [{}] ({})", op, r1, arg2, what)
             }
-            _ => format!("{:?} r{}, {}", op, r1, arg2),
+            _ => format!("{} r{}, {}", op, r1, arg2),
         }
       }
       OpCode::Jmp | OpCode::Call => {
-        if pc + 5 > self.heap.len() { return format!("{:?} <truncated>", op); }
+        if pc + 5 > self.heap.len() { return format!("{} <truncated>", op); }
         let addr = self.fetch_u32(pc + 1);
         let what = self.describe_addr(addr as usize);
-        format!("{:?} {} ({})", op, addr, what)
+        format!("{} {} ({})", op, addr, what)
       }
       OpCode::Push | OpCode::Pop => {
-        if pc + 5 > self.heap.len() { return format!("{:?} <truncated>", op); }
-        let reg = self.fetch_reg(pc + 1);
-        format!("{:?} r{}", op, reg)
+        if pc + 5 > self.heap.len() { return format!("{} <truncated>", op); }
+        let reg = self.heap_byte(pc + 1);
+        format!("{} r{}", op, reg)
       }
       OpCode::Ret => "RET".to_string(),
       OpCode::Syscall => "SYSCALL".to_string(),
       OpCode::Halt => "HALT".to_string(),
       OpCode::Break => "BREAK".to_string(),
       OpCode::Nop => "NOP".to_string(),
-      _ => format!("{:?} ({:02X})", op, op_byte),
+      _ => format!("{} ({:02X})", op, op_byte),
     }
   }
 
   fn describe_addr(&self, addr: usize) -> String {
+    let data = self.data_base..self.data_base + self.data_len;
+    let rodata = self.rodata_base..self.rodata_base + self.rodata_len;
     if addr < self.code_len {
       format!(".text+{}", addr)
-    } else if addr < self.code_len + self.data_len {
-      let off = addr - self.code_len;
+    } else if data.contains(&addr) {
       // Try to show data, as string if printable
-      let mut end = off;
-      while end < self.data_len && self.heap[self.code_len + end] != 0 {
-        end += 1;
-      }
-      let data = &self.heap[addr..(addr + (end - off)).min(self.heap.len())];
-      let text = String::from_utf8_lossy(data);
-      format!(".data+{} ('{}')", off, text)
-    } else if addr < self.code_len + self.data_len + self.rodata_len {
-      let off = addr - self.code_len - self.data_len;
-      let mut end = off;
-      while end < self.rodata_len && self.heap[self.code_len + self.data_len + end] != 0 {
-        end += 1;
-      }
-      let data = &self.heap[addr..(addr + (end - off)).min(self.heap.len())];
-      let text = String::from_utf8_lossy(data);
-      format!(".rodata+{} ('{}')", off, text)
+      format!(".data+{} ('{}')", addr - data.start, self.c_string(addr, data.end))
+    } else if rodata.contains(&addr) {
+      format!(".rodata+{} ('{}')", addr - rodata.start, self.c_string(addr, rodata.end))
     } else {
       format!("heap+{}", addr)
     }
   }
+
+  /// Bytes from `addr` up to a NUL or `end`, lossily as text.
+  fn c_string(&self, addr: usize, end: usize) -> String {
+    let bytes = &self.heap[addr..end];
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+  }
+}
+
+fn symbol_at<'a>(symbols: impl Iterator<Item = &'a SymbolEntry>, pc: usize) -> Option<(&'a str, usize)> {
+  symbols
+    .filter(|s| s.offset as usize <= pc)
+    .max_by_key(|s| s.offset)
+    .map(|s| (s.name.as_str(), pc - s.offset as usize))
+}
+
+/// Check the magic number, version and ISA revision of a `.leafobj`, `.leafexe` or `.leafso` file, and that its
+/// symbol and relocation tables are consistent (`LeafAsmObject::check`).
+pub(crate) fn check_header(file: &LeafAsmFile) -> Result<(), LeafError> {
+  if file.header.magic != *b"LAF\0" {
+    return Err(FormatError::BadMagic(file.header.magic).into());
+  }
+  if !(1..=FORMAT_VERSION).contains(&file.header.version) {
+    return Err(FormatError::UnsupportedVersion(file.header.version).into());
+  }
+  if file.header.required_isa_version() > ISA_VERSION {
+    return Err(FormatError::UnsupportedIsaVersion(file.header.isa_version).into());
+  }
+  file.object.check().into_result().map_err(|report| LeafError::from(FormatError::Invalid(report)))?;
+  file.verify_checksum()
+}
+
+/// The first of `sections` that runs into a later one: by how many bytes, and its largest symbols,
+/// which are the ones to move or shrink to make it fit. `.text` is at 0, so a section placed inside
+/// it counts even if it is empty.
+fn section_overflow(symbols: &[SymbolEntry], sections: &[(&str, u8, Range<usize>)]) -> Option<String> {
+  for (i, (name, section, range)) in sections.iter().enumerate() {
+    for (other, other_section, other_range) in &sections[i + 1..] {
+      let clash = match (*section, *other_section) {
+        (0, _) | (_, 0) => other_range.start < range.end,
+        _ => range.start < other_range.end && other_range.start < range.end,
+      };
+      if !clash {
+        continue;
+      }
+      let ((name, section, range), (other, other_start)) = if range.start <= other_range.start {
+        ((name, *section, range), (other, other_range.start))
+      } else {
+        ((other, *other_section, other_range), (name, range.start))
+      };
+      let mut message = format!("{} at 0x{:X}..0x{:X} runs 0x{:X} bytes into {}, which starts at 0x{:X}",
+        name, range.start, range.end, range.end - other_start, other, other_start);
+      let largest = largest_symbols(symbols, section, range.len(), 3);
+      if !largest.is_empty() {
+        let largest: Vec<String> = largest.iter().map(|(name, size)| format!("{} (0x{:X} bytes)", name, size)).collect();
+        message.push_str(&format!("; its largest symbols are {}", largest.join(", ")));
+      }
+      return Some(message);
+    }
+  }
+  None
 }
 
-pub fn disassembly_dump(object: &LeafAsmFile, vm: &VM) {
-  let code = &object.object.bytecode;
-  let mut pc = 0usize;
+/// Up to `count` symbols of `section`, biggest first, each taken to run up to the next symbol.
+fn largest_symbols(symbols: &[SymbolEntry], section: u8, len: usize, count: usize) -> Vec<(&str, usize)> {
+  let mut defined: Vec<&SymbolEntry> = symbols.iter().filter(|s| !s.external && s.section == section).collect();
+  defined.sort_by_key(|s| s.offset);
+  let mut sizes: Vec<(&str, usize)> = defined.iter().enumerate()
+    .map(|(i, s)| {
+      let end = defined[i + 1..].iter().map(|next| next.offset as usize).find(|&end| end > s.offset as usize).unwrap_or(len);
+      (s.name.as_str(), end.max(s.offset as usize) - s.offset as usize)
+    })
+    .filter(|(_, size)| *size > 0)
+    .collect();
+  sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+  sizes.truncate(count);
+  sizes
+}
 
+pub fn disassembly_dump(object: &LeafAsmFile) {
   info!("offset | bytes                                    | expected");
   info!("-----------------------------------------------------------------------");
+  for line in disassemble(&object.object.bytecode).lines() {
+    info!("{}", line);
+  }
+}
 
-  while pc < code.len() {
-    let offset = pc;
-    let (disasm, instr_len) = disassemble_at(vm, code, pc);
-    let instr_bytes: Vec<String> = code[pc..pc + instr_len.min(code.len() - pc)]
-      .iter()
-      .map(|b| format!("{:02X}", b))
-      .collect();
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use leaf_common::checksum::{Checksum, ChecksumAlgorithm};
+  use leaf_common::leaf_file::{ByteOrder, RelocationEntry, RelocationType};
+  use leaf_common::object_builder::LeafAsmObjectBuilder;
 
-    info!("0x{:04X} | {:<40} | {}", offset, instr_bytes.join(" "), disasm);
+  /// Encode one instruction: opcode byte, then each operand as a 4-byte little-endian word.
+  fn instr(opcode: OpCode, operands: &[u32]) -> Vec<u8> {
+    let mut bytes = vec![OpCode::opcode_to_byte(&opcode)];
+    for operand in operands {
+      bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+    bytes
+  }
 
-    pc += instr_len;
+  fn run(object: &LeafAsmObject) -> VM {
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(object).unwrap();
+    vm.run();
+    vm
   }
-}
 
-fn disassemble_at(_vm: &VM, code: &[u8], pc: usize) -> (String, usize) {
-  if pc >= code.len() {
-    return ("<invalid PC>".to_string(), 1);
+  #[test]
+  fn runs_arithmetic_until_halt() {
+    let code = [
+      instr(OpCode::Movi, &[1, 5]),
+      instr(OpCode::Movi, &[2, 7]),
+      instr(OpCode::Add, &[3, 1, 2]),
+      instr(OpCode::Halt, &[]),
+      instr(OpCode::Movi, &[3, 0]), // never reached
+    ].concat();
+    let vm = run(&LeafAsmObjectBuilder::new().text(code).build().unwrap());
+    assert_eq!(vm.registers[3], 12);
+    assert_eq!(vm.pc, 32);
   }
 
-  let op = OpCode::byte_to_opcode(code[pc]).unwrap_or(OpCode::Invalid);
+  #[test]
+  fn starts_at_entry_point_and_uses_the_stack() {
+    // helper: MOVI r1, 9; RET    main: CALL helper; HALT
+    let code = [
+      instr(OpCode::Movi, &[1, 9]),
+      instr(OpCode::Ret, &[]),
+      instr(OpCode::Call, &[0]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .define("helper", 0, 0)
+      .define("main", 0, 10)
+      .absolute_relocation(11, "helper", 0)
+      .entry_point("main")
+      .build()
+      .unwrap();
+    let vm = run(&object);
+    assert_eq!(vm.registers[1], 9);
+    assert_eq!(vm.registers[15], 0x1000);
+  }
 
-  match op {
-    OpCode::Movi => {
-      // MOVI reg, imm/label
-      if pc + 9 <= code.len() {
-        let reg = code[pc + 1];
-        let imm = u32::from_le_bytes([code[pc + 5], code[pc + 6], code[pc + 7], code[pc + 8]]);
-        (format!("MOVI r{}, {}", reg, imm), 9)
-      } else {
-        ("MOVI <truncated>".to_string(), code.len() - pc)
-      }
-    }
-    OpCode::Mov => {
-      if pc + 9 <= code.len() {
-        let r1 = code[pc + 1];
-        let r2 = code[pc + 5];
-        (format!("MOV r{}, r{}", r1, r2), 9)
-      } else {
-        ("MOV <truncated>".to_string(), code.len() - pc)
-      }
-    }
-    OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div |
-    OpCode::And | OpCode::Or | OpCode::Xor => {
-      if pc + 13 <= code.len() {
-        let r1 = code[pc + 1];
-        let r2 = code[pc + 5];
-        let r3 = code[pc + 9];
-        (format!("{:?} r{}, r{}, r{}", op, r1, r2, r3), 13)
-      } else {
-        (format!("{:?} <truncated>", op), code.len() - pc)
-      }
-    }
-    OpCode::Not => {
-      if pc + 9 <= code.len() {
-        let r1 = code[pc + 1];
-        let r2 = code[pc + 5];
-        (format!("NOT r{}, r{}", r1, r2), 9)
-      } else {
-        ("NOT <truncated>".to_string(), code.len() - pc)
-      }
-    }
-    OpCode::Jmp => {
-      if pc + 5 <= code.len() {
-        let addr = u32::from_le_bytes([code[pc + 1], code[pc + 2], code[pc + 3], code[pc + 4]]);
-        (format!("JMP {}", addr), 5)
-      } else {
-        ("JMP <truncated>".to_string(), code.len() - pc)
-      }
-    }
-    OpCode::Jz | OpCode::Jnz => {
-      if pc + 9 <= code.len() {
-        let r1 = code[pc + 1];
-        let addr = u32::from_le_bytes([code[pc + 5], code[pc + 6], code[pc + 7], code[pc + 8]]);
-        (format!("{:?} r{}, {}", op, r1, addr), 9)
-      } else {
-        (format!("{:?} <truncated>", op), code.len() - pc)
-      }
-    }
-    OpCode::Load | OpCode::Store => {
-      if pc + 9 <= code.len() {
-        let r1 = code[pc + 1];
-        let r2 = code[pc + 5];
-        (format!("{:?} r{}, [r{}]", op, r1, r2), 9)
-      } else {
-        (format!("{:?} <truncated>", op), code.len() - pc)
-      }
-    }
-    OpCode::Loadi | OpCode::Storei => {
-      if pc + 9 <= code.len() {
-        let r1 = code[pc + 1];
-        let addr = u32::from_le_bytes([code[pc + 5], code[pc + 6], code[pc + 7], code[pc + 8]]);
-        (format!("{:?} r{}, [{}]", op, r1, addr), 9)
-      } else {
-        (format!("{:?} <truncated>", op), code.len() - pc)
-      }
-    }
-    OpCode::Call => {
-      if pc + 5 <= code.len() {
-        let addr = u32::from_le_bytes([code[pc + 1], code[pc + 2], code[pc + 3], code[pc + 4]]);
-        (format!("CALL {}", addr), 5)
-      } else {
-        ("CALL <truncated>".to_string(), code.len() - pc)
-      }
-    }
-    OpCode::Ret | OpCode::Break | OpCode::Halt | OpCode::Syscall | OpCode::Nop => {
-      (format!("{:?}", op), 1)
-    }
-    OpCode::Push | OpCode::Pop => {
-      if pc + 5 <= code.len() {
-        let r1 = code[pc + 1];
-        (format!("{:?} r{}", op, r1), 5)
-      } else {
-        (format!("{:?} <truncated>", op), code.len() - pc)
-      }
-    }
-    _ => ("<invalid>".to_string(), 1)
+  #[test]
+  fn writes_to_text_and_rodata_fault_with_the_symbol() {
+    // STOREI r1, [message+3]; HALT   where .text is 10 bytes and .data 8
+    let code = [instr(OpCode::Storei, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(vec![0; 8])
+      .rodata(b"hello, world".to_vec())
+      .define("message", 2, 0)
+      .absolute_relocation(5, "message", 0)
+      .build()
+      .unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    vm.heap[5..9].copy_from_slice(&21u32.to_le_bytes());
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Fault("STOREI to .rodata at 0x0015 (message+0x3) at pc=0000".to_string())));
+    assert_eq!(&vm.heap[18..30], b"hello, world");
+
+    vm.load_object(&object).unwrap();
+    vm.heap[5..9].copy_from_slice(&2u32.to_le_bytes());
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Fault("STOREI to .text at 0x0002 at pc=0000".to_string())));
+
+    vm.memory_protection = false;
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+  }
+
+  #[test]
+  fn files_open_inside_the_filesystem_root() {
+    let root = std::env::temp_dir().join(format!("leaf-vm-files-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("in.txt"), "hello world").unwrap();
+    let syscall = |number: u64, args: &[Vec<u8>]| [args.concat(), instr(OpCode::Movi, &[0, number as u32]), instr(OpCode::Syscall, &[])].concat();
+    let movi = |r: u32, value: u32| instr(OpCode::Movi, &[r, value]);
+    let fd = || instr(OpCode::Mov, &[1, 10]);
+    // Names at .data+0, +7 and +17, and a buffer at +25
+    let code = |data: u32| [
+      syscall(SYS_OPEN, &[movi(1, data), movi(2, 0)]),
+      instr(OpCode::Mov, &[10, 0]),
+      syscall(SYS_SEEK, &[fd(), movi(2, 6), movi(3, 0)]),
+      instr(OpCode::Mov, &[13, 0]),
+      syscall(SYS_READ, &[fd(), movi(2, data + 25), movi(3, 5)]),
+      syscall(SYS_CLOSE, &[fd()]),
+      syscall(SYS_OPEN, &[movi(1, data + 7), movi(2, 0)]),
+      instr(OpCode::Mov, &[12, 0]),
+      syscall(SYS_OPEN, &[movi(1, data + 17), movi(2, 1)]),
+      instr(OpCode::Mov, &[10, 0]),
+      syscall(SYS_WRITE, &[fd(), movi(2, data + 25), movi(3, 5)]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let text = code(code(0).len() as u32);
+    let data = [&b"in.txt\0../in.txt\0out.txt\0"[..], &[0; 8]].concat();
+    let object = LeafAsmObjectBuilder::new().text(text).data(data).build().unwrap();
+
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.fs_root = Some(root.clone());
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[13], 6);
+    assert_eq!(vm.registers[12], u64::MAX);
+    assert_eq!(std::fs::read_to_string(root.join("out.txt")).unwrap(), "world");
+
+    // Without a root nothing opens
+    let vm = run(&object);
+    assert_eq!(vm.registers[10], u64::MAX);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn section_flags_guard_reads_writes_and_execution() {
+    let run_with = |text: Vec<u8>, data: Vec<u8>, section: u8, flags: SectionFlags| {
+      let object = LeafAsmObjectBuilder::new().text(text).data(data).rodata(vec![0; 8]).section_flags(section, flags).build().unwrap();
+      let mut vm = VM::new(0x1000);
+      vm.debug = false;
+      vm.load_object(&object).map(|()| {
+        vm.run();
+        vm.status.clone().unwrap()
+      }).map_err(|e| e.to_string())
+    };
+    let fault = |message: &str| Ok(ExitStatus::Fault(message.to_string()));
+    // .data made read-only, then .rodata unreadable; .text is 10 bytes and .data 8
+    let store = [instr(OpCode::Storei, &[1, 10]), instr(OpCode::Halt, &[])].concat();
+    assert_eq!(run_with(store, vec![0; 8], 1, SectionFlags::READ), fault("STOREI to .data at 0x000A at pc=0000"));
+    let load = [instr(OpCode::Loadi, &[1, 18]), instr(OpCode::Halt, &[])].concat();
+    assert_eq!(run_with(load, vec![0; 8], 2, SectionFlags(0)), fault("LOADI from .rodata at 0x0012 at pc=0000"));
+
+    // JMP into a HALT in .data, which only runs if .data is executable; a jump to the very start of
+    // .data would look like running off the end of .text
+    let jump = instr(OpCode::Jmp, &[6]);
+    let halt = [vec![0], instr(OpCode::Halt, &[])].concat();
+    assert_eq!(run_with(jump.clone(), halt.clone(), 1, SectionFlags::READ), fault("Execute from .data at pc=0006, which is not executable"));
+    assert_eq!(run_with(jump.clone(), halt.clone(), 1, SectionFlags::READ | SectionFlags::EXECUTE), Ok(ExitStatus::Halted));
+    let err = run_with(jump, halt, 1, SectionFlags::parse("rwx").unwrap()).unwrap_err();
+    assert_eq!(err, "invalid memory layout: .data is both writable and executable");
+  }
+
+  #[test]
+  fn layout_places_sections_and_guards_the_stack() {
+    // LOADI r1, [value]; main: CALL main
+    let code = [instr(OpCode::Loadi, &[1, 0]), instr(OpCode::Call, &[0])].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(42u64.to_le_bytes().to_vec())
+      .define("value", 1, 0)
+      .define("main", 0, 9)
+      .absolute_relocation(5, "value", 0)
+      .absolute_relocation(10, "main", 0)
+      .build()
+      .unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.layout = MemoryLayout { data_base: Some(0x100), stack_size: Some(0x40), ..MemoryLayout::default() };
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[1], 42);
+    assert_eq!(vm.registers[15], 0x1000 - 0x40);
+    assert_eq!(vm.status, Some(ExitStatus::Fault("Stack overflow in CALL at main+0x0: sp=0x0FC0, stack limit 0x0FC0".to_string())));
+
+    vm.layout.rodata_base = Some(0x104);
+    let object = LeafAsmObjectBuilder::new().text(vec![0; 8]).data(vec![0; 8]).rodata(vec![1]).build().unwrap();
+    assert!(matches!(vm.load_object(&object), Err(LeafError::Layout(_))));
+
+    vm.layout = MemoryLayout { data_base: Some(0x10), ..MemoryLayout::default() };
+    let object = LeafAsmObjectBuilder::new().text(vec![0; 0x18]).data(vec![0; 8])
+      .define("main", 0, 0).define("helper", 0, 4).define("buffer", 1, 0)
+      .build().unwrap();
+    assert_eq!(vm.load_object(&object).unwrap_err().to_string(), "invalid memory layout: \
+      .text at 0x0..0x18 runs 0x8 bytes into .data, which starts at 0x10; its largest symbols are helper (0x14 bytes), main (0x4 bytes)");
+  }
+
+  #[test]
+  fn segments_place_the_sections() {
+    // LOADI r1, [value]; STOREI r1, [value+8]; HALT   with .data at 0x200 and 16 bytes long in memory
+    let code = [instr(OpCode::Loadi, &[1, 0]), instr(OpCode::Storei, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let mut object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(7u64.to_le_bytes().to_vec())
+      .define("value", 1, 0)
+      .absolute_relocation(5, "value", 0)
+      .build()
+      .unwrap();
+    object.segments = object.packed_segments();
+    object.segments[1].address = 0x200;
+    object.segments[1].run_address = 0x200;
+    object.segments[1].mem_size = 16;
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    vm.heap[14..18].copy_from_slice(&0x208u32.to_le_bytes());
+    vm.run();
+    assert_eq!((vm.data_base, vm.data_len, vm.rodata_base), (0x200, 16, 0x210));
+    assert_eq!((vm.registers[1], vm.status.clone()), (7, Some(ExitStatus::Halted)));
+    assert_eq!(&vm.heap[0x208..0x210], &7u64.to_le_bytes());
+
+    // The layout still wins, and .text cannot move
+    vm.layout.data_base = Some(0x300);
+    vm.load_object(&object).unwrap();
+    assert_eq!(vm.data_base, 0x300);
+    object.segments[0].address = 0x40;
+    assert_eq!(vm.load_object(&object).unwrap_err().to_string(), "invalid memory layout: .text segment is at 0x40, but .text always loads at 0");
+  }
+
+  #[test]
+  fn sections_run_where_their_segments_say() {
+    // LOADI r1, [value]; HALT   with .data loaded after .text but running at 0x2000
+    let code = [instr(OpCode::Loadi, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let mut object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(7u64.to_le_bytes().to_vec())
+      .define("value", 1, 0)
+      .absolute_relocation(5, "value", 0)
+      .build()
+      .unwrap();
+    object.segments = object.packed_segments();
+    object.segments[1].run_address = 0x2000;
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    assert_eq!((vm.data_base, vm.data_run, vm.export("value")), (10, 0x2000, Some(0x2000)));
+    // Memory grows to cover it, and the program is what copies .data there
+    assert!(vm.heap.len() >= 0x2008);
+    assert_eq!(&vm.heap[0x2000..0x2008], &[0; 8]);
+    vm.heap.copy_within(10..18, 0x2000);
+    vm.run();
+    assert_eq!((vm.registers[1], vm.status.clone()), (7, Some(ExitStatus::Halted)));
+
+    vm.layout.data_base = Some(0x300);
+    assert_eq!(vm.load_object(&object).unwrap_err().to_string(),
+      "invalid memory layout: .data runs away from where it is loaded, so the layout cannot move it");
+  }
+
+  #[test]
+  fn relative_relocations_follow_the_layout() {
+    // MOVI r1, value - next instruction; HALT
+    let code = [instr(OpCode::Movi, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(vec![0; 8])
+      .define("value", 1, 0)
+      .relocation(RelocationEntry { offset: 5, symbol_index: 0, reloc_type: RelocationType::Relative, target_section: 0 })
+      .build()
+      .unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.layout = MemoryLayout { data_base: Some(0x100), ..MemoryLayout::default() };
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[1], 0x100 - 9);
+  }
+
+  #[test]
+  fn section_relocations_follow_the_layout() {
+    // MOVI r1, .data+8; HALT
+    let code = [instr(OpCode::Movi, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(vec![0; 16])
+      .section_relocation(5, 1, RelocationType::Absolute, 0)
+      .addend(0, 8)
+      .build()
+      .unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.layout = MemoryLayout { data_base: Some(0x100), ..MemoryLayout::default() };
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[1], 0x108);
+  }
+
+  #[test]
+  fn relocates_data_after_text() {
+    let code = [instr(OpCode::Loadi, &[1, 0]), instr(OpCode::Halt, &[])].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .data(42u64.to_le_bytes().to_vec())
+      .define("value", 1, 0)
+      .absolute_relocation(5, "value", 0)
+      .build()
+      .unwrap();
+    assert_eq!(run(&object).registers[1], 42);
+  }
+
+  #[test]
+  fn halt_exits_with_the_low_byte_of_r0() {
+    let code = [instr(OpCode::Movi, &[0, 0x103]), instr(OpCode::Halt, &[])].concat();
+    let vm = run(&LeafAsmObjectBuilder::new().text(code).build().unwrap());
+    assert_eq!(vm.exit_code(), Some(3));
+    assert_eq!(ExitStatus::Fault("bad".to_string()).exit_code(3), Some(FAULT_EXIT_CODE));
+  }
+
+  #[test]
+  fn exit_syscall_records_status_and_unknown_syscalls_fail() {
+    let code = [
+      instr(OpCode::Movi, &[0, 99]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Mov, &[2, 0]),
+      instr(OpCode::Movi, &[0, SYS_EXIT as u32]),
+      instr(OpCode::Movi, &[1, 7]),
+      instr(OpCode::Syscall, &[]),
+    ].concat();
+    let vm = run(&LeafAsmObjectBuilder::new().text(code).build().unwrap());
+    assert_eq!(vm.registers[2], u64::MAX);
+    assert_eq!(vm.status, Some(ExitStatus::Exited(7)));
+    assert_eq!(vm.exit_code(), Some(7));
+  }
+
+  #[test]
+  fn host_syscalls_take_precedence() {
+    let code = [
+      instr(OpCode::Movi, &[0, 0x40]),
+      instr(OpCode::Movi, &[1, 20]),
+      instr(OpCode::Syscall, &[]), // r0 = r1 + 1, memory[0x800] = 1
+      instr(OpCode::Mov, &[2, 0]),
+      instr(OpCode::Movi, &[0, SYS_EXIT as u32]),
+      instr(OpCode::Syscall, &[]), // refused by the host
+    ].concat();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.register_syscall(0x40, |regs, mem| {
+      regs[0] = regs[1] + 1;
+      mem[0x800] = 1;
+      Ok(())
+    });
+    vm.register_syscall(SYS_EXIT, |_, _| Err("exit is not allowed".to_string()));
+    vm.load_object(&LeafAsmObjectBuilder::new().text(code).build().unwrap()).unwrap();
+    vm.run();
+    assert_eq!(vm.registers[2], 21);
+    assert_eq!(vm.heap[0x800], 1);
+    assert_eq!(vm.status, Some(ExitStatus::Fault("Syscall 3 failed: exit is not allowed".to_string())));
+  }
+
+  #[test]
+  fn fuel_bounds_loops_and_deterministic_mode_refuses_the_clock() {
+    // loop: JMP loop
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.fuel = Some(100);
+    vm.load_object(&LeafAsmObjectBuilder::new().text(instr(OpCode::Jmp, &[0])).build().unwrap()).unwrap();
+    vm.run();
+    assert_eq!((vm.status, vm.fuel), (Some(ExitStatus::OutOfFuel), Some(0)));
+
+    let code = [instr(OpCode::Movi, &[0, SYS_TIME as u32]), instr(OpCode::Syscall, &[]), instr(OpCode::Halt, &[])].concat();
+    let object = LeafAsmObjectBuilder::new().text(code).build().unwrap();
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.deterministic = true;
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Fault("Syscall 10 is not allowed in deterministic mode".to_string())));
+
+    vm.allow_syscall(SYS_TIME);
+    vm.load_object(&object).unwrap();
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+  }
+
+  #[test]
+  fn timer_interrupts_run_the_vector_and_return() {
+    // __vectors: JMP tick    main: enable a 10-instruction timer and interrupts
+    // loop: r2 += 1 while r3 < 3    tick: r3 += 1; IRET
+    let code = [
+      instr(OpCode::Jmp, &[88]),
+      instr(OpCode::Movi, &[4, 1]),
+      instr(OpCode::Movi, &[5, 3]),
+      instr(OpCode::Movi, &[0, SYS_TIMER as u32]),
+      instr(OpCode::Movi, &[1, 10]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Movi, &[0, SYS_INT_ENABLE as u32]),
+      instr(OpCode::Syscall, &[]),
+      instr(OpCode::Add, &[2, 2, 4]),
+      instr(OpCode::Lt, &[6, 3, 5]),
+      instr(OpCode::Jnz, &[6, 52]),
+      instr(OpCode::Halt, &[]),
+      instr(OpCode::Add, &[3, 3, 4]),
+      instr(OpCode::Movi, &[0, SYS_IRET as u32]),
+      instr(OpCode::Syscall, &[]),
+    ].concat();
+    let object = LeafAsmObjectBuilder::new()
+      .text(code)
+      .define(VECTOR_TABLE_SYMBOL, 0, 0)
+      .define("main", 0, 5)
+      .entry_point("main")
+      .build()
+      .unwrap();
+    let vm = run(&object);
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+    assert_eq!(vm.registers[3], 3);
+    // IRET restored the r0 the handler overwrote, and popped its frame
+    assert_eq!((vm.registers[0], vm.registers[15]), (0, 0x1000));
+    assert!(vm.interrupts_enabled);
+  }
+
+  #[test]
+  fn break_calls_the_handler_with_the_current_symbol() {
+    // main: BREAK; MOVI r1, 1; BREAK; MOVI r1, 2; HALT
+    let code = [
+      instr(OpCode::Break, &[]),
+      instr(OpCode::Movi, &[1, 1]),
+      instr(OpCode::Break, &[]),
+      instr(OpCode::Movi, &[1, 2]),
+      instr(OpCode::Halt, &[]),
+    ].concat();
+    let object = LeafAsmObjectBuilder::new().text(code).define("main", 0, 0).build().unwrap();
+    let hits = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut vm = VM::new(0x1000);
+    vm.debug = false;
+    vm.load_object(&object).unwrap();
+    let seen = hits.clone();
+    vm.on_break(move |ctx| {
+      seen.borrow_mut().push((ctx.pc, ctx.symbol.map(|(name, offset)| (name.to_string(), offset)), ctx.registers[1]));
+      ctx.registers[3] += 1;
+      if ctx.pc == 0 { BreakAction::Continue } else { BreakAction::Pause }
+    });
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Breakpoint));
+    assert_eq!(vm.pc, 11);
+    assert_eq!(*hits.borrow(), vec![(0, Some(("main".to_string(), 0)), 0), (10, Some(("main".to_string(), 10)), 1)]);
+
+    // Resuming continues after the BREAK
+    vm.halted = false;
+    vm.run();
+    assert_eq!(vm.status, Some(ExitStatus::Halted));
+    assert_eq!((vm.registers[1], vm.registers[3]), (2, 2));
+  }
+
+  #[test]
+  fn rejects_bad_headers_and_stops_on_truncated_code() {
+    let mut file = LeafAsmFile {
+      header: leaf_common::leaf_file::LeafAsmObjectHeader { magic: *b"ELF\0", version: 1, byte_order: ByteOrder::Little, isa_version: 1, checksum: Checksum::Crc32(0), build_id: None },
+      object: LeafAsmObject::default(),
+    };
+    let mut vm = VM::new(0x100);
+    assert!(matches!(vm.load_program(&file), Err(LeafError::Format(FormatError::BadMagic(_)))));
+    file.header.magic = *b"LAF\0";
+    file.header.version = 9;
+    assert!(matches!(vm.load_program(&file), Err(LeafError::Format(FormatError::UnsupportedVersion(9)))));
+    file.header.version = 1;
+    file.header.isa_version = ISA_VERSION + 1;
+    assert!(matches!(vm.load_program(&file), Err(LeafError::Format(FormatError::UnsupportedIsaVersion(v))) if v == ISA_VERSION + 1));
+    file.header.isa_version = 0;
+    file.object.relocations.push(RelocationEntry { offset: 0, symbol_index: 2, reloc_type: RelocationType::Absolute, target_section: 1 });
+    let err = vm.load_program(&file).unwrap_err();
+    assert!(matches!(err, LeafError::Format(FormatError::Invalid(ref report)) if report.problems.len() == 2));
+    file.object.relocations.clear();
+    let err = vm.load_program(&file).unwrap_err();
+    assert!(matches!(err, LeafError::Format(FormatError::ChecksumMismatch { .. })));
+    file.header.checksum = ChecksumAlgorithm::Sha256.zero();
+    file.header.version = FORMAT_VERSION;
+    file.header.checksum = file.compute_checksum().unwrap();
+    assert!(vm.load_program(&file).is_ok());
+
+    let vm = run(&LeafAsmObject { bytecode: vec![0x16, 1, 0], ..LeafAsmObject::default() });
+    assert!(vm.halted);
+    assert_eq!(vm.pc, 0);
   }
 }